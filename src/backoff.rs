@@ -0,0 +1,157 @@
+use bazuka::client::PeerAddress;
+use rand::Rng;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+// Consecutive failures before we stop talking to a peer for a while
+const BREAKER_THRESHOLD: u32 = 5;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(120);
+
+/// Exponential backoff with "equal jitter": the delay is somewhere between
+/// half and the whole of `base * 2^failures`, so that workers which failed
+/// together don't retry together.
+#[derive(Debug, Clone, Default)]
+pub struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = std::cmp::min(
+            MAX_DELAY,
+            BASE_DELAY.saturating_mul(1u32 << self.failures.min(16)),
+        );
+        self.failures = self.failures.saturating_add(1);
+        let half = delay / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    backoff: Backoff,
+    consecutive_failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// Tracks the failures of each peer. Peers are skipped until their backoff
+/// delay is over, and once they have failed too many times in a row, their
+/// circuit is opened and they're left alone for a longer cooldown.
+#[derive(Debug, Default)]
+pub struct PeerHealth {
+    peers: HashMap<PeerAddress, PeerState>,
+}
+
+impl PeerHealth {
+    pub fn is_available(&self, peer: &PeerAddress) -> bool {
+        self.peers
+            .get(peer)
+            .and_then(|s| s.retry_at)
+            .map(|at| Instant::now() >= at)
+            .unwrap_or(true)
+    }
+
+    pub fn record_success(&mut self, peer: &PeerAddress) {
+        self.peers.remove(peer);
+    }
+
+    pub fn record_failure(&mut self, peer: &PeerAddress) {
+        let state = self.peers.entry(*peer).or_default();
+        state.consecutive_failures += 1;
+        let delay = if state.consecutive_failures >= BREAKER_THRESHOLD {
+            if state.consecutive_failures == BREAKER_THRESHOLD {
+                log::warn!(
+                    "{} failed {} times in a row, ignoring it for {}s",
+                    peer,
+                    BREAKER_THRESHOLD,
+                    BREAKER_COOLDOWN.as_secs()
+                );
+            }
+            BREAKER_COOLDOWN
+        } else {
+            state.backoff.next_delay()
+        };
+        state.retry_at = Some(Instant::now() + delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn peer(port: u16) -> PeerAddress {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn test_backoff_grows_exponentially() {
+        let mut backoff = Backoff::default();
+        for failures in 0..6 {
+            let full = BASE_DELAY * (1 << failures);
+            let delay = backoff.next_delay();
+            assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let mut backoff = Backoff::default();
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= MAX_DELAY);
+        }
+        assert!(backoff.next_delay() >= MAX_DELAY / 2);
+    }
+
+    #[test]
+    fn test_backoff_reset() {
+        let mut backoff = Backoff::default();
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= BASE_DELAY);
+    }
+
+    #[test]
+    fn test_failing_peer_is_skipped_until_success() {
+        let mut health = PeerHealth::default();
+        let (a, b) = (peer(8765), peer(8766));
+        health.record_failure(&a);
+        assert!(!health.is_available(&a));
+        assert!(health.is_available(&b));
+        health.record_success(&a);
+        assert!(health.is_available(&a));
+        assert_eq!(health.peers.get(&a).map(|s| s.consecutive_failures), None);
+    }
+
+    #[test]
+    fn test_breaker_opens_after_threshold() {
+        let mut health = PeerHealth::default();
+        let a = peer(8765);
+        for _ in 0..BREAKER_THRESHOLD - 1 {
+            health.record_failure(&a);
+        }
+        let retry_at = health.peers[&a].retry_at.unwrap();
+        assert!(retry_at < Instant::now() + BREAKER_COOLDOWN / 2);
+        health.record_failure(&a);
+        let retry_at = health.peers[&a].retry_at.unwrap();
+        assert!(retry_at > Instant::now() + BREAKER_COOLDOWN - Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_record_result() {
+        let health = Mutex::new(PeerHealth::default());
+        let a = peer(8765);
+        crate::client::record_result(&health, &a, &Err::<(), _>(()));
+        assert!(!health.lock().unwrap().is_available(&a));
+        crate::client::record_result(&health, &a, &Ok::<_, ()>(()));
+        assert!(health.lock().unwrap().is_available(&a));
+    }
+}
@@ -1,5 +1,9 @@
 use crate::circuits;
+use crate::debug::{DebugSystem, Unsatisfied};
+use crate::shapes::Shape;
+use crate::synthesis::SynthesizedCircuit;
 
+use bazuka::core::TokenId;
 use bazuka::zk::ZkScalar;
 use bellman::groth16;
 use bellman::groth16::Backend;
@@ -15,24 +19,40 @@ pub enum BankError {
     CannotProve(#[from] bellman::SynthesisError),
     #[error("snark proof incorrect!")]
     IncorrectProof,
+    #[error("proving cancelled!")]
+    Cancelled,
     #[error("kv-store error: {0}")]
     KvStoreError(#[from] bazuka::db::KvStoreError),
+    #[error("work of shape {0} cannot be proven with the params of shape {1}!")]
+    ShapeMismatch(Shape, Shape),
+    #[error("params of the {0} circuit are not loaded!")]
+    MissingParams(&'static str),
 }
 #[derive(Clone)]
 pub struct ZoroParams {
+    pub shape: Shape,
     pub deposit: groth16::Parameters<Bls12>,
     pub withdraw: groth16::Parameters<Bls12>,
     pub update: groth16::Parameters<Bls12>,
+    /// Only needed by the networks accepting multi-fee update batches
+    pub multi_fee_update: Option<groth16::Parameters<Bls12>>,
 }
 
 impl ZoroParams {
-    fn verify_keys(&self) -> ZoroVerifyKeys {
+    pub fn verify_keys(&self) -> ZoroVerifyKeys {
         ZoroVerifyKeys {
             update: self.update.vk.clone().into(),
             deposit: self.deposit.vk.clone().into(),
             withdraw: self.withdraw.vk.clone().into(),
+            multi_fee_update: self.multi_fee_update.as_ref().map(|p| p.vk.clone().into()),
         }
     }
+
+    fn multi_fee_update(&self) -> Result<&groth16::Parameters<Bls12>, BankError> {
+        self.multi_fee_update
+            .as_ref()
+            .ok_or(BankError::MissingParams("multi-fee-update"))
+    }
 }
 
 #[derive(Clone)]
@@ -40,6 +60,13 @@ pub struct ZoroVerifyKeys {
     pub deposit: bazuka::zk::groth16::Groth16VerifyingKey,
     pub withdraw: bazuka::zk::groth16::Groth16VerifyingKey,
     pub update: bazuka::zk::groth16::Groth16VerifyingKey,
+    pub multi_fee_update: Option<bazuka::zk::groth16::Groth16VerifyingKey>,
+}
+
+impl std::fmt::Debug for ZoroVerifyKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZoroVerifyKeys").finish_non_exhaustive()
+    }
 }
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
@@ -88,6 +115,10 @@ impl<
             ZoroCircuit::Deposit(_) => params.deposit.clone(),
             ZoroCircuit::Withdraw(_) => params.withdraw.clone(),
             ZoroCircuit::Update(_) => params.update.clone(),
+            ZoroCircuit::MultiFeeUpdate(_) => match &params.multi_fee_update {
+                Some(vk) => vk.clone(),
+                None => return false,
+            },
         }
         .into();
         bazuka::zk::groth16::groth16_verify(
@@ -100,37 +131,14 @@ impl<
             proof,
         )
     }
-    pub fn prove(
+    pub fn check_proof(
         &self,
-        params: ZoroParams,
-        backend: Backend,
-        cancel: Option<Arc<RwLock<bool>>>,
+        params: &ZoroParams,
+        proof: bellman::groth16::Proof<Bls12>,
     ) -> Result<bazuka::zk::groth16::Groth16Proof, BankError> {
         let proof = unsafe {
             std::mem::transmute::<bellman::groth16::Proof<Bls12>, bazuka::zk::groth16::Groth16Proof>(
-                match &self.circuit {
-                    ZoroCircuit::Deposit(circuit) => groth16::create_random_proof_with_backend(
-                        circuit.clone(),
-                        &params.deposit.clone(),
-                        &mut OsRng,
-                        backend.clone(),
-                        cancel.clone(),
-                    )?,
-                    ZoroCircuit::Withdraw(circuit) => groth16::create_random_proof_with_backend(
-                        circuit.clone(),
-                        &params.withdraw.clone(),
-                        &mut OsRng,
-                        backend.clone(),
-                        cancel.clone(),
-                    )?,
-                    ZoroCircuit::Update(circuit) => groth16::create_random_proof_with_backend(
-                        circuit.clone(),
-                        &params.update.clone(),
-                        &mut OsRng,
-                        backend.clone(),
-                        cancel.clone(),
-                    )?,
-                },
+                proof,
             )
         };
         let vks = params.verify_keys();
@@ -141,6 +149,98 @@ impl<
             Err(BankError::IncorrectProof)
         }
     }
+    pub fn prove(
+        &self,
+        params: ZoroParams,
+        backend: Backend,
+        cancel: Option<Arc<RwLock<bool>>>,
+    ) -> Result<bazuka::zk::groth16::Groth16Proof, BankError> {
+        let proof = match &self.circuit {
+            ZoroCircuit::Deposit(circuit) => groth16::create_random_proof_with_backend(
+                circuit.clone(),
+                &params.deposit.clone(),
+                &mut OsRng,
+                backend.clone(),
+                cancel.clone(),
+            )?,
+            ZoroCircuit::Withdraw(circuit) => groth16::create_random_proof_with_backend(
+                circuit.clone(),
+                &params.withdraw.clone(),
+                &mut OsRng,
+                backend.clone(),
+                cancel.clone(),
+            )?,
+            ZoroCircuit::Update(circuit) => groth16::create_random_proof_with_backend(
+                circuit.clone(),
+                &params.update.clone(),
+                &mut OsRng,
+                backend.clone(),
+                cancel.clone(),
+            )?,
+            ZoroCircuit::MultiFeeUpdate(circuit) => groth16::create_random_proof_with_backend(
+                circuit.clone(),
+                params.multi_fee_update()?,
+                &mut OsRng,
+                backend.clone(),
+                cancel.clone(),
+            )?,
+        };
+        self.check_proof(&params, proof)
+    }
+    /// Calculates the witness of the work without touching the MSM backend.
+    pub fn synthesize(&self) -> Result<SynthesizedCircuit, BankError> {
+        Ok(match &self.circuit {
+            ZoroCircuit::Deposit(circuit) => SynthesizedCircuit::synthesize(circuit.clone())?,
+            ZoroCircuit::Withdraw(circuit) => SynthesizedCircuit::synthesize(circuit.clone())?,
+            ZoroCircuit::Update(circuit) => SynthesizedCircuit::synthesize(circuit.clone())?,
+            ZoroCircuit::MultiFeeUpdate(circuit) => {
+                SynthesizedCircuit::synthesize(circuit.clone())?
+            }
+        })
+    }
+    /// Finds the first constraint the witness of the work doesn't satisfy.
+    pub fn debug(&self) -> Result<Option<Unsatisfied>, BankError> {
+        Ok(match &self.circuit {
+            ZoroCircuit::Deposit(circuit) => DebugSystem::check(circuit.clone())?,
+            ZoroCircuit::Withdraw(circuit) => DebugSystem::check(circuit.clone())?,
+            ZoroCircuit::Update(circuit) => DebugSystem::check(circuit.clone())?,
+            ZoroCircuit::MultiFeeUpdate(circuit) => DebugSystem::check(circuit.clone())?,
+        })
+    }
+    /// Same as `prove`, but with a witness previously calculated by `synthesize`.
+    pub fn prove_synthesized(
+        &self,
+        synthesized: SynthesizedCircuit,
+        params: ZoroParams,
+        backend: Backend,
+        cancel: Option<Arc<RwLock<bool>>>,
+    ) -> Result<bazuka::zk::groth16::Groth16Proof, BankError> {
+        let proof = self.prove_synthesized_unchecked(synthesized, &params, backend, cancel)?;
+        self.check_proof(&params, proof)
+    }
+    /// Creates the proof without verifying it, `check_proof` should be called
+    /// on the result before it's submitted.
+    pub fn prove_synthesized_unchecked(
+        &self,
+        synthesized: SynthesizedCircuit,
+        params: &ZoroParams,
+        backend: Backend,
+        cancel: Option<Arc<RwLock<bool>>>,
+    ) -> Result<bellman::groth16::Proof<Bls12>, BankError> {
+        let circuit_params = match &self.circuit {
+            ZoroCircuit::Deposit(_) => &params.deposit,
+            ZoroCircuit::Withdraw(_) => &params.withdraw,
+            ZoroCircuit::Update(_) => &params.update,
+            ZoroCircuit::MultiFeeUpdate(_) => params.multi_fee_update()?,
+        };
+        Ok(groth16::create_random_proof_with_backend(
+            synthesized,
+            circuit_params,
+            &mut OsRng,
+            backend,
+            cancel,
+        )?)
+    }
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -172,4 +272,44 @@ pub enum ZoroCircuit<
             { LOG4_TOKENS_TREE_SIZE },
         >,
     ),
+    MultiFeeUpdate(
+        circuits::MultiFeeUpdateCircuit<
+            { LOG4_UPDATE_BATCH_SIZE },
+            { LOG4_TREE_SIZE },
+            { LOG4_TOKENS_TREE_SIZE },
+        >,
+    ),
+}
+
+impl<
+        const LOG4_DEPOSIT_BATCH_SIZE: u8,
+        const LOG4_WITHDRAW_BATCH_SIZE: u8,
+        const LOG4_UPDATE_BATCH_SIZE: u8,
+        const LOG4_TREE_SIZE: u8,
+        const LOG4_TOKENS_TREE_SIZE: u8,
+    >
+    ZoroCircuit<
+        LOG4_DEPOSIT_BATCH_SIZE,
+        LOG4_WITHDRAW_BATCH_SIZE,
+        LOG4_UPDATE_BATCH_SIZE,
+        LOG4_TREE_SIZE,
+        LOG4_TOKENS_TREE_SIZE,
+    >
+{
+    pub fn name(&self) -> &'static str {
+        match self {
+            ZoroCircuit::Deposit(_) => "deposit",
+            ZoroCircuit::Withdraw(_) => "withdraw",
+            ZoroCircuit::Update(_) => "update",
+            ZoroCircuit::MultiFeeUpdate(_) => "multi_fee_update",
+        }
+    }
+    /// Tokens the fees are collected in, for the circuits which have fees.
+    pub fn fee_tokens(&self) -> Vec<TokenId> {
+        match self {
+            ZoroCircuit::Update(circuit) => vec![circuit.fee_token],
+            ZoroCircuit::MultiFeeUpdate(circuit) => circuit.fee_tokens.to_vec(),
+            _ => vec![],
+        }
+    }
 }
@@ -0,0 +1,31 @@
+use bellman::{ConstraintSystem, SynthesisError};
+use zeekit::common::{Number, UnsignedInteger};
+use zeekit::BellmanFr;
+
+/// Balance after receiving `amount`. Constrained to 64 bits, so that it can't
+/// overflow.
+pub fn increase_balance<CS: ConstraintSystem<BellmanFr>>(
+    cs: &mut CS,
+    balance: &UnsignedInteger,
+    amount: &UnsignedInteger,
+) -> Result<UnsignedInteger, SynthesisError> {
+    UnsignedInteger::constrain(
+        &mut *cs,
+        Number::from(balance.clone()) + Number::from(amount.clone()),
+        64,
+    )
+}
+
+/// Balance after paying `amount`. A negative balance wraps around the field,
+/// so constraining the result to 64 bits also checks `amount <= balance`.
+pub fn decrease_balance<CS: ConstraintSystem<BellmanFr>>(
+    cs: &mut CS,
+    balance: &UnsignedInteger,
+    amount: &UnsignedInteger,
+) -> Result<UnsignedInteger, SynthesisError> {
+    UnsignedInteger::constrain(
+        &mut *cs,
+        Number::from(balance.clone()) - Number::from(amount.clone()),
+        64,
+    )
+}
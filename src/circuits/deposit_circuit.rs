@@ -1,3 +1,4 @@
+use super::balance::increase_balance;
 use bazuka::core::{Money, MpnDeposit};
 use bazuka::crypto::jubjub;
 use bazuka::zk::{MpnAccount, ZkScalar};
@@ -58,7 +59,7 @@ pub struct DepositTransitionBatch<
     const LOG4_BATCH_SIZE: u8,
     const LOG4_TREE_SIZE: u8,
     const LOG4_TOKENS_TREE_SIZE: u8,
->(Vec<DepositTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>);
+>(pub(crate) Vec<DepositTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>);
 impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8>
     DepositTransitionBatch<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>
 {
@@ -98,6 +99,8 @@ pub struct DepositCircuit<
     pub next_state: ZkScalar, // Public
     pub transitions:
         Box<DepositTransitionBatch<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>, // Secret :)
+    /// Range-check the balances (See `Dimensions::strict_circuits`)
+    pub strict: bool,
 }
 
 impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8>
@@ -142,7 +145,8 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
         // Uncompress all the Deposit txs that were compressed inside aux_witness
         let mut tx_wits = Vec::new();
         let mut children = Vec::new();
-        for trans in self.transitions.0.iter() {
+        for (i, trans) in self.transitions.0.iter().enumerate() {
+            let cs = &mut cs.namespace(|| format!("tx {}", i));
             // If enabled, transaction is validated, otherwise neglected
             let enabled = AllocatedBit::alloc(&mut *cs, Some(trans.enabled))?;
 
@@ -163,8 +167,10 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
                 amount.clone(),
                 pub_key.clone(),
             ));
-            let pub_key_hash =
-                poseidon::poseidon(&mut *cs, &[&pub_key.x.into(), &pub_key.y.into()])?;
+            let pub_key_hash = poseidon::poseidon(
+                &mut cs.namespace(|| "pub key hash"),
+                &[&pub_key.x.into(), &pub_key.y.into()],
+            )?;
 
             let calldata = common::mux(
                 &mut *cs,
@@ -180,17 +186,26 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
                 AllocatedState::Value(calldata.into()),
             ]));
         }
-        let tx_root = reveal(&mut *cs, &state_model, &AllocatedState::Children(children))?;
+        let tx_root = reveal(
+            &mut cs.namespace(|| "tx root"),
+            &state_model,
+            &AllocatedState::Children(children),
+        )?;
         cs.enforce(
-            || "",
+            || "tx root",
             |lc| lc + aux_wit.get_variable(),
             |lc| lc + CS::one(),
             |lc| lc + tx_root.get_lc(),
         );
 
-        for (trans, (enabled_wit, tx_token_id_wit, tx_amount_wit, tx_pub_key_wit)) in
-            self.transitions.0.iter().zip(tx_wits.into_iter())
+        for (i, (trans, (enabled_wit, tx_token_id_wit, tx_amount_wit, tx_pub_key_wit))) in self
+            .transitions
+            .0
+            .iter()
+            .zip(tx_wits.into_iter())
+            .enumerate()
         {
+            let cs = &mut cs.namespace(|| format!("transition {}", i));
             // Tx index should always have at most LOG4_TREE_SIZE * 2 bits
             let tx_index_wit = UnsignedInteger::alloc(
                 &mut *cs,
@@ -205,7 +220,8 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
             )?;
 
             // Check if tx pub-key resides on the curve if tx is enabled
-            tx_pub_key_wit.assert_on_curve(&mut *cs, &enabled_wit)?;
+            tx_pub_key_wit
+                .assert_on_curve(&mut cs.namespace(|| "pub-key on curve"), &enabled_wit)?;
 
             let src_tx_nonce_wit =
                 AllocatedNum::alloc(&mut *cs, || Ok((trans.before.tx_nonce as u64).into()))?;
@@ -223,13 +239,13 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
             })?;
 
             // We don't need to make sure account balance is 64 bits. If everything works as expected
-            // nothing like this should happen.
+            // nothing like this should happen. (Unless the circuit is strict)
             let src_balance_wit = AllocatedNum::alloc(&mut *cs, || {
                 Ok(Into::<u64>::into(trans.before_balance.amount).into())
             })?;
 
             let src_token_balance_hash_wit = poseidon::poseidon(
-                &mut *cs,
+                &mut cs.namespace(|| "src token balance hash"),
                 &[
                     &src_token_id_wit.clone().into(),
                     &src_balance_wit.clone().into(),
@@ -246,7 +262,7 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
             }
 
             merkle::check_proof_poseidon4(
-                &mut *cs,
+                &mut cs.namespace(|| "balance proof"),
                 &enabled_wit,
                 &tx_token_index_wit.clone().into(),
                 &src_token_balance_hash_wit,
@@ -255,7 +271,7 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
             )?;
 
             let src_hash_wit = poseidon::poseidon(
-                &mut *cs,
+                &mut cs.namespace(|| "src account hash"),
                 &[
                     &src_tx_nonce_wit.clone().into(),
                     &src_withdraw_nonce_wit.clone().into(),
@@ -283,17 +299,17 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
                 &is_src_token_id_null,
                 &is_src_token_id_and_tx_token_id_equal,
             )?;
-            common::assert_true(&mut *cs, &token_id_valid);
+            common::assert_true(&mut cs.namespace(|| "token id"), &token_id_valid);
 
             // Address of account slot can either be empty or equal with tx destination
             let is_src_addr_null = src_addr_wit.is_null(&mut *cs)?;
             let is_src_and_tx_pub_key_equal = src_addr_wit.is_equal(&mut *cs, &tx_pub_key_wit)?;
             let addr_valid =
                 common::boolean_or(&mut *cs, &is_src_addr_null, &is_src_and_tx_pub_key_equal)?;
-            common::assert_true(&mut *cs, &addr_valid);
+            common::assert_true(&mut cs.namespace(|| "address"), &addr_valid);
 
             merkle::check_proof_poseidon4(
-                &mut *cs,
+                &mut cs.namespace(|| "account proof"),
                 &enabled_wit,
                 &tx_index_wit.clone().into(),
                 &src_hash_wit,
@@ -301,19 +317,30 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
                 &state_wit.clone().into(),
             )?;
 
-            let src_balance_lc = Number::from(src_balance_wit);
-            let tx_amount_lc = Number::from(tx_amount_wit);
+            let new_balance: Number = if self.strict {
+                // Account balance should have at most 64 bits, also once credited
+                let src_balance_wit = UnsignedInteger::constrain(
+                    &mut cs.namespace(|| "balance bits"),
+                    src_balance_wit.into(),
+                    64,
+                )?;
+                increase_balance(
+                    &mut cs.namespace(|| "balance range"),
+                    &src_balance_wit,
+                    &tx_amount_wit,
+                )?
+                .into()
+            } else {
+                Number::from(src_balance_wit) + Number::from(tx_amount_wit.clone())
+            };
 
             let new_balances_hash_wit = poseidon::poseidon(
-                &mut *cs,
-                &[
-                    &tx_token_id_wit.clone().into(),
-                    &(src_balance_lc.clone() + tx_amount_lc.clone()),
-                ],
+                &mut cs.namespace(|| "new balance hash"),
+                &[&tx_token_id_wit.clone().into(), &new_balance],
             )?;
 
             let new_balances_hash_wit = merkle::calc_root_poseidon4(
-                &mut *cs,
+                &mut cs.namespace(|| "new balances hash"),
                 &tx_token_index_wit,
                 &new_balances_hash_wit,
                 &src_balance_proof_wits,
@@ -321,7 +348,7 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
 
             // Calculate next-state hash and update state if tx is enabled
             let new_hash_wit = poseidon::poseidon(
-                &mut *cs,
+                &mut cs.namespace(|| "new account hash"),
                 &[
                     &src_tx_nonce_wit.clone().into(),
                     &src_withdraw_nonce_wit.clone().into(),
@@ -330,14 +357,23 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
                     &new_balances_hash_wit,
                 ],
             )?;
-            let next_state_wit =
-                merkle::calc_root_poseidon4(&mut *cs, &tx_index_wit, &new_hash_wit, &proof_wits)?;
-            state_wit = common::mux(&mut *cs, &enabled_wit, &state_wit.into(), &next_state_wit)?;
+            let next_state_wit = merkle::calc_root_poseidon4(
+                &mut cs.namespace(|| "next state"),
+                &tx_index_wit,
+                &new_hash_wit,
+                &proof_wits,
+            )?;
+            state_wit = common::mux(
+                &mut cs.namespace(|| "state update"),
+                &enabled_wit,
+                &state_wit.into(),
+                &next_state_wit,
+            )?;
         }
 
         // Check if applying txs result in the claimed next state
         cs.enforce(
-            || "",
+            || "next state",
             |lc| lc + state_wit.get_variable(),
             |lc| lc + CS::one(),
             |lc| lc + claimed_next_state_wit.get_variable(),
@@ -0,0 +1,1993 @@
+// Property-based tests of the MPN circuits. Random batches are applied on a
+// native model of the MPN state, every batch the model accepts should satisfy
+// its circuit (Completeness), and every mutation of a valid witness should not
+// (Soundness). Witnesses are checked by `DebugSystem`, so no params are needed.
+
+use super::*;
+use crate::debug::DebugSystem;
+use crate::mpn_model::{self, amount_of, balance, null_money, MpnState, SparseTree};
+use bazuka::core::{ContractId, Money, MpnAddress, TokenId};
+use bazuka::crypto::jubjub::{JubJub, PointAffine, PrivateKey};
+use bazuka::crypto::ZkSignatureScheme;
+use bazuka::wallet::TxBuilder;
+use bazuka::zk::{MpnAccount, ZkHasher as _, ZkScalar};
+use bellman::Circuit;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use std::str::FromStr;
+use zeekit::{merkle, BellmanFr};
+
+const LOG4_BATCH_SIZE: u8 = 1;
+const LOG4_TREE_SIZE: u8 = 1;
+const LOG4_TOKENS_TREE_SIZE: u8 = 1;
+
+const BATCH_SIZE: usize = 1 << (2 * LOG4_BATCH_SIZE);
+const NUM_ACCOUNTS: usize = 1 << (2 * LOG4_TREE_SIZE);
+const NUM_TOKEN_SLOTS: u64 = 1 << (2 * LOG4_TOKENS_TREE_SIZE);
+const NUM_TOKENS: usize = 2;
+
+type Deposits = DepositCircuit<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>;
+type Withdraws = WithdrawCircuit<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>;
+type Updates = UpdateCircuit<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>;
+type MultiFeeUpdates =
+    MultiFeeUpdateCircuit<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>;
+type Rotations = KeyRotationCircuit<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>;
+type Transfers = MultiTransferCircuit<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>;
+type Swaps = SwapCircuit<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>;
+type Sweeps = SweepCircuit<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>;
+
+fn hash(vals: &[ZkScalar]) -> ZkScalar {
+    bazuka::core::ZkHasher::hash(vals)
+}
+
+fn token(index: usize) -> TokenId {
+    [TokenId::Ziesha, TokenId::Custom(ZkScalar::from(123))][index]
+}
+
+fn contract_id() -> ContractId {
+    ContractId::from_str("0000000000000000000000000000000000000000000000000000000000000000")
+        .unwrap()
+}
+
+struct User {
+    builder: TxBuilder,
+    pub_key: PointAffine,
+}
+
+/// User `i` owns the account at index `i`.
+fn users() -> Vec<User> {
+    (0..NUM_ACCOUNTS)
+        .map(|i| {
+            let builder = TxBuilder::new(format!("user {}", i).as_bytes());
+            User {
+                pub_key: builder.get_zk_address().decompress(),
+                builder,
+            }
+        })
+        .collect()
+}
+
+/// Raw MPN keys, for the transactions `TxBuilder` can't sign.
+struct Key {
+    pub_key: PointAffine,
+    priv_key: PrivateKey,
+}
+
+/// Key `i` owns the account at index `i` once seeded, the rest are for
+/// rotating to.
+fn keys() -> Vec<Key> {
+    (0..2 * NUM_ACCOUNTS)
+        .map(|i| {
+            let (pub_key, priv_key) =
+                JubJub::<bazuka::core::ZkHasher>::generate_keys(format!("key {}", i).as_bytes());
+            Key {
+                pub_key: pub_key.decompress(),
+                priv_key,
+            }
+        })
+        .collect()
+}
+
+/// Owns the account of the same index, once seeded.
+trait Owner {
+    fn pub_key(&self) -> &PointAffine;
+}
+
+impl Owner for User {
+    fn pub_key(&self) -> &PointAffine {
+        &self.pub_key
+    }
+}
+
+impl Owner for Key {
+    fn pub_key(&self) -> &PointAffine {
+        &self.pub_key
+    }
+}
+
+fn proof<const LOG4_SIZE: u8>(tree: &SparseTree, index: u64) -> merkle::Proof<LOG4_SIZE> {
+    merkle::Proof(tree.proof(index))
+}
+
+fn balances_tree(account: &MpnAccount) -> SparseTree {
+    mpn_model::balances_tree(account, LOG4_TOKENS_TREE_SIZE)
+}
+
+fn is_null(address: &PointAffine) -> bool {
+    *address == PointAffine::default()
+}
+
+#[derive(Debug, Clone)]
+struct DepositOp {
+    user: usize,
+    token: usize,
+    token_index: u64,
+    amount: u64,
+}
+
+#[derive(Debug, Clone)]
+struct WithdrawOp {
+    user: usize,
+    signer: usize,
+    token_index: u64,
+    fee_token_index: u64,
+    amount: u64,
+    fee: u64,
+}
+
+#[derive(Debug, Clone)]
+struct UpdateOp {
+    src: usize,
+    dst: usize,
+    token_index: u64,
+    fee_token_index: u64,
+    dst_token_index: u64,
+    amount: u64,
+    fee: u64,
+}
+
+#[derive(Debug, Clone)]
+struct RotationOp {
+    user: usize,
+    new_key: usize,
+    fee_token_index: u64,
+    fee: u64,
+}
+
+#[derive(Debug, Clone)]
+struct PayoutOp {
+    dst: usize,
+    token_index: u64,
+    amount: u64,
+}
+
+#[derive(Debug, Clone)]
+struct MultiTransferOp {
+    src: usize,
+    token_index: u64,
+    fee_token_index: u64,
+    payouts: Vec<PayoutOp>,
+    fee: u64,
+}
+
+#[derive(Debug, Clone)]
+struct OrderOp {
+    user: usize,
+    give_token_index: u64,
+    take_token_index: u64,
+    fee_token_index: u64,
+    fee: u64,
+}
+
+/// Each side gives the token of its give slot.
+#[derive(Debug, Clone)]
+struct SwapOp {
+    a: OrderOp,
+    b: OrderOp,
+    a_gives: u64,
+    b_gives: u64,
+}
+
+#[derive(Debug, Clone)]
+struct SweepOp {
+    user: usize,
+    /// Slots to free, and where their balances go in `dst`
+    slots: Vec<(u64, u64)>,
+    dst: usize,
+    fee_token_index: u64,
+    fee: u64,
+}
+
+/// Native model of the MPN contract. Transactions it rejects are skipped, the
+/// way a node would leave them out of its batches.
+#[derive(Clone)]
+struct Mpn {
+    state: MpnState,
+    /// Lets through the transactions the circuits should reject on their own,
+    /// for checking that they do
+    forge: bool,
+}
+
+impl Default for Mpn {
+    fn default() -> Self {
+        Self {
+            state: MpnState::new(LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE),
+            forge: false,
+        }
+    }
+}
+
+impl Mpn {
+    fn account(&self, index: u64) -> MpnAccount {
+        self.state.account(index)
+    }
+
+    fn proof(&self, index: u64) -> merkle::Proof<LOG4_TREE_SIZE> {
+        merkle::Proof(self.state.proof(index))
+    }
+
+    fn root(&self) -> ZkScalar {
+        self.state.root()
+    }
+
+    /// `balance + amount`, which can't overflow unless forging.
+    fn credit(&self, balance: Money, amount: u64) -> Option<u64> {
+        if self.forge {
+            Some(amount_of(balance).wrapping_add(amount))
+        } else {
+            amount_of(balance).checked_add(amount)
+        }
+    }
+
+    /// `balance - amount`, which can't underflow unless forging.
+    fn debit(&self, balance: Money, amount: u64) -> Option<u64> {
+        if self.forge {
+            Some(amount_of(balance).wrapping_sub(amount))
+        } else {
+            amount_of(balance).checked_sub(amount)
+        }
+    }
+
+    fn deposit(
+        &mut self,
+        pub_key: &PointAffine,
+        op: &DepositOp,
+    ) -> Option<DepositTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>> {
+        let index = op.user as u64;
+        let pub_key = pub_key.clone();
+        let before = self.account(index);
+        if !is_null(&before.address) && before.address != pub_key {
+            return None;
+        }
+        let before_balance = balance(&before, op.token_index);
+        if before_balance != null_money() && before_balance.token_id != token(op.token) {
+            return None;
+        }
+        let new_balance = self.credit(before_balance, op.amount)?;
+        let trans = DepositTransition {
+            enabled: true,
+            tx: Deposit {
+                mpn_deposit: None,
+                index,
+                token_index: op.token_index,
+                pub_key: pub_key.clone(),
+                amount: Money::new(token(op.token), op.amount),
+            },
+            before_balances_hash: balances_tree(&before).root(),
+            before_balance,
+            proof: self.proof(index),
+            balance_proof: proof(&balances_tree(&before), op.token_index),
+            before: before.clone(),
+        };
+        let mut after = before;
+        after.address = pub_key;
+        after
+            .tokens
+            .insert(op.token_index, Money::new(token(op.token), new_balance));
+        self.state.set_account(index, after);
+        Some(trans)
+    }
+
+    fn withdraw(
+        &mut self,
+        users: &[User],
+        op: &WithdrawOp,
+    ) -> Option<WithdrawTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>> {
+        let (index, signer) = (op.user as u64, op.signer);
+        let before = self.account(index);
+        if before.address != users[op.user].pub_key || (signer != op.user && !self.forge) {
+            return None;
+        }
+        let token_balance = balance(&before, op.token_index);
+        if token_balance == null_money() {
+            return None;
+        }
+        let mut middle = before.clone();
+        middle.tokens.insert(
+            op.token_index,
+            Money::new(
+                token_balance.token_id,
+                amount_of(token_balance).checked_sub(op.amount)?,
+            ),
+        );
+        let fee_balance = balance(&middle, op.fee_token_index);
+        if fee_balance == null_money() {
+            return None;
+        }
+        let mut after = middle.clone();
+        after.tokens.insert(
+            op.fee_token_index,
+            Money::new(
+                fee_balance.token_id,
+                amount_of(fee_balance).checked_sub(op.fee)?,
+            ),
+        );
+        after.withdraw_nonce += 1;
+        after.address = users[signer].pub_key.clone();
+
+        let amount = Money::new(token_balance.token_id, op.amount);
+        let fee = Money::new(fee_balance.token_id, op.fee);
+        let signed = users[signer].builder.withdraw_mpn(
+            "".into(),
+            contract_id(),
+            after.withdraw_nonce,
+            op.token_index,
+            amount,
+            op.fee_token_index,
+            fee,
+            users[signer].builder.get_address(),
+        );
+        let trans = WithdrawTransition {
+            enabled: true,
+            tx: Withdraw {
+                mpn_withdraw: None,
+                index,
+                token_index: op.token_index,
+                fee_token_index: op.fee_token_index,
+                pub_key: users[signer].pub_key.clone(),
+                fingerprint: signed.payment.fingerprint(),
+                nonce: after.withdraw_nonce,
+                sig: signed.zk_sig.clone(),
+                amount,
+                fee,
+            },
+            before_token_balance: token_balance,
+            before_fee_balance: fee_balance,
+            proof: self.proof(index),
+            token_balance_proof: proof(&balances_tree(&before), op.token_index),
+            before_token_hash: balances_tree(&before).root(),
+            fee_balance_proof: proof(&balances_tree(&middle), op.fee_token_index),
+            before,
+        };
+        self.state.set_account(index, after);
+        Some(trans)
+    }
+
+    fn update(
+        &mut self,
+        users: &[User],
+        op: &UpdateOp,
+        fee_token: TokenId,
+    ) -> Option<Transition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>> {
+        // Src and dst may be the same account, so dst is read after src is updated
+        let mut next = self.clone();
+        let (src_index, dst_index) = (op.src as u64, op.dst as u64);
+        let src_before = next.account(src_index);
+        if src_before.address != users[op.src].pub_key {
+            return None;
+        }
+        let src_balance = balance(&src_before, op.token_index);
+        if src_balance == null_money() {
+            return None;
+        }
+        let mut src_middle = src_before.clone();
+        src_middle.tokens.insert(
+            op.token_index,
+            Money::new(src_balance.token_id, self.debit(src_balance, op.amount)?),
+        );
+        let src_fee_balance = balance(&src_middle, op.fee_token_index);
+        if src_fee_balance.token_id != fee_token {
+            return None;
+        }
+        let mut src_after = src_middle.clone();
+        src_after.tokens.insert(
+            op.fee_token_index,
+            Money::new(fee_token, self.debit(src_fee_balance, op.fee)?),
+        );
+        src_after.tx_nonce += 1;
+        let src_proof = next.proof(src_index);
+        next.state.set_account(src_index, src_after.clone());
+
+        let dst_before = next.account(dst_index);
+        if !is_null(&dst_before.address) && dst_before.address != users[op.dst].pub_key {
+            return None;
+        }
+        let dst_balance = balance(&dst_before, op.dst_token_index);
+        if dst_balance != null_money()
+            && dst_balance.token_id != src_balance.token_id
+            && !self.forge
+        {
+            return None;
+        }
+        let mut dst_after = dst_before.clone();
+        dst_after.address = users[op.dst].pub_key.clone();
+        dst_after.tokens.insert(
+            op.dst_token_index,
+            Money::new(src_balance.token_id, self.credit(dst_balance, op.amount)?),
+        );
+        let dst_proof = next.proof(dst_index);
+        next.state.set_account(dst_index, dst_after);
+
+        let tx = users[op.src].builder.create_mpn_transaction(
+            op.token_index,
+            MpnAddress {
+                pub_key: users[op.dst].builder.get_zk_address(),
+            },
+            op.dst_token_index,
+            Money::new(src_balance.token_id, op.amount),
+            op.fee_token_index,
+            Money::new(fee_token, op.fee),
+            src_after.tx_nonce,
+        );
+        let trans = Transition {
+            enabled: true,
+            src_index,
+            src_token_index: op.token_index,
+            src_fee_token_index: op.fee_token_index,
+            dst_token_index: op.dst_token_index,
+            tx,
+            src_before_balances_hash: balances_tree(&src_before).root(),
+            src_before_balance: src_balance,
+            src_before_fee_balance: src_fee_balance,
+            src_proof,
+            src_balance_proof: proof(&balances_tree(&src_before), op.token_index),
+            src_fee_balance_proof: proof(&balances_tree(&src_middle), op.fee_token_index),
+            src_before,
+            dst_index,
+            dst_before_balances_hash: balances_tree(&dst_before).root(),
+            dst_before_balance: dst_balance,
+            dst_proof,
+            dst_balance_proof: proof(&balances_tree(&dst_before), op.dst_token_index),
+            dst_before,
+        };
+        *self = next;
+        Some(trans)
+    }
+
+    /// Accounts are owned by `keys`, the way `seeded` deposits to them.
+    fn multi_transfer(
+        &mut self,
+        keys: &[Key],
+        op: &MultiTransferOp,
+        fee_token: TokenId,
+    ) -> Option<MultiTransferTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>> {
+        let mut next = self.clone();
+        let src_index = op.src as u64;
+        let src_before = next.account(src_index);
+        let signer = keys.iter().find(|k| k.pub_key == src_before.address)?;
+        let src_balance = balance(&src_before, op.token_index);
+        if src_balance == null_money() {
+            return None;
+        }
+        let total = op
+            .payouts
+            .iter()
+            .try_fold(0u64, |total, p| total.checked_add(p.amount))?;
+        let mut src_middle = src_before.clone();
+        src_middle.tokens.insert(
+            op.token_index,
+            Money::new(
+                src_balance.token_id,
+                amount_of(src_balance).checked_sub(total)?,
+            ),
+        );
+        let src_fee_balance = balance(&src_middle, op.fee_token_index);
+        if src_fee_balance.token_id != fee_token {
+            return None;
+        }
+        let mut src_after = src_middle.clone();
+        src_after.tokens.insert(
+            op.fee_token_index,
+            Money::new(fee_token, amount_of(src_fee_balance).checked_sub(op.fee)?),
+        );
+        src_after.tx_nonce += 1;
+        let src_proof = next.proof(src_index);
+        next.state.set_account(src_index, src_after.clone());
+
+        let mut payouts = Vec::new();
+        for p in op.payouts.iter() {
+            let dst_index = p.dst as u64;
+            let dst_before = next.account(dst_index);
+            if !is_null(&dst_before.address) && dst_before.address != keys[p.dst].pub_key {
+                return None;
+            }
+            let dst_balance = balance(&dst_before, p.token_index);
+            if dst_balance != null_money() && dst_balance.token_id != src_balance.token_id {
+                return None;
+            }
+            let mut dst_after = dst_before.clone();
+            dst_after.address = keys[p.dst].pub_key.clone();
+            dst_after.tokens.insert(
+                p.token_index,
+                Money::new(
+                    src_balance.token_id,
+                    amount_of(dst_balance).checked_add(p.amount)?,
+                ),
+            );
+            payouts.push(PayoutTransition {
+                index: dst_index,
+                token_index: p.token_index,
+                before_balances_hash: balances_tree(&dst_before).root(),
+                before_balance: dst_balance,
+                proof: next.proof(dst_index),
+                balance_proof: proof(&balances_tree(&dst_before), p.token_index),
+                before: dst_before,
+            });
+            next.state.set_account(dst_index, dst_after);
+        }
+
+        let mut tx = MultiTransfer {
+            nonce: src_after.tx_nonce,
+            token_id: src_balance.token_id,
+            payouts: op
+                .payouts
+                .iter()
+                .map(|p| Payout {
+                    dst_pub_key: keys[p.dst].pub_key.clone(),
+                    amount: p.amount,
+                })
+                .collect(),
+            fee: Money::new(fee_token, op.fee),
+            sig: Default::default(),
+        };
+        tx.sig = JubJub::<bazuka::core::ZkHasher>::sign(&signer.priv_key, tx.hash());
+        let trans = MultiTransferTransition {
+            enabled: true,
+            src_index,
+            src_token_index: op.token_index,
+            src_fee_token_index: op.fee_token_index,
+            tx,
+            src_before_balances_hash: balances_tree(&src_before).root(),
+            src_before_balance: src_balance,
+            src_before_fee_balance: src_fee_balance,
+            src_proof,
+            src_balance_proof: proof(&balances_tree(&src_before), op.token_index),
+            src_fee_balance_proof: proof(&balances_tree(&src_middle), op.fee_token_index),
+            src_before,
+            payouts,
+        };
+        *self = next;
+        Some(trans)
+    }
+
+    /// Side `a` is settled first, and `b` on top of it. When forging, `b` takes
+    /// less than `a` gives.
+    fn swap(
+        &mut self,
+        keys: &[Key],
+        op: &SwapOp,
+        fee_token: TokenId,
+    ) -> Option<SwapTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>> {
+        let a_token = balance(&self.account(op.a.user as u64), op.a.give_token_index).token_id;
+        let b_token = balance(&self.account(op.b.user as u64), op.b.give_token_index).token_id;
+        let a_gives = Money::new(a_token, op.a_gives);
+        let b_gives = Money::new(b_token, op.b_gives);
+        let b_takes = if self.forge {
+            Money::new(a_token, op.a_gives / 2)
+        } else {
+            a_gives
+        };
+        let mut next = self.clone();
+        let a = next.swap_side(keys, &op.a, a_gives, b_gives, fee_token)?;
+        let b = next.swap_side(keys, &op.b, b_gives, b_takes, fee_token)?;
+        *self = next;
+        Some(SwapTransition {
+            enabled: true,
+            a,
+            b,
+        })
+    }
+
+    fn swap_side(
+        &mut self,
+        keys: &[Key],
+        op: &OrderOp,
+        give: Money,
+        take: Money,
+        fee_token: TokenId,
+    ) -> Option<SwapSide<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>> {
+        let index = op.user as u64;
+        let before = self.account(index);
+        let signer = keys.iter().find(|k| k.pub_key == before.address)?;
+        let give_balance = balance(&before, op.give_token_index);
+        if give_balance == null_money() || give_balance.token_id != give.token_id {
+            return None;
+        }
+        let mut give_after = before.clone();
+        give_after.tokens.insert(
+            op.give_token_index,
+            Money::new(
+                give.token_id,
+                amount_of(give_balance).checked_sub(amount_of(give))?,
+            ),
+        );
+        let take_balance = balance(&give_after, op.take_token_index);
+        if take_balance != null_money() && take_balance.token_id != take.token_id {
+            return None;
+        }
+        let mut take_after = give_after.clone();
+        take_after.tokens.insert(
+            op.take_token_index,
+            Money::new(
+                take.token_id,
+                amount_of(take_balance).checked_add(amount_of(take))?,
+            ),
+        );
+        let fee_balance = balance(&take_after, op.fee_token_index);
+        if fee_balance.token_id != fee_token {
+            return None;
+        }
+        let mut after = take_after.clone();
+        after.tokens.insert(
+            op.fee_token_index,
+            Money::new(fee_token, amount_of(fee_balance).checked_sub(op.fee)?),
+        );
+        after.tx_nonce += 1;
+
+        let mut order = SwapOrder {
+            nonce: after.tx_nonce,
+            give,
+            take,
+            fee: Money::new(fee_token, op.fee),
+            sig: Default::default(),
+        };
+        order.sig = JubJub::<bazuka::core::ZkHasher>::sign(&signer.priv_key, order.hash());
+        let side = SwapSide {
+            index,
+            give_token_index: op.give_token_index,
+            take_token_index: op.take_token_index,
+            fee_token_index: op.fee_token_index,
+            order,
+            before_balances_hash: balances_tree(&before).root(),
+            before_give_balance: give_balance,
+            before_take_balance: take_balance,
+            before_fee_balance: fee_balance,
+            proof: self.proof(index),
+            give_balance_proof: proof(&balances_tree(&before), op.give_token_index),
+            take_balance_proof: proof(&balances_tree(&give_after), op.take_token_index),
+            fee_balance_proof: proof(&balances_tree(&take_after), op.fee_token_index),
+            before,
+        };
+        self.state.set_account(index, after);
+        Some(side)
+    }
+
+    /// When forging, the signed balances are one less than the actual ones.
+    fn sweep(
+        &mut self,
+        keys: &[Key],
+        op: &SweepOp,
+        fee_token: TokenId,
+    ) -> Option<SweepTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>> {
+        let mut next = self.clone();
+        let index = op.user as u64;
+        let before = next.account(index);
+        let signer = keys.iter().find(|k| k.pub_key == before.address)?;
+        let dst_pub_key = keys[op.dst].pub_key.clone();
+
+        let mut freed = before.clone();
+        let mut swept = Vec::new();
+        let mut balance_proofs = Vec::new();
+        for (token_index, _) in op.slots.iter() {
+            swept.push(balance(&freed, *token_index));
+            balance_proofs.push(proof(&balances_tree(&freed), *token_index));
+            freed.tokens.remove(token_index);
+        }
+        let fee_balance = balance(&freed, op.fee_token_index);
+        if fee_balance.token_id != fee_token {
+            return None;
+        }
+        let mut after = freed.clone();
+        after.tokens.insert(
+            op.fee_token_index,
+            Money::new(fee_token, amount_of(fee_balance).checked_sub(op.fee)?),
+        );
+        after.tx_nonce += 1;
+        let account_proof = next.proof(index);
+        next.state.set_account(index, after.clone());
+
+        let mut slots = Vec::new();
+        for ((_, dst_token_index), (swept, balance_proof)) in op
+            .slots
+            .iter()
+            .zip(swept.iter().zip(balance_proofs.into_iter()))
+        {
+            if amount_of(*swept) == 0 {
+                slots.push(SweptSlotTransition {
+                    balance_proof,
+                    ..Default::default()
+                });
+                continue;
+            }
+            let dst_index = op.dst as u64;
+            let dst_before = next.account(dst_index);
+            if !is_null(&dst_before.address) && dst_before.address != dst_pub_key {
+                return None;
+            }
+            let dst_balance = balance(&dst_before, *dst_token_index);
+            if dst_balance != null_money() && dst_balance.token_id != swept.token_id {
+                return None;
+            }
+            let mut dst_after = dst_before.clone();
+            dst_after.address = dst_pub_key.clone();
+            dst_after.tokens.insert(
+                *dst_token_index,
+                Money::new(
+                    swept.token_id,
+                    amount_of(dst_balance).checked_add(amount_of(*swept))?,
+                ),
+            );
+            slots.push(SweptSlotTransition {
+                balance_proof,
+                dst_index,
+                dst_token_index: *dst_token_index,
+                dst_before_balances_hash: balances_tree(&dst_before).root(),
+                dst_before_balance: dst_balance,
+                dst_proof: next.proof(dst_index),
+                dst_balance_proof: proof(&balances_tree(&dst_before), *dst_token_index),
+                dst_before,
+            });
+            next.state.set_account(dst_index, dst_after);
+        }
+
+        let mut tx = Sweep {
+            nonce: after.tx_nonce,
+            slots: op
+                .slots
+                .iter()
+                .zip(swept.iter())
+                .map(|((token_index, _), swept)| SweptSlot {
+                    token_index: *token_index,
+                    balance: if self.forge {
+                        Money::new(swept.token_id, amount_of(*swept).wrapping_sub(1))
+                    } else {
+                        *swept
+                    },
+                })
+                .collect(),
+            dst_pub_key,
+            fee: Money::new(fee_token, op.fee),
+            sig: Default::default(),
+        };
+        tx.sig = JubJub::<bazuka::core::ZkHasher>::sign(&signer.priv_key, tx.hash());
+        let trans = SweepTransition {
+            enabled: true,
+            index,
+            fee_token_index: op.fee_token_index,
+            tx,
+            before_balances_hash: balances_tree(&before).root(),
+            before_fee_balance: fee_balance,
+            proof: account_proof,
+            fee_balance_proof: proof(&balances_tree(&freed), op.fee_token_index),
+            before,
+            slots,
+        };
+        *self = next;
+        Some(trans)
+    }
+
+    /// Signed by the current key of the account, or by the new one if forging.
+    fn rotate(
+        &mut self,
+        keys: &[Key],
+        op: &RotationOp,
+        fee_token: TokenId,
+    ) -> Option<KeyRotationTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>> {
+        let index = op.user as u64;
+        let before = self.account(index);
+        let signer = if self.forge {
+            &keys[op.new_key]
+        } else {
+            keys.iter().find(|k| k.pub_key == before.address)?
+        };
+        let fee_balance = balance(&before, op.fee_token_index);
+        if is_null(&before.address) || fee_balance.token_id != fee_token {
+            return None;
+        }
+        let new_pub_key = keys[op.new_key].pub_key.clone();
+        let mut after = before.clone();
+        after.tokens.insert(
+            op.fee_token_index,
+            Money::new(fee_token, amount_of(fee_balance).checked_sub(op.fee)?),
+        );
+        after.tx_nonce += 1;
+        after.address = new_pub_key.clone();
+
+        let mut tx = KeyRotation {
+            nonce: after.tx_nonce,
+            new_pub_key,
+            fee: Money::new(fee_token, op.fee),
+            sig: Default::default(),
+        };
+        tx.sig = JubJub::<bazuka::core::ZkHasher>::sign(&signer.priv_key, tx.hash());
+        let trans = KeyRotationTransition {
+            enabled: true,
+            index,
+            fee_token_index: op.fee_token_index,
+            tx,
+            before_balances_hash: balances_tree(&before).root(),
+            before_fee_balance: fee_balance,
+            proof: self.proof(index),
+            fee_balance_proof: proof(&balances_tree(&before), op.fee_token_index),
+            before,
+        };
+        self.state.set_account(index, after);
+        Some(trans)
+    }
+}
+
+/// Root of the tx list revealed by the deposit and withdraw circuits.
+fn tx_root(txs: Vec<Vec<ZkScalar>>) -> ZkScalar {
+    SparseTree::from_leaves(
+        LOG4_BATCH_SIZE,
+        ZkScalar::from(0),
+        txs.into_iter()
+            .enumerate()
+            .map(|(i, fields)| (i as u64, hash(&fields))),
+    )
+    .root()
+}
+
+fn enabled_or_zero(enabled: bool, value: ZkScalar) -> ZkScalar {
+    if enabled {
+        value
+    } else {
+        ZkScalar::from(0)
+    }
+}
+
+fn pad<T: Default>(mut transitions: Vec<T>) -> Vec<T> {
+    transitions.resize_with(BATCH_SIZE, T::default);
+    transitions
+}
+
+/// Transitions of the first ops the model accepts, as many as fit in a batch.
+fn accepted<O, T>(
+    mpn: &mut Mpn,
+    ops: &[O],
+    mut apply: impl FnMut(&mut Mpn, &O) -> Option<T>,
+) -> Vec<T> {
+    ops.iter()
+        .filter_map(|op| apply(mpn, op))
+        .take(BATCH_SIZE)
+        .collect()
+}
+
+/// A batch of the circuits whose fees are all paid in a single token, like
+/// `UpdateCircuit`: `aux_data` is `poseidon(fee_token, fee_sum)`.
+struct FeeBatch<T> {
+    state: ZkScalar,
+    aux_data: ZkScalar,
+    next_state: ZkScalar,
+    transitions: Vec<T>,
+}
+
+fn fee_batch<O, T: Default>(
+    mpn: &mut Mpn,
+    ops: &[O],
+    fee_token: TokenId,
+    apply: impl FnMut(&mut Mpn, &O) -> Option<T>,
+    fee: impl Fn(&T) -> u64,
+) -> FeeBatch<T> {
+    let state = mpn.root();
+    let transitions = accepted(mpn, ops, apply);
+    let fee_sum: u64 = transitions.iter().map(fee).sum();
+    FeeBatch {
+        state,
+        aux_data: hash(&[fee_token.into(), fee_sum.into()]),
+        next_state: mpn.root(),
+        transitions: pad(transitions),
+    }
+}
+
+fn deposit_batch<O: Owner>(mpn: &mut Mpn, owners: &[O], ops: &[DepositOp]) -> Deposits {
+    let state = mpn.root();
+    let transitions = pad(accepted(mpn, ops, |mpn, op| {
+        mpn.deposit(owners[op.user].pub_key(), op)
+    }));
+    let aux_data = tx_root(
+        transitions
+            .iter()
+            .map(|t| {
+                vec![
+                    ZkScalar::from(t.enabled as u64),
+                    t.tx.amount.token_id.into(),
+                    amount_of(t.tx.amount).into(),
+                    enabled_or_zero(t.enabled, hash(&[t.tx.pub_key.0, t.tx.pub_key.1])),
+                ]
+            })
+            .collect(),
+    );
+    Deposits {
+        commitment: ZkScalar::from(1234),
+        height: 1,
+        state,
+        aux_data,
+        next_state: mpn.root(),
+        transitions: Box::new(DepositTransitionBatch(transitions)),
+        strict: true,
+    }
+}
+
+fn withdraw_transitions(
+    mpn: &Mpn,
+    transitions: Vec<WithdrawTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>,
+    state: ZkScalar,
+) -> Withdraws {
+    let transitions = pad(transitions);
+    let aux_data = tx_root(
+        transitions
+            .iter()
+            .map(|t| {
+                let calldata = hash(&[
+                    t.tx.pub_key.0,
+                    t.tx.pub_key.1,
+                    (t.tx.nonce as u64).into(),
+                    t.tx.sig.r.0,
+                    t.tx.sig.r.1,
+                    t.tx.sig.s,
+                ]);
+                vec![
+                    ZkScalar::from(t.enabled as u64),
+                    t.tx.amount.token_id.into(),
+                    amount_of(t.tx.amount).into(),
+                    t.tx.fee.token_id.into(),
+                    amount_of(t.tx.fee).into(),
+                    t.tx.fingerprint,
+                    enabled_or_zero(t.enabled, calldata),
+                ]
+            })
+            .collect(),
+    );
+    Withdraws {
+        commitment: ZkScalar::from(1234),
+        height: 1,
+        state,
+        aux_data,
+        next_state: mpn.root(),
+        transitions: Box::new(WithdrawTransitionBatch(transitions)),
+        strict: true,
+    }
+}
+
+fn withdraw_batch(mpn: &mut Mpn, users: &[User], ops: &[WithdrawOp]) -> Withdraws {
+    let state = mpn.root();
+    let transitions = accepted(mpn, ops, |mpn, op| mpn.withdraw(users, op));
+    withdraw_transitions(mpn, transitions, state)
+}
+
+fn update_batch(mpn: &mut Mpn, users: &[User], ops: &[UpdateOp], fee_token: TokenId) -> Updates {
+    let batch = fee_batch(
+        mpn,
+        ops,
+        fee_token,
+        |mpn, op| mpn.update(users, op, fee_token),
+        |t| amount_of(t.tx.fee),
+    );
+    Updates {
+        commitment: ZkScalar::from(1234),
+        height: 1,
+        state: batch.state,
+        aux_data: batch.aux_data,
+        next_state: batch.next_state,
+        fee_token,
+        transitions: Box::new(TransitionBatch(batch.transitions)),
+        strict: true,
+    }
+}
+
+/// Each update pays its fee in the token of its own index. `aux_data` commits
+/// to the fee sums of the slots the way nodes compute them: the fees of a
+/// token are only counted in its first slot.
+fn multi_fee_update_batch(
+    mpn: &mut Mpn,
+    users: &[User],
+    ops: &[(UpdateOp, usize)],
+    fee_tokens: [TokenId; FEE_SLOTS],
+) -> MultiFeeUpdates {
+    let state = mpn.root();
+    let transitions = pad(accepted(mpn, ops, |mpn, (op, fee_token)| {
+        mpn.update(users, op, token(*fee_token))
+    }));
+    let mut preimage = Vec::new();
+    for (i, slot) in fee_tokens.iter().enumerate() {
+        let fee_sum: u64 = if fee_tokens[..i].contains(slot) {
+            0
+        } else {
+            transitions
+                .iter()
+                .filter(|t| t.enabled && t.tx.fee.token_id == *slot)
+                .map(|t| amount_of(t.tx.fee))
+                .sum()
+        };
+        preimage.push((*slot).into());
+        preimage.push(fee_sum.into());
+    }
+    MultiFeeUpdates {
+        commitment: ZkScalar::from(1234),
+        height: 1,
+        state,
+        aux_data: hash(&preimage),
+        next_state: mpn.root(),
+        fee_tokens,
+        transitions: Box::new(TransitionBatch(transitions)),
+        strict: true,
+    }
+}
+
+fn rotation_batch(
+    mpn: &mut Mpn,
+    keys: &[Key],
+    ops: &[RotationOp],
+    fee_token: TokenId,
+) -> Rotations {
+    let batch = fee_batch(
+        mpn,
+        ops,
+        fee_token,
+        |mpn, op| mpn.rotate(keys, op, fee_token),
+        |t| amount_of(t.tx.fee),
+    );
+    Rotations {
+        commitment: ZkScalar::from(1234),
+        height: 1,
+        state: batch.state,
+        aux_data: batch.aux_data,
+        next_state: batch.next_state,
+        fee_token,
+        transitions: Box::new(KeyRotationTransitionBatch(batch.transitions)),
+    }
+}
+
+fn multi_transfer_batch(
+    mpn: &mut Mpn,
+    keys: &[Key],
+    ops: &[MultiTransferOp],
+    fee_token: TokenId,
+) -> Transfers {
+    let batch = fee_batch(
+        mpn,
+        ops,
+        fee_token,
+        |mpn, op| mpn.multi_transfer(keys, op, fee_token),
+        |t| amount_of(t.tx.fee),
+    );
+    Transfers {
+        commitment: ZkScalar::from(1234),
+        height: 1,
+        state: batch.state,
+        aux_data: batch.aux_data,
+        next_state: batch.next_state,
+        fee_token,
+        transitions: Box::new(MultiTransferTransitionBatch(batch.transitions)),
+    }
+}
+
+fn swap_batch(mpn: &mut Mpn, keys: &[Key], ops: &[SwapOp], fee_token: TokenId) -> Swaps {
+    let batch = fee_batch(
+        mpn,
+        ops,
+        fee_token,
+        |mpn, op| mpn.swap(keys, op, fee_token),
+        |t| amount_of(t.a.order.fee) + amount_of(t.b.order.fee),
+    );
+    Swaps {
+        commitment: ZkScalar::from(1234),
+        height: 1,
+        state: batch.state,
+        aux_data: batch.aux_data,
+        next_state: batch.next_state,
+        fee_token,
+        transitions: Box::new(SwapTransitionBatch(batch.transitions)),
+    }
+}
+
+fn sweep_batch(mpn: &mut Mpn, keys: &[Key], ops: &[SweepOp], fee_token: TokenId) -> Sweeps {
+    let batch = fee_batch(
+        mpn,
+        ops,
+        fee_token,
+        |mpn, op| mpn.sweep(keys, op, fee_token),
+        |t| amount_of(t.tx.fee),
+    );
+    Sweeps {
+        commitment: ZkScalar::from(1234),
+        height: 1,
+        state: batch.state,
+        aux_data: batch.aux_data,
+        next_state: batch.next_state,
+        fee_token,
+        transitions: Box::new(SweepTransitionBatch(batch.transitions)),
+    }
+}
+
+fn seeded<O: Owner>(owners: &[O], deposits: &[DepositOp]) -> Mpn {
+    let mut mpn = Mpn::default();
+    for op in deposits {
+        mpn.deposit(owners[op.user].pub_key(), op);
+    }
+    mpn
+}
+
+/// Seeded, then lets through the transactions the circuits should reject.
+fn forged<O: Owner>(owners: &[O], deposits: &[DepositOp]) -> Mpn {
+    let mut mpn = seeded(owners, deposits);
+    mpn.forge = true;
+    mpn
+}
+
+fn check<C: Circuit<BellmanFr>>(circuit: C) -> Option<String> {
+    DebugSystem::check(circuit)
+        .unwrap()
+        .map(|unsatisfied| unsatisfied.to_string())
+}
+
+fn bump(v: &mut ZkScalar) {
+    *v = *v + ZkScalar::from(1);
+}
+
+fn bump_money(v: &mut Money) {
+    *v = Money::new(v.token_id, amount_of(*v).wrapping_add(1));
+}
+
+const PUBLIC_MUTATIONS: u8 = 3;
+
+/// The circuits under test, which take a batch of transitions along with the
+/// same public inputs.
+trait Batch: Circuit<BellmanFr> + Clone {
+    /// Number of ways `mutate_transition` tampers with a transition
+    const MUTATIONS: u8;
+    fn enabled(&self) -> Vec<bool>;
+    /// Tampers with a public input. The commitment and height are not checked
+    /// against anything by the circuits, so they are left alone.
+    fn mutate_public(&mut self, kind: u8);
+    fn mutate_transition(&mut self, index: usize, kind: u8);
+}
+
+macro_rules! batch {
+    ($circuit:ty, $mutations:expr, |$t:ident, $kind:ident| $mutate:expr) => {
+        impl Batch for $circuit {
+            const MUTATIONS: u8 = $mutations;
+            fn enabled(&self) -> Vec<bool> {
+                self.transitions.0.iter().map(|t| t.enabled).collect()
+            }
+            fn mutate_public(&mut self, kind: u8) {
+                match kind % PUBLIC_MUTATIONS {
+                    0 => bump(&mut self.state),
+                    1 => bump(&mut self.aux_data),
+                    _ => bump(&mut self.next_state),
+                }
+            }
+            fn mutate_transition(&mut self, index: usize, $kind: u8) {
+                let $t = &mut self.transitions.0[index];
+                $mutate
+            }
+        }
+    };
+}
+
+batch!(Deposits, 6, |t, kind| match kind {
+    0 => bump_money(&mut t.tx.amount),
+    1 => bump_money(&mut t.before_balance),
+    2 => bump(&mut t.proof.0[0][0]),
+    3 => bump(&mut t.balance_proof.0[0][1]),
+    4 => bump(&mut t.before_balances_hash),
+    _ => t.enabled = false,
+});
+
+batch!(Withdraws, 8, |t, kind| match kind {
+    0 => bump_money(&mut t.tx.amount),
+    1 => bump_money(&mut t.tx.fee),
+    2 => t.tx.nonce += 1,
+    3 => bump(&mut t.tx.sig.s),
+    4 => bump(&mut t.tx.fingerprint),
+    5 => bump_money(&mut t.before_token_balance),
+    6 => bump_money(&mut t.before_fee_balance),
+    _ => bump(&mut t.fee_balance_proof.0[0][2]),
+});
+
+batch!(Updates, 9, |t, kind| match kind {
+    0 => bump_money(&mut t.tx.amount),
+    1 => bump_money(&mut t.tx.fee),
+    2 => t.tx.nonce += 1,
+    3 => bump(&mut t.tx.sig.s),
+    4 => bump_money(&mut t.src_before_balance),
+    5 => bump_money(&mut t.src_before_fee_balance),
+    6 => bump_money(&mut t.dst_before_balance),
+    7 => bump(&mut t.src_proof.0[0][0]),
+    _ => bump(&mut t.dst_proof.0[0][2]),
+});
+
+batch!(Rotations, 8, |t, kind| match kind {
+    0 => bump_money(&mut t.tx.fee),
+    1 => t.tx.nonce += 1,
+    2 => bump(&mut t.tx.sig.s),
+    3 => bump(&mut t.tx.new_pub_key.1),
+    4 => bump_money(&mut t.before_fee_balance),
+    5 => bump(&mut t.before_balances_hash),
+    6 => bump(&mut t.proof.0[0][1]),
+    _ => bump(&mut t.fee_balance_proof.0[0][0]),
+});
+
+batch!(
+    Transfers,
+    8,
+    |t, kind| match (kind, t.tx.payouts.is_empty()) {
+        (0, _) => bump_money(&mut t.tx.fee),
+        (1, _) => t.tx.nonce += 1,
+        (2, _) => bump(&mut t.tx.sig.s),
+        (3, _) => bump_money(&mut t.src_before_balance),
+        (4, _) => bump(&mut t.src_proof.0[0][1]),
+        (5, false) => t.tx.payouts[0].amount = t.tx.payouts[0].amount.wrapping_add(1),
+        (6, false) => bump(&mut t.payouts[0].proof.0[0][2]),
+        (7, false) => {
+            t.tx.payouts.pop();
+        }
+        _ => bump(&mut t.src_fee_balance_proof.0[0][0]),
+    }
+);
+
+batch!(Swaps, 8, |t, kind| match kind {
+    0 => bump_money(&mut t.a.order.fee),
+    1 => t.b.order.nonce += 1,
+    2 => bump(&mut t.a.order.sig.s),
+    3 => bump_money(&mut t.b.order.take),
+    4 => bump_money(&mut t.a.before_give_balance),
+    5 => bump(&mut t.b.take_balance_proof.0[0][1]),
+    6 => bump(&mut t.a.proof.0[0][0]),
+    _ => t.enabled = false,
+});
+
+batch!(Sweeps, 8, |t, kind| match (kind, t.tx.slots.is_empty()) {
+    (0, _) => bump_money(&mut t.tx.fee),
+    (1, _) => t.tx.nonce += 1,
+    (2, _) => bump(&mut t.tx.sig.s),
+    (3, _) => bump(&mut t.tx.dst_pub_key.0),
+    (4, _) => bump_money(&mut t.before_fee_balance),
+    (5, false) => bump_money(&mut t.tx.slots[0].balance),
+    (6, false) => bump(&mut t.slots[0].balance_proof.0[0][2]),
+    (7, false) => {
+        t.tx.slots.pop();
+    }
+    _ => bump(&mut t.proof.0[0][1]),
+});
+
+/// Tampers with a public input, or with the `slot`th enabled transition.
+fn mutate<C: Batch>(mut circuit: C, kind: u8, slot: usize) -> C {
+    let enabled = circuit
+        .enabled()
+        .into_iter()
+        .enumerate()
+        .filter(|(_, e)| *e)
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    match kind % (PUBLIC_MUTATIONS + C::MUTATIONS) {
+        k if k < PUBLIC_MUTATIONS || enabled.is_empty() => circuit.mutate_public(k),
+        k => circuit.mutate_transition(enabled[slot % enabled.len()], k - PUBLIC_MUTATIONS),
+    }
+    circuit
+}
+
+/// A batch the model accepts should satisfy its circuit, and no longer once
+/// tampered with.
+fn check_batch<C: Batch>(circuit: C, kind: u8, slot: usize) -> Result<(), TestCaseError> {
+    prop_assert_eq!(check(circuit.clone()), None);
+    prop_assert!(check(mutate(circuit, kind, slot)).is_some());
+    Ok(())
+}
+
+/// The first transition of `circuit`, which the model was forged into
+/// accepting, should be rejected by the check in namespace `check_name`.
+fn assert_rejected<C: Batch>(circuit: C, check_name: &str) {
+    assert!(circuit.enabled()[0]);
+    let unsatisfied =
+        check(circuit).unwrap_or_else(|| panic!("transition 0 passed the {} check", check_name));
+    assert!(
+        unsatisfied.contains(&format!("transition 0/{}", check_name)),
+        "{}",
+        unsatisfied
+    );
+}
+
+fn amount() -> impl Strategy<Value = u64> {
+    // Mostly spendable amounts, sometimes ones that overflow the balances
+    prop_oneof![4 => 0..1_000_000u64, 1 => any::<u64>()]
+}
+
+fn deposit_op() -> impl Strategy<Value = DepositOp> {
+    (0..NUM_ACCOUNTS, 0..NUM_TOKENS, 0..NUM_TOKEN_SLOTS, amount()).prop_map(
+        |(user, token, token_index, amount)| DepositOp {
+            user,
+            token,
+            token_index,
+            amount,
+        },
+    )
+}
+
+fn withdraw_op() -> impl Strategy<Value = WithdrawOp> {
+    (
+        0..NUM_ACCOUNTS,
+        0..NUM_TOKEN_SLOTS,
+        0..NUM_TOKEN_SLOTS,
+        amount(),
+        0..1000u64,
+    )
+        .prop_map(
+            |(user, token_index, fee_token_index, amount, fee)| WithdrawOp {
+                user,
+                signer: user,
+                token_index,
+                fee_token_index,
+                amount,
+                fee,
+            },
+        )
+}
+
+fn update_op() -> impl Strategy<Value = UpdateOp> {
+    (
+        0..NUM_ACCOUNTS,
+        0..NUM_ACCOUNTS,
+        0..NUM_TOKEN_SLOTS,
+        0..NUM_TOKEN_SLOTS,
+        0..NUM_TOKEN_SLOTS,
+        amount(),
+        0..1000u64,
+    )
+        .prop_map(
+            |(src, dst, token_index, fee_token_index, dst_token_index, amount, fee)| UpdateOp {
+                src,
+                dst,
+                token_index,
+                fee_token_index,
+                dst_token_index,
+                amount,
+                fee,
+            },
+        )
+}
+
+fn rotation_op() -> impl Strategy<Value = RotationOp> {
+    (
+        0..NUM_ACCOUNTS,
+        0..2 * NUM_ACCOUNTS,
+        0..NUM_TOKEN_SLOTS,
+        0..1000u64,
+    )
+        .prop_map(|(user, new_key, fee_token_index, fee)| RotationOp {
+            user,
+            new_key,
+            fee_token_index,
+            fee,
+        })
+}
+
+fn multi_transfer_op() -> impl Strategy<Value = MultiTransferOp> {
+    let payout =
+        (0..NUM_ACCOUNTS, 0..NUM_TOKEN_SLOTS, amount()).prop_map(|(dst, token_index, amount)| {
+            PayoutOp {
+                dst,
+                token_index,
+                amount,
+            }
+        });
+    (
+        0..NUM_ACCOUNTS,
+        0..NUM_TOKEN_SLOTS,
+        0..NUM_TOKEN_SLOTS,
+        vec(payout, 0..=PAYOUTS),
+        0..1000u64,
+    )
+        .prop_map(
+            |(src, token_index, fee_token_index, payouts, fee)| MultiTransferOp {
+                src,
+                token_index,
+                fee_token_index,
+                payouts,
+                fee,
+            },
+        )
+}
+
+fn order_op() -> impl Strategy<Value = OrderOp> {
+    (
+        0..NUM_ACCOUNTS,
+        0..NUM_TOKEN_SLOTS,
+        0..NUM_TOKEN_SLOTS,
+        prop::option::of(0..NUM_TOKEN_SLOTS),
+        0..1000u64,
+    )
+        .prop_map(
+            |(user, give_token_index, take_token_index, fee_token_index, fee)| OrderOp {
+                user,
+                give_token_index,
+                take_token_index,
+                // Paying the fee from the give slot makes matching orders likelier
+                fee_token_index: fee_token_index.unwrap_or(give_token_index),
+                fee,
+            },
+        )
+}
+
+fn swap_op() -> impl Strategy<Value = SwapOp> {
+    (order_op(), order_op(), amount(), amount()).prop_map(|(a, b, a_gives, b_gives)| SwapOp {
+        a,
+        b,
+        a_gives,
+        b_gives,
+    })
+}
+
+fn sweep_op() -> impl Strategy<Value = SweepOp> {
+    (
+        0..NUM_ACCOUNTS,
+        vec((0..NUM_TOKEN_SLOTS, 0..NUM_TOKEN_SLOTS), 0..=SWEEP_SLOTS),
+        0..NUM_ACCOUNTS,
+        0..NUM_TOKEN_SLOTS,
+        0..1000u64,
+    )
+        .prop_map(|(user, slots, dst, fee_token_index, fee)| SweepOp {
+            user,
+            slots,
+            dst,
+            fee_token_index,
+            fee,
+        })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(24))]
+
+    #[test]
+    fn prop_deposit_batches(
+        seed in vec(deposit_op(), 0..8),
+        ops in vec(deposit_op(), 0..6),
+        (kind, slot) in any::<(u8, usize)>(),
+    ) {
+        let users = users();
+        let mut mpn = seeded(&users, &seed);
+        check_batch(deposit_batch(&mut mpn, &users, &ops), kind, slot)?;
+    }
+
+    #[test]
+    fn prop_withdraw_batches(
+        seed in vec(deposit_op(), 1..12),
+        ops in vec(withdraw_op(), 0..6),
+        (kind, slot) in any::<(u8, usize)>(),
+    ) {
+        let users = users();
+        let mut mpn = seeded(&users, &seed);
+        check_batch(withdraw_batch(&mut mpn, &users, &ops), kind, slot)?;
+    }
+
+    #[test]
+    fn prop_update_batches(
+        seed in vec(deposit_op(), 1..12),
+        ops in vec(update_op(), 0..6),
+        fee_token in 0..NUM_TOKENS,
+        (kind, slot) in any::<(u8, usize)>(),
+    ) {
+        let users = users();
+        let mut mpn = seeded(&users, &seed);
+        check_batch(update_batch(&mut mpn, &users, &ops, token(fee_token)), kind, slot)?;
+    }
+
+    #[test]
+    fn prop_key_rotation_batches(
+        seed in vec(deposit_op(), 1..12),
+        ops in vec(rotation_op(), 0..6),
+        fee_token in 0..NUM_TOKENS,
+        (kind, slot) in any::<(u8, usize)>(),
+    ) {
+        let keys = keys();
+        let mut mpn = seeded(&keys, &seed);
+        check_batch(rotation_batch(&mut mpn, &keys, &ops, token(fee_token)), kind, slot)?;
+    }
+
+    #[test]
+    fn prop_multi_transfer_batches(
+        seed in vec(deposit_op(), 1..12),
+        ops in vec(multi_transfer_op(), 0..6),
+        fee_token in 0..NUM_TOKENS,
+        (kind, slot) in any::<(u8, usize)>(),
+    ) {
+        let keys = keys();
+        let mut mpn = seeded(&keys, &seed);
+        check_batch(multi_transfer_batch(&mut mpn, &keys, &ops, token(fee_token)), kind, slot)?;
+    }
+
+    #[test]
+    fn prop_swap_batches(
+        seed in vec(deposit_op(), 4..16),
+        ops in vec(swap_op(), 0..8),
+        fee_token in 0..NUM_TOKENS,
+        (kind, slot) in any::<(u8, usize)>(),
+    ) {
+        let keys = keys();
+        let mut mpn = seeded(&keys, &seed);
+        check_batch(swap_batch(&mut mpn, &keys, &ops, token(fee_token)), kind, slot)?;
+    }
+
+    #[test]
+    fn prop_sweep_batches(
+        seed in vec(deposit_op(), 1..12),
+        ops in vec(sweep_op(), 0..6),
+        fee_token in 0..NUM_TOKENS,
+        (kind, slot) in any::<(u8, usize)>(),
+    ) {
+        let keys = keys();
+        let mut mpn = seeded(&keys, &seed);
+        check_batch(sweep_batch(&mut mpn, &keys, &ops, token(fee_token)), kind, slot)?;
+    }
+}
+
+fn foreign_withdraw(amount: u64, fee: u64) -> Withdraws {
+    let users = users();
+    let mut mpn = forged(
+        &users,
+        &[DepositOp {
+            user: 0,
+            token: 0,
+            token_index: 0,
+            amount: 1000,
+        }],
+    );
+    let state = mpn.root();
+    let withdraw = WithdrawOp {
+        user: 0,
+        signer: 1,
+        token_index: 0,
+        fee_token_index: 0,
+        amount,
+        fee,
+    };
+    let transitions = vec![mpn.withdraw(&users, &withdraw).unwrap()];
+    withdraw_transitions(&mpn, transitions, state)
+}
+
+fn update_into_slot_of_other_token(amount: u64, fee: u64) -> Updates {
+    let users = users();
+    let mut mpn = forged(
+        &users,
+        &[
+            DepositOp {
+                user: 0,
+                token: 0,
+                token_index: 0,
+                amount: 1000,
+            },
+            DepositOp {
+                user: 1,
+                token: 1,
+                token_index: 0,
+                amount: 1000,
+            },
+        ],
+    );
+    let update = UpdateOp {
+        src: 0,
+        dst: 1,
+        token_index: 0,
+        fee_token_index: 0,
+        dst_token_index: 0,
+        amount,
+        fee,
+    };
+    update_batch(&mut mpn, &users, &[update], token(0))
+}
+
+#[test]
+fn test_withdraw_from_foreign_account_rejected() {
+    assert_rejected(foreign_withdraw(500, 10), "address");
+}
+
+#[test]
+fn test_update_into_slot_of_other_token_rejected() {
+    assert_rejected(update_into_slot_of_other_token(500, 10), "dst token id");
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(8))]
+
+    // Forgeries only the strict circuits reject. The circuits of the `mainnet`
+    // shape keep accepting them until its VKs are replaced (See SECURITY.md).
+    // The balance forgeries aren't recorded, since the model can't represent
+    // balances beyond 64 bits.
+    #[test]
+    fn prop_lax_circuits_accept_known_forgeries(amount in 1..990u64, fee in 0..10u64) {
+        let mut withdraw = foreign_withdraw(amount, fee);
+        withdraw.strict = false;
+        prop_assert_eq!(check(withdraw), None);
+
+        let mut update = update_into_slot_of_other_token(amount, fee);
+        update.strict = false;
+        prop_assert_eq!(check(update), None);
+    }
+}
+
+#[test]
+fn test_deposit_overflowing_balance_rejected() {
+    let users = users();
+    let mut mpn = forged(
+        &users,
+        &[DepositOp {
+            user: 0,
+            token: 0,
+            token_index: 0,
+            amount: u64::MAX - 10,
+        }],
+    );
+    let deposit = DepositOp {
+        user: 0,
+        token: 0,
+        token_index: 0,
+        amount: 100,
+    };
+    let circuit = deposit_batch(&mut mpn, &users, &[deposit]);
+    assert_rejected(circuit, "balance range");
+}
+
+#[test]
+fn test_update_overflowing_dst_balance_rejected() {
+    let users = users();
+    let mut mpn = forged(
+        &users,
+        &[
+            DepositOp {
+                user: 0,
+                token: 0,
+                token_index: 0,
+                amount: 1000,
+            },
+            DepositOp {
+                user: 1,
+                token: 0,
+                token_index: 0,
+                amount: u64::MAX - 10,
+            },
+        ],
+    );
+    let update = UpdateOp {
+        src: 0,
+        dst: 1,
+        token_index: 0,
+        fee_token_index: 0,
+        dst_token_index: 0,
+        amount: 500,
+        fee: 10,
+    };
+    let circuit = update_batch(&mut mpn, &users, &[update], token(0));
+    assert_rejected(circuit, "dst balance range");
+}
+
+#[test]
+fn test_update_with_fee_above_fee_balance_rejected() {
+    let users = users();
+    // The fee is paid from another slot than the amount, so that only the fee
+    // balance falls short
+    let mut mpn = forged(
+        &users,
+        &[
+            DepositOp {
+                user: 0,
+                token: 0,
+                token_index: 0,
+                amount: 1000,
+            },
+            DepositOp {
+                user: 0,
+                token: 1,
+                token_index: 1,
+                amount: 5,
+            },
+        ],
+    );
+    let update = UpdateOp {
+        src: 0,
+        dst: 1,
+        token_index: 0,
+        fee_token_index: 1,
+        dst_token_index: 0,
+        amount: 500,
+        fee: 10,
+    };
+    let circuit = update_batch(&mut mpn, &users, &[update], token(1));
+    assert_rejected(circuit, "src fee balance range");
+}
+
+#[test]
+fn test_rotation_signed_by_new_key_rejected() {
+    let keys = keys();
+    let mut mpn = forged(
+        &keys,
+        &[DepositOp {
+            user: 0,
+            token: 0,
+            token_index: 0,
+            amount: 1000,
+        }],
+    );
+    let rotation = RotationOp {
+        user: 0,
+        new_key: NUM_ACCOUNTS,
+        fee_token_index: 0,
+        fee: 10,
+    };
+    let circuit = rotation_batch(&mut mpn, &keys, &[rotation], token(0));
+    assert_rejected(circuit, "signature");
+}
+
+#[test]
+fn test_multi_transfer_pays_the_same_destination_twice() {
+    let keys = keys();
+    let mut mpn = seeded(
+        &keys,
+        &[DepositOp {
+            user: 0,
+            token: 0,
+            token_index: 0,
+            amount: 1000,
+        }],
+    );
+    let payout = PayoutOp {
+        dst: 1,
+        token_index: 2,
+        amount: 300,
+    };
+    let transfer = MultiTransferOp {
+        src: 0,
+        token_index: 0,
+        fee_token_index: 0,
+        payouts: vec![payout.clone(), payout],
+        fee: 10,
+    };
+    let circuit = multi_transfer_batch(&mut mpn, &keys, &[transfer], token(0));
+    assert!(circuit.transitions.0[0].enabled);
+    assert_eq!(check(circuit), None);
+    assert_eq!(amount_of(balance(&mpn.account(0), 0)), 390);
+    assert_eq!(amount_of(balance(&mpn.account(1), 2)), 600);
+}
+
+fn swap_seed() -> Vec<DepositOp> {
+    vec![
+        DepositOp {
+            user: 0,
+            token: 0,
+            token_index: 0,
+            amount: 1000,
+        },
+        DepositOp {
+            user: 1,
+            token: 1,
+            token_index: 1,
+            amount: 1000,
+        },
+        DepositOp {
+            user: 1,
+            token: 0,
+            token_index: 2,
+            amount: 100,
+        },
+    ]
+}
+
+fn swap_of_tokens() -> SwapOp {
+    SwapOp {
+        a: OrderOp {
+            user: 0,
+            give_token_index: 0,
+            take_token_index: 3,
+            fee_token_index: 0,
+            fee: 10,
+        },
+        b: OrderOp {
+            user: 1,
+            give_token_index: 1,
+            take_token_index: 2,
+            fee_token_index: 2,
+            fee: 20,
+        },
+        a_gives: 400,
+        b_gives: 700,
+    }
+}
+
+#[test]
+fn test_swap_settles_both_sides() {
+    let keys = keys();
+    let mut mpn = seeded(&keys, &swap_seed());
+    let circuit = swap_batch(&mut mpn, &keys, &[swap_of_tokens()], token(0));
+    assert!(circuit.transitions.0[0].enabled);
+    assert_eq!(check(circuit), None);
+    assert_eq!(amount_of(balance(&mpn.account(0), 0)), 590);
+    assert_eq!(balance(&mpn.account(0), 3), Money::new(token(1), 700));
+    assert_eq!(amount_of(balance(&mpn.account(1), 1)), 300);
+    assert_eq!(amount_of(balance(&mpn.account(1), 2)), 480);
+}
+
+#[test]
+fn test_swap_of_unmatched_orders_rejected() {
+    let keys = keys();
+    let mut mpn = forged(&keys, &swap_seed());
+    let circuit = swap_batch(&mut mpn, &keys, &[swap_of_tokens()], token(0));
+    assert_rejected(circuit, "a give amount");
+}
+
+fn dusty_account() -> Vec<DepositOp> {
+    vec![
+        DepositOp {
+            user: 0,
+            token: 0,
+            token_index: 0,
+            amount: 1000,
+        },
+        DepositOp {
+            user: 0,
+            token: 1,
+            token_index: 1,
+            amount: 5,
+        },
+        DepositOp {
+            user: 0,
+            token: 1,
+            token_index: 2,
+            amount: 0,
+        },
+    ]
+}
+
+fn sweep_dust() -> SweepOp {
+    SweepOp {
+        user: 0,
+        slots: vec![(1, 3), (2, 0)],
+        dst: 1,
+        fee_token_index: 0,
+        fee: 10,
+    }
+}
+
+#[test]
+fn test_sweep_frees_slots_and_moves_dust() {
+    let keys = keys();
+    let mut mpn = seeded(&keys, &dusty_account());
+    let circuit = sweep_batch(&mut mpn, &keys, &[sweep_dust()], token(0));
+    assert!(circuit.transitions.0[0].enabled);
+    assert_eq!(check(circuit), None);
+    let account = mpn.account(0);
+    assert_eq!(balance(&account, 0), Money::new(token(0), 990));
+    assert_eq!(balance(&account, 1), null_money());
+    assert_eq!(balance(&account, 2), null_money());
+    assert_eq!(balance(&mpn.account(1), 3), Money::new(token(1), 5));
+
+    // Freed slots can take another token
+    let deposit = DepositOp {
+        user: 0,
+        token: 0,
+        token_index: 1,
+        amount: 50,
+    };
+    let circuit = deposit_batch(&mut mpn, &keys, &[deposit]);
+    assert!(circuit.transitions.0[0].enabled);
+    assert_eq!(check(circuit), None);
+}
+
+#[test]
+fn test_sweep_of_changed_balance_rejected() {
+    let keys = keys();
+    let mut mpn = forged(&keys, &dusty_account());
+    let circuit = sweep_batch(&mut mpn, &keys, &[sweep_dust()], token(0));
+    assert_rejected(circuit, "slot 0/balance proof");
+}
+
+/// User 0 holds both tokens, user 1 only the first one.
+fn two_token_seed() -> Vec<DepositOp> {
+    vec![
+        DepositOp {
+            user: 0,
+            token: 0,
+            token_index: 0,
+            amount: 1000,
+        },
+        DepositOp {
+            user: 0,
+            token: 1,
+            token_index: 1,
+            amount: 1000,
+        },
+        DepositOp {
+            user: 1,
+            token: 0,
+            token_index: 0,
+            amount: 1000,
+        },
+    ]
+}
+
+/// A transfer of the first token from user 0 to user 1, paying the fee in
+/// the given token (Which user 0 keeps in the slot of the same index).
+fn transfer_paying_fee_in(fee_token: usize, fee: u64) -> (UpdateOp, usize) {
+    (
+        UpdateOp {
+            src: 0,
+            dst: 1,
+            token_index: 0,
+            fee_token_index: fee_token as u64,
+            dst_token_index: 0,
+            amount: 100,
+            fee,
+        },
+        fee_token,
+    )
+}
+
+#[test]
+fn test_multi_fee_update_of_mixed_fee_tokens() {
+    let users = users();
+    let mut mpn = seeded(&users, &two_token_seed());
+    let ops = [
+        transfer_paying_fee_in(1, 7),
+        transfer_paying_fee_in(0, 12),
+        transfer_paying_fee_in(1, 3),
+    ];
+    let circuit = multi_fee_update_batch(
+        &mut mpn,
+        &users,
+        &ops,
+        [token(1), token(0), TokenId::Ziesha],
+    );
+    assert!(circuit.transitions.0[..3].iter().all(|t| t.enabled));
+    assert_eq!(check(circuit.clone()), None);
+
+    // The sums are bound to their slots
+    let mut swapped = circuit;
+    swapped.fee_tokens = [token(0), token(1), TokenId::Ziesha];
+    assert!(check(swapped).is_some());
+}
+
+#[test]
+fn test_multi_fee_update_counts_repeated_slot_once() {
+    let users = users();
+    let mut mpn = seeded(&users, &two_token_seed());
+    let ops = [transfer_paying_fee_in(0, 10), transfer_paying_fee_in(0, 5)];
+    // The single fee token is padded with itself (`token(0)` is Ziesha)
+    let fee_tokens = [token(0), TokenId::Ziesha, TokenId::Ziesha];
+    let circuit = multi_fee_update_batch(&mut mpn, &users, &ops, fee_tokens);
+    assert_eq!(check(circuit.clone()), None);
+
+    let mut double_counted = circuit;
+    double_counted.aux_data = hash(
+        &fee_tokens
+            .iter()
+            .flat_map(|t| [(*t).into(), ZkScalar::from(15)])
+            .collect::<Vec<_>>(),
+    );
+    let unsatisfied = check(double_counted).expect("fees counted in every slot were accepted");
+    assert!(unsatisfied.contains("fee sums"), "{}", unsatisfied);
+}
+
+#[test]
+fn test_multi_fee_update_of_fee_token_outside_slots_rejected() {
+    let users = users();
+    let mut mpn = seeded(&users, &two_token_seed());
+    let ops = [transfer_paying_fee_in(0, 10), transfer_paying_fee_in(1, 7)];
+    // The fee paid in `token(1)` can't be collected by any of the slots
+    let circuit = multi_fee_update_batch(
+        &mut mpn,
+        &users,
+        &ops,
+        [token(0), TokenId::Ziesha, TokenId::Ziesha],
+    );
+    assert!(circuit.transitions.0[1].enabled);
+    assert!(check(circuit).is_some());
+}
@@ -0,0 +1,344 @@
+use super::balance::decrease_balance;
+use bazuka::core::{Money, TokenId};
+use bazuka::crypto::jubjub;
+use bazuka::zk::{MpnAccount, ZkHasher as _, ZkScalar};
+use bellman::gadgets::boolean::{AllocatedBit, Boolean};
+use bellman::gadgets::num::AllocatedNum;
+use bellman::{Circuit, ConstraintSystem, SynthesisError};
+use zeekit::common::Number;
+use zeekit::common::UnsignedInteger;
+use zeekit::eddsa::AllocatedPoint;
+use zeekit::merkle;
+use zeekit::{common, eddsa, poseidon, BellmanFr};
+
+/// Moves an account to a new key. The account stays at its index, which is no
+/// longer the one derived from its key: it has to be looked up by index once
+/// rotated.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct KeyRotation {
+    pub nonce: u32,
+    pub new_pub_key: jubjub::PointAffine,
+    pub fee: Money,
+    #[serde(default)]
+    pub sig: jubjub::Signature,
+}
+
+impl KeyRotation {
+    /// Message signed with the current key of the account:
+    /// `poseidon(KEY_ROTATION_TAG, nonce, new_pub_key.x, new_pub_key.y, fee_token_id, fee)`
+    pub fn hash(&self) -> ZkScalar {
+        bazuka::core::ZkHasher::hash(&[
+            super::KEY_ROTATION_TAG.into(),
+            (self.nonce as u64).into(),
+            self.new_pub_key.0,
+            self.new_pub_key.1,
+            self.fee.token_id.into(),
+            Into::<u64>::into(self.fee.amount).into(),
+        ])
+    }
+}
+
+// Validation:
+// 0. Check verify_proof(curr_root, before, proof)
+// 1. Check verify_sig(tx, before.address)
+// 2. after := before, with the new key, tx nonce incremented and the fee paid
+// 3. root_after := calc_new_root(after, proof)
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct KeyRotationTransition<const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8> {
+    pub enabled: bool,
+    pub index: u64,
+    pub fee_token_index: u64,
+    pub tx: KeyRotation,
+    pub before: MpnAccount, // after can be derived
+    pub before_balances_hash: ZkScalar,
+    pub before_fee_balance: Money,
+    pub proof: merkle::Proof<LOG4_TREE_SIZE>,
+    pub fee_balance_proof: merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct KeyRotationTransitionBatch<
+    const LOG4_BATCH_SIZE: u8,
+    const LOG4_TREE_SIZE: u8,
+    const LOG4_TOKENS_TREE_SIZE: u8,
+>(pub(crate) Vec<KeyRotationTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>);
+impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8> Default
+    for KeyRotationTransitionBatch<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>
+{
+    fn default() -> Self {
+        Self(
+            (0..1 << (2 * LOG4_BATCH_SIZE))
+                .map(|_| KeyRotationTransition::default())
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+/// Same public inputs as `UpdateCircuit`: The fees of the rotations are paid
+/// in a single token, and `aux_data` is `poseidon(fee_token, fee_sum)`.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct KeyRotationCircuit<
+    const LOG4_BATCH_SIZE: u8,
+    const LOG4_TREE_SIZE: u8,
+    const LOG4_TOKENS_TREE_SIZE: u8,
+> {
+    pub commitment: ZkScalar, // Public
+    pub height: u64,          // Public
+    pub state: ZkScalar,      // Public
+    pub aux_data: ZkScalar,   // Public
+    pub next_state: ZkScalar, // Public
+    pub fee_token: TokenId,   // Private
+    pub transitions:
+        Box<KeyRotationTransitionBatch<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>, // Secret :)
+}
+
+impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8>
+    Circuit<BellmanFr>
+    for KeyRotationCircuit<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>
+{
+    fn synthesize<CS: ConstraintSystem<BellmanFr>>(
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        // Reward commitment feeded as input
+        let commit_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.commitment.into()))?;
+        commit_wit.inputize(&mut *cs)?;
+
+        // Contract height feeded as input
+        let height_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.height.into()))?;
+        height_wit.inputize(&mut *cs)?;
+
+        // Previous state feeded as input
+        let mut state_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.state.into()))?;
+        state_wit.inputize(&mut *cs)?;
+
+        let accepted_fee_token = AllocatedNum::alloc(&mut *cs, || {
+            Ok(Into::<ZkScalar>::into(self.fee_token).into())
+        })?;
+
+        // Sum of internal tx fees feeded as input
+        let aux_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.aux_data.into()))?;
+        aux_wit.inputize(&mut *cs)?;
+
+        // Expected next state feeded as input
+        let claimed_next_state_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.next_state.into()))?;
+        claimed_next_state_wit.inputize(&mut *cs)?;
+
+        // Sum of tx fees as a linear-combination of tx fees
+        let mut fee_sum = Number::zero();
+
+        for (i, trans) in self.transitions.0.iter().enumerate() {
+            let cs = &mut cs.namespace(|| format!("transition {}", i));
+            // If enabled, transaction is validated, otherwise neglected
+            let enabled_wit = Boolean::Is(AllocatedBit::alloc(&mut *cs, Some(trans.enabled))?);
+
+            // Account index should always have at most LOG4_TREE_SIZE * 2 bits
+            let index_wit = UnsignedInteger::alloc(
+                &mut *cs,
+                (trans.index as u64).into(),
+                LOG4_TREE_SIZE as usize * 2,
+            )?;
+            let fee_token_index_wit = UnsignedInteger::alloc(
+                &mut *cs,
+                (trans.fee_token_index as u64).into(),
+                LOG4_TOKENS_TREE_SIZE as usize * 2,
+            )?;
+
+            let tx_nonce_wit =
+                AllocatedNum::alloc(&mut *cs, || Ok((trans.before.tx_nonce as u64).into()))?;
+            let withdraw_nonce_wit =
+                AllocatedNum::alloc(&mut *cs, || Ok((trans.before.withdraw_nonce as u64).into()))?;
+
+            // Current key of the account, the rotation is signed with
+            let addr_wit = AllocatedPoint::alloc(&mut *cs, || Ok(trans.before.address))?;
+            addr_wit.assert_on_curve(&mut *cs, &enabled_wit)?;
+
+            // New key should reside on curve (Which also rules out the null point)
+            let new_addr_wit = AllocatedPoint::alloc(&mut *cs, || Ok(trans.tx.new_pub_key))?;
+            new_addr_wit.assert_on_curve(&mut cs.namespace(|| "new key on curve"), &enabled_wit)?;
+
+            let balances_hash_wit =
+                AllocatedNum::alloc(&mut *cs, || Ok(trans.before_balances_hash.into()))?;
+
+            let fee_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+                Ok(Into::<ZkScalar>::into(trans.before_fee_balance.token_id).into())
+            })?;
+            let tx_fee_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+                Ok(Into::<ZkScalar>::into(trans.tx.fee.token_id).into())
+            })?;
+            Number::from(accepted_fee_token.clone()).assert_equal_if_enabled(
+                &mut cs.namespace(|| "accepted fee token"),
+                &enabled_wit,
+                &tx_fee_token_id_wit.clone().into(),
+            )?;
+            Number::from(fee_token_id_wit.clone()).assert_equal(
+                &mut cs.namespace(|| "fee token"),
+                &tx_fee_token_id_wit.clone().into(),
+            );
+
+            // Fee-token balance should always have at most 64 bits
+            let fee_balance_wit =
+                UnsignedInteger::alloc_64(&mut *cs, trans.before_fee_balance.amount.into())?;
+            let fee_balance_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "fee balance hash"),
+                &[
+                    &fee_token_id_wit.clone().into(),
+                    &fee_balance_wit.clone().into(),
+                ],
+            )?;
+            let mut fee_balance_proof_wits = Vec::new();
+            for b in trans.fee_balance_proof.0.clone() {
+                fee_balance_proof_wits.push([
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+                ]);
+            }
+            merkle::check_proof_poseidon4(
+                &mut cs.namespace(|| "fee balance proof"),
+                &enabled_wit,
+                &fee_token_index_wit.clone().into(),
+                &fee_balance_hash_wit,
+                &fee_balance_proof_wits,
+                &balances_hash_wit.clone().into(),
+            )?;
+
+            // Tx fee should be <= fee-token balance
+            let tx_fee_wit = UnsignedInteger::alloc_64(&mut *cs, trans.tx.fee.amount.into())?;
+            let new_fee_balance_wit = decrease_balance(
+                &mut cs.namespace(|| "fee balance range"),
+                &fee_balance_wit,
+                &tx_fee_wit,
+            )?;
+            let new_fee_balance_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "new fee balance hash"),
+                &[
+                    &fee_token_id_wit.clone().into(),
+                    &new_fee_balance_wit.into(),
+                ],
+            )?;
+            let new_balances_hash_wit = merkle::calc_root_poseidon4(
+                &mut cs.namespace(|| "new balances hash"),
+                &fee_token_index_wit.into(),
+                &new_fee_balance_hash_wit,
+                &fee_balance_proof_wits,
+            )?;
+
+            let hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "account hash"),
+                &[
+                    &tx_nonce_wit.clone().into(),
+                    &withdraw_nonce_wit.clone().into(),
+                    &addr_wit.x.clone().into(),
+                    &addr_wit.y.clone().into(),
+                    &balances_hash_wit.clone().into(),
+                ],
+            )?;
+            let mut proof_wits = Vec::new();
+            for b in trans.proof.0.clone() {
+                proof_wits.push([
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+                ]);
+            }
+            merkle::check_proof_poseidon4(
+                &mut cs.namespace(|| "account proof"),
+                &enabled_wit,
+                &index_wit.clone().into(),
+                &hash_wit,
+                &proof_wits,
+                &state_wit.clone().into(),
+            )?;
+
+            // Check tx nonce is equal with account nonce to prevent replaying the rotation
+            let tx_nonce_signed_wit =
+                AllocatedNum::alloc(&mut *cs, || Ok((trans.tx.nonce as u64).into()))?;
+            let new_tx_nonce_wit =
+                Number::from(tx_nonce_wit) + Number::constant::<CS>(BellmanFr::one());
+            Number::from(tx_nonce_signed_wit.clone()).assert_equal_if_enabled(
+                &mut cs.namespace(|| "nonce"),
+                &enabled_wit,
+                &new_tx_nonce_wit,
+            )?;
+
+            // Nonces and balances carry over, only the key is replaced
+            let new_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "new account hash"),
+                &[
+                    &new_tx_nonce_wit,
+                    &withdraw_nonce_wit.into(),
+                    &new_addr_wit.x.clone().into(),
+                    &new_addr_wit.y.clone().into(),
+                    &new_balances_hash_wit,
+                ],
+            )?;
+            let next_state_wit = merkle::calc_root_poseidon4(
+                &mut cs.namespace(|| "next state"),
+                &index_wit.into(),
+                &new_hash_wit,
+                &proof_wits,
+            )?;
+            state_wit = common::mux(
+                &mut cs.namespace(|| "state update"),
+                &enabled_wit,
+                &state_wit.into(),
+                &next_state_wit,
+            )?;
+
+            let tx_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "tx hash"),
+                &[
+                    &Number::constant::<CS>(BellmanFr::from(super::KEY_ROTATION_TAG)),
+                    &tx_nonce_signed_wit.into(),
+                    &new_addr_wit.x.clone().into(),
+                    &new_addr_wit.y.clone().into(),
+                    &tx_fee_token_id_wit.into(),
+                    &tx_fee_wit.clone().into(),
+                ],
+            )?;
+            let tx_sig_r_wit = AllocatedPoint::alloc(&mut *cs, || Ok(trans.tx.sig.r))?;
+            // Check if sig_r resides on curve
+            tx_sig_r_wit.assert_on_curve(&mut *cs, &enabled_wit)?;
+            let tx_sig_s_wit = AllocatedNum::alloc(&mut *cs, || Ok(trans.tx.sig.s.into()))?;
+
+            // Check EdDSA signature, made with the current key of the account
+            eddsa::verify_eddsa(
+                &mut cs.namespace(|| "signature"),
+                &enabled_wit,
+                &addr_wit,
+                &tx_hash_wit,
+                &tx_sig_r_wit,
+                &tx_sig_s_wit,
+            )?;
+
+            // Fee is zero if transaction slot is empty, otherwise it equals to transaction fee
+            let final_fee =
+                common::mux(&mut *cs, &enabled_wit, &Number::zero(), &tx_fee_wit.into())?;
+            fee_sum.add_num(BellmanFr::one(), &final_fee);
+        }
+
+        let fee_sum_and_token_hash = poseidon::poseidon(
+            &mut cs.namespace(|| "fee sum hash"),
+            &[&accepted_fee_token.into(), &fee_sum.into()],
+        )?;
+
+        // Check if sum of tx fees is equal with the feeded aux
+        cs.enforce(
+            || "fee sum",
+            |lc| lc + aux_wit.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + fee_sum_and_token_hash.get_lc(),
+        );
+
+        // Check if applying txs result in the claimed next state
+        cs.enforce(
+            || "next state",
+            |lc| lc + state_wit.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + claimed_next_state_wit.get_variable(),
+        );
+
+        Ok(())
+    }
+}
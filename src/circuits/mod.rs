@@ -1,9 +1,31 @@
+mod balance;
 mod deposit_circuit;
+mod key_rotation_circuit;
+mod multi_fee_update_circuit;
+mod multi_transfer_circuit;
+mod swap_circuit;
+mod sweep_circuit;
 mod update_circuit;
 mod withdraw_circuit;
 pub use deposit_circuit::*;
+pub use key_rotation_circuit::*;
+pub use multi_fee_update_circuit::*;
+pub use multi_transfer_circuit::*;
+pub use swap_circuit::*;
+pub use sweep_circuit::*;
 pub use update_circuit::*;
 pub use withdraw_circuit::*;
 
+// Domain-separation tags, the first element of the messages signed for the
+// transactions that have their own message, so that a signature for one kind
+// of transaction can't be replayed as another. They're above `u32::MAX`, so
+// that they can't be mistaken for the nonce the MPN messages start with.
+pub const KEY_ROTATION_TAG: u64 = 1 << 32;
+pub const MULTI_TRANSFER_TAG: u64 = (1 << 32) + 1;
+pub const SWAP_ORDER_TAG: u64 = (1 << 32) + 2;
+pub const SWEEP_TAG: u64 = (1 << 32) + 3;
+
+#[cfg(test)]
+mod fuzz;
 #[cfg(test)]
 mod test;
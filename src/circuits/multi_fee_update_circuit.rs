@@ -0,0 +1,139 @@
+use super::update_circuit::{synthesize_transition, TransitionBatch};
+use bazuka::core::TokenId;
+use bazuka::zk::ZkScalar;
+use bellman::gadgets::boolean::Boolean;
+use bellman::gadgets::num::AllocatedNum;
+use bellman::{Circuit, ConstraintSystem, SynthesisError};
+use zeekit::common::Number;
+use zeekit::{common, poseidon, BellmanFr};
+
+/// Number of distinct fee tokens a multi-fee batch can collect fees in.
+pub const FEE_SLOTS: usize = 3;
+
+/// Same as `UpdateCircuit`, but transactions may pay their fees in any of the
+/// `FEE_SLOTS` tokens of the batch. The fees are summed per token, and
+/// `aux_data` commits to all of the slots:
+/// `poseidon(token_1, fee_sum_1, ..., token_n, fee_sum_n)`
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MultiFeeUpdateCircuit<
+    const LOG4_BATCH_SIZE: u8,
+    const LOG4_TREE_SIZE: u8,
+    const LOG4_TOKENS_TREE_SIZE: u8,
+> {
+    pub commitment: ZkScalar,             // Public
+    pub height: u64,                      // Public
+    pub state: ZkScalar,                  // Public
+    pub aux_data: ZkScalar,               // Public
+    pub next_state: ZkScalar,             // Public
+    pub fee_tokens: [TokenId; FEE_SLOTS], // Private
+    pub transitions: Box<TransitionBatch<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>, // Secret :)
+    /// Apply the checks of the strict circuits (See `Dimensions::strict_circuits`)
+    pub strict: bool,
+}
+
+impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8>
+    Circuit<BellmanFr>
+    for MultiFeeUpdateCircuit<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>
+{
+    fn synthesize<CS: ConstraintSystem<BellmanFr>>(
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        // Reward commitment feeded as input
+        let commit_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.commitment.into()))?;
+        commit_wit.inputize(&mut *cs)?;
+
+        // Contract height feeded as input
+        let height_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.height.into()))?;
+        height_wit.inputize(&mut *cs)?;
+
+        // Previous state feeded as input
+        let mut state_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.state.into()))?;
+        state_wit.inputize(&mut *cs)?;
+
+        let mut fee_token_wits = Vec::new();
+        for token in self.fee_tokens.iter() {
+            fee_token_wits.push(AllocatedNum::alloc(&mut *cs, || {
+                Ok(Into::<ZkScalar>::into(*token).into())
+            })?);
+        }
+
+        // Hash of the fee tokens and their sums feeded as input
+        let aux_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.aux_data.into()))?;
+        aux_wit.inputize(&mut *cs)?;
+
+        // Expected next state feeded as input
+        let claimed_next_state_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.next_state.into()))?;
+        claimed_next_state_wit.inputize(&mut *cs)?;
+
+        // Sum of tx fees of each slot as a linear-combination of tx fees
+        let mut fee_sums = vec![Number::zero(); FEE_SLOTS];
+
+        for (i, trans) in self.transitions.0.iter().enumerate() {
+            let cs = &mut cs.namespace(|| format!("transition {}", i));
+            let mut slot_selectors = Vec::new();
+            let final_fee = synthesize_transition(
+                &mut *cs,
+                trans,
+                &mut state_wit,
+                self.strict,
+                |cs, enabled_wit, tx_fee_token_id_wit| {
+                    // A fee is only counted in the first slot of its token, so
+                    // that repeated slots can't count it twice
+                    let mut matched = Boolean::constant(false);
+                    for fee_token_wit in fee_token_wits.iter() {
+                        let is_slot_token = Number::from(fee_token_wit.clone())
+                            .is_equal(&mut *cs, &tx_fee_token_id_wit.clone().into())?;
+                        slot_selectors.push(Boolean::and(
+                            &mut *cs,
+                            &is_slot_token,
+                            &matched.not(),
+                        )?);
+                        matched = common::boolean_or(&mut *cs, &matched, &is_slot_token)?;
+                    }
+                    // Fee token of a non-empty transaction should be one of the slots
+                    let token_valid = common::boolean_or(&mut *cs, &matched, &enabled_wit.not())?;
+                    common::assert_true(&mut *cs, &token_valid);
+                    Ok(())
+                },
+            )?;
+            for (fee_sum, selector) in fee_sums.iter_mut().zip(slot_selectors.iter()) {
+                let slot_fee = common::mux(
+                    &mut cs.namespace(|| "slot fee"),
+                    selector,
+                    &Number::zero(),
+                    &final_fee.clone().into(),
+                )?;
+                fee_sum.add_num(BellmanFr::one(), &slot_fee);
+            }
+        }
+
+        let mut aux_preimage = Vec::new();
+        for (fee_token_wit, fee_sum) in fee_token_wits.iter().zip(fee_sums.iter()) {
+            aux_preimage.push(Number::from(fee_token_wit.clone()));
+            aux_preimage.push(fee_sum.clone());
+        }
+        let fee_sums_and_tokens_hash = poseidon::poseidon(
+            &mut cs.namespace(|| "fee sums hash"),
+            &aux_preimage.iter().collect::<Vec<_>>(),
+        )?;
+
+        // Check if the fee sums are equal with the feeded aux
+        cs.enforce(
+            || "fee sums",
+            |lc| lc + aux_wit.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + fee_sums_and_tokens_hash.get_lc(),
+        );
+
+        // Check if applying txs result in the claimed next state
+        cs.enforce(
+            || "next state",
+            |lc| lc + state_wit.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + claimed_next_state_wit.get_variable(),
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,637 @@
+use super::balance::{decrease_balance, increase_balance};
+use bazuka::core::{Money, TokenId};
+use bazuka::crypto::jubjub;
+use bazuka::zk::{MpnAccount, ZkHasher as _, ZkScalar};
+use bellman::gadgets::boolean::{AllocatedBit, Boolean};
+use bellman::gadgets::num::AllocatedNum;
+use bellman::{Circuit, ConstraintSystem, SynthesisError};
+use zeekit::common::Number;
+use zeekit::common::UnsignedInteger;
+use zeekit::eddsa::AllocatedPoint;
+use zeekit::merkle;
+use zeekit::{common, eddsa, poseidon, BellmanFr};
+
+/// Number of destinations a single multi-transfer can pay.
+pub const PAYOUTS: usize = 4;
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Payout {
+    pub dst_pub_key: jubjub::PointAffine,
+    pub amount: u64,
+}
+
+/// Pays up to `PAYOUTS` destinations in a single token, with one nonce and fee.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct MultiTransfer {
+    pub nonce: u32,
+    pub token_id: TokenId,
+    pub payouts: Vec<Payout>,
+    pub fee: Money,
+    #[serde(default)]
+    pub sig: jubjub::Signature,
+}
+
+impl MultiTransfer {
+    /// Message signed by the source account:
+    /// `poseidon(MULTI_TRANSFER_TAG, nonce, token_id, payouts_hash, fee_token_id, fee)`, where
+    /// `payouts_hash` chains `poseidon(prev, dst.x, dst.y, amount)` over the
+    /// payouts, starting from zero.
+    pub fn hash(&self) -> ZkScalar {
+        let mut payouts_hash = ZkScalar::from(0);
+        for payout in self.payouts.iter() {
+            payouts_hash = bazuka::core::ZkHasher::hash(&[
+                payouts_hash,
+                payout.dst_pub_key.0,
+                payout.dst_pub_key.1,
+                payout.amount.into(),
+            ]);
+        }
+        bazuka::core::ZkHasher::hash(&[
+            super::MULTI_TRANSFER_TAG.into(),
+            (self.nonce as u64).into(),
+            self.token_id.into(),
+            payouts_hash,
+            self.fee.token_id.into(),
+            Into::<u64>::into(self.fee.amount).into(),
+        ])
+    }
+}
+
+/// Destination side of a payout, against the state left by the previous one.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct PayoutTransition<const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8> {
+    pub index: u64,
+    pub token_index: u64,
+    pub before: MpnAccount, // after can be derived
+    pub before_balances_hash: ZkScalar,
+    pub before_balance: Money,
+    pub proof: merkle::Proof<LOG4_TREE_SIZE>,
+    pub balance_proof: merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+}
+
+// Validation:
+// 0. Check verify_sig(tx)
+// 1. Check verify_proof(curr_root, src_before, src_proof)
+// 2. src_after := src_before - (sum of payouts + fee), with tx nonce incremented
+// 3. For every payout: Check verify_proof(curr_root, dst_before, dst_proof)
+//    and apply dst_after := dst_before + amount
+// 4. Check next_state == root after the last payout
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct MultiTransferTransition<const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8> {
+    pub enabled: bool,
+    pub src_index: u64,
+    pub src_token_index: u64,
+    pub src_fee_token_index: u64,
+    pub tx: MultiTransfer,
+    pub src_before: MpnAccount, // src_after can be derived
+    pub src_before_balances_hash: ZkScalar,
+    pub src_before_balance: Money,
+    pub src_before_fee_balance: Money,
+    pub src_proof: merkle::Proof<LOG4_TREE_SIZE>,
+    pub src_balance_proof: merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+    pub src_fee_balance_proof: merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+    /// One per payout of `tx`, in the same order. Missing ones are empty.
+    pub payouts: Vec<PayoutTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MultiTransferTransitionBatch<
+    const LOG4_BATCH_SIZE: u8,
+    const LOG4_TREE_SIZE: u8,
+    const LOG4_TOKENS_TREE_SIZE: u8,
+>(pub(crate) Vec<MultiTransferTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>);
+impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8> Default
+    for MultiTransferTransitionBatch<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>
+{
+    fn default() -> Self {
+        Self(
+            (0..1 << (2 * LOG4_BATCH_SIZE))
+                .map(|_| MultiTransferTransition::default())
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+/// Same public inputs as `UpdateCircuit`, `aux_data` is
+/// `poseidon(fee_token, fee_sum)`. A transition costs a single signature and
+/// source update, plus a destination update per payout.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MultiTransferCircuit<
+    const LOG4_BATCH_SIZE: u8,
+    const LOG4_TREE_SIZE: u8,
+    const LOG4_TOKENS_TREE_SIZE: u8,
+> {
+    pub commitment: ZkScalar, // Public
+    pub height: u64,          // Public
+    pub state: ZkScalar,      // Public
+    pub aux_data: ZkScalar,   // Public
+    pub next_state: ZkScalar, // Public
+    pub fee_token: TokenId,   // Private
+    pub transitions:
+        Box<MultiTransferTransitionBatch<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>, // Secret :)
+}
+
+impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8>
+    Circuit<BellmanFr>
+    for MultiTransferCircuit<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>
+{
+    fn synthesize<CS: ConstraintSystem<BellmanFr>>(
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        // Reward commitment feeded as input
+        let commit_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.commitment.into()))?;
+        commit_wit.inputize(&mut *cs)?;
+
+        // Contract height feeded as input
+        let height_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.height.into()))?;
+        height_wit.inputize(&mut *cs)?;
+
+        // Previous state feeded as input
+        let mut state_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.state.into()))?;
+        state_wit.inputize(&mut *cs)?;
+
+        let accepted_fee_token = AllocatedNum::alloc(&mut *cs, || {
+            Ok(Into::<ZkScalar>::into(self.fee_token).into())
+        })?;
+
+        // Sum of internal tx fees feeded as input
+        let aux_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.aux_data.into()))?;
+        aux_wit.inputize(&mut *cs)?;
+
+        // Expected next state feeded as input
+        let claimed_next_state_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.next_state.into()))?;
+        claimed_next_state_wit.inputize(&mut *cs)?;
+
+        // Sum of tx fees as a linear-combination of tx fees
+        let mut fee_sum = Number::zero();
+
+        for (i, trans) in self.transitions.0.iter().enumerate() {
+            let cs = &mut cs.namespace(|| format!("transition {}", i));
+            // If enabled, transaction is validated, otherwise neglected
+            let enabled_wit = Boolean::Is(AllocatedBit::alloc(&mut *cs, Some(trans.enabled))?);
+
+            let src_index_wit = UnsignedInteger::alloc(
+                &mut *cs,
+                (trans.src_index as u64).into(),
+                LOG4_TREE_SIZE as usize * 2,
+            )?;
+            let src_token_index_wit = UnsignedInteger::alloc(
+                &mut *cs,
+                (trans.src_token_index as u64).into(),
+                LOG4_TOKENS_TREE_SIZE as usize * 2,
+            )?;
+            let src_fee_token_index_wit = UnsignedInteger::alloc(
+                &mut *cs,
+                (trans.src_fee_token_index as u64).into(),
+                LOG4_TOKENS_TREE_SIZE as usize * 2,
+            )?;
+
+            let src_tx_nonce_wit =
+                AllocatedNum::alloc(&mut *cs, || Ok((trans.src_before.tx_nonce as u64).into()))?;
+            let src_withdraw_nonce_wit = AllocatedNum::alloc(&mut *cs, || {
+                Ok((trans.src_before.withdraw_nonce as u64).into())
+            })?;
+            let src_addr_wit = AllocatedPoint::alloc(&mut *cs, || Ok(trans.src_before.address))?;
+            // Sender address should be on curve in case transaction slot is non-empty
+            src_addr_wit.assert_on_curve(&mut *cs, &enabled_wit)?;
+            let src_before_balances_hash =
+                AllocatedNum::alloc(&mut *cs, || Ok(trans.src_before_balances_hash.into()))?;
+
+            let tx_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+                Ok(Into::<ZkScalar>::into(trans.tx.token_id).into())
+            })?;
+            let tx_fee_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+                Ok(Into::<ZkScalar>::into(trans.tx.fee.token_id).into())
+            })?;
+            Number::from(accepted_fee_token.clone()).assert_equal_if_enabled(
+                &mut cs.namespace(|| "accepted fee token"),
+                &enabled_wit,
+                &tx_fee_token_id_wit.clone().into(),
+            )?;
+
+            // Payouts are only enabled in enabled transitions, and each enabled
+            // one is committed to in the signed payouts hash
+            let mut payout_enabled_wits = Vec::new();
+            let mut payout_dst_wits = Vec::new();
+            let mut payout_amount_wits = Vec::new();
+            let mut total_amount = Number::zero();
+            let mut payouts_hash = Number::zero();
+            for j in 0..PAYOUTS {
+                let cs = &mut cs.namespace(|| format!("payout {} hash", j));
+                let payout = trans.tx.payouts.get(j).cloned().unwrap_or_default();
+                let present_wit = Boolean::Is(AllocatedBit::alloc(
+                    &mut *cs,
+                    Some(j < trans.tx.payouts.len()),
+                )?);
+                let payout_enabled_wit = Boolean::and(&mut *cs, &enabled_wit, &present_wit)?;
+
+                let dst_addr_wit = AllocatedPoint::alloc(&mut *cs, || Ok(payout.dst_pub_key))?;
+                // Destination address should be on curve in case the payout is non-empty
+                dst_addr_wit.assert_on_curve(&mut *cs, &payout_enabled_wit)?;
+                // Payout amount should at most have 64 bits
+                let amount_wit = UnsignedInteger::alloc_64(&mut *cs, payout.amount.into())?;
+
+                let payout_hash = poseidon::poseidon(
+                    &mut cs.namespace(|| "payout hash"),
+                    &[
+                        &payouts_hash,
+                        &dst_addr_wit.x.clone().into(),
+                        &dst_addr_wit.y.clone().into(),
+                        &amount_wit.clone().into(),
+                    ],
+                )?;
+                payouts_hash =
+                    common::mux(&mut *cs, &payout_enabled_wit, &payouts_hash, &payout_hash)?.into();
+                let final_amount = common::mux(
+                    &mut *cs,
+                    &payout_enabled_wit,
+                    &Number::zero(),
+                    &amount_wit.clone().into(),
+                )?;
+                total_amount.add_num(BellmanFr::one(), &final_amount);
+
+                payout_enabled_wits.push(payout_enabled_wit);
+                payout_dst_wits.push(dst_addr_wit);
+                payout_amount_wits.push(amount_wit);
+            }
+            // Sum of the payouts should also fit in 64 bits
+            let total_amount_wit = UnsignedInteger::constrain(
+                &mut cs.namespace(|| "total amount range"),
+                total_amount,
+                64,
+            )?;
+
+            let src_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+                Ok(Into::<ZkScalar>::into(trans.src_before_balance.token_id).into())
+            })?;
+            Number::from(src_token_id_wit.clone()).assert_equal(
+                &mut cs.namespace(|| "amount token"),
+                &tx_token_id_wit.clone().into(),
+            );
+            let src_balance_wit =
+                UnsignedInteger::alloc_64(&mut *cs, trans.src_before_balance.amount.into())?;
+            let src_token_balance_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "src token balance hash"),
+                &[
+                    &src_token_id_wit.clone().into(),
+                    &src_balance_wit.clone().into(),
+                ],
+            )?;
+            let mut src_balance_proof_wits = Vec::new();
+            for b in trans.src_balance_proof.0.clone() {
+                src_balance_proof_wits.push([
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+                ]);
+            }
+            merkle::check_proof_poseidon4(
+                &mut cs.namespace(|| "src balance proof"),
+                &enabled_wit,
+                &src_token_index_wit.clone().into(),
+                &src_token_balance_hash_wit,
+                &src_balance_proof_wits,
+                &src_before_balances_hash.clone().into(),
+            )?;
+
+            // Sum of the payouts should be <= src balance
+            let new_src_balance_wit = decrease_balance(
+                &mut cs.namespace(|| "src balance range"),
+                &src_balance_wit,
+                &total_amount_wit,
+            )?;
+            let new_src_token_balance_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "new src token balance hash"),
+                &[
+                    &src_token_id_wit.clone().into(),
+                    &new_src_balance_wit.into(),
+                ],
+            )?;
+            let balance_middle_root = merkle::calc_root_poseidon4(
+                &mut cs.namespace(|| "balance middle root"),
+                &src_token_index_wit.into(),
+                &new_src_token_balance_hash_wit,
+                &src_balance_proof_wits,
+            )?;
+
+            let src_fee_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+                Ok(Into::<ZkScalar>::into(trans.src_before_fee_balance.token_id).into())
+            })?;
+            Number::from(src_fee_token_id_wit.clone()).assert_equal(
+                &mut cs.namespace(|| "fee token"),
+                &tx_fee_token_id_wit.clone().into(),
+            );
+            let src_fee_balance_wit =
+                UnsignedInteger::alloc_64(&mut *cs, trans.src_before_fee_balance.amount.into())?;
+            let src_fee_token_balance_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "src fee token balance hash"),
+                &[
+                    &src_fee_token_id_wit.clone().into(),
+                    &src_fee_balance_wit.clone().into(),
+                ],
+            )?;
+            let mut src_fee_balance_proof_wits = Vec::new();
+            for b in trans.src_fee_balance_proof.0.clone() {
+                src_fee_balance_proof_wits.push([
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+                ]);
+            }
+            merkle::check_proof_poseidon4(
+                &mut cs.namespace(|| "src fee balance proof"),
+                &enabled_wit,
+                &src_fee_token_index_wit.clone().into(),
+                &src_fee_token_balance_hash_wit,
+                &src_fee_balance_proof_wits,
+                &balance_middle_root,
+            )?;
+
+            // Tx fee should be <= src fee-token balance (After the payouts are
+            // paid, if the tokens are the same)
+            let tx_fee_wit = UnsignedInteger::alloc_64(&mut *cs, trans.tx.fee.amount.into())?;
+            let new_src_fee_balance_wit = decrease_balance(
+                &mut cs.namespace(|| "src fee balance range"),
+                &src_fee_balance_wit,
+                &tx_fee_wit,
+            )?;
+            let new_src_fee_token_balance_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "new src fee token balance hash"),
+                &[
+                    &src_fee_token_id_wit.clone().into(),
+                    &new_src_fee_balance_wit.into(),
+                ],
+            )?;
+            let src_balance_final_root = merkle::calc_root_poseidon4(
+                &mut cs.namespace(|| "src balance final root"),
+                &src_fee_token_index_wit.into(),
+                &new_src_fee_token_balance_hash_wit,
+                &src_fee_balance_proof_wits,
+            )?;
+
+            let src_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "src account hash"),
+                &[
+                    &src_tx_nonce_wit.clone().into(),
+                    &src_withdraw_nonce_wit.clone().into(),
+                    &src_addr_wit.x.clone().into(),
+                    &src_addr_wit.y.clone().into(),
+                    &src_before_balances_hash.into(),
+                ],
+            )?;
+            let mut src_proof_wits = Vec::new();
+            for b in trans.src_proof.0.clone() {
+                src_proof_wits.push([
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+                ]);
+            }
+            merkle::check_proof_poseidon4(
+                &mut cs.namespace(|| "src account proof"),
+                &enabled_wit,
+                &src_index_wit.clone().into(),
+                &src_hash_wit,
+                &src_proof_wits,
+                &state_wit.clone().into(),
+            )?;
+
+            // Source nonce is incremented once, no matter how many payouts
+            let new_src_tx_nonce_wit =
+                Number::from(src_tx_nonce_wit) + Number::constant::<CS>(BellmanFr::one());
+            let new_src_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "new src account hash"),
+                &[
+                    &new_src_tx_nonce_wit,
+                    &src_withdraw_nonce_wit.into(),
+                    &src_addr_wit.x.clone().into(),
+                    &src_addr_wit.y.clone().into(),
+                    &src_balance_final_root,
+                ],
+            )?;
+            let middle_root_wit = merkle::calc_root_poseidon4(
+                &mut cs.namespace(|| "middle root"),
+                &src_index_wit.into(),
+                &new_src_hash_wit,
+                &src_proof_wits,
+            )?;
+            state_wit = common::mux(
+                &mut cs.namespace(|| "src state update"),
+                &enabled_wit,
+                &state_wit.into(),
+                &middle_root_wit,
+            )?;
+
+            // Each payout is applied on the state left by the previous one, so
+            // that a destination may be paid more than once
+            for j in 0..PAYOUTS {
+                let cs = &mut cs.namespace(|| format!("payout {}", j));
+                let payout = trans.payouts.get(j).cloned().unwrap_or_default();
+                let payout_enabled_wit = &payout_enabled_wits[j];
+                let tx_dst_addr_wit = &payout_dst_wits[j];
+
+                let dst_index_wit = UnsignedInteger::alloc(
+                    &mut *cs,
+                    (payout.index as u64).into(),
+                    LOG4_TREE_SIZE as usize * 2,
+                )?;
+                let dst_token_index_wit = UnsignedInteger::alloc(
+                    &mut *cs,
+                    (payout.token_index as u64).into(),
+                    LOG4_TOKENS_TREE_SIZE as usize * 2,
+                )?;
+                let dst_tx_nonce_wit =
+                    AllocatedNum::alloc(&mut *cs, || Ok((payout.before.tx_nonce as u64).into()))?;
+                let dst_withdraw_nonce_wit = AllocatedNum::alloc(&mut *cs, || {
+                    Ok((payout.before.withdraw_nonce as u64).into())
+                })?;
+                // Destination address doesn't necessarily need to reside on curve as it might be empty
+                let dst_addr_wit = AllocatedPoint::alloc(&mut *cs, || Ok(payout.before.address))?;
+                let dst_before_balances_hash =
+                    AllocatedNum::alloc(&mut *cs, || Ok(payout.before_balances_hash.into()))?;
+
+                // Address of destination account slot can either be empty or equal with payout
+                // destination. Empty payout slots are not checked, they aren't applied anyway.
+                let is_dst_null = dst_addr_wit.is_null(&mut *cs)?;
+                let is_dst_and_tx_dst_equal = dst_addr_wit.is_equal(&mut *cs, tx_dst_addr_wit)?;
+                let addr_valid =
+                    common::boolean_or(&mut *cs, &is_dst_null, &is_dst_and_tx_dst_equal)?;
+                let addr_valid =
+                    common::boolean_or(&mut *cs, &addr_valid, &payout_enabled_wit.not())?;
+                common::assert_true(&mut cs.namespace(|| "dst address"), &addr_valid);
+
+                let dst_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+                    Ok(Into::<ZkScalar>::into(payout.before_balance.token_id).into())
+                })?;
+                // Token-id of dst slot can either be empty or equal with tx token-id
+                let is_dst_token_id_null =
+                    Number::from(dst_token_id_wit.clone()).is_zero(&mut *cs)?;
+                let is_dst_token_id_and_tx_token_id_equal = Number::from(dst_token_id_wit.clone())
+                    .is_equal(&mut *cs, &tx_token_id_wit.clone().into())?;
+                let dst_token_id_valid = common::boolean_or(
+                    &mut *cs,
+                    &is_dst_token_id_null,
+                    &is_dst_token_id_and_tx_token_id_equal,
+                )?;
+                let dst_token_id_valid =
+                    common::boolean_or(&mut *cs, &dst_token_id_valid, &payout_enabled_wit.not())?;
+                common::assert_true(&mut cs.namespace(|| "dst token id"), &dst_token_id_valid);
+
+                // Dst balance should always have at most 64 bits
+                let dst_balance_wit =
+                    UnsignedInteger::alloc_64(&mut *cs, payout.before_balance.amount.into())?;
+                let dst_token_balance_hash_wit = poseidon::poseidon(
+                    &mut cs.namespace(|| "dst token balance hash"),
+                    &[&dst_token_id_wit.into(), &dst_balance_wit.clone().into()],
+                )?;
+                // New dst balance should also fit in 64 bits
+                let new_dst_balance_wit = increase_balance(
+                    &mut cs.namespace(|| "dst balance range"),
+                    &dst_balance_wit,
+                    &payout_amount_wits[j],
+                )?;
+                let new_dst_token_balance_hash_wit = poseidon::poseidon(
+                    &mut cs.namespace(|| "new dst token balance hash"),
+                    &[&tx_token_id_wit.clone().into(), &new_dst_balance_wit.into()],
+                )?;
+
+                let mut dst_balance_proof_wits = Vec::new();
+                for b in payout.balance_proof.0.clone() {
+                    dst_balance_proof_wits.push([
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+                    ]);
+                }
+                merkle::check_proof_poseidon4(
+                    &mut cs.namespace(|| "dst balance proof"),
+                    payout_enabled_wit,
+                    &dst_token_index_wit.clone().into(),
+                    &dst_token_balance_hash_wit,
+                    &dst_balance_proof_wits,
+                    &dst_before_balances_hash.clone().into(),
+                )?;
+                let dst_balance_final_root = merkle::calc_root_poseidon4(
+                    &mut cs.namespace(|| "dst balance final root"),
+                    &dst_token_index_wit.into(),
+                    &new_dst_token_balance_hash_wit,
+                    &dst_balance_proof_wits,
+                )?;
+
+                let dst_hash_wit = poseidon::poseidon(
+                    &mut cs.namespace(|| "dst account hash"),
+                    &[
+                        &dst_tx_nonce_wit.clone().into(),
+                        &dst_withdraw_nonce_wit.clone().into(),
+                        &dst_addr_wit.x.clone().into(),
+                        &dst_addr_wit.y.clone().into(),
+                        &dst_before_balances_hash.into(),
+                    ],
+                )?;
+                let mut dst_proof_wits = Vec::new();
+                for b in payout.proof.0.clone() {
+                    dst_proof_wits.push([
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+                    ]);
+                }
+                merkle::check_proof_poseidon4(
+                    &mut cs.namespace(|| "dst account proof"),
+                    payout_enabled_wit,
+                    &dst_index_wit.clone().into(),
+                    &dst_hash_wit,
+                    &dst_proof_wits,
+                    &state_wit.clone().into(),
+                )?;
+
+                let new_dst_hash_wit = poseidon::poseidon(
+                    &mut cs.namespace(|| "new dst account hash"),
+                    &[
+                        &dst_tx_nonce_wit.into(),
+                        &dst_withdraw_nonce_wit.into(),
+                        &tx_dst_addr_wit.x.clone().into(),
+                        &tx_dst_addr_wit.y.clone().into(),
+                        &dst_balance_final_root,
+                    ],
+                )?;
+                let next_state_wit = merkle::calc_root_poseidon4(
+                    &mut cs.namespace(|| "next state"),
+                    &dst_index_wit.into(),
+                    &new_dst_hash_wit,
+                    &dst_proof_wits,
+                )?;
+                state_wit = common::mux(
+                    &mut cs.namespace(|| "state update"),
+                    payout_enabled_wit,
+                    &state_wit.into(),
+                    &next_state_wit,
+                )?;
+            }
+
+            // Check tx nonce is equal with account nonce to prevent double spending
+            let tx_nonce_wit =
+                AllocatedNum::alloc(&mut *cs, || Ok((trans.tx.nonce as u64).into()))?;
+            Number::from(tx_nonce_wit.clone()).assert_equal_if_enabled(
+                &mut cs.namespace(|| "nonce"),
+                &enabled_wit,
+                &new_src_tx_nonce_wit,
+            )?;
+
+            let tx_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "tx hash"),
+                &[
+                    &Number::constant::<CS>(BellmanFr::from(super::MULTI_TRANSFER_TAG)),
+                    &tx_nonce_wit.into(),
+                    &tx_token_id_wit.into(),
+                    &payouts_hash,
+                    &tx_fee_token_id_wit.into(),
+                    &tx_fee_wit.clone().into(),
+                ],
+            )?;
+            let tx_sig_r_wit = AllocatedPoint::alloc(&mut *cs, || Ok(trans.tx.sig.r))?;
+            // Check if sig_r resides on curve
+            tx_sig_r_wit.assert_on_curve(&mut *cs, &enabled_wit)?;
+            let tx_sig_s_wit = AllocatedNum::alloc(&mut *cs, || Ok(trans.tx.sig.s.into()))?;
+
+            // Check EdDSA signature
+            eddsa::verify_eddsa(
+                &mut cs.namespace(|| "signature"),
+                &enabled_wit,
+                &src_addr_wit,
+                &tx_hash_wit,
+                &tx_sig_r_wit,
+                &tx_sig_s_wit,
+            )?;
+
+            // Fee is zero if transaction slot is empty, otherwise it equals to transaction fee
+            let final_fee =
+                common::mux(&mut *cs, &enabled_wit, &Number::zero(), &tx_fee_wit.into())?;
+            fee_sum.add_num(BellmanFr::one(), &final_fee);
+        }
+
+        let fee_sum_and_token_hash = poseidon::poseidon(
+            &mut cs.namespace(|| "fee sum hash"),
+            &[&accepted_fee_token.into(), &fee_sum.into()],
+        )?;
+
+        // Check if sum of tx fees is equal with the feeded aux
+        cs.enforce(
+            || "fee sum",
+            |lc| lc + aux_wit.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + fee_sum_and_token_hash.get_lc(),
+        );
+
+        // Check if applying txs result in the claimed next state
+        cs.enforce(
+            || "next state",
+            |lc| lc + state_wit.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + claimed_next_state_wit.get_variable(),
+        );
+
+        Ok(())
+    }
+}
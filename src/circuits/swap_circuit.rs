@@ -0,0 +1,501 @@
+use super::balance::{decrease_balance, increase_balance};
+use bazuka::core::{Money, TokenId};
+use bazuka::crypto::jubjub;
+use bazuka::zk::{MpnAccount, ZkHasher as _, ZkScalar};
+use bellman::gadgets::boolean::{AllocatedBit, Boolean};
+use bellman::gadgets::num::AllocatedNum;
+use bellman::{Circuit, ConstraintSystem, SynthesisError};
+use zeekit::common::Number;
+use zeekit::common::UnsignedInteger;
+use zeekit::eddsa::AllocatedPoint;
+use zeekit::merkle;
+use zeekit::{common, eddsa, poseidon, BellmanFr};
+
+/// Gives `give` in exchange of `take`, to whichever order it's matched with.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct SwapOrder {
+    pub nonce: u32,
+    pub give: Money,
+    pub take: Money,
+    pub fee: Money,
+    #[serde(default)]
+    pub sig: jubjub::Signature,
+}
+
+impl SwapOrder {
+    /// Message signed by the account placing the order:
+    /// `poseidon(SWAP_ORDER_TAG, nonce, give_token_id, give, take_token_id, take, fee_token_id, fee)`
+    pub fn hash(&self) -> ZkScalar {
+        bazuka::core::ZkHasher::hash(&[
+            super::SWAP_ORDER_TAG.into(),
+            (self.nonce as u64).into(),
+            self.give.token_id.into(),
+            Into::<u64>::into(self.give.amount).into(),
+            self.take.token_id.into(),
+            Into::<u64>::into(self.take.amount).into(),
+            self.fee.token_id.into(),
+            Into::<u64>::into(self.fee.amount).into(),
+        ])
+    }
+}
+
+/// One of the accounts of a swap. The balances of the give, take and fee slots
+/// are each taken after the previous slots are updated.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct SwapSide<const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8> {
+    pub index: u64,
+    pub give_token_index: u64,
+    pub take_token_index: u64,
+    pub fee_token_index: u64,
+    pub order: SwapOrder,
+    pub before: MpnAccount, // after can be derived
+    pub before_balances_hash: ZkScalar,
+    pub before_give_balance: Money,
+    pub before_take_balance: Money,
+    pub before_fee_balance: Money,
+    pub proof: merkle::Proof<LOG4_TREE_SIZE>,
+    pub give_balance_proof: merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+    pub take_balance_proof: merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+    pub fee_balance_proof: merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+}
+
+// Validation:
+// 0. Check a.give == b.take and b.give == a.take
+// 1. For a, then b: Check verify_sig(order) and verify_proof(curr_root, before, proof)
+// 2. after := before - give + take - fee, with tx nonce incremented
+// 3. curr_root := calc_new_root(after, proof)
+// 4. Check next_state == root after b
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct SwapTransition<const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8> {
+    pub enabled: bool,
+    pub a: SwapSide<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>,
+    pub b: SwapSide<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SwapTransitionBatch<
+    const LOG4_BATCH_SIZE: u8,
+    const LOG4_TREE_SIZE: u8,
+    const LOG4_TOKENS_TREE_SIZE: u8,
+>(pub(crate) Vec<SwapTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>);
+impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8> Default
+    for SwapTransitionBatch<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>
+{
+    fn default() -> Self {
+        Self(
+            (0..1 << (2 * LOG4_BATCH_SIZE))
+                .map(|_| SwapTransition::default())
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+/// Same public inputs as `UpdateCircuit`: Both sides of a swap pay their fees
+/// in the fee token of the batch, and `aux_data` is `poseidon(fee_token, fee_sum)`.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SwapCircuit<
+    const LOG4_BATCH_SIZE: u8,
+    const LOG4_TREE_SIZE: u8,
+    const LOG4_TOKENS_TREE_SIZE: u8,
+> {
+    pub commitment: ZkScalar, // Public
+    pub height: u64,          // Public
+    pub state: ZkScalar,      // Public
+    pub aux_data: ZkScalar,   // Public
+    pub next_state: ZkScalar, // Public
+    pub fee_token: TokenId,   // Private
+    pub transitions:
+        Box<SwapTransitionBatch<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>, // Secret :)
+}
+
+impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8>
+    Circuit<BellmanFr> for SwapCircuit<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>
+{
+    fn synthesize<CS: ConstraintSystem<BellmanFr>>(
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        // Reward commitment feeded as input
+        let commit_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.commitment.into()))?;
+        commit_wit.inputize(&mut *cs)?;
+
+        // Contract height feeded as input
+        let height_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.height.into()))?;
+        height_wit.inputize(&mut *cs)?;
+
+        // Previous state feeded as input
+        let mut state_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.state.into()))?;
+        state_wit.inputize(&mut *cs)?;
+
+        let accepted_fee_token = AllocatedNum::alloc(&mut *cs, || {
+            Ok(Into::<ZkScalar>::into(self.fee_token).into())
+        })?;
+
+        // Sum of internal tx fees feeded as input
+        let aux_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.aux_data.into()))?;
+        aux_wit.inputize(&mut *cs)?;
+
+        // Expected next state feeded as input
+        let claimed_next_state_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.next_state.into()))?;
+        claimed_next_state_wit.inputize(&mut *cs)?;
+
+        // Sum of tx fees as a linear-combination of tx fees
+        let mut fee_sum = Number::zero();
+
+        for (i, trans) in self.transitions.0.iter().enumerate() {
+            let cs = &mut cs.namespace(|| format!("transition {}", i));
+            // If enabled, both orders are validated and settled, otherwise neglected
+            let enabled_wit = Boolean::Is(AllocatedBit::alloc(&mut *cs, Some(trans.enabled))?);
+
+            let a = synthesize_side(
+                &mut cs.namespace(|| "a"),
+                &enabled_wit,
+                &accepted_fee_token,
+                &trans.a,
+                &mut state_wit,
+            )?;
+            let b = synthesize_side(
+                &mut cs.namespace(|| "b"),
+                &enabled_wit,
+                &accepted_fee_token,
+                &trans.b,
+                &mut state_wit,
+            )?;
+
+            // Each side should receive exactly what the other one gives
+            Number::from(a.give_token_id).assert_equal_if_enabled(
+                &mut cs.namespace(|| "a give token"),
+                &enabled_wit,
+                &b.take_token_id.into(),
+            )?;
+            Number::from(a.give_amount).assert_equal_if_enabled(
+                &mut cs.namespace(|| "a give amount"),
+                &enabled_wit,
+                &b.take_amount.into(),
+            )?;
+            Number::from(b.give_token_id).assert_equal_if_enabled(
+                &mut cs.namespace(|| "b give token"),
+                &enabled_wit,
+                &a.take_token_id.into(),
+            )?;
+            Number::from(b.give_amount).assert_equal_if_enabled(
+                &mut cs.namespace(|| "b give amount"),
+                &enabled_wit,
+                &a.take_amount.into(),
+            )?;
+
+            fee_sum.add_num(BellmanFr::one(), &a.fee);
+            fee_sum.add_num(BellmanFr::one(), &b.fee);
+        }
+
+        let fee_sum_and_token_hash = poseidon::poseidon(
+            &mut cs.namespace(|| "fee sum hash"),
+            &[&accepted_fee_token.into(), &fee_sum.into()],
+        )?;
+
+        // Check if sum of tx fees is equal with the feeded aux
+        cs.enforce(
+            || "fee sum",
+            |lc| lc + aux_wit.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + fee_sum_and_token_hash.get_lc(),
+        );
+
+        // Check if applying txs result in the claimed next state
+        cs.enforce(
+            || "next state",
+            |lc| lc + state_wit.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + claimed_next_state_wit.get_variable(),
+        );
+
+        Ok(())
+    }
+}
+
+/// What a side of a swap gives and takes, for matching it with the other side.
+struct SwapSideWits {
+    give_token_id: AllocatedNum<BellmanFr>,
+    give_amount: UnsignedInteger,
+    take_token_id: AllocatedNum<BellmanFr>,
+    take_amount: UnsignedInteger,
+    /// Zero if the transition is disabled
+    fee: AllocatedNum<BellmanFr>,
+}
+
+/// Checks the `(token_id, balance)` of a token slot against `root`, and
+/// returns the root after the slot is set to `after`.
+fn update_balance_slot<CS: ConstraintSystem<BellmanFr>, const LOG4_TOKENS_TREE_SIZE: u8>(
+    cs: &mut CS,
+    enabled_wit: &Boolean,
+    index_wit: &UnsignedInteger,
+    (token_id_wit, balance_wit): (&AllocatedNum<BellmanFr>, &UnsignedInteger),
+    (new_token_id_wit, new_balance_wit): (&AllocatedNum<BellmanFr>, UnsignedInteger),
+    proof: &merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+    root: &Number,
+) -> Result<Number, SynthesisError> {
+    let balance_hash_wit = poseidon::poseidon(
+        &mut cs.namespace(|| "balance hash"),
+        &[&token_id_wit.clone().into(), &balance_wit.clone().into()],
+    )?;
+    let mut proof_wits = Vec::new();
+    for b in proof.0.clone() {
+        proof_wits.push([
+            AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+            AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+            AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+        ]);
+    }
+    merkle::check_proof_poseidon4(
+        &mut cs.namespace(|| "proof"),
+        enabled_wit,
+        &index_wit.clone().into(),
+        &balance_hash_wit,
+        &proof_wits,
+        root,
+    )?;
+    let new_balance_hash_wit = poseidon::poseidon(
+        &mut cs.namespace(|| "new balance hash"),
+        &[&new_token_id_wit.clone().into(), &new_balance_wit.into()],
+    )?;
+    merkle::calc_root_poseidon4(
+        &mut cs.namespace(|| "new balances hash"),
+        &index_wit.clone().into(),
+        &new_balance_hash_wit,
+        &proof_wits,
+    )
+}
+
+/// Validates the order of a side and applies it on `state_wit`, when enabled.
+fn synthesize_side<
+    CS: ConstraintSystem<BellmanFr>,
+    const LOG4_TREE_SIZE: u8,
+    const LOG4_TOKENS_TREE_SIZE: u8,
+>(
+    cs: &mut CS,
+    enabled_wit: &Boolean,
+    accepted_fee_token: &AllocatedNum<BellmanFr>,
+    side: &SwapSide<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>,
+    state_wit: &mut AllocatedNum<BellmanFr>,
+) -> Result<SwapSideWits, SynthesisError> {
+    // Account index should always have at most LOG4_TREE_SIZE * 2 bits
+    let index_wit = UnsignedInteger::alloc(
+        &mut *cs,
+        (side.index as u64).into(),
+        LOG4_TREE_SIZE as usize * 2,
+    )?;
+    let mut token_index_wits = Vec::new();
+    for token_index in [
+        side.give_token_index,
+        side.take_token_index,
+        side.fee_token_index,
+    ] {
+        token_index_wits.push(UnsignedInteger::alloc(
+            &mut *cs,
+            (token_index as u64).into(),
+            LOG4_TOKENS_TREE_SIZE as usize * 2,
+        )?);
+    }
+
+    let tx_nonce_wit = AllocatedNum::alloc(&mut *cs, || Ok((side.before.tx_nonce as u64).into()))?;
+    let withdraw_nonce_wit =
+        AllocatedNum::alloc(&mut *cs, || Ok((side.before.withdraw_nonce as u64).into()))?;
+    let addr_wit = AllocatedPoint::alloc(&mut *cs, || Ok(side.before.address))?;
+    // Address should be on curve in case transaction slot is non-empty
+    addr_wit.assert_on_curve(&mut *cs, enabled_wit)?;
+    let balances_hash_wit = AllocatedNum::alloc(&mut *cs, || Ok(side.before_balances_hash.into()))?;
+
+    // Order amounts and fee should at most have 64 bits
+    let give_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+        Ok(Into::<ZkScalar>::into(side.order.give.token_id).into())
+    })?;
+    let give_amount_wit = UnsignedInteger::alloc_64(&mut *cs, side.order.give.amount.into())?;
+    let take_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+        Ok(Into::<ZkScalar>::into(side.order.take.token_id).into())
+    })?;
+    let take_amount_wit = UnsignedInteger::alloc_64(&mut *cs, side.order.take.amount.into())?;
+    let fee_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+        Ok(Into::<ZkScalar>::into(side.order.fee.token_id).into())
+    })?;
+    let fee_wit = UnsignedInteger::alloc_64(&mut *cs, side.order.fee.amount.into())?;
+
+    Number::from(accepted_fee_token.clone()).assert_equal_if_enabled(
+        &mut cs.namespace(|| "accepted fee token"),
+        enabled_wit,
+        &fee_token_id_wit.clone().into(),
+    )?;
+
+    // Given amount should be <= balance of the give slot
+    let give_slot_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+        Ok(Into::<ZkScalar>::into(side.before_give_balance.token_id).into())
+    })?;
+    Number::from(give_slot_token_id_wit.clone()).assert_equal(
+        &mut cs.namespace(|| "give token"),
+        &give_token_id_wit.clone().into(),
+    );
+    let give_balance_wit =
+        UnsignedInteger::alloc_64(&mut *cs, side.before_give_balance.amount.into())?;
+    let new_give_balance_wit = decrease_balance(
+        &mut cs.namespace(|| "give balance range"),
+        &give_balance_wit,
+        &give_amount_wit,
+    )?;
+    let give_root = update_balance_slot(
+        &mut cs.namespace(|| "give balance"),
+        enabled_wit,
+        &token_index_wits[0],
+        (&give_slot_token_id_wit, &give_balance_wit),
+        (&give_slot_token_id_wit, new_give_balance_wit),
+        &side.give_balance_proof,
+        &balances_hash_wit.clone().into(),
+    )?;
+
+    // Token-id of the take slot can either be empty or equal with the taken token-id
+    let take_slot_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+        Ok(Into::<ZkScalar>::into(side.before_take_balance.token_id).into())
+    })?;
+    let is_take_token_id_null = Number::from(take_slot_token_id_wit.clone()).is_zero(&mut *cs)?;
+    let is_take_token_id_equal = Number::from(take_slot_token_id_wit.clone())
+        .is_equal(&mut *cs, &take_token_id_wit.clone().into())?;
+    let take_token_id_valid =
+        common::boolean_or(&mut *cs, &is_take_token_id_null, &is_take_token_id_equal)?;
+    common::assert_true(&mut cs.namespace(|| "take token id"), &take_token_id_valid);
+    let take_balance_wit =
+        UnsignedInteger::alloc_64(&mut *cs, side.before_take_balance.amount.into())?;
+    // New balance of the take slot should also fit in 64 bits
+    let new_take_balance_wit = increase_balance(
+        &mut cs.namespace(|| "take balance range"),
+        &take_balance_wit,
+        &take_amount_wit,
+    )?;
+    let take_root = update_balance_slot(
+        &mut cs.namespace(|| "take balance"),
+        enabled_wit,
+        &token_index_wits[1],
+        (&take_slot_token_id_wit, &take_balance_wit),
+        (&take_token_id_wit, new_take_balance_wit),
+        &side.take_balance_proof,
+        &give_root,
+    )?;
+
+    // Fee should be <= balance of the fee slot (After the swap is settled)
+    let fee_slot_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+        Ok(Into::<ZkScalar>::into(side.before_fee_balance.token_id).into())
+    })?;
+    Number::from(fee_slot_token_id_wit.clone())
+        .assert_equal(&mut cs.namespace(|| "fee token"), &fee_token_id_wit.into());
+    let fee_balance_wit =
+        UnsignedInteger::alloc_64(&mut *cs, side.before_fee_balance.amount.into())?;
+    let new_fee_balance_wit = decrease_balance(
+        &mut cs.namespace(|| "fee balance range"),
+        &fee_balance_wit,
+        &fee_wit,
+    )?;
+    let new_balances_hash_wit = update_balance_slot(
+        &mut cs.namespace(|| "fee balance"),
+        enabled_wit,
+        &token_index_wits[2],
+        (&fee_slot_token_id_wit, &fee_balance_wit),
+        (&fee_slot_token_id_wit, new_fee_balance_wit),
+        &side.fee_balance_proof,
+        &take_root,
+    )?;
+
+    let hash_wit = poseidon::poseidon(
+        &mut cs.namespace(|| "account hash"),
+        &[
+            &tx_nonce_wit.clone().into(),
+            &withdraw_nonce_wit.clone().into(),
+            &addr_wit.x.clone().into(),
+            &addr_wit.y.clone().into(),
+            &balances_hash_wit.into(),
+        ],
+    )?;
+    let mut proof_wits = Vec::new();
+    for b in side.proof.0.clone() {
+        proof_wits.push([
+            AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+            AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+            AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+        ]);
+    }
+    merkle::check_proof_poseidon4(
+        &mut cs.namespace(|| "account proof"),
+        enabled_wit,
+        &index_wit.clone().into(),
+        &hash_wit,
+        &proof_wits,
+        &state_wit.clone().into(),
+    )?;
+
+    // Check order nonce is equal with account nonce to prevent replaying the order
+    let new_tx_nonce_wit = Number::from(tx_nonce_wit) + Number::constant::<CS>(BellmanFr::one());
+    let order_nonce_wit = AllocatedNum::alloc(&mut *cs, || Ok((side.order.nonce as u64).into()))?;
+    Number::from(order_nonce_wit.clone()).assert_equal_if_enabled(
+        &mut cs.namespace(|| "nonce"),
+        enabled_wit,
+        &new_tx_nonce_wit,
+    )?;
+
+    let new_hash_wit = poseidon::poseidon(
+        &mut cs.namespace(|| "new account hash"),
+        &[
+            &new_tx_nonce_wit,
+            &withdraw_nonce_wit.into(),
+            &addr_wit.x.clone().into(),
+            &addr_wit.y.clone().into(),
+            &new_balances_hash_wit,
+        ],
+    )?;
+    let next_state_wit = merkle::calc_root_poseidon4(
+        &mut cs.namespace(|| "next state"),
+        &index_wit.into(),
+        &new_hash_wit,
+        &proof_wits,
+    )?;
+    *state_wit = common::mux(
+        &mut cs.namespace(|| "state update"),
+        enabled_wit,
+        &state_wit.clone().into(),
+        &next_state_wit,
+    )?;
+
+    let order_hash_wit = poseidon::poseidon(
+        &mut cs.namespace(|| "order hash"),
+        &[
+            &Number::constant::<CS>(BellmanFr::from(super::SWAP_ORDER_TAG)),
+            &order_nonce_wit.into(),
+            &give_token_id_wit.clone().into(),
+            &give_amount_wit.clone().into(),
+            &take_token_id_wit.clone().into(),
+            &take_amount_wit.clone().into(),
+            &fee_slot_token_id_wit.into(),
+            &fee_wit.clone().into(),
+        ],
+    )?;
+    let sig_r_wit = AllocatedPoint::alloc(&mut *cs, || Ok(side.order.sig.r))?;
+    // Check if sig_r resides on curve
+    sig_r_wit.assert_on_curve(&mut *cs, enabled_wit)?;
+    let sig_s_wit = AllocatedNum::alloc(&mut *cs, || Ok(side.order.sig.s.into()))?;
+
+    // Check EdDSA signature of the order
+    eddsa::verify_eddsa(
+        &mut cs.namespace(|| "signature"),
+        enabled_wit,
+        &addr_wit,
+        &order_hash_wit,
+        &sig_r_wit,
+        &sig_s_wit,
+    )?;
+
+    // Fee is zero if transaction slot is empty, otherwise it equals to order fee
+    let fee = common::mux(&mut *cs, enabled_wit, &Number::zero(), &fee_wit.into())?;
+
+    Ok(SwapSideWits {
+        give_token_id: give_token_id_wit,
+        give_amount: give_amount_wit,
+        take_token_id: take_token_id_wit,
+        take_amount: take_amount_wit,
+        fee,
+    })
+}
@@ -0,0 +1,619 @@
+use super::balance::{decrease_balance, increase_balance};
+use bazuka::core::{Money, TokenId};
+use bazuka::crypto::jubjub;
+use bazuka::zk::{MpnAccount, ZkHasher as _, ZkScalar};
+use bellman::gadgets::boolean::{AllocatedBit, Boolean};
+use bellman::gadgets::num::AllocatedNum;
+use bellman::{Circuit, ConstraintSystem, SynthesisError};
+use zeekit::common::Number;
+use zeekit::common::UnsignedInteger;
+use zeekit::eddsa::AllocatedPoint;
+use zeekit::merkle;
+use zeekit::{common, eddsa, poseidon, BellmanFr};
+
+/// Number of token slots a single sweep can free.
+pub const SWEEP_SLOTS: usize = 4;
+
+/// A token slot to free, along with the balance it's expected to have.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct SweptSlot {
+    pub token_index: u64,
+    pub balance: Money,
+}
+
+/// Resets up to `SWEEP_SLOTS` token slots of an account to the null token, so
+/// that they can be reused. Non-zero balances of the slots are moved to
+/// `dst_pub_key`. The account itself is kept, along with its nonces, so that
+/// its old transactions can't be replayed.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Sweep {
+    pub nonce: u32,
+    pub slots: Vec<SweptSlot>,
+    pub dst_pub_key: jubjub::PointAffine,
+    pub fee: Money,
+    #[serde(default)]
+    pub sig: jubjub::Signature,
+}
+
+impl Sweep {
+    /// Message signed by the account:
+    /// `poseidon(SWEEP_TAG, nonce, slots_hash, dst.x, dst.y, fee_token_id, fee)`, where
+    /// `slots_hash` chains `poseidon(prev, token_index, token_id, balance)`
+    /// over the slots, starting from zero. Committing to the balances keeps a
+    /// deposit made after signing from being swept.
+    pub fn hash(&self) -> ZkScalar {
+        let mut slots_hash = ZkScalar::from(0);
+        for slot in self.slots.iter() {
+            slots_hash = bazuka::core::ZkHasher::hash(&[
+                slots_hash,
+                slot.token_index.into(),
+                slot.balance.token_id.into(),
+                Into::<u64>::into(slot.balance.amount).into(),
+            ]);
+        }
+        bazuka::core::ZkHasher::hash(&[
+            super::SWEEP_TAG.into(),
+            (self.nonce as u64).into(),
+            slots_hash,
+            self.dst_pub_key.0,
+            self.dst_pub_key.1,
+            self.fee.token_id.into(),
+            Into::<u64>::into(self.fee.amount).into(),
+        ])
+    }
+}
+
+/// Witnesses of a swept slot. The destination side is only used when the
+/// slot has a non-zero balance, and is against the state left by the
+/// previous slot.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct SweptSlotTransition<const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8> {
+    pub balance_proof: merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+    pub dst_index: u64,
+    pub dst_token_index: u64,
+    pub dst_before: MpnAccount, // dst_after can be derived
+    pub dst_before_balances_hash: ZkScalar,
+    pub dst_before_balance: Money,
+    pub dst_proof: merkle::Proof<LOG4_TREE_SIZE>,
+    pub dst_balance_proof: merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+}
+
+// Validation:
+// 0. Check verify_sig(tx)
+// 1. Check verify_proof(curr_root, before, proof)
+// 2. after := before with the swept slots nulled and the fee paid, tx nonce incremented
+// 3. For every swept slot with a balance: dst_after := dst_before + balance
+// 4. Check next_state == root after the last slot
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct SweepTransition<const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8> {
+    pub enabled: bool,
+    pub index: u64,
+    pub fee_token_index: u64,
+    pub tx: Sweep,
+    pub before: MpnAccount, // after can be derived
+    pub before_balances_hash: ZkScalar,
+    pub before_fee_balance: Money,
+    pub proof: merkle::Proof<LOG4_TREE_SIZE>,
+    /// Against the balances of the account after the slots are freed
+    pub fee_balance_proof: merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+    /// One per slot of `tx`, in the same order. Missing ones are empty.
+    pub slots: Vec<SweptSlotTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SweepTransitionBatch<
+    const LOG4_BATCH_SIZE: u8,
+    const LOG4_TREE_SIZE: u8,
+    const LOG4_TOKENS_TREE_SIZE: u8,
+>(pub(crate) Vec<SweepTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>);
+impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8> Default
+    for SweepTransitionBatch<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>
+{
+    fn default() -> Self {
+        Self(
+            (0..1 << (2 * LOG4_BATCH_SIZE))
+                .map(|_| SweepTransition::default())
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+/// Same public inputs as `UpdateCircuit`, `aux_data` is
+/// `poseidon(fee_token, fee_sum)`.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SweepCircuit<
+    const LOG4_BATCH_SIZE: u8,
+    const LOG4_TREE_SIZE: u8,
+    const LOG4_TOKENS_TREE_SIZE: u8,
+> {
+    pub commitment: ZkScalar, // Public
+    pub height: u64,          // Public
+    pub state: ZkScalar,      // Public
+    pub aux_data: ZkScalar,   // Public
+    pub next_state: ZkScalar, // Public
+    pub fee_token: TokenId,   // Private
+    pub transitions:
+        Box<SweepTransitionBatch<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>, // Secret :)
+}
+
+impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8>
+    Circuit<BellmanFr> for SweepCircuit<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>
+{
+    fn synthesize<CS: ConstraintSystem<BellmanFr>>(
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        // Reward commitment feeded as input
+        let commit_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.commitment.into()))?;
+        commit_wit.inputize(&mut *cs)?;
+
+        // Contract height feeded as input
+        let height_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.height.into()))?;
+        height_wit.inputize(&mut *cs)?;
+
+        // Previous state feeded as input
+        let mut state_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.state.into()))?;
+        state_wit.inputize(&mut *cs)?;
+
+        let accepted_fee_token = AllocatedNum::alloc(&mut *cs, || {
+            Ok(Into::<ZkScalar>::into(self.fee_token).into())
+        })?;
+
+        // Sum of internal tx fees feeded as input
+        let aux_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.aux_data.into()))?;
+        aux_wit.inputize(&mut *cs)?;
+
+        // Expected next state feeded as input
+        let claimed_next_state_wit = AllocatedNum::alloc(&mut *cs, || Ok(self.next_state.into()))?;
+        claimed_next_state_wit.inputize(&mut *cs)?;
+
+        // Sum of tx fees as a linear-combination of tx fees
+        let mut fee_sum = Number::zero();
+
+        // Hash of an empty token slot
+        let null_balance_hash = poseidon::poseidon(
+            &mut cs.namespace(|| "null balance hash"),
+            &[&Number::zero(), &Number::zero()],
+        )?;
+
+        for (i, trans) in self.transitions.0.iter().enumerate() {
+            let cs = &mut cs.namespace(|| format!("transition {}", i));
+            // If enabled, transaction is validated, otherwise neglected
+            let enabled_wit = Boolean::Is(AllocatedBit::alloc(&mut *cs, Some(trans.enabled))?);
+
+            // Account index should always have at most LOG4_TREE_SIZE * 2 bits
+            let index_wit = UnsignedInteger::alloc(
+                &mut *cs,
+                (trans.index as u64).into(),
+                LOG4_TREE_SIZE as usize * 2,
+            )?;
+            let fee_token_index_wit = UnsignedInteger::alloc(
+                &mut *cs,
+                (trans.fee_token_index as u64).into(),
+                LOG4_TOKENS_TREE_SIZE as usize * 2,
+            )?;
+
+            let tx_nonce_wit =
+                AllocatedNum::alloc(&mut *cs, || Ok((trans.before.tx_nonce as u64).into()))?;
+            let withdraw_nonce_wit =
+                AllocatedNum::alloc(&mut *cs, || Ok((trans.before.withdraw_nonce as u64).into()))?;
+            let addr_wit = AllocatedPoint::alloc(&mut *cs, || Ok(trans.before.address))?;
+            // Address should be on curve in case transaction slot is non-empty
+            addr_wit.assert_on_curve(&mut *cs, &enabled_wit)?;
+            let balances_hash_wit =
+                AllocatedNum::alloc(&mut *cs, || Ok(trans.before_balances_hash.into()))?;
+
+            let tx_dst_addr_wit = AllocatedPoint::alloc(&mut *cs, || Ok(trans.tx.dst_pub_key))?;
+
+            // Every swept slot is nulled in the balances tree, one after the
+            // other. Only the slots of enabled transitions are swept, and each
+            // one is committed to in the signed slots hash.
+            let mut balances_root: Number = balances_hash_wit.clone().into();
+            let mut slots_hash = Number::zero();
+            let mut credit_wits = Vec::new();
+            for j in 0..SWEEP_SLOTS {
+                let cs = &mut cs.namespace(|| format!("slot {}", j));
+                let slot = trans.tx.slots.get(j).cloned().unwrap_or_default();
+                let slot_trans = trans.slots.get(j).cloned().unwrap_or_default();
+                let present_wit = Boolean::Is(AllocatedBit::alloc(
+                    &mut *cs,
+                    Some(j < trans.tx.slots.len()),
+                )?);
+                let slot_enabled_wit = Boolean::and(&mut *cs, &enabled_wit, &present_wit)?;
+
+                let token_index_wit = UnsignedInteger::alloc(
+                    &mut *cs,
+                    (slot.token_index as u64).into(),
+                    LOG4_TOKENS_TREE_SIZE as usize * 2,
+                )?;
+                let token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+                    Ok(Into::<ZkScalar>::into(slot.balance.token_id).into())
+                })?;
+                // Swept balance should always have at most 64 bits
+                let balance_wit = UnsignedInteger::alloc_64(&mut *cs, slot.balance.amount.into())?;
+
+                let slot_hash = poseidon::poseidon(
+                    &mut cs.namespace(|| "slot hash"),
+                    &[
+                        &slots_hash,
+                        &token_index_wit.clone().into(),
+                        &token_id_wit.clone().into(),
+                        &balance_wit.clone().into(),
+                    ],
+                )?;
+                slots_hash =
+                    common::mux(&mut *cs, &slot_enabled_wit, &slots_hash, &slot_hash)?.into();
+
+                let balance_hash_wit = poseidon::poseidon(
+                    &mut cs.namespace(|| "balance hash"),
+                    &[&token_id_wit.clone().into(), &balance_wit.clone().into()],
+                )?;
+                let mut balance_proof_wits = Vec::new();
+                for b in slot_trans.balance_proof.0.clone() {
+                    balance_proof_wits.push([
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+                    ]);
+                }
+                merkle::check_proof_poseidon4(
+                    &mut cs.namespace(|| "balance proof"),
+                    &slot_enabled_wit,
+                    &token_index_wit.clone().into(),
+                    &balance_hash_wit,
+                    &balance_proof_wits,
+                    &balances_root,
+                )?;
+                let new_balances_root = merkle::calc_root_poseidon4(
+                    &mut cs.namespace(|| "new balances root"),
+                    &token_index_wit.into(),
+                    &null_balance_hash,
+                    &balance_proof_wits,
+                )?;
+                balances_root = common::mux(
+                    &mut *cs,
+                    &slot_enabled_wit,
+                    &balances_root,
+                    &new_balances_root,
+                )?
+                .into();
+
+                // Only non-zero balances are moved to the destination
+                let is_balance_zero = Number::from(balance_wit.clone()).is_zero(&mut *cs)?;
+                let credit_enabled_wit =
+                    Boolean::and(&mut *cs, &slot_enabled_wit, &is_balance_zero.not())?;
+                credit_wits.push((credit_enabled_wit, token_id_wit, balance_wit));
+            }
+
+            let fee_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+                Ok(Into::<ZkScalar>::into(trans.before_fee_balance.token_id).into())
+            })?;
+            let tx_fee_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+                Ok(Into::<ZkScalar>::into(trans.tx.fee.token_id).into())
+            })?;
+            Number::from(accepted_fee_token.clone()).assert_equal_if_enabled(
+                &mut cs.namespace(|| "accepted fee token"),
+                &enabled_wit,
+                &tx_fee_token_id_wit.clone().into(),
+            )?;
+            // A freed slot has the null token, so the fee can't be paid from it
+            Number::from(fee_token_id_wit.clone()).assert_equal(
+                &mut cs.namespace(|| "fee token"),
+                &tx_fee_token_id_wit.clone().into(),
+            );
+
+            // Fee-token balance should always have at most 64 bits
+            let fee_balance_wit =
+                UnsignedInteger::alloc_64(&mut *cs, trans.before_fee_balance.amount.into())?;
+            let fee_balance_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "fee balance hash"),
+                &[
+                    &fee_token_id_wit.clone().into(),
+                    &fee_balance_wit.clone().into(),
+                ],
+            )?;
+            let mut fee_balance_proof_wits = Vec::new();
+            for b in trans.fee_balance_proof.0.clone() {
+                fee_balance_proof_wits.push([
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+                ]);
+            }
+            merkle::check_proof_poseidon4(
+                &mut cs.namespace(|| "fee balance proof"),
+                &enabled_wit,
+                &fee_token_index_wit.clone().into(),
+                &fee_balance_hash_wit,
+                &fee_balance_proof_wits,
+                &balances_root,
+            )?;
+
+            // Tx fee should be <= fee-token balance
+            let tx_fee_wit = UnsignedInteger::alloc_64(&mut *cs, trans.tx.fee.amount.into())?;
+            let new_fee_balance_wit = decrease_balance(
+                &mut cs.namespace(|| "fee balance range"),
+                &fee_balance_wit,
+                &tx_fee_wit,
+            )?;
+            let new_fee_balance_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "new fee balance hash"),
+                &[&fee_token_id_wit.into(), &new_fee_balance_wit.into()],
+            )?;
+            let new_balances_hash_wit = merkle::calc_root_poseidon4(
+                &mut cs.namespace(|| "new balances hash"),
+                &fee_token_index_wit.into(),
+                &new_fee_balance_hash_wit,
+                &fee_balance_proof_wits,
+            )?;
+
+            let hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "account hash"),
+                &[
+                    &tx_nonce_wit.clone().into(),
+                    &withdraw_nonce_wit.clone().into(),
+                    &addr_wit.x.clone().into(),
+                    &addr_wit.y.clone().into(),
+                    &balances_hash_wit.into(),
+                ],
+            )?;
+            let mut proof_wits = Vec::new();
+            for b in trans.proof.0.clone() {
+                proof_wits.push([
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+                    AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+                ]);
+            }
+            merkle::check_proof_poseidon4(
+                &mut cs.namespace(|| "account proof"),
+                &enabled_wit,
+                &index_wit.clone().into(),
+                &hash_wit,
+                &proof_wits,
+                &state_wit.clone().into(),
+            )?;
+
+            // Check tx nonce is equal with account nonce to prevent replaying the sweep
+            let new_tx_nonce_wit =
+                Number::from(tx_nonce_wit) + Number::constant::<CS>(BellmanFr::one());
+            let tx_nonce_signed_wit =
+                AllocatedNum::alloc(&mut *cs, || Ok((trans.tx.nonce as u64).into()))?;
+            Number::from(tx_nonce_signed_wit.clone()).assert_equal_if_enabled(
+                &mut cs.namespace(|| "nonce"),
+                &enabled_wit,
+                &new_tx_nonce_wit,
+            )?;
+
+            let new_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "new account hash"),
+                &[
+                    &new_tx_nonce_wit,
+                    &withdraw_nonce_wit.into(),
+                    &addr_wit.x.clone().into(),
+                    &addr_wit.y.clone().into(),
+                    &new_balances_hash_wit,
+                ],
+            )?;
+            let next_state_wit = merkle::calc_root_poseidon4(
+                &mut cs.namespace(|| "next state"),
+                &index_wit.into(),
+                &new_hash_wit,
+                &proof_wits,
+            )?;
+            state_wit = common::mux(
+                &mut cs.namespace(|| "state update"),
+                &enabled_wit,
+                &state_wit.into(),
+                &next_state_wit,
+            )?;
+
+            // Swept balances are credited to the destination one after the
+            // other, the account may be its own destination
+            for (j, (credit_enabled_wit, token_id_wit, amount_wit)) in
+                credit_wits.into_iter().enumerate()
+            {
+                let cs = &mut cs.namespace(|| format!("credit {}", j));
+                let slot_trans = trans.slots.get(j).cloned().unwrap_or_default();
+
+                // Destination address should be on curve in case something is credited
+                tx_dst_addr_wit.assert_on_curve(&mut *cs, &credit_enabled_wit)?;
+
+                let dst_index_wit = UnsignedInteger::alloc(
+                    &mut *cs,
+                    (slot_trans.dst_index as u64).into(),
+                    LOG4_TREE_SIZE as usize * 2,
+                )?;
+                let dst_token_index_wit = UnsignedInteger::alloc(
+                    &mut *cs,
+                    (slot_trans.dst_token_index as u64).into(),
+                    LOG4_TOKENS_TREE_SIZE as usize * 2,
+                )?;
+                let dst_tx_nonce_wit = AllocatedNum::alloc(&mut *cs, || {
+                    Ok((slot_trans.dst_before.tx_nonce as u64).into())
+                })?;
+                let dst_withdraw_nonce_wit = AllocatedNum::alloc(&mut *cs, || {
+                    Ok((slot_trans.dst_before.withdraw_nonce as u64).into())
+                })?;
+                // Destination address doesn't necessarily need to reside on curve as it might be empty
+                let dst_addr_wit =
+                    AllocatedPoint::alloc(&mut *cs, || Ok(slot_trans.dst_before.address))?;
+                let dst_before_balances_hash = AllocatedNum::alloc(&mut *cs, || {
+                    Ok(slot_trans.dst_before_balances_hash.into())
+                })?;
+
+                // Address of destination account slot can either be empty or equal with tx
+                // destination. Nothing is checked when nothing is credited.
+                let is_dst_null = dst_addr_wit.is_null(&mut *cs)?;
+                let is_dst_and_tx_dst_equal = dst_addr_wit.is_equal(&mut *cs, &tx_dst_addr_wit)?;
+                let addr_valid =
+                    common::boolean_or(&mut *cs, &is_dst_null, &is_dst_and_tx_dst_equal)?;
+                let addr_valid =
+                    common::boolean_or(&mut *cs, &addr_valid, &credit_enabled_wit.not())?;
+                common::assert_true(&mut cs.namespace(|| "dst address"), &addr_valid);
+
+                let dst_token_id_wit = AllocatedNum::alloc(&mut *cs, || {
+                    Ok(Into::<ZkScalar>::into(slot_trans.dst_before_balance.token_id).into())
+                })?;
+                // Token-id of dst slot can either be empty or equal with the swept token-id
+                let is_dst_token_id_null =
+                    Number::from(dst_token_id_wit.clone()).is_zero(&mut *cs)?;
+                let is_dst_token_id_equal = Number::from(dst_token_id_wit.clone())
+                    .is_equal(&mut *cs, &token_id_wit.clone().into())?;
+                let dst_token_id_valid =
+                    common::boolean_or(&mut *cs, &is_dst_token_id_null, &is_dst_token_id_equal)?;
+                let dst_token_id_valid =
+                    common::boolean_or(&mut *cs, &dst_token_id_valid, &credit_enabled_wit.not())?;
+                common::assert_true(&mut cs.namespace(|| "dst token id"), &dst_token_id_valid);
+
+                // Dst balance should always have at most 64 bits
+                let dst_balance_wit = UnsignedInteger::alloc_64(
+                    &mut *cs,
+                    slot_trans.dst_before_balance.amount.into(),
+                )?;
+                let dst_token_balance_hash_wit = poseidon::poseidon(
+                    &mut cs.namespace(|| "dst token balance hash"),
+                    &[&dst_token_id_wit.into(), &dst_balance_wit.clone().into()],
+                )?;
+                // New dst balance should also fit in 64 bits
+                let new_dst_balance_wit = increase_balance(
+                    &mut cs.namespace(|| "dst balance range"),
+                    &dst_balance_wit,
+                    &amount_wit,
+                )?;
+                let new_dst_token_balance_hash_wit = poseidon::poseidon(
+                    &mut cs.namespace(|| "new dst token balance hash"),
+                    &[&token_id_wit.into(), &new_dst_balance_wit.into()],
+                )?;
+
+                let mut dst_balance_proof_wits = Vec::new();
+                for b in slot_trans.dst_balance_proof.0.clone() {
+                    dst_balance_proof_wits.push([
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+                    ]);
+                }
+                merkle::check_proof_poseidon4(
+                    &mut cs.namespace(|| "dst balance proof"),
+                    &credit_enabled_wit,
+                    &dst_token_index_wit.clone().into(),
+                    &dst_token_balance_hash_wit,
+                    &dst_balance_proof_wits,
+                    &dst_before_balances_hash.clone().into(),
+                )?;
+                let dst_balance_final_root = merkle::calc_root_poseidon4(
+                    &mut cs.namespace(|| "dst balance final root"),
+                    &dst_token_index_wit.into(),
+                    &new_dst_token_balance_hash_wit,
+                    &dst_balance_proof_wits,
+                )?;
+
+                let dst_hash_wit = poseidon::poseidon(
+                    &mut cs.namespace(|| "dst account hash"),
+                    &[
+                        &dst_tx_nonce_wit.clone().into(),
+                        &dst_withdraw_nonce_wit.clone().into(),
+                        &dst_addr_wit.x.clone().into(),
+                        &dst_addr_wit.y.clone().into(),
+                        &dst_before_balances_hash.into(),
+                    ],
+                )?;
+                let mut dst_proof_wits = Vec::new();
+                for b in slot_trans.dst_proof.0.clone() {
+                    dst_proof_wits.push([
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[0].into()))?,
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[1].into()))?,
+                        AllocatedNum::alloc(&mut *cs, || Ok(b[2].into()))?,
+                    ]);
+                }
+                merkle::check_proof_poseidon4(
+                    &mut cs.namespace(|| "dst account proof"),
+                    &credit_enabled_wit,
+                    &dst_index_wit.clone().into(),
+                    &dst_hash_wit,
+                    &dst_proof_wits,
+                    &state_wit.clone().into(),
+                )?;
+
+                let new_dst_hash_wit = poseidon::poseidon(
+                    &mut cs.namespace(|| "new dst account hash"),
+                    &[
+                        &dst_tx_nonce_wit.into(),
+                        &dst_withdraw_nonce_wit.into(),
+                        &tx_dst_addr_wit.x.clone().into(),
+                        &tx_dst_addr_wit.y.clone().into(),
+                        &dst_balance_final_root,
+                    ],
+                )?;
+                let next_state_wit = merkle::calc_root_poseidon4(
+                    &mut cs.namespace(|| "next state"),
+                    &dst_index_wit.into(),
+                    &new_dst_hash_wit,
+                    &dst_proof_wits,
+                )?;
+                state_wit = common::mux(
+                    &mut cs.namespace(|| "state update"),
+                    &credit_enabled_wit,
+                    &state_wit.into(),
+                    &next_state_wit,
+                )?;
+            }
+
+            let tx_hash_wit = poseidon::poseidon(
+                &mut cs.namespace(|| "tx hash"),
+                &[
+                    &Number::constant::<CS>(BellmanFr::from(super::SWEEP_TAG)),
+                    &tx_nonce_signed_wit.into(),
+                    &slots_hash,
+                    &tx_dst_addr_wit.x.clone().into(),
+                    &tx_dst_addr_wit.y.clone().into(),
+                    &tx_fee_token_id_wit.into(),
+                    &tx_fee_wit.clone().into(),
+                ],
+            )?;
+            let tx_sig_r_wit = AllocatedPoint::alloc(&mut *cs, || Ok(trans.tx.sig.r))?;
+            // Check if sig_r resides on curve
+            tx_sig_r_wit.assert_on_curve(&mut *cs, &enabled_wit)?;
+            let tx_sig_s_wit = AllocatedNum::alloc(&mut *cs, || Ok(trans.tx.sig.s.into()))?;
+
+            // Check EdDSA signature
+            eddsa::verify_eddsa(
+                &mut cs.namespace(|| "signature"),
+                &enabled_wit,
+                &addr_wit,
+                &tx_hash_wit,
+                &tx_sig_r_wit,
+                &tx_sig_s_wit,
+            )?;
+
+            // Fee is zero if transaction slot is empty, otherwise it equals to transaction fee
+            let final_fee =
+                common::mux(&mut *cs, &enabled_wit, &Number::zero(), &tx_fee_wit.into())?;
+            fee_sum.add_num(BellmanFr::one(), &final_fee);
+        }
+
+        let fee_sum_and_token_hash = poseidon::poseidon(
+            &mut cs.namespace(|| "fee sum hash"),
+            &[&accepted_fee_token.into(), &fee_sum.into()],
+        )?;
+
+        // Check if sum of tx fees is equal with the feeded aux
+        cs.enforce(
+            || "fee sum",
+            |lc| lc + aux_wit.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + fee_sum_and_token_hash.get_lc(),
+        );
+
+        // Check if applying txs result in the claimed next state
+        cs.enforce(
+            || "next state",
+            |lc| lc + state_wit.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + claimed_next_state_wit.get_variable(),
+        );
+
+        Ok(())
+    }
+}
@@ -1,17 +1,26 @@
+use super::balance::{decrease_balance, increase_balance};
 use super::*;
 use crate::bank::{Bank, Provable};
-use bazuka::core::{ContractId, Money, MpnAddress, TokenId};
+use crate::debug::DebugSystem;
+use crate::shapes::Shape;
+use bazuka::core::{Address, ContractId, Money, MpnAddress, TokenId};
+use bazuka::crypto::jubjub;
 use bazuka::crypto::DeriveMpnAccountIndex;
 use bazuka::db::{KvStore, RamKvStore};
+use bazuka::mpn::{MpnWork, MpnWorkData};
 use bazuka::wallet::TxBuilder;
 use bazuka::zk::KvStoreStateManager;
 use bazuka::zk::ZkDataLocator;
 use bazuka::zk::{ZkCompressedState, ZkContract, ZkScalar, ZkStateModel};
+use bazuka::zk::{ZkDeltaPairs, ZkStateBuilder};
 use bellman::groth16;
+use bellman::{Circuit, ConstraintSystem, SynthesisError};
 use bls12_381::Bls12;
 use rand::rngs::OsRng;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
+use zeekit::common::UnsignedInteger;
+use zeekit::BellmanFr;
 
 fn fresh_db(log4_tree_size: u8, log4_token_size: u8) -> (RamKvStore, ContractId) {
     let state_model = ZkStateModel::List {
@@ -478,3 +487,485 @@ fn test_withdraw_tx_different_fee() {
         Some(&ZkScalar::from(8000000000))
     );
 }
+
+struct BalanceCircuit {
+    balance: u64,
+    amount: u64,
+    increase: bool,
+}
+
+impl Circuit<BellmanFr> for BalanceCircuit {
+    fn synthesize<CS: ConstraintSystem<BellmanFr>>(
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        let balance = UnsignedInteger::alloc_64(&mut *cs, self.balance.into())?;
+        let amount = UnsignedInteger::alloc_64(&mut *cs, self.amount.into())?;
+        if self.increase {
+            increase_balance(&mut *cs, &balance, &amount)?;
+        } else {
+            decrease_balance(&mut *cs, &balance, &amount)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_satisfied<C: Circuit<BellmanFr>>(circuit: C) -> bool {
+    matches!(DebugSystem::check(circuit), Ok(None))
+}
+
+#[test]
+fn test_balance_overflow_rejected() {
+    assert!(is_satisfied(BalanceCircuit {
+        balance: u64::MAX - 10,
+        amount: 10,
+        increase: true,
+    }));
+    assert!(!is_satisfied(BalanceCircuit {
+        balance: u64::MAX - 10,
+        amount: 11,
+        increase: true,
+    }));
+    assert!(!is_satisfied(BalanceCircuit {
+        balance: u64::MAX,
+        amount: u64::MAX,
+        increase: true,
+    }));
+}
+
+#[test]
+fn test_balance_underflow_rejected() {
+    assert!(is_satisfied(BalanceCircuit {
+        balance: 100,
+        amount: 100,
+        increase: false,
+    }));
+    assert!(!is_satisfied(BalanceCircuit {
+        balance: 100,
+        amount: 101,
+        increase: false,
+    }));
+    assert!(!is_satisfied(BalanceCircuit {
+        balance: 0,
+        amount: u64::MAX,
+        increase: false,
+    }));
+}
+
+// Works recorded from a node, whose public inputs are the ones the node's MPN
+// contract expects their proofs to have. Generated by `generate_golden_vectors`.
+const GOLDEN_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/circuits/fixtures/golden");
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct GoldenVector {
+    shape: Shape,
+    address: Address,
+    fee_policy: TokenId,
+    work: MpnWork,
+    circuit: String,
+    fee_tokens: Vec<TokenId>,
+    commitment: ZkScalar,
+}
+
+/// The golden vectors, which are required: without them, nothing pins the
+/// public inputs to the node's.
+fn golden_vectors() -> Vec<(String, GoldenVector)> {
+    let mut paths = std::fs::read_dir(GOLDEN_DIR)
+        .into_iter()
+        .flatten()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(
+        !paths.is_empty(),
+        "no golden vectors in {}, see generate_golden_vectors",
+        GOLDEN_DIR
+    );
+    paths
+        .into_iter()
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let vector = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+            (name, vector)
+        })
+        .collect()
+}
+
+/// Root of an arity-4 merkle tree, from a leaf and its proof (The siblings of
+/// the leaf at each level, from the bottom).
+fn native_root(mut index: u64, leaf: ZkScalar, proof: &[[ZkScalar; 3]]) -> ZkScalar {
+    use bazuka::zk::ZkHasher;
+    let mut node = leaf;
+    for siblings in proof {
+        let mut children = siblings.to_vec();
+        children.insert((index % 4) as usize, node);
+        node = bazuka::core::ZkHasher::hash(&children);
+        index /= 4;
+    }
+    node
+}
+
+fn native_account_hash(
+    tx_nonce: u32,
+    withdraw_nonce: u32,
+    address: &jubjub::PointAffine,
+    balances_hash: ZkScalar,
+) -> ZkScalar {
+    use bazuka::zk::ZkHasher;
+    bazuka::core::ZkHasher::hash(&[
+        (tx_nonce as u64).into(),
+        (withdraw_nonce as u64).into(),
+        address.0,
+        address.1,
+        balances_hash,
+    ])
+}
+
+fn native_balance_hash(token_id: TokenId, amount: u64) -> ZkScalar {
+    crate::mpn_model::balance_hash(Money::new(token_id, amount))
+}
+
+/// Replaces the account at `index` of the `state` root, after checking that
+/// `proof` proves the `before` account against it.
+fn native_set_account(
+    state: &mut ZkScalar,
+    index: u64,
+    before: ZkScalar,
+    after: ZkScalar,
+    proof: &[[ZkScalar; 3]],
+) {
+    assert_eq!(native_root(index, before, proof), *state, "account proof");
+    *state = native_root(index, after, proof);
+}
+
+/// State root after the transitions of a work are applied on its state, the
+/// way the MPN contract applies them.
+fn native_next_state(work: &MpnWork) -> ZkScalar {
+    use crate::mpn_model::amount_of;
+    let mut state = work.public_inputs.state;
+    match &work.data {
+        MpnWorkData::Deposit(deposits) => {
+            for d in deposits {
+                let amount = d.tx.payment.amount;
+                let balances_hash = native_root(
+                    d.token_index,
+                    native_balance_hash(
+                        amount.token_id,
+                        amount_of(d.before_balance) + amount_of(amount),
+                    ),
+                    &d.balance_proof,
+                );
+                native_set_account(
+                    &mut state,
+                    d.account_index,
+                    native_account_hash(
+                        d.before.tx_nonce,
+                        d.before.withdraw_nonce,
+                        &d.before.address,
+                        d.before_balances_hash,
+                    ),
+                    native_account_hash(
+                        d.before.tx_nonce,
+                        d.before.withdraw_nonce,
+                        &d.tx.zk_address.0.decompress(),
+                        balances_hash,
+                    ),
+                    &d.proof,
+                );
+            }
+        }
+        MpnWorkData::Withdraw(withdraws) => {
+            for w in withdraws {
+                let (amount, fee) = (w.tx.payment.amount, w.tx.payment.fee);
+                let balances_hash = native_root(
+                    w.token_index,
+                    native_balance_hash(
+                        amount.token_id,
+                        amount_of(w.before_token_balance) - amount_of(amount),
+                    ),
+                    &w.token_balance_proof,
+                );
+                let balances_hash = native_root(
+                    w.fee_token_index,
+                    native_balance_hash(
+                        fee.token_id,
+                        amount_of(w.before_fee_balance) - amount_of(fee),
+                    ),
+                    &w.fee_balance_proof,
+                );
+                native_set_account(
+                    &mut state,
+                    w.account_index,
+                    native_account_hash(
+                        w.before.tx_nonce,
+                        w.before.withdraw_nonce,
+                        &w.before.address,
+                        w.before_token_hash,
+                    ),
+                    native_account_hash(
+                        w.before.tx_nonce,
+                        w.before.withdraw_nonce + 1,
+                        &w.tx.zk_address.0.decompress(),
+                        balances_hash,
+                    ),
+                    &w.proof,
+                );
+            }
+        }
+        MpnWorkData::Update(updates) => {
+            for u in updates {
+                let (amount, fee) = (u.tx.amount, u.tx.fee);
+                let src_balances_hash = native_root(
+                    u.src_token_index,
+                    native_balance_hash(
+                        amount.token_id,
+                        amount_of(u.src_before_balance) - amount_of(amount),
+                    ),
+                    &u.src_balance_proof,
+                );
+                let src_balances_hash = native_root(
+                    u.src_fee_token_index,
+                    native_balance_hash(
+                        fee.token_id,
+                        amount_of(u.src_before_fee_balance) - amount_of(fee),
+                    ),
+                    &u.src_fee_balance_proof,
+                );
+                native_set_account(
+                    &mut state,
+                    u.src_index,
+                    native_account_hash(
+                        u.src_before.tx_nonce,
+                        u.src_before.withdraw_nonce,
+                        &u.src_before.address,
+                        u.src_before_balances_hash,
+                    ),
+                    native_account_hash(
+                        u.src_before.tx_nonce + 1,
+                        u.src_before.withdraw_nonce,
+                        &u.src_before.address,
+                        src_balances_hash,
+                    ),
+                    &u.src_proof,
+                );
+                let dst_balances_hash = native_root(
+                    u.dst_token_index,
+                    native_balance_hash(
+                        amount.token_id,
+                        amount_of(u.dst_before_balance) + amount_of(amount),
+                    ),
+                    &u.dst_balance_proof,
+                );
+                native_set_account(
+                    &mut state,
+                    u.dst_index,
+                    native_account_hash(
+                        u.dst_before.tx_nonce,
+                        u.dst_before.withdraw_nonce,
+                        &u.dst_before.address,
+                        u.dst_before_balances_hash,
+                    ),
+                    native_account_hash(
+                        u.dst_before.tx_nonce,
+                        u.dst_before.withdraw_nonce,
+                        &u.tx.dst_pub_key.0.decompress(),
+                        dst_balances_hash,
+                    ),
+                    &u.dst_proof,
+                );
+            }
+        }
+    }
+    state
+}
+
+/// Root of a tx list, as revealed by the deposit and withdraw circuits.
+fn native_tx_root(log4_batch_size: u8, txs: Vec<Vec<ZkScalar>>) -> ZkScalar {
+    let num_fields = txs.first().map(|tx| tx.len()).unwrap_or_default();
+    let mut builder = ZkStateBuilder::<bazuka::core::ZkHasher>::new(ZkStateModel::List {
+        item_type: Box::new(ZkStateModel::Struct {
+            field_types: vec![ZkStateModel::Scalar; num_fields],
+        }),
+        log4_size: log4_batch_size,
+    });
+    for (i, tx) in txs.into_iter().enumerate() {
+        builder
+            .batch_set(&ZkDeltaPairs(
+                tx.into_iter()
+                    .enumerate()
+                    .map(|(j, v)| (ZkDataLocator(vec![i as u64, j as u64]), Some(v)))
+                    .collect(),
+            ))
+            .unwrap();
+    }
+    builder.compress().unwrap().state_hash
+}
+
+fn native_aux_data(shape: Shape, work: &MpnWork, fee_tokens: &[TokenId]) -> ZkScalar {
+    use bazuka::zk::ZkHasher;
+    let dimensions = shape.dimensions();
+    match &work.data {
+        // Enabled, token-id, amount, hash of the pub-key
+        MpnWorkData::Deposit(deposits) => native_tx_root(
+            dimensions.log4_deposit_batch_size,
+            deposits
+                .iter()
+                .map(|d| {
+                    let pub_key = d.tx.zk_address.0.decompress();
+                    vec![
+                        ZkScalar::from(1),
+                        d.tx.payment.amount.token_id.into(),
+                        Into::<u64>::into(d.tx.payment.amount.amount).into(),
+                        bazuka::core::ZkHasher::hash(&[pub_key.0, pub_key.1]),
+                    ]
+                })
+                .collect(),
+        ),
+        // Enabled, token-id, amount, fee token-id, fee, fingerprint, hash of
+        // the pub-key, nonce and signature
+        MpnWorkData::Withdraw(withdraws) => native_tx_root(
+            dimensions.log4_withdraw_batch_size,
+            withdraws
+                .iter()
+                .map(|w| {
+                    let pub_key = w.tx.zk_address.0.decompress();
+                    vec![
+                        ZkScalar::from(1),
+                        w.tx.payment.amount.token_id.into(),
+                        Into::<u64>::into(w.tx.payment.amount.amount).into(),
+                        w.tx.payment.fee.token_id.into(),
+                        Into::<u64>::into(w.tx.payment.fee.amount).into(),
+                        w.tx.payment.fingerprint(),
+                        bazuka::core::ZkHasher::hash(&[
+                            pub_key.0,
+                            pub_key.1,
+                            (w.tx.zk_nonce as u64).into(),
+                            w.tx.zk_sig.r.0,
+                            w.tx.zk_sig.r.1,
+                            w.tx.zk_sig.s,
+                        ]),
+                    ]
+                })
+                .collect(),
+        ),
+        // Each fee token followed by the sum of the fees paid in it, where a
+        // repeated (Padding) token collects nothing
+        MpnWorkData::Update(updates) => {
+            let mut preimage = Vec::new();
+            for (i, token) in fee_tokens.iter().enumerate() {
+                let fee_sum: u64 = if fee_tokens[..i].contains(token) {
+                    0
+                } else {
+                    updates
+                        .iter()
+                        .filter(|u| u.tx.fee.token_id == *token)
+                        .map(|u| Into::<u64>::into(u.tx.fee.amount))
+                        .sum()
+                };
+                preimage.push((*token).into());
+                preimage.push(fee_sum.into());
+            }
+            bazuka::core::ZkHasher::hash(&preimage)
+        }
+    }
+}
+
+#[test]
+fn test_golden_public_inputs_native() {
+    for (name, v) in golden_vectors() {
+        let inputs = &v.work.public_inputs;
+        assert_eq!(
+            crate::work_commitment(&v.address, v.work.reward),
+            v.commitment,
+            "{}: commitment",
+            name
+        );
+        assert_eq!(
+            native_aux_data(v.shape, &v.work, &v.fee_tokens),
+            inputs.aux_data,
+            "{}: aux_data",
+            name
+        );
+        assert_eq!(
+            native_next_state(&v.work),
+            inputs.next_state,
+            "{}: next_state",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_golden_public_inputs_synthesized() {
+    for (name, v) in golden_vectors() {
+        let inputs = v.work.public_inputs.clone();
+        let work = v.shape.work(v.address, v.work, v.fee_policy);
+        assert_eq!(work.circuit_name(), v.circuit, "{}: circuit", name);
+        assert_eq!(work.fee_tokens(), v.fee_tokens, "{}: fee tokens", name);
+        if let Some(unsatisfied) = work.debug().unwrap() {
+            panic!("{}: {} is not satisfied", name, unsatisfied);
+        }
+        let expected = [
+            v.commitment,
+            inputs.height.into(),
+            inputs.state,
+            inputs.aux_data,
+            inputs.next_state,
+        ]
+        .into_iter()
+        .map(BellmanFr::from)
+        .collect::<Vec<_>>();
+        assert_eq!(
+            work.synthesize().unwrap().inputs(),
+            &expected[..],
+            "{}: public inputs",
+            name
+        );
+    }
+}
+
+/// Captures the first work of each circuit whose proof the node accepted in a
+/// recorded session (See `--record`) as a golden vector. The expected values
+/// are the ones the node's MPN contract verified the proof against, so a work
+/// our circuits don't satisfy is captured too, and fails the golden tests.
+/// Capture them from mainnet sessions, on the shape the network uses:
+///
+/// ZORO_GOLDEN_SESSION=session.jsonl ZORO_GOLDEN_SHAPE=mainnet \
+///     cargo test generate_golden_vectors -- --ignored
+#[test]
+#[ignore]
+fn generate_golden_vectors() {
+    let session = std::env::var("ZORO_GOLDEN_SESSION").expect("ZORO_GOLDEN_SESSION is not set");
+    let shape = std::env::var("ZORO_GOLDEN_SHAPE")
+        .map(|s| Shape::from_str(&s).unwrap())
+        .unwrap_or(Shape::Mainnet);
+    let fee_policy = TokenId::Ziesha;
+    std::fs::create_dir_all(GOLDEN_DIR).unwrap();
+    let mut captured = std::collections::HashSet::new();
+    for (_, address, _, work) in
+        crate::session::accepted_works(std::path::Path::new(&session)).unwrap()
+    {
+        let shaped = shape.work(address.clone(), work.clone(), fee_policy);
+        let circuit = shaped.circuit_name();
+        if captured.contains(circuit) {
+            continue;
+        }
+        if let Some(unsatisfied) = shaped.debug().unwrap() {
+            println!("The {} work is not satisfied: {}", circuit, unsatisfied);
+        }
+        let vector = GoldenVector {
+            shape,
+            address: address.clone(),
+            fee_policy,
+            circuit: circuit.to_string(),
+            fee_tokens: shaped.fee_tokens(),
+            commitment: crate::work_commitment(&address, work.reward),
+            work,
+        };
+        let path = format!("{}/{}_{}.json", GOLDEN_DIR, shape, circuit);
+        std::fs::write(&path, serde_json::to_vec_pretty(&vector).unwrap()).unwrap();
+        println!("Wrote {}", path);
+        captured.insert(circuit);
+    }
+}
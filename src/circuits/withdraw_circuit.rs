@@ -1,3 +1,4 @@
+use super::balance::decrease_balance;
 use bazuka::core::{Money, MpnWithdraw};
 use bazuka::crypto::jubjub;
 use bazuka::zk::{MpnAccount, ZkScalar};
@@ -74,7 +75,7 @@ pub struct WithdrawTransitionBatch<
     const LOG4_BATCH_SIZE: u8,
     const LOG4_TREE_SIZE: u8,
     const LOG4_TOKENS_TREE_SIZE: u8,
->(Vec<WithdrawTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>);
+>(pub(crate) Vec<WithdrawTransition<LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>);
 impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8>
     WithdrawTransitionBatch<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>
 {
@@ -114,6 +115,8 @@ pub struct WithdrawCircuit<
     pub next_state: ZkScalar, // Public
     pub transitions:
         Box<WithdrawTransitionBatch<LOG4_BATCH_SIZE, LOG4_TREE_SIZE, LOG4_TOKENS_TREE_SIZE>>, // Secret :)
+    /// Apply the checks of the strict circuits (See `Dimensions::strict_circuits`)
+    pub strict: bool,
 }
 
 impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE_SIZE: u8>
@@ -161,7 +164,8 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
         // Uncompress all the Withdraw txs that were compressed inside aux_witness
         let mut tx_wits = Vec::new();
         let mut children = Vec::new();
-        for trans in self.transitions.0.iter() {
+        for (i, trans) in self.transitions.0.iter().enumerate() {
+            let cs = &mut cs.namespace(|| format!("tx {}", i));
             // If enabled, transaction is validated, otherwise neglected
             let enabled = AllocatedBit::alloc(&mut *cs, Some(trans.enabled))?;
 
@@ -202,7 +206,7 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
             ));
 
             let calldata_hash = poseidon::poseidon(
-                &mut *cs,
+                &mut cs.namespace(|| "calldata hash"),
                 &[
                     &pub_key.x.into(),
                     &pub_key.y.into(),
@@ -230,30 +234,43 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
                 AllocatedState::Value(calldata.into()),
             ]));
         }
-        let tx_root = reveal(&mut *cs, &state_model, &AllocatedState::Children(children))?;
+        let tx_root = reveal(
+            &mut cs.namespace(|| "tx root"),
+            &state_model,
+            &AllocatedState::Children(children),
+        )?;
         cs.enforce(
-            || "",
+            || "tx root",
             |lc| lc + aux_wit.get_variable(),
             |lc| lc + CS::one(),
             |lc| lc + tx_root.get_lc(),
         );
 
         for (
-            trans,
+            i,
             (
-                enabled_wit,
-                tx_amount_token_id_wit,
-                tx_amount_wit,
-                tx_fee_token_id_wit,
-                tx_fee_wit,
-                fingerprint_wit,
-                tx_pub_key_wit,
-                tx_nonce_wit,
-                tx_sig_r_wit,
-                tx_sig_s_wit,
+                trans,
+                (
+                    enabled_wit,
+                    tx_amount_token_id_wit,
+                    tx_amount_wit,
+                    tx_fee_token_id_wit,
+                    tx_fee_wit,
+                    fingerprint_wit,
+                    tx_pub_key_wit,
+                    tx_nonce_wit,
+                    tx_sig_r_wit,
+                    tx_sig_s_wit,
+                ),
             ),
-        ) in self.transitions.0.iter().zip(tx_wits.into_iter())
+        ) in self
+            .transitions
+            .0
+            .iter()
+            .zip(tx_wits.into_iter())
+            .enumerate()
         {
+            let cs = &mut cs.namespace(|| format!("transition {}", i));
             // Tx index should always have at most LOG4_TREE_SIZE * 2 bits
             let tx_index_wit = UnsignedInteger::alloc(
                 &mut *cs,
@@ -277,7 +294,7 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
             tx_pub_key_wit.assert_on_curve(&mut *cs, &enabled_wit)?;
 
             let tx_hash_wit = poseidon::poseidon(
-                &mut *cs,
+                &mut cs.namespace(|| "tx hash"),
                 &[
                     &fingerprint_wit.clone().into(),
                     &tx_nonce_wit.clone().into(),
@@ -287,7 +304,7 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
             tx_sig_r_wit.assert_on_curve(&mut *cs, &enabled_wit)?;
             // Check EdDSA signature
             eddsa::verify_eddsa(
-                &mut *cs,
+                &mut cs.namespace(|| "signature"),
                 &enabled_wit,
                 &tx_pub_key_wit,
                 &tx_hash_wit,
@@ -303,6 +320,15 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
             let src_addr_wit = AllocatedPoint::alloc(&mut *cs, || Ok(trans.before.address))?;
             src_addr_wit.assert_on_curve(&mut *cs, &enabled_wit)?;
 
+            // Account should belong to the signer of the tx
+            if self.strict {
+                let is_src_and_tx_pub_key_equal =
+                    src_addr_wit.is_equal(&mut *cs, &tx_pub_key_wit)?;
+                let addr_valid =
+                    common::boolean_or(&mut *cs, &enabled_wit.not(), &is_src_and_tx_pub_key_equal)?;
+                common::assert_true(&mut cs.namespace(|| "address"), &addr_valid);
+            }
+
             let src_balances_before_token_hash_wit =
                 AllocatedNum::alloc(&mut *cs, || Ok(trans.before_token_hash.into()))?;
 
@@ -310,17 +336,19 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
                 Ok(Into::<ZkScalar>::into(trans.before_token_balance.token_id).into())
             })?;
 
-            Number::from(src_token_id_wit.clone())
-                .assert_equal(&mut *cs, &tx_amount_token_id_wit.into());
+            Number::from(src_token_id_wit.clone()).assert_equal(
+                &mut cs.namespace(|| "amount token"),
+                &tx_amount_token_id_wit.into(),
+            );
 
             // We don't need to make sure account balance is 64 bits. If everything works as expected
-            // nothing like this should happen.
+            // nothing like this should happen. (Unless the circuit is strict)
             let src_balance_wit = AllocatedNum::alloc(&mut *cs, || {
                 Ok(Into::<u64>::into(trans.before_token_balance.amount).into())
             })?;
 
             let src_token_balance_hash_wit = poseidon::poseidon(
-                &mut *cs,
+                &mut cs.namespace(|| "src token balance hash"),
                 &[
                     &src_token_id_wit.clone().into(),
                     &src_balance_wit.clone().into(),
@@ -335,22 +363,35 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
                 ]);
             }
             merkle::check_proof_poseidon4(
-                &mut *cs,
+                &mut cs.namespace(|| "balance proof"),
                 &enabled_wit,
                 &tx_token_index_wit.clone().into(),
                 &src_token_balance_hash_wit.clone().into(),
                 &src_token_balance_proof_wits,
                 &src_balances_before_token_hash_wit.clone().into(),
             )?;
+            let new_balance: Number = if self.strict {
+                // Withdrawn amount should be <= token balance
+                let src_balance_wit = UnsignedInteger::constrain(
+                    &mut cs.namespace(|| "balance bits"),
+                    src_balance_wit.into(),
+                    64,
+                )?;
+                decrease_balance(
+                    &mut cs.namespace(|| "balance range"),
+                    &src_balance_wit,
+                    &tx_amount_wit,
+                )?
+                .into()
+            } else {
+                Number::from(src_balance_wit) - Number::from(tx_amount_wit.clone())
+            };
             let new_token_balance_hash_wit = poseidon::poseidon(
-                &mut *cs,
-                &[
-                    &src_token_id_wit.clone().into(),
-                    &(Number::from(src_balance_wit.clone()) - Number::from(tx_amount_wit.clone())),
-                ],
+                &mut cs.namespace(|| "new token balance hash"),
+                &[&src_token_id_wit.clone().into(), &new_balance],
             )?;
             let balance_middle_root = merkle::calc_root_poseidon4(
-                &mut *cs,
+                &mut cs.namespace(|| "balance middle root"),
                 &tx_token_index_wit.clone().into(),
                 &new_token_balance_hash_wit,
                 &src_token_balance_proof_wits,
@@ -360,17 +401,19 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
                 Ok(Into::<ZkScalar>::into(trans.before_fee_balance.token_id).into())
             })?;
 
-            Number::from(src_fee_token_id_wit.clone())
-                .assert_equal(&mut *cs, &tx_fee_token_id_wit.into());
+            Number::from(src_fee_token_id_wit.clone()).assert_equal(
+                &mut cs.namespace(|| "fee token"),
+                &tx_fee_token_id_wit.into(),
+            );
 
             // We don't need to make sure account balance is 64 bits. If everything works as expected
-            // nothing like this should happen.
+            // nothing like this should happen. (Unless the circuit is strict)
             let src_fee_balance_wit = AllocatedNum::alloc(&mut *cs, || {
                 Ok(Into::<u64>::into(trans.before_fee_balance.amount).into())
             })?;
 
             let src_fee_token_balance_hash_wit = poseidon::poseidon(
-                &mut *cs,
+                &mut cs.namespace(|| "src fee token balance hash"),
                 &[
                     &src_fee_token_id_wit.clone().into(),
                     &src_fee_balance_wit.clone().into(),
@@ -387,7 +430,7 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
             }
 
             merkle::check_proof_poseidon4(
-                &mut *cs,
+                &mut cs.namespace(|| "fee balance proof"),
                 &enabled_wit,
                 &tx_fee_token_index_wit.clone().into(),
                 &src_fee_token_balance_hash_wit.clone().into(),
@@ -395,16 +438,30 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
                 &balance_middle_root,
             )?;
 
+            let new_fee_balance: Number = if self.strict {
+                // Fee should be <= fee-token balance (After the amount is paid, if the
+                // tokens are the same)
+                let src_fee_balance_wit = UnsignedInteger::constrain(
+                    &mut cs.namespace(|| "fee balance bits"),
+                    src_fee_balance_wit.into(),
+                    64,
+                )?;
+                decrease_balance(
+                    &mut cs.namespace(|| "fee balance range"),
+                    &src_fee_balance_wit,
+                    &tx_fee_wit,
+                )?
+                .into()
+            } else {
+                Number::from(src_fee_balance_wit) - Number::from(tx_fee_wit.clone())
+            };
             let new_fee_token_balance_hash_wit = poseidon::poseidon(
-                &mut *cs,
-                &[
-                    &src_fee_token_id_wit.clone().into(),
-                    &(Number::from(src_fee_balance_wit.clone()) - Number::from(tx_fee_wit.clone())),
-                ],
+                &mut cs.namespace(|| "new fee token balance hash"),
+                &[&src_fee_token_id_wit.clone().into(), &new_fee_balance],
             )?;
 
             let src_hash_wit = poseidon::poseidon(
-                &mut *cs,
+                &mut cs.namespace(|| "src account hash"),
                 &[
                     &src_tx_nonce_wit.clone().into(),
                     &src_withdraw_nonce_wit.clone().into(),
@@ -422,7 +479,7 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
                 ]);
             }
             merkle::check_proof_poseidon4(
-                &mut *cs,
+                &mut cs.namespace(|| "account proof"),
                 &enabled_wit,
                 &tx_index_wit.clone().into(),
                 &src_hash_wit,
@@ -432,14 +489,14 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
 
             // Check tx nonce is equal with account nonce to prevent double spending
             Number::from(tx_nonce_wit).assert_equal_if_enabled(
-                &mut *cs,
+                &mut cs.namespace(|| "nonce"),
                 &enabled_wit,
                 &(Number::from(src_withdraw_nonce_wit.clone())
                     + Number::constant::<CS>(BellmanFr::one())),
             )?;
 
             let balance_final_root = merkle::calc_root_poseidon4(
-                &mut *cs,
+                &mut cs.namespace(|| "balance final root"),
                 &tx_fee_token_index_wit.clone().into(),
                 &new_fee_token_balance_hash_wit,
                 &src_fee_token_balance_proof_wits,
@@ -447,7 +504,7 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
 
             // Calculate next-state hash and update state if tx is enabled
             let new_hash_wit = poseidon::poseidon(
-                &mut *cs,
+                &mut cs.namespace(|| "new account hash"),
                 &[
                     &src_tx_nonce_wit.clone().into(),
                     &(Number::from(src_withdraw_nonce_wit)
@@ -457,14 +514,23 @@ impl<const LOG4_BATCH_SIZE: u8, const LOG4_TREE_SIZE: u8, const LOG4_TOKENS_TREE
                     &balance_final_root,
                 ],
             )?;
-            let next_state_wit =
-                merkle::calc_root_poseidon4(&mut *cs, &tx_index_wit, &new_hash_wit, &proof_wits)?;
-            state_wit = common::mux(&mut *cs, &enabled_wit, &state_wit.into(), &next_state_wit)?;
+            let next_state_wit = merkle::calc_root_poseidon4(
+                &mut cs.namespace(|| "next state"),
+                &tx_index_wit,
+                &new_hash_wit,
+                &proof_wits,
+            )?;
+            state_wit = common::mux(
+                &mut cs.namespace(|| "state update"),
+                &enabled_wit,
+                &state_wit.into(),
+                &next_state_wit,
+            )?;
         }
 
         // Check if applying txs result in the claimed next state
         cs.enforce(
-            || "",
+            || "next state",
             |lc| lc + state_wit.get_variable(),
             |lc| lc + CS::one(),
             |lc| lc + claimed_next_state_wit.get_variable(),
@@ -1,8 +1,14 @@
-use bazuka::client::{messages::ValidatorClaim, Limit, NodeError};
+use crate::backoff::PeerHealth;
+use crate::mock::MockNode;
+use crate::session::Session;
+use crate::ZoroError;
+
+use bazuka::client::{messages::ValidatorClaim, Limit, NodeError, PeerAddress};
 use bazuka::core::{Address, MpnAddress};
 
 use std::collections::HashMap;
 use std::future::Future;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 #[derive(Clone)]
@@ -73,7 +79,6 @@ impl SyncClient {
         })
         .await
     }
-    #[allow(dead_code)]
     pub async fn get_mpn_account(
         &self,
         addr: MpnAddress,
@@ -82,3 +87,179 @@ impl SyncClient {
             .await
     }
 }
+
+/// Where the requests of the prover are sent to.
+#[derive(Clone)]
+pub enum Transport {
+    Network,
+    /// Every node is answered by the in-process mock network
+    Mock(Arc<MockNode>),
+}
+
+/// Opens the clients of the prover. Exchanges are recorded/replayed when a
+/// session is given.
+#[derive(Clone)]
+pub struct Connector {
+    network: String,
+    transport: Transport,
+    session: Option<Arc<Session>>,
+}
+
+impl Connector {
+    pub fn new(network: &str, transport: Transport, session: Option<Arc<Session>>) -> Self {
+        Self {
+            network: network.to_string(),
+            transport,
+            session,
+        }
+    }
+    pub fn connect(&self, node: PeerAddress, timeout: Duration) -> NodeClient {
+        NodeClient {
+            node,
+            timeout,
+            client: SyncClient::new(node, &self.network, timeout),
+            transport: self.transport.clone(),
+            session: self.session.clone(),
+        }
+    }
+}
+
+/// Client of a single node, through the transport of its `Connector`.
+pub struct NodeClient {
+    node: PeerAddress,
+    timeout: Duration,
+    client: SyncClient,
+    transport: Transport,
+    session: Option<Arc<Session>>,
+}
+
+impl NodeClient {
+    async fn request<R, MockFut, NodeFut>(
+        &self,
+        method: &'static str,
+        request: serde_json::Value,
+        mock: impl FnOnce(Arc<MockNode>) -> MockFut,
+        node: impl FnOnce(SyncClient) -> NodeFut,
+    ) -> Result<R, ZoroError>
+    where
+        R: serde::Serialize + serde::de::DeserializeOwned,
+        MockFut: Future<Output = R>,
+        NodeFut: Future<Output = Result<R, NodeError>>,
+    {
+        let send = async move {
+            match &self.transport {
+                Transport::Mock(mock_node) => {
+                    Ok(tokio::time::timeout(self.timeout, mock(mock_node.clone())).await?)
+                }
+                Transport::Network => Ok(node(self.client.clone()).await?),
+            }
+        };
+        match &self.session {
+            Some(session) => {
+                session
+                    .exchange(self.node.to_string(), method, request, send)
+                    .await
+            }
+            None => send.await,
+        }
+    }
+    pub async fn get_mpn_works(
+        &self,
+        address: Address,
+    ) -> Result<bazuka::client::messages::GetMpnWorkResponse, ZoroError> {
+        let node = self.node;
+        self.request(
+            "get_mpn_works",
+            serde_json::json!({ "address": address }),
+            {
+                let address = address.clone();
+                move |mock| async move { mock.get_mpn_works(node, address).await }
+            },
+            |client| async move { client.get_mpn_works(address).await },
+        )
+        .await
+    }
+    pub async fn post_mpn_solution(
+        &self,
+        address: Address,
+        proofs: HashMap<usize, bazuka::zk::ZkProof>,
+    ) -> Result<bazuka::client::messages::PostMpnSolutionResponse, ZoroError> {
+        // Proofs are random, only the solved works are worth comparing
+        let mut ids = proofs.keys().cloned().collect::<Vec<_>>();
+        ids.sort_unstable();
+        let node = self.node;
+        self.request(
+            "post_mpn_solution",
+            serde_json::json!({ "address": address, "works": ids }),
+            {
+                let (address, proofs) = (address.clone(), proofs.clone());
+                move |mock| async move { mock.post_mpn_proof(node, address, proofs).await }
+            },
+            |client| async move { client.post_mpn_solution(address, proofs).await },
+        )
+        .await
+    }
+    pub async fn get_mpn_account(
+        &self,
+        address: MpnAddress,
+    ) -> Result<bazuka::client::messages::GetMpnAccountResponse, ZoroError> {
+        self.request(
+            "get_mpn_account",
+            serde_json::json!({ "address": address.to_string() }),
+            {
+                let address = address.clone();
+                move |mock| async move { mock.get_mpn_account(address).await }
+            },
+            |client| async move { client.get_mpn_account(address).await },
+        )
+        .await
+    }
+    pub async fn validator_claim(&self) -> Result<Option<ValidatorClaim>, ZoroError> {
+        self.request(
+            "validator_claim",
+            serde_json::Value::Null,
+            |mock| async move { mock.validator_claim().await },
+            |client| async move { client.validator_claim().await },
+        )
+        .await
+    }
+}
+
+pub fn record_result<T, E>(
+    health: &Mutex<PeerHealth>,
+    peer: &bazuka::client::PeerAddress,
+    result: &Result<T, E>,
+) {
+    let mut health = health.lock().unwrap();
+    if result.is_ok() {
+        health.record_success(peer);
+    } else {
+        health.record_failure(peer);
+    }
+}
+
+/// Asks the given peers for the validator claim, one after the other, until
+/// one of them responds. Peers which have been failing recently are skipped.
+pub async fn find_validator(
+    peers: &[bazuka::client::PeerAddress],
+    connector: &Connector,
+    timeout: Duration,
+    health: &Mutex<PeerHealth>,
+) -> Result<Option<ValidatorClaim>, ZoroError> {
+    let mut last_err = None;
+    for peer in peers {
+        if !health.lock().unwrap().is_available(peer) {
+            continue;
+        }
+        let result = connector.connect(*peer, timeout).validator_claim().await;
+        record_result(health, peer, &result);
+        match result {
+            Ok(claim) => return Ok(claim),
+            Err(e) => {
+                log::warn!("Unable to get the validator claim from {}: {}", peer, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or(ZoroError::NoPeersAvailable))
+}
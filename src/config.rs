@@ -1,6 +1,42 @@
-pub const LOG4_TREE_SIZE: u8 = 15;
-pub const LOG4_TOKENS_TREE_SIZE: u8 = 3;
-pub const LOG4_DEPOSIT_BATCH_SIZE: u8 = 3;
-pub const LOG4_WITHDRAW_BATCH_SIZE: u8 = 3;
-pub const LOG4_UPDATE_BATCH_SIZE: u8 = 4;
+/// Log4 sizes of the MPN trees, and of the batches each circuit proves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Dimensions {
+    pub log4_tree_size: u8,
+    pub log4_tokens_tree_size: u8,
+    pub log4_deposit_batch_size: u8,
+    pub log4_withdraw_batch_size: u8,
+    pub log4_update_batch_size: u8,
+    /// Whether the deposit, withdraw and update circuits range-check the
+    /// balances they change, and check the owners of the accounts and the
+    /// tokens of the slots they touch. Those checks change the circuits, so
+    /// shapes whose params are already deployed (Their VKs are built into the
+    /// nodes) can't have them.
+    pub strict_circuits: bool,
+}
+
+pub const MAINNET: Dimensions = Dimensions {
+    log4_tree_size: 15,
+    log4_tokens_tree_size: 3,
+    log4_deposit_batch_size: 3,
+    log4_withdraw_batch_size: 3,
+    log4_update_batch_size: 4,
+    strict_circuits: false,
+};
+
+/// Mainnet sizes, with the strict circuits. Needs new params to be deployed.
+pub const MAINNET_STRICT: Dimensions = Dimensions {
+    strict_circuits: true,
+    ..MAINNET
+};
+
+/// Small circuits for test networks, their params are quick to generate.
+pub const DEV: Dimensions = Dimensions {
+    log4_tree_size: 10,
+    log4_tokens_tree_size: 3,
+    log4_deposit_batch_size: 1,
+    log4_withdraw_batch_size: 1,
+    log4_update_batch_size: 1,
+    strict_circuits: true,
+};
+
 pub const LOG4_SUPER_UPDATE_BATCH_SIZE: u8 = 5;
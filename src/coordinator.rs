@@ -0,0 +1,535 @@
+use crate::bank::{BankError, ZoroParams, ZoroVerifyKeys};
+use crate::client::Connector;
+use crate::prover::{Prover, Proving};
+use crate::settings::ProveConfig;
+use crate::{alice_shuffle, CoordinatorOpt, ZoroError, ZoroWork};
+
+use bazuka::zk::groth16::Groth16Proof;
+use bellman::groth16::Backend;
+use bincode::Options;
+use colored::Colorize;
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode};
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// Remote workers let the coordinator know they are still alive this often
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// How often the coordinator checks whether its workers are done
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// Requests of the workers are tiny, bigger ones are refused before being read
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+// Assignments carry a whole work
+const MAX_RESPONSE_SIZE: u64 = 256 * 1024 * 1024;
+// Header carrying the token shared by the coordinator and its workers
+const TOKEN_HEADER: &str = "x-zoro-token";
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Assignment {
+    pub round: u64,
+    pub id: usize,
+    pub work: ZoroWork,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct AssignRequest {
+    pub worker: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct AssignResponse {
+    pub assignment: Option<Assignment>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct HeartbeatRequest {
+    pub worker: String,
+    pub round: u64,
+    pub id: usize,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct HeartbeatResponse {
+    pub keep_going: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SubmitRequest {
+    pub worker: String,
+    pub round: u64,
+    pub id: usize,
+    pub proof: Groth16Proof,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SubmitResponse {
+    pub accepted: bool,
+}
+
+struct Lease {
+    worker: String,
+    host: IpAddr,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+struct Round {
+    id: u64,
+    works: HashMap<usize, ZoroWork>,
+    leases: HashMap<usize, Lease>,
+    proofs: HashMap<usize, Groth16Proof>,
+}
+
+impl Round {
+    fn is_done(&self) -> bool {
+        self.proofs.len() == self.works.len()
+    }
+}
+
+/// Hands out the works of the current round to remote workers and collects
+/// their proofs. A work whose worker stops sending heartbeats is given to the
+/// next idle worker. Worker names are chosen by the workers themselves, so
+/// the works a single machine may hold are limited by its address.
+pub struct Coordinator {
+    verify_keys: ZoroVerifyKeys,
+    token: String,
+    lease_timeout: Duration,
+    max_leases_per_host: usize,
+    round: Mutex<Round>,
+}
+
+impl Coordinator {
+    pub fn new(
+        verify_keys: ZoroVerifyKeys,
+        token: String,
+        lease_timeout: Duration,
+        max_leases_per_host: usize,
+    ) -> Self {
+        Self {
+            verify_keys,
+            token,
+            lease_timeout,
+            max_leases_per_host,
+            round: Mutex::new(Round::default()),
+        }
+    }
+
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        let given = req
+            .headers()
+            .get(TOKEN_HEADER)
+            .map(|v| v.as_bytes())
+            .unwrap_or_default();
+        // Compared in constant time, so that the token can't be guessed byte
+        // by byte
+        given.len() == self.token.len()
+            && given
+                .iter()
+                .zip(self.token.as_bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    fn start_round(&self, works: HashMap<usize, ZoroWork>) -> u64 {
+        let mut round = self.round.lock().unwrap();
+        let id = round.id + 1;
+        *round = Round {
+            id,
+            works,
+            leases: HashMap::new(),
+            proofs: HashMap::new(),
+        };
+        id
+    }
+
+    fn is_done(&self) -> bool {
+        self.round.lock().unwrap().is_done()
+    }
+
+    fn take_proofs(&self) -> HashMap<usize, Groth16Proof> {
+        let mut round = self.round.lock().unwrap();
+        round.works.clear();
+        round.leases.clear();
+        std::mem::take(&mut round.proofs)
+    }
+
+    /// Hands the works to the workers, and waits until all of them are proven
+    /// or the round is cancelled. Like with local proving, the proofs
+    /// finished before a cancellation are returned too.
+    pub async fn prove(
+        &self,
+        works: Vec<(usize, ZoroWork)>,
+        cancel: Arc<RwLock<bool>>,
+    ) -> (HashMap<usize, Groth16Proof>, Result<(), BankError>) {
+        self.start_round(works.into_iter().collect());
+        while !self.is_done() {
+            if *cancel.read().unwrap() {
+                return (self.take_proofs(), Err(BankError::Cancelled));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        (self.take_proofs(), Ok(()))
+    }
+
+    fn assign(&self, req: AssignRequest, host: IpAddr) -> AssignResponse {
+        let mut round = self.round.lock().unwrap();
+        // A worker asking for work is idle, so whatever it was holding is lost
+        round.leases.retain(|_, l| l.worker != req.worker);
+
+        let lease_timeout = self.lease_timeout;
+        let held = round
+            .leases
+            .values()
+            .filter(|l| l.host == host && l.last_seen.elapsed() <= lease_timeout)
+            .count();
+        if held >= self.max_leases_per_host {
+            return AssignResponse { assignment: None };
+        }
+        let mut candidates = round
+            .works
+            .keys()
+            .filter(|id| !round.proofs.contains_key(*id))
+            .filter(|id| match round.leases.get(*id) {
+                Some(lease) => lease.last_seen.elapsed() > lease_timeout,
+                None => true,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        // Prefer works nobody has touched over the ones being reassigned
+        candidates.sort_by_key(|id| (round.leases.contains_key(id), *id));
+
+        let assignment = if let Some(id) = candidates.first().cloned() {
+            if let Some(lease) = round.leases.get(&id) {
+                println!(
+                    "{} Reassigning work {} of {} to {}",
+                    "WARNING:".bright_red(),
+                    id,
+                    lease.worker,
+                    req.worker
+                );
+            }
+            round.leases.insert(
+                id,
+                Lease {
+                    worker: req.worker.clone(),
+                    host,
+                    last_seen: Instant::now(),
+                },
+            );
+            Some(Assignment {
+                round: round.id,
+                id,
+                work: round.works[&id].clone(),
+            })
+        } else {
+            None
+        };
+        AssignResponse { assignment }
+    }
+
+    fn heartbeat(&self, req: HeartbeatRequest) -> HeartbeatResponse {
+        let mut round = self.round.lock().unwrap();
+        if round.id != req.round || round.proofs.contains_key(&req.id) {
+            return HeartbeatResponse { keep_going: false };
+        }
+        let keep_going = match round.leases.get_mut(&req.id) {
+            Some(lease) if lease.worker == req.worker => {
+                lease.last_seen = Instant::now();
+                true
+            }
+            _ => false,
+        };
+        HeartbeatResponse { keep_going }
+    }
+
+    fn submit(&self, req: SubmitRequest) -> SubmitResponse {
+        let work = {
+            let round = self.round.lock().unwrap();
+            if round.id != req.round || round.proofs.contains_key(&req.id) {
+                return SubmitResponse { accepted: false };
+            }
+            round.works.get(&req.id).cloned()
+        };
+        // Verifying takes a while, the other workers shouldn't wait for it
+        let accepted = match work {
+            Some(work) => work.verify(&self.verify_keys, &req.proof),
+            None => false,
+        };
+
+        let mut round = self.round.lock().unwrap();
+        // The round may have ended, or someone else solved the work meanwhile
+        if round.id != req.round || round.proofs.contains_key(&req.id) {
+            return SubmitResponse { accepted: false };
+        }
+        round.leases.remove(&req.id);
+        if accepted {
+            println!("{} solved work {}!", req.worker, req.id);
+            round.proofs.insert(req.id, req.proof);
+        } else {
+            println!(
+                "{} {} submitted an incorrect proof for work {}!",
+                "WARNING:".bright_red(),
+                req.worker,
+                req.id
+            );
+        }
+        SubmitResponse { accepted }
+    }
+}
+
+/// Bincode encoding of the messages, refusing to allocate more than `limit`
+/// bytes for them.
+fn codec(limit: u64) -> impl Options {
+    bincode::DefaultOptions::new().with_limit(limit)
+}
+
+/// Reads a body, giving up as soon as it's longer than `limit` bytes.
+async fn read_body(mut body: Body, limit: u64) -> Result<Option<Vec<u8>>, ZoroError> {
+    if body.size_hint().lower() > limit {
+        return Ok(None);
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if (bytes.len() + chunk.len()) as u64 > limit {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Some(bytes))
+}
+
+fn empty_response(status: StatusCode) -> Result<Response<Body>, ZoroError> {
+    Ok(Response::builder().status(status).body(Body::empty())?)
+}
+
+async fn handle(
+    coordinator: Arc<Coordinator>,
+    host: IpAddr,
+    req: Request<Body>,
+) -> Result<Response<Body>, ZoroError> {
+    if !coordinator.is_authorized(&req) {
+        return empty_response(StatusCode::UNAUTHORIZED);
+    }
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let body = match read_body(req.into_body(), MAX_REQUEST_SIZE).await? {
+        Some(body) => body,
+        None => return empty_response(StatusCode::PAYLOAD_TOO_LARGE),
+    };
+    let request = codec(MAX_REQUEST_SIZE);
+    let response = codec(MAX_RESPONSE_SIZE);
+    let resp = match (method, path.as_str()) {
+        (Method::POST, "/assign") => {
+            response.serialize(&coordinator.assign(request.deserialize(&body)?, host))?
+        }
+        (Method::POST, "/heartbeat") => {
+            response.serialize(&coordinator.heartbeat(request.deserialize(&body)?))?
+        }
+        (Method::POST, "/submit") => {
+            let req = request.deserialize(&body)?;
+            // Off the async runtime, since verifying a proof is CPU-bound
+            response
+                .serialize(&tokio::task::spawn_blocking(move || coordinator.submit(req)).await?)?
+        }
+        _ => return empty_response(StatusCode::NOT_FOUND),
+    };
+    Ok(Response::new(Body::from(resp)))
+}
+
+async fn serve(coordinator: Arc<Coordinator>, addr: SocketAddr) -> Result<(), ZoroError> {
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let coordinator = coordinator.clone();
+        let host = conn.remote_addr().ip();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let coordinator = coordinator.clone();
+                async move {
+                    Ok::<_, Infallible>(match handle(coordinator, host, req).await {
+                        Ok(resp) => resp,
+                        Err(e) => Response::builder()
+                            .status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(Body::from(e.to_string()))
+                            .unwrap(),
+                    })
+                }
+            }))
+        }
+    });
+    println!("Coordinator listening on {}...", addr);
+    Server::bind(&addr).serve(make_svc).await?;
+    Ok(())
+}
+
+/// Serves the workers, while the rounds are driven like those of `zoro prove`,
+/// only proven by the workers. Returns when the server stops.
+pub async fn run_coordinator(
+    opt: CoordinatorOpt,
+    conf: ProveConfig,
+    connector: Connector,
+    verify_keys: ZoroVerifyKeys,
+) -> Result<(), ZoroError> {
+    let coordinator = Arc::new(Coordinator::new(
+        verify_keys,
+        opt.token.clone(),
+        Duration::from_secs(opt.lease_timeout),
+        opt.max_leases_per_host,
+    ));
+    let prover = Arc::new(Prover::new(
+        conf,
+        connector,
+        Proving::Remote(coordinator.clone()),
+    ));
+    tokio::select! {
+        result = serve(coordinator, opt.listen) => result,
+        _ = prover.run_worker(0) => Ok(()),
+    }
+}
+
+/// Where the workers find their coordinator.
+#[derive(Clone)]
+pub struct CoordinatorAddress {
+    pub addr: String,
+    pub token: String,
+}
+
+async fn call<Req: serde::Serialize, Resp: serde::de::DeserializeOwned>(
+    http: &Client<HttpConnector>,
+    coordinator: &CoordinatorAddress,
+    path: &str,
+    req: &Req,
+) -> Result<Resp, ZoroError> {
+    let req = Request::post(format!("http://{}{}", coordinator.addr, path))
+        .header(TOKEN_HEADER, &coordinator.token)
+        .body(Body::from(codec(MAX_REQUEST_SIZE).serialize(req)?))?;
+    let resp = tokio::time::timeout(REQUEST_TIMEOUT, http.request(req)).await??;
+    let status = resp.status();
+    let body = match read_body(resp.into_body(), MAX_RESPONSE_SIZE).await? {
+        Some(body) => body,
+        None => return Err(ZoroError::CoordinatorError("response is too large".into())),
+    };
+    if !status.is_success() {
+        return Err(ZoroError::CoordinatorError(if body.is_empty() {
+            status.to_string()
+        } else {
+            String::from_utf8_lossy(&body).to_string()
+        }));
+    }
+    Ok(codec(MAX_RESPONSE_SIZE).deserialize(&body)?)
+}
+
+/// Asks the coordinator for a single work, proves it and sends the proof
+/// back. Returns `false` when there was nothing to do.
+async fn work_once(
+    http: &Client<HttpConnector>,
+    coordinator: &CoordinatorAddress,
+    name: &str,
+    params: ZoroParams,
+    backend: Backend,
+) -> Result<bool, ZoroError> {
+    let resp: AssignResponse = call(
+        http,
+        coordinator,
+        "/assign",
+        &AssignRequest {
+            worker: name.to_string(),
+        },
+    )
+    .await?;
+    let assignment = if let Some(assignment) = resp.assignment {
+        assignment
+    } else {
+        return Ok(false);
+    };
+    println!(
+        "Got SNARK-work {} of round {} to solve: {}",
+        assignment.id,
+        assignment.round,
+        assignment.work.describe()
+    );
+    alice_shuffle();
+
+    let cancel = Arc::new(RwLock::new(false));
+    let heartbeat = {
+        let http = http.clone();
+        let coordinator = coordinator.clone();
+        let req = HeartbeatRequest {
+            worker: name.to_string(),
+            round: assignment.round,
+            id: assignment.id,
+        };
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+                match call::<_, HeartbeatResponse>(&http, &coordinator, "/heartbeat", &req).await {
+                    Ok(resp) if !resp.keep_going => {
+                        println!("Work is not needed anymore!");
+                        *cancel.write().unwrap() = true;
+                    }
+                    _ => {}
+                }
+            }
+        })
+    };
+
+    let start = Instant::now();
+    let work = assignment.work;
+    let proof =
+        tokio::task::spawn_blocking(move || work.prove(params, backend, Some(cancel))).await;
+    heartbeat.abort();
+    let proof = proof??;
+    println!(
+        "{} {}ms",
+        "Proving took:".bright_green(),
+        start.elapsed().as_millis()
+    );
+
+    let resp: SubmitResponse = call(
+        http,
+        coordinator,
+        "/submit",
+        &SubmitRequest {
+            worker: name.to_string(),
+            round: assignment.round,
+            id: assignment.id,
+            proof,
+        },
+    )
+    .await?;
+    if resp.accepted {
+        println!("Your proof was accepted by the coordinator!");
+    } else {
+        println!("Your proof was rejected by the coordinator!");
+    }
+    Ok(true)
+}
+
+pub async fn run_worker(
+    coordinator: CoordinatorAddress,
+    name: String,
+    params: ZoroParams,
+    backend: Backend,
+) {
+    let http = Client::new();
+    loop {
+        match work_once(&http, &coordinator, &name, params.clone(), backend.clone()).await {
+            Ok(true) => {}
+            Ok(false) => {
+                tokio::time::sleep(Duration::from_millis(1000)).await;
+            }
+            Err(e) => {
+                println!("Error while proving: {}", e);
+                tokio::time::sleep(Duration::from_millis(1000)).await;
+            }
+        }
+    }
+}
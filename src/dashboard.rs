@@ -0,0 +1,346 @@
+use crate::events::{Event, EventSink, Stage};
+
+use colored::Colorize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Number of rounds kept in the latency history
+const LATENCY_HISTORY: usize = 10;
+// Number of recent messages (Warnings, errors...) shown at the bottom
+const MAX_MESSAGES: usize = 5;
+const BAR_WIDTH: usize = 30;
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+struct WorkProgress {
+    status: String,
+    since: Instant,
+    slot: Option<usize>,
+}
+
+struct RoundProgress {
+    works: usize,
+    proven: usize,
+    deadline: Option<Duration>,
+    started: Instant,
+}
+
+#[derive(Default)]
+struct DashboardState {
+    worker_status: BTreeMap<usize, (u64, String)>,
+    round_worker: HashMap<u64, usize>,
+    validator: Option<String>,
+    rounds: BTreeMap<u64, RoundProgress>,
+    works: BTreeMap<(u64, usize), WorkProgress>,
+    latencies: VecDeque<(u64, u64)>,
+    submitted: usize,
+    accepted: usize,
+    messages: VecDeque<String>,
+}
+
+impl DashboardState {
+    fn set_status(&mut self, round: u64, status: String) {
+        if let Some(worker) = self.round_worker.get(&round) {
+            self.worker_status.insert(*worker, (round, status));
+        }
+    }
+
+    fn message(&mut self, msg: String) {
+        self.messages.push_back(msg);
+        if self.messages.len() > MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+    }
+
+    fn end_round(&mut self, round: u64) {
+        self.rounds.remove(&round);
+        self.works.retain(|(r, _), _| *r != round);
+    }
+
+    fn apply(&mut self, event: &Event) {
+        match event {
+            Event::RoundStarted { round, worker } => {
+                // A worker only runs a single round at a time
+                if let Some((prev, _)) = self.worker_status.get(worker).cloned() {
+                    self.end_round(prev);
+                    self.round_worker.remove(&prev);
+                }
+                self.round_worker.insert(*round, *worker);
+                self.set_status(*round, "Finding the validator".into());
+            }
+            Event::ValidatorFound { round, validator } => {
+                self.validator = Some(validator.clone());
+                self.set_status(*round, "Fetching works".into());
+            }
+            Event::NoValidator { round } => {
+                self.validator = None;
+                self.set_status(*round, "No validator".into());
+            }
+            Event::WorksReceived { round, works } => {
+                if *works == 0 {
+                    self.set_status(*round, "Idle, no work to do".into());
+                } else {
+                    self.rounds.insert(
+                        *round,
+                        RoundProgress {
+                            works: *works,
+                            proven: 0,
+                            deadline: None,
+                            started: Instant::now(),
+                        },
+                    );
+                    self.set_status(*round, format!("Proving {} works", works));
+                }
+            }
+            Event::WorksRefused {
+                round,
+                works,
+                deadline_ms,
+            } => {
+                if let Some(progress) = self.rounds.get_mut(round) {
+                    progress.works = progress.works.saturating_sub(works.len());
+                    progress.deadline = Some(Duration::from_millis(*deadline_ms));
+                }
+                self.message(format!(
+                    "Skipped works {:?}, they can't be proven in {}s",
+                    works,
+                    deadline_ms / 1000
+                ));
+            }
+            Event::WorkAssigned { round, work, slot } => {
+                if let Some(progress) = self.works.get_mut(&(*round, *work)) {
+                    progress.slot = Some(*slot);
+                }
+            }
+            Event::SpanStarted { round, work, stage } => {
+                if let Some(work) = work {
+                    let slot = self.works.get(&(*round, *work)).and_then(|w| w.slot);
+                    self.works.insert(
+                        (*round, *work),
+                        WorkProgress {
+                            status: format!("{:?}", stage),
+                            since: Instant::now(),
+                            slot,
+                        },
+                    );
+                } else if *stage == Stage::Submit {
+                    self.set_status(*round, "Submitting proofs".into());
+                }
+            }
+            Event::SpanFinished {
+                round,
+                work,
+                stage,
+                error,
+                ..
+            } => {
+                if let (Some(work), None) = (work, error) {
+                    match stage {
+                        Stage::Synthesize => {
+                            if let Some(progress) = self.works.get_mut(&(*round, *work)) {
+                                progress.status = "Queued".into();
+                                progress.since = Instant::now();
+                            }
+                        }
+                        Stage::Verify => {
+                            self.works.remove(&(*round, *work));
+                            if let Some(progress) = self.rounds.get_mut(round) {
+                                progress.proven += 1;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                if let Some(error) = error {
+                    self.message(format!("{:?} failed: {}", stage, error));
+                }
+            }
+            Event::ValidatorChanged { round } => {
+                self.message(format!("Validator changed during round {}", round));
+            }
+            Event::DeadlineReached { round } => {
+                self.message(format!("Deadline reached in round {}", round));
+            }
+            Event::WorksProven {
+                round,
+                duration_ms,
+                deadline_ms,
+                ..
+            } => {
+                self.latencies.push_back((*duration_ms, *deadline_ms));
+                if self.latencies.len() > LATENCY_HISTORY {
+                    self.latencies.pop_front();
+                }
+                self.end_round(*round);
+            }
+            Event::ProofsSubmitted {
+                round,
+                submitted,
+                accepted,
+            } => {
+                self.submitted += submitted;
+                self.accepted += accepted;
+                self.set_status(
+                    *round,
+                    format!("{}/{} proofs accepted", accepted, submitted),
+                );
+            }
+            Event::RoundFailed { round, error, .. } => {
+                self.end_round(*round);
+                self.set_status(*round, format!("Error: {}", error));
+                self.message(format!("Error while proving: {}", error));
+            }
+        }
+    }
+}
+
+fn bar(done: f64) -> String {
+    let filled = ((done.clamp(0.0, 1.0)) * BAR_WIDTH as f64).round() as usize;
+    format!("[{}{}]", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled))
+}
+
+/// Full-screen view of what the workers are doing, redrawn periodically.
+/// It's fed by the same events as the text/JSON logs.
+pub struct Dashboard {
+    state: Mutex<DashboardState>,
+    slots: Vec<String>,
+}
+
+impl EventSink for Dashboard {
+    fn handle(&self, event: &Event) {
+        self.state.lock().unwrap().apply(event);
+    }
+}
+
+impl Dashboard {
+    /// `slots` are the names of the pipeline slots (GPUs, or the CPU) the
+    /// works are assigned to.
+    pub fn new(slots: Vec<String>) -> Self {
+        Self {
+            state: Mutex::new(DashboardState::default()),
+            slots,
+        }
+    }
+
+    /// Redraws the dashboard until the process ends.
+    pub fn start(self: Arc<Self>) {
+        std::thread::spawn(move || loop {
+            let screen = self.render();
+            let mut out = std::io::stdout().lock();
+            // Clear the screen and move the cursor to the top
+            let _ = write!(out, "\x1b[2J\x1b[H{}", screen);
+            let _ = out.flush();
+            drop(out);
+            std::thread::sleep(REFRESH_INTERVAL);
+        });
+    }
+
+    fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut lines = Vec::new();
+        lines.push(format!(
+            "{} v{}    Validator: {}",
+            "Zoro!".bright_green(),
+            env!("CARGO_PKG_VERSION"),
+            state
+                .validator
+                .clone()
+                .unwrap_or_else(|| "-".into())
+                .bright_yellow()
+        ));
+        lines.push(String::new());
+
+        lines.push("Workers".bright_cyan().to_string());
+        for (worker, (round, status)) in state.worker_status.iter() {
+            lines.push(format!("  #{:<3} round {:<6} {}", worker, round, status));
+        }
+        lines.push(String::new());
+
+        lines.push("Rounds".bright_cyan().to_string());
+        for (round, progress) in state.rounds.iter() {
+            let elapsed = progress.started.elapsed();
+            let deadline = progress
+                .deadline
+                .map(|d| format!("/{}s", d.as_secs()))
+                .unwrap_or_default();
+            lines.push(format!(
+                "  round {:<6} {} {}/{} proven, {}s{}",
+                round,
+                bar(progress.proven as f64 / progress.works.max(1) as f64),
+                progress.proven,
+                progress.works,
+                elapsed.as_secs(),
+                deadline
+            ));
+        }
+        lines.push(String::new());
+
+        lines.push("Works in progress".bright_cyan().to_string());
+        for ((round, work), progress) in state.works.iter() {
+            let slot = progress
+                .slot
+                .and_then(|s| self.slots.get(s).cloned())
+                .unwrap_or_else(|| "-".into());
+            lines.push(format!(
+                "  round {:<6} work {:<4} {:<10} {:<8} {}s",
+                round,
+                work,
+                progress.status,
+                slot,
+                progress.since.elapsed().as_secs()
+            ));
+        }
+        lines.push(String::new());
+
+        lines.push("Backends".bright_cyan().to_string());
+        for (slot, name) in self.slots.iter().enumerate() {
+            // A slot proves a single work at a time, and only the works it
+            // took have a slot
+            let proving = state
+                .works
+                .iter()
+                .find(|(_, progress)| progress.slot == Some(slot));
+            lines.push(match proving {
+                Some(((round, work), progress)) => format!(
+                    "  {:<8} round {:<6} work {:<4} {:<10} {}s",
+                    name,
+                    round,
+                    work,
+                    progress.status,
+                    progress.since.elapsed().as_secs()
+                ),
+                None => format!("  {:<8} idle", name),
+            });
+        }
+        lines.push(String::new());
+
+        lines.push("Proving latency (Recent rounds)".bright_cyan().to_string());
+        for (duration_ms, deadline_ms) in state.latencies.iter() {
+            let line = format!(
+                "  {} {}ms / {}ms",
+                bar(*duration_ms as f64 / (*deadline_ms).max(1) as f64),
+                duration_ms,
+                deadline_ms
+            );
+            lines.push(if duration_ms > deadline_ms {
+                line.bright_red().to_string()
+            } else {
+                line
+            });
+        }
+        lines.push(String::new());
+
+        lines.push(format!(
+            "{} {}/{}",
+            "Accepted proofs:".bright_green(),
+            state.accepted,
+            state.submitted
+        ));
+        lines.push(String::new());
+        for msg in state.messages.iter() {
+            lines.push(msg.bright_red().to_string());
+        }
+        lines.join("\n") + "\n"
+    }
+}
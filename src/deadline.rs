@@ -0,0 +1,111 @@
+use crate::ZoroWork;
+
+use bazuka::client::messages::ValidatorClaim;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// Weight of the newest sample in the moving average of proving times
+const HISTORY_WEIGHT: f64 = 0.3;
+// Number of validator tenures kept for learning the deadline
+const MAX_TENURES: usize = 16;
+// Minimum number of observed tenures before trusting the learned deadline
+const MIN_TENURES: usize = 3;
+
+/// Keeps track of how long proving takes for each circuit, and how long the
+/// validators of the network keep their claim, in order to decide whether a
+/// batch of works can be proven before it becomes useless.
+pub struct ProvingDeadline {
+    configured: Duration,
+    learn: bool,
+    msm_times: HashMap<&'static str, Duration>,
+    current_claim: Option<(ValidatorClaim, Instant, bool)>,
+    tenures: VecDeque<Duration>,
+}
+
+impl ProvingDeadline {
+    pub fn new(configured: Duration, learn: bool) -> Self {
+        Self {
+            configured,
+            learn,
+            msm_times: HashMap::new(),
+            current_claim: None,
+            tenures: VecDeque::new(),
+        }
+    }
+
+    /// Should be called whenever the validator claim of the network is queried.
+    pub fn observe_claim(&mut self, claim: &Option<ValidatorClaim>) {
+        let claim = if let Some(claim) = claim {
+            claim
+        } else {
+            return;
+        };
+        match &self.current_claim {
+            Some((curr, _, _)) if curr == claim => {}
+            Some((_, since, complete)) => {
+                // Only tenures we have seen the beginning of are meaningful
+                if *complete {
+                    self.tenures.push_back(since.elapsed());
+                    if self.tenures.len() > MAX_TENURES {
+                        self.tenures.pop_front();
+                    }
+                }
+                self.current_claim = Some((claim.clone(), Instant::now(), true));
+            }
+            None => {
+                self.current_claim = Some((claim.clone(), Instant::now(), false));
+            }
+        }
+    }
+
+    /// Time we have for proving a batch of works, learned from the network if
+    /// requested, but never more than the configured deadline.
+    pub fn deadline(&self) -> Duration {
+        if self.learn && self.tenures.len() >= MIN_TENURES {
+            let mut tenures = self.tenures.iter().cloned().collect::<Vec<_>>();
+            tenures.sort();
+            std::cmp::min(self.configured, tenures[tenures.len() / 2])
+        } else {
+            self.configured
+        }
+    }
+
+    pub fn record(&mut self, circuit: &'static str, msm_time: Duration) {
+        let avg = self
+            .msm_times
+            .get(circuit)
+            .map(|prev| prev.mul_f64(1.0 - HISTORY_WEIGHT) + msm_time.mul_f64(HISTORY_WEIGHT))
+            .unwrap_or(msm_time);
+        self.msm_times.insert(circuit, avg);
+    }
+
+    pub fn predict(&self, circuit: &'static str) -> Option<Duration> {
+        self.msm_times.get(circuit).cloned()
+    }
+
+    /// Keeps as many works as can be proven in time by `msm_slots` parallel
+    /// provers, and returns the ids of the refused ones. Works of circuits we
+    /// have no history of are always accepted, and so is the first work, so
+    /// that a circuit predicted to be too slow gets a chance to prove it isn't.
+    pub fn schedule(
+        &self,
+        mut works: Vec<(usize, ZoroWork)>,
+        msm_slots: usize,
+    ) -> (Vec<(usize, ZoroWork)>, Vec<usize>) {
+        let deadline = self.deadline();
+        works.sort_by_key(|(id, _)| *id);
+        let mut total = Duration::ZERO;
+        let mut accepted = Vec::new();
+        let mut refused = Vec::new();
+        for (id, work) in works {
+            let predicted = self.predict(work.circuit_name()).unwrap_or_default();
+            if accepted.is_empty() || (total + predicted) / msm_slots.max(1) as u32 <= deadline {
+                total += predicted;
+                accepted.push((id, work));
+            } else {
+                refused.push(id);
+            }
+        }
+        (accepted, refused)
+    }
+}
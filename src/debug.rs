@@ -0,0 +1,129 @@
+use bellman::{Circuit, ConstraintSystem, Index, LinearCombination, SynthesisError, Variable};
+use ff::Field;
+use zeekit::BellmanFr;
+
+/// A constraint which is not satisfied by the witness of a circuit.
+#[derive(Debug, Clone)]
+pub struct Unsatisfied {
+    pub index: usize,
+    /// Namespaces the constraint was enforced in, e.g `transition 3/nonce`
+    pub path: String,
+}
+
+impl std::fmt::Display for Unsatisfied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "constraint #{} ({})", self.index, self.path)
+    }
+}
+
+/// Checks the witness of a circuit while it's being synthesized, keeping
+/// track of the namespaces so that the first unsatisfied constraint can be
+/// traced back to the check it belongs to. Unlike bellman's test constraint
+/// system, duplicate names are fine (Most gadgets don't name their
+/// constraints).
+pub struct DebugSystem {
+    inputs: Vec<BellmanFr>, // Including ONE
+    aux: Vec<BellmanFr>,
+    namespaces: Vec<String>,
+    num_constraints: usize,
+    unsatisfied: Option<Unsatisfied>,
+}
+
+impl DebugSystem {
+    pub fn check<C: Circuit<BellmanFr>>(circuit: C) -> Result<Option<Unsatisfied>, SynthesisError> {
+        let mut cs = Self {
+            inputs: vec![BellmanFr::one()],
+            aux: Vec::new(),
+            namespaces: Vec::new(),
+            num_constraints: 0,
+            unsatisfied: None,
+        };
+        circuit.synthesize(&mut cs)?;
+        Ok(cs.unsatisfied)
+    }
+
+    fn eval(&self, lc: &LinearCombination<BellmanFr>) -> BellmanFr {
+        let mut sum = BellmanFr::zero();
+        for (var, coeff) in lc.as_ref() {
+            let value = match var.get_unchecked() {
+                Index::Input(i) => self.inputs[i],
+                Index::Aux(i) => self.aux[i],
+            };
+            sum += value * coeff;
+        }
+        sum
+    }
+}
+
+impl ConstraintSystem<BellmanFr> for DebugSystem {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(&mut self, _annotation: A, f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.aux.push(f()?);
+        Ok(Variable::new_unchecked(Index::Aux(self.aux.len() - 1)))
+    }
+
+    fn alloc_input<F, A, AR>(&mut self, _annotation: A, f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.inputs.push(f()?);
+        Ok(Variable::new_unchecked(Index::Input(self.inputs.len() - 1)))
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(&mut self, annotation: A, a: LA, b: LB, c: LC)
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+        LA: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LB: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LC: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+    {
+        let index = self.num_constraints;
+        self.num_constraints += 1;
+        if self.unsatisfied.is_some() {
+            return;
+        }
+        let a = self.eval(&a(LinearCombination::zero()));
+        let b = self.eval(&b(LinearCombination::zero()));
+        let c = self.eval(&c(LinearCombination::zero()));
+        if a * b != c {
+            let mut path = self.namespaces.clone();
+            let name: String = annotation().into();
+            if !name.is_empty() {
+                path.push(name);
+            }
+            self.unsatisfied = Some(Unsatisfied {
+                index,
+                path: if path.is_empty() {
+                    "<root>".into()
+                } else {
+                    path.join("/")
+                },
+            });
+        }
+    }
+
+    fn push_namespace<NR, N>(&mut self, name_fn: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+        self.namespaces.push(name_fn().into());
+    }
+
+    fn pop_namespace(&mut self) {
+        self.namespaces.pop();
+    }
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
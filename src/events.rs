@@ -0,0 +1,297 @@
+use colored::Colorize;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+/// Stages of proving a batch of works, each one traced with a span.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Fetch,
+    Synthesize,
+    Prove,
+    Verify,
+    Submit,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct PipelineStats {
+    pub synthesis_ms_per_work: u64,
+    pub msm_ms_per_work: u64,
+    pub msm_utilization: u64,
+    pub max_queued: usize,
+}
+
+/// Everything interesting that happens while proving. All the progress
+/// reports of `zoro prove` go through these, so that they can be printed
+/// as text, as JSON, or displayed by other frontends.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    RoundStarted {
+        round: u64,
+        worker: usize,
+    },
+    ValidatorFound {
+        round: u64,
+        validator: String,
+    },
+    NoValidator {
+        round: u64,
+    },
+    WorksReceived {
+        round: u64,
+        works: usize,
+    },
+    WorksRefused {
+        round: u64,
+        works: Vec<usize>,
+        deadline_ms: u64,
+    },
+    WorkAssigned {
+        round: u64,
+        work: usize,
+        slot: usize,
+    },
+    SpanStarted {
+        round: u64,
+        work: Option<usize>,
+        stage: Stage,
+    },
+    SpanFinished {
+        round: u64,
+        work: Option<usize>,
+        stage: Stage,
+        duration_ms: u64,
+        error: Option<String>,
+    },
+    ValidatorChanged {
+        round: u64,
+    },
+    DeadlineReached {
+        round: u64,
+    },
+    WorksProven {
+        round: u64,
+        proofs: usize,
+        duration_ms: u64,
+        deadline_ms: u64,
+        pipeline: PipelineStats,
+    },
+    ProofsSubmitted {
+        round: u64,
+        submitted: usize,
+        accepted: usize,
+    },
+    RoundFailed {
+        round: u64,
+        worker: usize,
+        error: String,
+        /// Retrying is unlikely to help, someone has to look at the prover
+        critical: bool,
+    },
+}
+
+pub trait EventSink: Send + Sync {
+    fn handle(&self, event: &Event);
+}
+
+static SINKS: RwLock<Vec<Arc<dyn EventSink>>> = RwLock::new(Vec::new());
+static NEXT_ROUND: AtomicU64 = AtomicU64::new(0);
+
+pub fn subscribe(sink: Arc<dyn EventSink>) {
+    SINKS.write().unwrap().push(sink);
+}
+
+pub fn emit(event: Event) {
+    for sink in SINKS.read().unwrap().iter() {
+        sink.handle(&event);
+    }
+}
+
+/// Unique id of a proving round, shared between all the workers.
+pub fn new_round() -> u64 {
+    NEXT_ROUND.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Measures a single stage of a round, from creation until `finish`.
+pub struct Span {
+    round: u64,
+    work: Option<usize>,
+    stage: Stage,
+    start: Instant,
+}
+
+impl Span {
+    pub fn enter(round: u64, work: Option<usize>, stage: Stage) -> Self {
+        emit(Event::SpanStarted { round, work, stage });
+        Self {
+            round,
+            work,
+            stage,
+            start: Instant::now(),
+        }
+    }
+
+    /// Ends the span with the outcome of its stage, and passes it through.
+    pub fn finish<T, E: Display>(self, result: Result<T, E>) -> Result<T, E> {
+        emit(Event::SpanFinished {
+            round: self.round,
+            work: self.work,
+            stage: self.stage,
+            duration_ms: self.start.elapsed().as_millis() as u64,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+        result
+    }
+}
+
+/// Human readable output, as zoro has always printed it.
+pub struct TextSink;
+
+impl EventSink for TextSink {
+    fn handle(&self, event: &Event) {
+        match event {
+            Event::RoundStarted { .. } => println!("Finding the validator..."),
+            Event::ValidatorFound { validator, .. } => println!("{} is validator!", validator),
+            Event::NoValidator { .. } => {}
+            Event::WorksReceived { works, .. } => {
+                if *works == 0 {
+                    println!("No work to do!");
+                } else {
+                    println!("Got {} SNARK-works to solve...", works);
+                    crate::alice_shuffle();
+                }
+            }
+            Event::WorksRefused {
+                works, deadline_ms, ..
+            } => println!(
+                "{} Skipping works {:?}, they can't be proven in {}s!",
+                "WARNING:".bright_red(),
+                works,
+                deadline_ms / 1000
+            ),
+            Event::WorkAssigned { .. } | Event::SpanStarted { .. } => {}
+            Event::SpanFinished {
+                work,
+                stage,
+                duration_ms,
+                error,
+                ..
+            } => {
+                let work = work.map(|w| format!(" of work {}", w)).unwrap_or_default();
+                match error {
+                    Some(e) => {
+                        log::debug!("{:?}{} failed after {}ms: {}", stage, work, duration_ms, e)
+                    }
+                    None => log::debug!("{:?}{} took {}ms", stage, work, duration_ms),
+                }
+            }
+            Event::ValidatorChanged { .. } => println!("Validator changed!"),
+            Event::DeadlineReached { .. } => println!(
+                "{} Deadline reached, cancelling the proofs!",
+                "WARNING:".bright_red()
+            ),
+            Event::WorksProven {
+                duration_ms,
+                deadline_ms,
+                pipeline,
+                ..
+            } => {
+                println!(
+                    "{} synthesis {}ms/work, msm {}ms/work, msm utilization {}%, max queued {}",
+                    "Pipeline:".bright_green(),
+                    pipeline.synthesis_ms_per_work,
+                    pipeline.msm_ms_per_work,
+                    pipeline.msm_utilization,
+                    pipeline.max_queued
+                );
+                println!("{} {}ms", "Proving took:".bright_green(), duration_ms);
+                if duration_ms > deadline_ms {
+                    println!("{} {}", "WARNING:".bright_red(), "Your proving time is too high! You will most probably not win any rewards with this latency.");
+                }
+            }
+            Event::ProofsSubmitted { accepted, .. } => {
+                println!("{} of your proofs were accepted!", accepted)
+            }
+            Event::RoundFailed {
+                error, critical, ..
+            } => {
+                if *critical {
+                    println!("{} {}", "Critical error while proving:".bright_red(), error)
+                } else {
+                    println!("Error while proving: {}", error)
+                }
+            }
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct JsonRecord<'a> {
+    timestamp: u128,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// One JSON object per line, for log aggregators.
+pub struct JsonSink;
+
+impl EventSink for JsonSink {
+    fn handle(&self, event: &Event) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        if let Ok(line) = serde_json::to_string(&JsonRecord { timestamp, event }) {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Formats the `log` records the same way the events are printed.
+pub fn init_logger(level: &str, format: LogFormat) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level));
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            use std::io::Write;
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or_default();
+            writeln!(
+                buf,
+                "{}",
+                serde_json::json!({
+                    "timestamp": timestamp,
+                    "event": "log",
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                })
+            )
+        });
+    }
+    builder.init();
+}
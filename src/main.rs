@@ -1,11 +1,30 @@
+mod backoff;
 mod bank;
 mod circuits;
 mod client;
 mod config;
+mod coordinator;
+mod dashboard;
+mod deadline;
+mod debug;
+mod events;
+mod mock;
+mod mpn_model;
+mod notify;
+mod pipeline;
+mod pools;
+mod profiles;
+mod prover;
+mod r1cs;
+mod session;
+mod settings;
+mod shapes;
+mod stats;
+mod synthesis;
 
 use bazuka::client::PeerAddress;
 
-use bazuka::core::{Address, TokenId};
+use bazuka::core::{Address, Money, MpnAddress, TokenId};
 
 use bazuka::mpn::{MpnWork, MpnWorkData};
 
@@ -13,36 +32,62 @@ use bellman::gpu::{Brand, Device};
 use bellman::groth16::Backend;
 use bellman::{groth16, Circuit};
 use bls12_381::Bls12;
-use client::SyncClient;
 use colored::Colorize;
 
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
-use rayon::prelude::*;
-use std::collections::HashMap;
 use std::fs::File;
-
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::sync::Mutex;
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
 use structopt::StructOpt;
 
 use zeekit::BellmanFr;
 
 #[derive(Debug, Clone, StructOpt)]
 struct GenerateParamsOpt {
+    /// Dimensions of the circuits, e.g `mainnet` or `dev`
+    #[structopt(long, default_value = "mainnet")]
+    shape: shapes::Shape,
     #[structopt(long, default_value = "super_update_params.dat")]
     super_update_circuit_params: PathBuf,
-    #[structopt(long, default_value = "update_params.dat")]
-    update_circuit_params: PathBuf,
-    #[structopt(long, default_value = "deposit_params.dat")]
-    deposit_circuit_params: PathBuf,
-    #[structopt(long, default_value = "withdraw_params.dat")]
-    withdraw_circuit_params: PathBuf,
+    #[structopt(long)]
+    update_circuit_params: Option<PathBuf>,
+    #[structopt(long)]
+    deposit_circuit_params: Option<PathBuf>,
+    #[structopt(long)]
+    withdraw_circuit_params: Option<PathBuf>,
+    #[structopt(long)]
+    multi_fee_update_circuit_params: Option<PathBuf>,
 }
 
-#[derive(Clone, serde::Serialize, serde::Deserialize)]
+impl GenerateParamsOpt {
+    /// Paths of the params, those of the shape unless given.
+    fn param_paths(&self) -> shapes::ParamPaths {
+        let defaults = self.shape.param_paths();
+        shapes::ParamPaths {
+            update: self
+                .update_circuit_params
+                .clone()
+                .unwrap_or(defaults.update),
+            deposit: self
+                .deposit_circuit_params
+                .clone()
+                .unwrap_or(defaults.deposit),
+            withdraw: self
+                .withdraw_circuit_params
+                .clone()
+                .unwrap_or(defaults.withdraw),
+            multi_fee_update: self
+                .multi_fee_update_circuit_params
+                .clone()
+                .unwrap_or(defaults.multi_fee_update),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 struct Optimization {
     n_g1: usize,
     window_size_g1: usize,
@@ -67,51 +112,277 @@ impl Into<bellman::gpu::OptParams> for Optimization {
 
 #[derive(Debug, Clone, StructOpt)]
 struct ProveOpt {
+    /// Config file, defaults to ~/.zoro/config.toml (Command line flags override it)
     #[structopt(long)]
-    network: String,
+    config: Option<PathBuf>,
+    #[structopt(long)]
+    network: Option<String>,
+    #[structopt(long)]
+    connect: Vec<PeerAddress>,
+    /// Dimensions of the circuits, e.g `mainnet` or `dev`
     #[structopt(long)]
-    connect: PeerAddress,
+    shape: Option<shapes::Shape>,
     #[structopt(long, default_value = "super_update_params.dat")]
     super_update_circuit_params: PathBuf,
-    #[structopt(long, default_value = "update_params.dat")]
-    update_circuit_params: PathBuf,
-    #[structopt(long, default_value = "deposit_params.dat")]
-    deposit_circuit_params: PathBuf,
-    #[structopt(long, default_value = "withdraw_params.dat")]
-    withdraw_circuit_params: PathBuf,
+    #[structopt(long)]
+    update_circuit_params: Option<PathBuf>,
+    #[structopt(long)]
+    deposit_circuit_params: Option<PathBuf>,
+    #[structopt(long)]
+    withdraw_circuit_params: Option<PathBuf>,
+    /// Only needed for proving multi-fee update batches
+    #[structopt(long)]
+    multi_fee_update_circuit_params: Option<PathBuf>,
+    #[structopt(long)]
+    gpu: bool,
+    /// Prove on the CPU, even if the config file enables the GPU
+    #[structopt(long, conflicts_with = "gpu")]
+    no_gpu: bool,
+    /// Index of a GPU device to use (Can be repeated, all devices by default)
+    #[structopt(long = "gpu-device")]
+    gpu_devices: Vec<usize>,
+    #[structopt(long)]
+    workers: Option<usize>,
+    #[structopt(long)]
+    address: Option<Address>,
+    /// Maximum number of synthesized witnesses waiting for the MSM backend
+    #[structopt(long)]
+    synthesis_queue: Option<usize>,
+    /// Threads used for witness synthesis (Shared between all workers)
+    #[structopt(long)]
+    synthesis_threads: Option<usize>,
+    /// Threads used for the CPU part of the multiexps
+    #[structopt(long)]
+    msm_threads: Option<usize>,
+    /// Cores Zoro is allowed to run on, e.g 0-15,32-47
+    #[structopt(long)]
+    cpu_set: Option<pools::CpuSet>,
+    /// Pin each synthesis thread to a single core of the cpu-set
+    #[structopt(long)]
+    pin_threads: bool,
+    #[structopt(long, conflicts_with = "pin-threads")]
+    no_pin_threads: bool,
+    /// Seconds we have for proving a batch of works before it's worthless
+    #[structopt(long)]
+    max_proving_time: Option<u64>,
+    /// Shorten the deadline according to how long validators keep their claim
+    #[structopt(long)]
+    learn_deadline: bool,
+    #[structopt(long, conflicts_with = "learn-deadline")]
+    no_learn_deadline: bool,
+    /// Format of the progress reports, `text` or `json`
+    #[structopt(long)]
+    log_format: Option<events::LogFormat>,
+    /// Show a live dashboard instead of the progress reports
+    #[structopt(long)]
+    tui: bool,
+    /// Record the traffic with the nodes to the given file
+    #[structopt(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// Feed the traffic recorded in the given file, instead of talking to the nodes
+    #[structopt(long)]
+    replay: Option<PathBuf>,
+    /// Prove the works of an in-process mock validator instead of the network
+    #[structopt(long)]
+    mock_node: bool,
+    /// Seconds after which the mock validator hands its claim to another one
+    #[structopt(long)]
+    mock_validator_switch: Option<u64>,
+    /// Milliseconds the mock node waits before each response
+    #[structopt(long)]
+    mock_slow_responses: Option<u64>,
+    /// Ratio of the mock works that are impossible to prove (0.0 - 1.0)
+    #[structopt(long, default_value = "0")]
+    mock_invalid_works: f64,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct CoordinatorOpt {
+    /// Rounds are configured like those of `zoro prove`, only the proving
+    /// flags are ignored
+    #[structopt(flatten)]
+    prove: ProveOpt,
+    /// Only reachable from this machine by default, listen on a public address
+    /// for workers running elsewhere
+    #[structopt(long, default_value = "127.0.0.1:8766")]
+    listen: SocketAddr,
+    /// Secret the workers have to present for getting works
+    #[structopt(long)]
+    token: String,
+    /// Seconds without a heartbeat before a work is given to another worker
+    #[structopt(long, default_value = "10")]
+    lease_timeout: u64,
+    /// Works a single machine may be proving at the same time
+    #[structopt(long, default_value = "8")]
+    max_leases_per_host: usize,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct WorkerOpt {
+    #[structopt(long)]
+    coordinator: String,
+    /// Secret given to the coordinator with `--token`
+    #[structopt(long)]
+    token: String,
+    #[structopt(long)]
+    name: Option<String>,
+    /// Network profile whose params are used
+    #[structopt(long, default_value = "pelmeni-9")]
+    network: String,
+    /// Dimensions of the circuits, those of the network by default
+    #[structopt(long)]
+    shape: Option<shapes::Shape>,
+    #[structopt(long)]
+    update_circuit_params: Option<PathBuf>,
+    #[structopt(long)]
+    deposit_circuit_params: Option<PathBuf>,
+    #[structopt(long)]
+    withdraw_circuit_params: Option<PathBuf>,
+    #[structopt(long)]
+    multi_fee_update_circuit_params: Option<PathBuf>,
     #[structopt(long)]
     gpu: bool,
     #[structopt(long, default_value = "1")]
     workers: usize,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct MpnAccountOpt {
     #[structopt(long)]
-    address: Address,
+    address: MpnAddress,
+    /// Network profile whose nodes are asked
+    #[structopt(long, default_value = "pelmeni-9")]
+    network: String,
+    /// Node to ask, the first peer of the network by default
+    #[structopt(long)]
+    connect: Option<PeerAddress>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct TxHashOpt {
+    /// Kind of the transaction: `key-rotation`, `multi-transfer`, `swap-order` or `sweep`
+    #[structopt(long)]
+    kind: String,
+    /// JSON file of the transaction, whose `sig` may be left out
+    #[structopt(long)]
+    tx: PathBuf,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct DebugWorkOpt {
+    /// Session file recorded by `zoro prove --record`
+    #[structopt(long)]
+    session: PathBuf,
+    /// Only check the work with the given id
+    #[structopt(long)]
+    work_id: Option<usize>,
+    /// Network profile the session was recorded on
+    #[structopt(long, default_value = "pelmeni-9")]
+    network: String,
+    /// Dimensions of the circuits, those of the network by default
+    #[structopt(long)]
+    shape: Option<shapes::Shape>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct CircuitStatsOpt {
+    /// Dimensions of the circuits, e.g `mainnet` or `dev`
+    #[structopt(long, default_value = "mainnet")]
+    shape: shapes::Shape,
+    /// Only measure the given circuit, e.g `update`
+    #[structopt(long)]
+    circuit: Option<String>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+enum R1csOpt {
+    /// Write the constraints of a circuit to an iden3 .r1cs file
+    Export {
+        /// Dimensions of the circuit, e.g `mainnet` or `dev`
+        #[structopt(long, default_value = "mainnet")]
+        shape: shapes::Shape,
+        /// e.g `update`, `multi_fee_update`, `deposit` or `withdraw`
+        #[structopt(long)]
+        circuit: String,
+        #[structopt(long)]
+        output: PathBuf,
+    },
+    /// Write the witness of a work of a recorded session to an iden3 .wtns file
+    Witness {
+        /// Session file recorded by `zoro prove --record`
+        #[structopt(long)]
+        session: PathBuf,
+        #[structopt(long)]
+        work_id: usize,
+        /// Network profile the session was recorded on
+        #[structopt(long, default_value = "pelmeni-9")]
+        network: String,
+        /// Dimensions of the circuits, those of the network by default
+        #[structopt(long)]
+        shape: Option<shapes::Shape>,
+        #[structopt(long)]
+        output: PathBuf,
+        /// Also write the constraints of the circuit of the work
+        #[structopt(long)]
+        r1cs: Option<PathBuf>,
+    },
+    /// Check a .wtns witness against the constraints of a .r1cs file
+    Check {
+        #[structopt(long)]
+        r1cs: PathBuf,
+        #[structopt(long)]
+        wtns: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, StructOpt)]
+enum ConfigOpt {
+    /// Validate the config file of `zoro prove`
+    Check {
+        #[structopt(long)]
+        config: Option<PathBuf>,
+    },
 }
 
 #[derive(Debug, Clone, StructOpt)]
 #[structopt(name = "Zoro", about = "Ziesha's MPN Executor")]
 enum Opt {
     Prove(ProveOpt),
+    Config(ConfigOpt),
     GenerateParams(GenerateParamsOpt),
+    /// Print the VKs of the params of a shape, as the `verify_keys` of a network profile
+    VerifyKeys(GenerateParamsOpt),
+    Coordinator(CoordinatorOpt),
+    Worker(WorkerOpt),
+    /// Find the first unsatisfied constraint of the works of a recorded session
+    DebugWork(DebugWorkOpt),
+    /// Show an MPN account, as a node of the network sees it
+    MpnAccount(MpnAccountOpt),
+    /// Print the message to sign for a transaction of the circuits nodes don't issue works for yet
+    TxHash(TxHashOpt),
+    /// Count the constraints of the circuits, per namespace
+    CircuitStats(CircuitStatsOpt),
+    /// Export circuits and witnesses to the iden3 formats, and check them
+    R1cs(R1csOpt),
 }
 
-const MAXIMUM_PROVING_TIME: Duration = Duration::from_secs(50);
-
-fn load_params<C: Circuit<BellmanFr> + Default, R: Rng>(
+/// Loads the params of `circuit` (An empty instance of it) from `path`, or
+/// generates them when a `rng` is given.
+fn load_params<C: Circuit<BellmanFr>, R: Rng>(
+    circuit: C,
     path: PathBuf,
     rng: Option<R>,
 ) -> groth16::Parameters<Bls12> {
     if let Some(mut rng) = rng {
         println!("Generating {}...", path.to_string_lossy());
-        let c = C::default();
-
-        let p = groth16::generate_random_parameters::<Bls12, _, _>(c, &mut rng).unwrap();
+        let p = groth16::generate_random_parameters::<Bls12, _, _>(circuit, &mut rng).unwrap();
         let param_file = File::create(path.clone()).expect("Unable to create parameters file!");
         p.write(param_file)
             .expect("Unable to write parameters file!");
         log::info!("VK of {}: {}", path.to_string_lossy(), vk_to_hex(&p.vk));
         p
     } else {
-        println!("Loading {}...", path.to_string_lossy());
+        log::info!("Loading {}...", path.to_string_lossy());
         let param_file = File::open(path.clone()).expect("Unable to open parameters file!");
         let p = groth16::Parameters::<Bls12>::read(
             param_file, false, /* false for better performance*/
@@ -158,23 +429,167 @@ pub enum ZoroError {
     FromHexError(#[from] hex::FromHexError),
     #[error("kv-store error happened: {0}")]
     KvStoreError(#[from] bazuka::db::KvStoreError),
+    #[error("coordinator error: {0}")]
+    CoordinatorError(String),
+    #[error("configuration error: {0}")]
+    ConfigError(String),
+    #[error("none of the peers are available!")]
+    NoPeersAvailable,
+    #[error("peer {0} is ignored after failing repeatedly!")]
+    PeerUnavailable(String),
+    #[error("replayed error: {1}")]
+    ReplayedError(ErrorClass, String),
+    #[error("the replayed session is over!")]
+    ReplayFinished,
+    #[error("{0} is not in the replayed session!")]
+    ReplayMismatch(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// Part of the normal life of a prover, e.g someone else was faster
+    Expected,
+    /// Network/peer problems which may go away if we retry later
+    Transient,
+    /// Our proofs are wrong (E.g the params don't match the circuits). Retried
+    /// like transient errors, since the works may be the problem, but alerted.
+    Critical,
+    /// Problems with our own setup, retrying won't help
+    Fatal,
+}
+
+impl ZoroError {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            ZoroError::Aborted
+            | ZoroError::NotValidator
+            | ZoroError::BankError(bank::BankError::Cancelled) => ErrorClass::Expected,
+            ZoroError::BankError(bank::BankError::IncorrectProof) => ErrorClass::Critical,
+            ZoroError::ConfigError(_)
+            | ZoroError::ReplayFinished
+            | ZoroError::ReplayMismatch(_)
+            | ZoroError::BankError(bank::BankError::ShapeMismatch(_, _))
+            | ZoroError::BankError(bank::BankError::MissingParams(_)) => ErrorClass::Fatal,
+            ZoroError::IoError(_)
+            | ZoroError::NodeError(_)
+            | ZoroError::BankError(_)
+            | ZoroError::JoinError(_)
+            | ZoroError::HttpServerError(_)
+            | ZoroError::HttpError(_)
+            | ZoroError::BincodeError(_)
+            | ZoroError::JsonError(_)
+            | ZoroError::HttpTimeout(_)
+            | ZoroError::FromHexError(_)
+            | ZoroError::KvStoreError(_)
+            | ZoroError::CoordinatorError(_)
+            | ZoroError::NoPeersAvailable
+            | ZoroError::PeerUnavailable(_) => ErrorClass::Transient,
+            ZoroError::ReplayedError(class, _) => *class,
+        }
+    }
+}
+
+type ZoroWork = shapes::ShapedWork;
+
+/// The token the fees of an update batch are collected in. The work only
+/// commits to it through its aux-data (Hash of the token and the sum of the
+/// fees paid in it), so the fee tokens of the transactions and the one of the
+/// validator policy are tried against it. None if the batch is not a
+/// single-token one.
+fn update_fee_token(
+    updates: &[bazuka::mpn::UpdateTransition],
+    aux_data: bazuka::zk::ZkScalar,
+    policy: TokenId,
+) -> Option<TokenId> {
+    use bazuka::zk::ZkHasher;
+    let mut candidates = vec![policy];
+    for update in updates {
+        if !candidates.contains(&update.tx.fee.token_id) {
+            candidates.push(update.tx.fee.token_id);
+        }
+    }
+    candidates
+        .into_iter()
+        .find(|token| match fee_sum(updates, *token) {
+            Some(sum) => bazuka::core::ZkHasher::hash(&[(*token).into(), sum.into()]) == aux_data,
+            None => false,
+        })
 }
 
-type ZoroWork = bank::ZoroWork<
-    { config::LOG4_DEPOSIT_BATCH_SIZE },
-    { config::LOG4_WITHDRAW_BATCH_SIZE },
-    { config::LOG4_UPDATE_BATCH_SIZE },
-    { config::LOG4_TREE_SIZE },
-    { config::LOG4_TOKENS_TREE_SIZE },
->;
+/// Sum of the fees paid in the given token, None if it overflows (No valid
+/// batch can commit to it then).
+fn fee_sum(updates: &[bazuka::mpn::UpdateTransition], token: TokenId) -> Option<u64> {
+    updates
+        .iter()
+        .filter(|u| u.tx.fee.token_id == token)
+        .try_fold(0u64, |sum, u| {
+            sum.checked_add(Into::<u64>::into(u.tx.fee.amount))
+        })
+}
 
-fn to_zoro_work(address: Address, work: MpnWork) -> ZoroWork {
+/// Fee slots of a multi-fee update batch: The distinct fee tokens of the
+/// transactions in the order they first appear, padded with Ziesha. Returned
+/// only when the aux-data of the work commits to them.
+fn multi_fee_tokens(
+    updates: &[bazuka::mpn::UpdateTransition],
+    aux_data: bazuka::zk::ZkScalar,
+) -> Option<[TokenId; circuits::FEE_SLOTS]> {
+    use bazuka::zk::ZkHasher;
+    let mut tokens = Vec::new();
+    for update in updates {
+        if !tokens.contains(&update.tx.fee.token_id) {
+            tokens.push(update.tx.fee.token_id);
+        }
+    }
+    if tokens.len() > circuits::FEE_SLOTS {
+        return None;
+    }
+    tokens.resize(circuits::FEE_SLOTS, TokenId::Ziesha);
+    let mut preimage = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        // Only the first slot of a token collects its fees
+        let fee_sum = if tokens[..i].contains(token) {
+            0
+        } else {
+            fee_sum(updates, *token)?
+        };
+        preimage.push((*token).into());
+        preimage.push(fee_sum.into());
+    }
+    (bazuka::core::ZkHasher::hash(&preimage) == aux_data).then(|| tokens.try_into().unwrap())
+}
+
+/// Commitment to the prover of a work and its reward, so that the proof can't
+/// be claimed by someone else.
+fn work_commitment(address: &Address, reward: Money) -> bazuka::zk::ZkScalar {
     use bazuka::core::hash::Hash;
-    let commitment = bazuka::zk::ZkScalar::new(
-        bazuka::core::Hasher::hash(&bincode::serialize(&(address.clone(), work.reward)).unwrap())
+    bazuka::zk::ZkScalar::new(
+        bazuka::core::Hasher::hash(&bincode::serialize(&(address.clone(), reward)).unwrap())
             .as_ref(),
-    );
-    ZoroWork {
+    )
+}
+
+fn to_zoro_work<
+    const LOG4_DEPOSIT_BATCH_SIZE: u8,
+    const LOG4_WITHDRAW_BATCH_SIZE: u8,
+    const LOG4_UPDATE_BATCH_SIZE: u8,
+    const LOG4_TREE_SIZE: u8,
+    const LOG4_TOKENS_TREE_SIZE: u8,
+>(
+    address: Address,
+    work: MpnWork,
+    fee_policy: TokenId,
+    strict: bool,
+) -> bank::ZoroWork<
+    LOG4_DEPOSIT_BATCH_SIZE,
+    LOG4_WITHDRAW_BATCH_SIZE,
+    LOG4_UPDATE_BATCH_SIZE,
+    LOG4_TREE_SIZE,
+    LOG4_TOKENS_TREE_SIZE,
+> {
+    let commitment = work_commitment(&address, work.reward);
+    bank::ZoroWork {
         commitment,
         height: work.public_inputs.height.into(),
         state: work.public_inputs.state,
@@ -182,7 +597,7 @@ fn to_zoro_work(address: Address, work: MpnWork) -> ZoroWork {
         next_state: work.public_inputs.next_state,
         circuit: match &work.data {
             MpnWorkData::Deposit(deposits) => {
-                println!("{} deposits", deposits.len());
+                log::info!("{} deposits", deposits.len());
                 bank::ZoroCircuit::Deposit(circuits::DepositCircuit {
                     commitment,
                     height: work.public_inputs.height.into(),
@@ -190,10 +605,11 @@ fn to_zoro_work(address: Address, work: MpnWork) -> ZoroWork {
                     aux_data: work.public_inputs.aux_data,
                     next_state: work.public_inputs.next_state,
                     transitions: Box::new(circuits::DepositTransitionBatch::new(deposits.clone())),
+                    strict,
                 })
             }
             MpnWorkData::Withdraw(withdraws) => {
-                println!("{} withdraws", withdraws.len());
+                log::info!("{} withdraws", withdraws.len());
                 bank::ZoroCircuit::Withdraw(circuits::WithdrawCircuit {
                     commitment,
                     height: work.public_inputs.height.into(),
@@ -203,24 +619,268 @@ fn to_zoro_work(address: Address, work: MpnWork) -> ZoroWork {
                     transitions: Box::new(circuits::WithdrawTransitionBatch::new(
                         withdraws.clone(),
                     )),
+                    strict,
                 })
             }
             MpnWorkData::Update(updates) => {
-                println!("{} updates", updates.len());
-                bank::ZoroCircuit::Update(circuits::UpdateCircuit {
-                    commitment,
-                    height: work.public_inputs.height.into(),
-                    state: work.public_inputs.state,
-                    aux_data: work.public_inputs.aux_data,
-                    next_state: work.public_inputs.next_state,
-                    fee_token: TokenId::Ziesha,
-                    transitions: Box::new(circuits::TransitionBatch::new(updates.clone())),
-                })
+                let aux_data = work.public_inputs.aux_data;
+                match update_fee_token(updates, aux_data, fee_policy) {
+                    Some(fee_token) => {
+                        log::info!("{} updates (Fees in {})", updates.len(), fee_token);
+                        bank::ZoroCircuit::Update(circuits::UpdateCircuit {
+                            commitment,
+                            height: work.public_inputs.height.into(),
+                            state: work.public_inputs.state,
+                            aux_data,
+                            next_state: work.public_inputs.next_state,
+                            fee_token,
+                            transitions: Box::new(circuits::TransitionBatch::new(updates.clone())),
+                            strict,
+                        })
+                    }
+                    None => match multi_fee_tokens(updates, aux_data) {
+                        Some(fee_tokens) => {
+                            log::info!(
+                                "{} updates (Fees in {})",
+                                updates.len(),
+                                fee_tokens
+                                    .iter()
+                                    .map(|token| token.to_string())
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            );
+                            bank::ZoroCircuit::MultiFeeUpdate(circuits::MultiFeeUpdateCircuit {
+                                commitment,
+                                height: work.public_inputs.height.into(),
+                                state: work.public_inputs.state,
+                                aux_data,
+                                next_state: work.public_inputs.next_state,
+                                fee_tokens,
+                                transitions: Box::new(circuits::TransitionBatch::new(
+                                    updates.clone(),
+                                )),
+                                strict,
+                            })
+                        }
+                        None => {
+                            log::warn!(
+                                "The fee token of the update work is unknown, assuming {}!",
+                                fee_policy
+                            );
+                            bank::ZoroCircuit::Update(circuits::UpdateCircuit {
+                                commitment,
+                                height: work.public_inputs.height.into(),
+                                state: work.public_inputs.state,
+                                aux_data,
+                                next_state: work.public_inputs.next_state,
+                                fee_token: fee_policy,
+                                transitions: Box::new(circuits::TransitionBatch::new(
+                                    updates.clone(),
+                                )),
+                                strict,
+                            })
+                        }
+                    },
+                }
             }
         },
     }
 }
 
+fn load_zoro_params(
+    shape: shapes::Shape,
+    paths: &shapes::ParamPaths,
+    verify_keys: Option<&bank::ZoroVerifyKeys>,
+) -> Result<bank::ZoroParams, ZoroError> {
+    let params = shape.load_params(paths, None::<ChaCha20Rng>);
+    let verif_keys = match verify_keys {
+        Some(verif_keys) => verif_keys.clone(),
+        None => {
+            log::warn!(
+                "Verifying keys of the network are unknown, make sure the validators use the same params!"
+            );
+            return Ok(params);
+        }
+    };
+    let loaded = params.verify_keys();
+    for (circuit, expected, loaded) in [
+        ("deposit", verif_keys.deposit, loaded.deposit),
+        ("withdraw", verif_keys.withdraw, loaded.withdraw),
+        ("update", verif_keys.update, loaded.update),
+    ] {
+        if expected != loaded {
+            return Err(ZoroError::ConfigError(format!(
+                "incorrect {}-params! Regenerate params via: zoro generate-params --shape {}",
+                circuit, shape
+            )));
+        }
+    }
+    match (verif_keys.multi_fee_update, loaded.multi_fee_update) {
+        (Some(expected), Some(loaded)) if expected != loaded => {
+            return Err(ZoroError::ConfigError(format!(
+                "incorrect multi-fee-update-params! Regenerate params via: zoro generate-params --shape {}",
+                shape
+            )));
+        }
+        (Some(_), None) => log::warn!(
+            "Multi-fee-update params are not loaded, multi-fee update works cannot be proven!"
+        ),
+        (None, Some(_)) => log::warn!(
+            "The network has no multi-fee-update VK, make sure its validators use the same multi-fee-update params!"
+        ),
+        _ => {}
+    }
+    Ok(params)
+}
+
+fn gpu_opt_params(tuning: Option<&Optimization>) -> bellman::gpu::OptParams {
+    match tuning {
+        Some(tuning) => tuning.clone().into(),
+        None => bellman::gpu::OptParams {
+            n_g1: 32 * 1024 * 1024,
+            window_size_g1: 10,
+            groups_g1: 807,
+            n_g2: 16 * 1024 * 1024,
+            window_size_g2: 9,
+            groups_g2: 723,
+        },
+    }
+}
+
+fn create_backend(
+    gpu: bool,
+    devices: Option<&[usize]>,
+    tuning: Option<&Optimization>,
+) -> Result<Backend, ZoroError> {
+    if gpu {
+        let available = Device::by_brand(Brand::Nvidia).unwrap_or_default();
+        if available.is_empty() {
+            return Err(ZoroError::ConfigError("no Nvidia GPUs found!".into()));
+        }
+        if let Some(missing) = devices
+            .unwrap_or_default()
+            .iter()
+            .find(|d| **d >= available.len())
+        {
+            return Err(ZoroError::ConfigError(format!(
+                "GPU device {} not found, there are only {} devices!",
+                missing,
+                available.len()
+            )));
+        }
+        Ok(Backend::Gpu(Arc::new(Mutex::new(
+            available
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| devices.map(|devs| devs.contains(i)).unwrap_or(true))
+                .map(|(_, d)| (d, gpu_opt_params(tuning)))
+                .collect(),
+        ))))
+    } else {
+        Ok(Backend::Cpu)
+    }
+}
+
+/// Splits the backend into a pipeline slot per GPU, so that each of them
+/// proves works of its own.
+fn create_slots(
+    gpu: bool,
+    devices: Option<&[usize]>,
+    tuning: Option<&Optimization>,
+) -> Result<Vec<pipeline::Slot>, ZoroError> {
+    let backend = create_backend(gpu, devices, tuning)?;
+    let gpus = match &backend {
+        Backend::Gpu(gpus) => std::mem::take(&mut *gpus.lock().unwrap()),
+        _ => {
+            return Ok(vec![pipeline::Slot {
+                name: "CPU".into(),
+                backend,
+            }])
+        }
+    };
+    // The selected devices are kept in the order they were found
+    let indices = match devices {
+        Some(devices) => {
+            let mut devices = devices.to_vec();
+            devices.sort_unstable();
+            devices.dedup();
+            devices
+        }
+        None => (0..gpus.len()).collect(),
+    };
+    Ok(indices
+        .into_iter()
+        .zip(gpus)
+        .map(|(index, gpu)| pipeline::Slot {
+            name: format!("GPU {}", index),
+            backend: Backend::Gpu(Arc::new(Mutex::new(vec![gpu]))),
+        })
+        .collect())
+}
+
+fn load_prove_config(opt: &ProveOpt) -> Result<settings::ProveConfig, settings::SettingsError> {
+    let settings = settings::Settings::load(opt.config.as_deref())?;
+    settings::ProveConfig::resolve(opt, settings)
+}
+
+/// Profile of a network, for the modes which don't read the config file
+/// (Custom networks are still taken from it).
+fn find_profile(network: &str) -> profiles::NetworkProfile {
+    settings::Settings::load(None)
+        .and_then(|settings| profiles::find(network, &settings.networks))
+        .unwrap_or_else(|e| config_error(e))
+}
+
+fn write_file<F: FnOnce(&mut std::io::BufWriter<File>) -> std::io::Result<()>>(
+    path: &std::path::Path,
+    f: F,
+) -> Result<(), ZoroError> {
+    use std::io::Write;
+    let mut out = std::io::BufWriter::new(File::create(path)?);
+    f(&mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Connects to the nodes of the network, to a mock validator, or to those of
+/// a recorded session, as configured.
+fn connect(conf: &settings::ProveConfig, verify_keys: bank::ZoroVerifyKeys) -> client::Connector {
+    let session = match (&conf.record, &conf.replay) {
+        (Some(path), _) => Some(session::record(path).unwrap_or_else(|e| fatal_error(e))),
+        (None, Some(path)) => {
+            println!("{}", "Replaying a recorded session!".bright_yellow());
+            Some(session::replay(path).unwrap_or_else(|e| fatal_error(e)))
+        }
+        (None, None) => None,
+    };
+    let transport = match &conf.mock {
+        Some(faults) => {
+            println!(
+                "{}",
+                "Proving the works of a mock validator!".bright_yellow()
+            );
+            client::Transport::Mock(Arc::new(mock::MockNode::new(
+                faults.clone(),
+                conf.shape,
+                conf.profile.fee_token,
+                verify_keys,
+            )))
+        }
+        None => client::Transport::Network,
+    };
+    client::Connector::new(&conf.network, transport, session)
+}
+
+fn config_error(e: settings::SettingsError) -> ! {
+    println!("{} {}", "Configuration error:".bright_red(), e);
+    std::process::exit(1);
+}
+
+fn fatal_error(e: ZoroError) -> ! {
+    println!("{} {}", "Fatal error:".bright_red(), e);
+    std::process::exit(1);
+}
+
 fn alice_shuffle() {
     println!(
         "{} {} {}",
@@ -230,241 +890,604 @@ fn alice_shuffle() {
     );
 }
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
-    println!(
-        "{} v{} - A CPU/GPU-based MPN Executor for Ziesha Cryptocurrency",
-        "Zoro!".bright_green(),
-        env!("CARGO_PKG_VERSION")
-    );
+fn main() {
     let opt = Opt::from_args();
 
+    let tui = matches!(&opt, Opt::Prove(prove_opt) if prove_opt.tui);
+    // The logger of `zoro prove` (And `zoro coordinator`) is configured by the
+    // config file too
+    let prove_conf = match &opt {
+        Opt::Prove(prove_opt)
+        | Opt::Coordinator(CoordinatorOpt {
+            prove: prove_opt, ..
+        }) => Some(load_prove_config(prove_opt).unwrap_or_else(|e| config_error(e))),
+        _ => None,
+    };
+    let log_format = match &prove_conf {
+        Some(conf) => {
+            events::init_logger(&conf.log_level, conf.log_format);
+            conf.log_format
+        }
+        None => {
+            env_logger::init();
+            events::LogFormat::Text
+        }
+    };
+    match log_format {
+        _ if tui => {}
+        events::LogFormat::Text => {
+            println!(
+                "{} v{} - A CPU/GPU-based MPN Executor for Ziesha Cryptocurrency",
+                "Zoro!".bright_green(),
+                env!("CARGO_PKG_VERSION")
+            );
+            events::subscribe(Arc::new(events::TextSink));
+        }
+        events::LogFormat::Json => events::subscribe(Arc::new(events::JsonSink)),
+    }
+
+    // Built before the runtime starts, so that its threads are restricted to
+    // the cpu-set too
+    let pools = prove_conf
+        .as_ref()
+        .filter(|_| matches!(opt, Opt::Prove(_)))
+        .map(|conf| {
+            let (cores, budget) = pools::plan_threads(
+                conf.gpu,
+                conf.synthesis_threads,
+                conf.msm_threads,
+                conf.cpu_set.clone(),
+            );
+            // Bellman reads its thread count from the environment the first
+            // time a multiexp is done. Changing the environment races with the
+            // threads reading it, so it's set while we are still alone.
+            std::env::set_var("BELLMAN_NUM_CPUS", budget.msm.to_string());
+            pools::ProverPools::new(&cores, budget, conf.pin_threads)
+        });
+
+    tokio::runtime::Runtime::new()
+        .expect("cannot start the async runtime")
+        .block_on(run(opt, prove_conf, pools, tui));
+}
+
+async fn run(
+    opt: Opt,
+    prove_conf: Option<settings::ProveConfig>,
+    pools: Option<pools::ProverPools>,
+    tui: bool,
+) {
     match opt {
         Opt::GenerateParams(opt) => {
             let rng = Some(ChaCha20Rng::seed_from_u64(123456));
-
-            load_params::<
-                circuits::DepositCircuit<
-                    { config::LOG4_DEPOSIT_BATCH_SIZE },
-                    { config::LOG4_TREE_SIZE },
-                    { config::LOG4_TOKENS_TREE_SIZE },
-                >,
-                _,
-            >(opt.deposit_circuit_params, rng.clone());
-
-            load_params::<
-                circuits::WithdrawCircuit<
-                    { config::LOG4_WITHDRAW_BATCH_SIZE },
-                    { config::LOG4_TREE_SIZE },
-                    { config::LOG4_TOKENS_TREE_SIZE },
-                >,
-                _,
-            >(opt.withdraw_circuit_params, rng.clone());
-
-            load_params::<
-                circuits::UpdateCircuit<
-                    { config::LOG4_UPDATE_BATCH_SIZE },
-                    { config::LOG4_TREE_SIZE },
-                    { config::LOG4_TOKENS_TREE_SIZE },
-                >,
-                _,
-            >(opt.update_circuit_params, rng.clone());
+            opt.shape.load_params(&opt.param_paths(), rng);
         }
 
-        Opt::Prove(opt) => {
-            let verif_keys = bank::ZoroVerifyKeys {
-                update: bazuka::config::blockchain::MPN_UPDATE_VK.clone(),
-                deposit: bazuka::config::blockchain::MPN_DEPOSIT_VK.clone(),
-                withdraw: bazuka::config::blockchain::MPN_WITHDRAW_VK.clone(),
-            };
+        Opt::VerifyKeys(opt) => {
+            let params = opt
+                .shape
+                .load_params(&opt.param_paths(), None::<ChaCha20Rng>);
+            // Lines of a `[networks.NAME]` section of the config file
+            let mut keys = vec![
+                ("update", &params.update),
+                ("deposit", &params.deposit),
+                ("withdraw", &params.withdraw),
+            ];
+            if let Some(p) = &params.multi_fee_update {
+                keys.push(("multi_fee_update", p));
+            }
+            println!("shape = \"{}\"", opt.shape);
+            println!(
+                "verify_keys = {{ {} }}",
+                keys.iter()
+                    .map(|(name, p)| format!("{} = \"{}\"", name, vk_to_hex(&p.vk)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
 
-            let deposit_params =
-                load_params::<
-                    circuits::DepositCircuit<
-                        { config::LOG4_DEPOSIT_BATCH_SIZE },
-                        { config::LOG4_TREE_SIZE },
-                        { config::LOG4_TOKENS_TREE_SIZE },
-                    >,
-                    _,
-                >(opt.deposit_circuit_params.clone(), None::<ChaCha20Rng>);
-            if Into::<bazuka::zk::groth16::Groth16VerifyingKey>::into(deposit_params.vk.clone())
-                != verif_keys.deposit
-            {
-                panic!("Incorrect deposit-params! Regenerate params via: zoro generate-params");
+        Opt::Config(ConfigOpt::Check { config }) => {
+            let prove_opt = ProveOpt::from_iter(["zoro"]);
+            let settings =
+                settings::Settings::load(config.as_deref()).unwrap_or_else(|e| config_error(e));
+            let conf = settings::ProveConfig::resolve(&prove_opt, settings)
+                .unwrap_or_else(|e| config_error(e));
+            if let Err(e) = conf.check() {
+                config_error(e);
             }
+            println!("{} {:#?}", "Configuration is valid:".bright_green(), conf);
+        }
 
-            let withdraw_params =
-                load_params::<
-                    circuits::WithdrawCircuit<
-                        { config::LOG4_WITHDRAW_BATCH_SIZE },
-                        { config::LOG4_TREE_SIZE },
-                        { config::LOG4_TOKENS_TREE_SIZE },
-                    >,
-                    _,
-                >(opt.withdraw_circuit_params.clone(), None::<ChaCha20Rng>);
-            if Into::<bazuka::zk::groth16::Groth16VerifyingKey>::into(withdraw_params.vk.clone())
-                != verif_keys.withdraw
-            {
-                panic!("Incorrect withdraw-params! Regenerate params via: zoro generate-params");
+        Opt::Prove(_) => {
+            let opt = prove_conf.expect("resolved before the logger is started");
+            if let Err(e) = opt.check() {
+                config_error(e);
             }
+            let zoro_params = load_zoro_params(
+                opt.shape,
+                &shapes::ParamPaths {
+                    update: opt.update_circuit_params.clone(),
+                    deposit: opt.deposit_circuit_params.clone(),
+                    withdraw: opt.withdraw_circuit_params.clone(),
+                    multi_fee_update: opt.multi_fee_update_circuit_params.clone(),
+                },
+                // The keys of the network are meaningless for another shape
+                opt.profile
+                    .verify_keys
+                    .as_ref()
+                    .filter(|_| opt.shape == opt.profile.shape),
+            )
+            .unwrap_or_else(|e| fatal_error(e));
+            let slots = create_slots(opt.gpu, opt.gpu_devices.as_deref(), opt.gpu_tuning.as_ref())
+                .unwrap_or_else(|e| fatal_error(e));
+            let pools = pools.expect("built before the runtime is started");
 
-            let update_params =
-                load_params::<
-                    circuits::UpdateCircuit<
-                        { config::LOG4_UPDATE_BATCH_SIZE },
-                        { config::LOG4_TREE_SIZE },
-                        { config::LOG4_TOKENS_TREE_SIZE },
-                    >,
-                    _,
-                >(opt.update_circuit_params.clone(), None::<ChaCha20Rng>);
-            if Into::<bazuka::zk::groth16::Groth16VerifyingKey>::into(update_params.vk.clone())
-                != verif_keys.update
-            {
-                panic!("Incorrect update-params! Regenerate params via: zoro generate-params");
+            let connector = connect(&opt, zoro_params.verify_keys());
+            if !opt.notify.webhooks.is_empty() {
+                events::subscribe(Arc::new(notify::Notifier::new(opt.notify.clone())));
+            }
+            if tui {
+                let dashboard = Arc::new(dashboard::Dashboard::new(
+                    slots.iter().map(|slot| slot.name.clone()).collect(),
+                ));
+                events::subscribe(dashboard.clone());
+                dashboard.start();
             }
 
-            let zoro_params = bank::ZoroParams {
-                update: update_params.clone(),
-                deposit: deposit_params.clone(),
-                withdraw: withdraw_params.clone(),
+            let workers = opt.workers;
+            let local = prover::LocalProver {
+                params: zoro_params,
+                slots,
+                pools,
             };
+            let prover = Arc::new(prover::Prover::new(
+                opt,
+                connector,
+                prover::Proving::Local(Arc::new(local)),
+            ));
+            let workers: Vec<_> = (0..workers)
+                .map(|worker| prover.clone().run_worker(worker))
+                .collect();
+            futures::future::join_all(workers).await;
+        }
 
-            let backend = if opt.gpu {
-                Backend::Gpu(Arc::new(Mutex::new(
-                    Device::by_brand(Brand::Nvidia)
-                        .unwrap()
-                        .into_iter()
-                        .map(|d| {
-                            (
-                                d,
-                                bellman::gpu::OptParams {
-                                    n_g1: 32 * 1024 * 1024,
-                                    window_size_g1: 10,
-                                    groups_g1: 807,
-                                    n_g2: 16 * 1024 * 1024,
-                                    window_size_g2: 9,
-                                    groups_g2: 723,
-                                },
-                            )
-                        })
-                        .collect(),
-                )))
-            } else {
-                Backend::Cpu
+        Opt::Coordinator(opt) => {
+            let conf = prove_conf.expect("resolved before the logger is started");
+            let verify_keys = match conf
+                .profile
+                .verify_keys
+                .clone()
+                .filter(|_| conf.shape == conf.profile.shape)
+            {
+                Some(verify_keys) => verify_keys,
+                // Proofs are checked against our own params then
+                None => load_zoro_params(
+                    conf.shape,
+                    &shapes::ParamPaths {
+                        update: conf.update_circuit_params.clone(),
+                        deposit: conf.deposit_circuit_params.clone(),
+                        withdraw: conf.withdraw_circuit_params.clone(),
+                        multi_fee_update: conf.multi_fee_update_circuit_params.clone(),
+                    },
+                    None,
+                )
+                .unwrap_or_else(|e| fatal_error(e))
+                .verify_keys(),
             };
+            let connector = connect(&conf, verify_keys.clone());
+            if !conf.notify.webhooks.is_empty() {
+                events::subscribe(Arc::new(notify::Notifier::new(conf.notify.clone())));
+            }
+            if let Err(e) = coordinator::run_coordinator(opt, conf, connector, verify_keys).await {
+                fatal_error(e);
+            }
+        }
 
-            let new_worker = || async {
-                let backend = backend.clone();
-                let zoro_params = zoro_params.clone();
-                let opt = opt.clone();
-                loop {
-                    let backend = backend.clone();
-                    let zoro_params = zoro_params.clone();
-                    let opt = opt.clone();
-                    if let Err(e) = async move {
-                        let backend = backend.clone();
-                        let zoro_params = zoro_params.clone();
-                        let opt = opt.clone();
-                        let cancel = Arc::new(RwLock::new(false));
-
-                        println!("Finding the validator...");
-                        let client = SyncClient::new(opt.connect, &opt.network, Duration::from_secs(2));
-                        let validator_claim = client.validator_claim().await?;
-
-                        if let Some(claim) = validator_claim.clone() {
-                            println!("{} is validator!", claim.node);
-                            let client = SyncClient::new(claim.node, &opt.network,Duration::from_secs(5));
-
-                            let works = client.get_mpn_works(opt.address.clone()).await?;
-
-                            let (cancel_controller_tx, mut cancel_controller_rx) =
-                                tokio::sync::mpsc::unbounded_channel::<()>();
-                            let cancel_cloned = cancel.clone();
-                            let cancel_controller =
-                                tokio::task::spawn(async move {
-                                    loop {
-                                        match cancel_controller_rx.try_recv() {
-                                    Ok(_)
-                                    | Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
-                                        break;
-                                    }
-                                    Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {
-                                        let client = SyncClient::new(opt.connect, &opt.network,Duration::from_secs(1));
-                                        if let Ok(new_claim) = client.validator_claim().await {
-                                            if new_claim != validator_claim {
-                                                println!("Validator changed!");
-                                                *cancel_cloned.write().unwrap() = true;
-                                            }
-                                        }
-                                    }
-                                }
-                                        std::thread::sleep(std::time::Duration::from_millis(3000));
-                                    }
-                                    Ok::<(), ZoroError>(())
-                                });
-                                if !works.works.is_empty() {
-                                println!("Got {} SNARK-works to solve...", works.works.len());
-                                alice_shuffle();
-                                let start = std::time::Instant::now();
-                                let pool = rayon::ThreadPoolBuilder::new()
-                                    .num_threads(32)
-                                    .build()
-                                    .unwrap();
-                                let prover_address = opt.address.clone();
-                                let proofs = tokio::task::spawn_blocking(move || {
-                                    pool.install(|| -> Result<
-                                    HashMap<usize, bazuka::zk::groth16::Groth16Proof>,
-                                    bank::BankError,
-                                > {
-                                    works
-                                        .works
-                                        .into_par_iter()
-                                        .map(|(id, p)| {
-                                            to_zoro_work(prover_address.clone(), p).prove(
-                                                zoro_params.clone(),
-                                                backend.clone(),
-                                                Some(cancel.clone()),
-                                            )
-                                            .map(|p| (id, p))
-                                        })
-                                        .collect()
-                                })
-                                })
-                                .await??;
-                                println!(
-                                    "{} {}ms",
-                                    "Proving took:".bright_green(),
-                                    start.elapsed().as_millis()
-                                );
-                                if start.elapsed() > MAXIMUM_PROVING_TIME {
-                                    println!("{} {}", "WARNING:".bright_red(), "Your proving time is too high! You will most probably not win any rewards with this latency.");
-                                }
-
-                                let resp = client.post_mpn_solution(opt.address.clone(), proofs.into_iter().map(|(id,proof)| {
-                                    (id, bazuka::zk::ZkProof::Groth16(Box::new(proof)))
-                                }).collect()).await?;
-                                println!("{} of your proofs were accepted!", resp.accepted);
-
-                                let _ = cancel_controller_tx.send(());
-                                cancel_controller.await??;
-                            }
-                            else {
-                                println!("No work to do!");
-                            }
-                        }
-                        std::thread::sleep(std::time::Duration::from_millis(1000));
-                        Ok::<(), ZoroError>(())
+        Opt::DebugWork(opt) => {
+            let profile = find_profile(&opt.network);
+            let shape = opt.shape.unwrap_or(profile.shape);
+            let works = session::recorded_works(&opt.session).unwrap_or_else(|e| fatal_error(e));
+            let mut found = false;
+            for (seq, address, id, work) in works {
+                if opt.work_id.map(|w| w != id).unwrap_or(false) {
+                    continue;
+                }
+                found = true;
+                let work = shape.work(address, work, profile.fee_token);
+                print!("Work {} of exchange #{} ({}): ", id, seq, work.describe());
+                match work.debug() {
+                    Ok(None) => println!("{}", "All constraints satisfied".bright_green()),
+                    Ok(Some(unsatisfied)) => {
+                        println!("{} {}", "Unsatisfied".bright_red(), unsatisfied)
                     }
-                    .await
-                    {
-                        println!("Error while proving: {}", e);
-                        std::thread::sleep(std::time::Duration::from_millis(1000));
+                    Err(e) => println!("{} {}", "Synthesis failed:".bright_red(), e),
+                }
+            }
+            if !found {
+                println!("{}", "No works found in the session!".bright_yellow());
+            }
+        }
+
+        Opt::CircuitStats(opt) => {
+            let circuits = match &opt.circuit {
+                Some(circuit) => vec![circuit.as_str()],
+                None => shapes::Shape::CIRCUITS.to_vec(),
+            };
+            for name in circuits {
+                let stats = opt
+                    .shape
+                    .circuit_stats(name)
+                    .unwrap_or_else(|e| fatal_error(bank::BankError::from(e).into()))
+                    .unwrap_or_else(|| {
+                        fatal_error(ZoroError::ConfigError(format!(
+                            "unknown circuit `{}`, the circuits are: {}",
+                            name,
+                            shapes::Shape::CIRCUITS.join(", ")
+                        )))
+                    });
+                println!(
+                    "{} ({}): {} constraints, {} variables, {} public inputs",
+                    name.bright_green(),
+                    opt.shape,
+                    stats.num_constraints,
+                    stats.num_variables,
+                    stats.num_inputs
+                );
+                for group in stats.groups() {
+                    if group.instances > 1 {
+                        println!(
+                            "  {} (x{}): {} constraints, {} each",
+                            group.name.bright_cyan(),
+                            group.instances,
+                            group.num_constraints,
+                            group.num_constraints / group.instances
+                        );
+                    } else {
+                        println!(
+                            "  {}: {} constraints",
+                            group.name.bright_cyan(),
+                            group.num_constraints
+                        );
+                    }
+                    let mut checks = group.checks.into_iter().collect::<Vec<_>>();
+                    checks.sort_by(|a, b| b.1.cmp(&a.1));
+                    for (check, count) in checks {
+                        println!("    {}: {}", check, count);
                     }
                 }
+            }
+        }
+
+        Opt::R1cs(R1csOpt::Export {
+            shape,
+            circuit,
+            output,
+        }) => {
+            let synthesized = shape
+                .synthesize_circuit(&circuit)
+                .unwrap_or_else(|e| fatal_error(bank::BankError::from(e).into()))
+                .unwrap_or_else(|| {
+                    fatal_error(ZoroError::ConfigError(format!(
+                        "unknown circuit `{}`",
+                        circuit
+                    )))
+                });
+            write_file(&output, |out| r1cs::write_r1cs(&synthesized, out))
+                .unwrap_or_else(|e| fatal_error(e));
+            println!(
+                "{} {} constraints written to {}",
+                "Done!".bright_green(),
+                synthesized.num_constraints(),
+                output.to_string_lossy()
+            );
+        }
+
+        Opt::R1cs(R1csOpt::Witness {
+            session,
+            work_id,
+            network,
+            shape,
+            output,
+            r1cs,
+        }) => {
+            let profile = find_profile(&network);
+            let shape = shape.unwrap_or(profile.shape);
+            let (_, address, _, work) = session::recorded_works(&session)
+                .unwrap_or_else(|e| fatal_error(e))
+                .into_iter()
+                .find(|(_, _, id, _)| *id == work_id)
+                .unwrap_or_else(|| {
+                    fatal_error(ZoroError::ConfigError(format!(
+                        "work {} is not in the session",
+                        work_id
+                    )))
+                });
+            let work = shape.work(address, work, profile.fee_token);
+            let synthesized = work.synthesize().unwrap_or_else(|e| fatal_error(e.into()));
+            write_file(&output, |out| r1cs::write_wtns(&synthesized, out))
+                .unwrap_or_else(|e| fatal_error(e));
+            if let Some(path) = &r1cs {
+                write_file(path, |out| r1cs::write_r1cs(&synthesized, out))
+                    .unwrap_or_else(|e| fatal_error(e));
+            }
+            println!(
+                "{} Witness of the {} work written to {}",
+                "Done!".bright_green(),
+                work.describe(),
+                output.to_string_lossy()
+            );
+        }
+
+        Opt::R1cs(R1csOpt::Check { r1cs, wtns }) => {
+            let check = || -> Result<Option<usize>, ZoroError> {
+                let constraints = r1cs::read_r1cs(std::io::BufReader::new(File::open(&r1cs)?))?;
+                let witness = r1cs::read_wtns(std::io::BufReader::new(File::open(&wtns)?))?;
+                println!(
+                    "{} constraints, {} wires, {} public inputs",
+                    constraints.constraints.len(),
+                    constraints.num_wires,
+                    constraints.num_public_inputs
+                );
+                Ok(constraints.first_unsatisfied(&witness)?)
+            };
+            match check().unwrap_or_else(|e| fatal_error(e)) {
+                None => println!("{}", "All constraints satisfied".bright_green()),
+                Some(index) => {
+                    println!("{} constraint #{}", "Unsatisfied".bright_red(), index);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Opt::MpnAccount(opt) => {
+            let profile = find_profile(&opt.network);
+            let peer = match opt.connect.or_else(|| profile.peers.first().cloned()) {
+                Some(peer) => peer,
+                None => config_error(settings::SettingsError::Missing("connect")),
             };
-            let workers: Vec<_> = (0..opt.workers).map(|_| new_worker()).collect();
+            let connector =
+                client::Connector::new(&profile.network, client::Transport::Network, None);
+            let resp = connector
+                .connect(peer, std::time::Duration::from_secs(5))
+                .get_mpn_account(opt.address.clone())
+                .await
+                .unwrap_or_else(|e| fatal_error(e));
+            println!("{}", serde_json::to_string_pretty(&resp.account).unwrap());
+        }
+
+        Opt::TxHash(opt) => {
+            let tx = std::fs::read(&opt.tx).unwrap_or_else(|e| fatal_error(e.into()));
+            let hash = match opt.kind.as_str() {
+                "key-rotation" => {
+                    serde_json::from_slice::<circuits::KeyRotation>(&tx).map(|tx| tx.hash())
+                }
+                "multi-transfer" => {
+                    serde_json::from_slice::<circuits::MultiTransfer>(&tx).map(|tx| tx.hash())
+                }
+                "swap-order" => {
+                    serde_json::from_slice::<circuits::SwapOrder>(&tx).map(|tx| tx.hash())
+                }
+                "sweep" => serde_json::from_slice::<circuits::Sweep>(&tx).map(|tx| tx.hash()),
+                kind => config_error(settings::SettingsError::Invalid("kind", kind.into())),
+            }
+            .unwrap_or_else(|e| fatal_error(e.into()));
+            println!("{}", serde_json::to_string(&hash).unwrap());
+        }
+
+        Opt::Worker(opt) => {
+            let profile = find_profile(&opt.network);
+            let shape = opt.shape.unwrap_or(profile.shape);
+            let defaults = profile.param_paths(shape);
+            let zoro_params = load_zoro_params(
+                shape,
+                &shapes::ParamPaths {
+                    update: opt.update_circuit_params.clone().unwrap_or(defaults.update),
+                    deposit: opt
+                        .deposit_circuit_params
+                        .clone()
+                        .unwrap_or(defaults.deposit),
+                    withdraw: opt
+                        .withdraw_circuit_params
+                        .clone()
+                        .unwrap_or(defaults.withdraw),
+                    multi_fee_update: opt
+                        .multi_fee_update_circuit_params
+                        .clone()
+                        .unwrap_or(defaults.multi_fee_update),
+                },
+                profile
+                    .verify_keys
+                    .as_ref()
+                    .filter(|_| shape == profile.shape),
+            )
+            .unwrap_or_else(|e| fatal_error(e));
+            let backend = create_backend(opt.gpu, None, None).unwrap_or_else(|e| fatal_error(e));
+            let name = opt
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("worker-{}", rand::thread_rng().gen::<u32>()));
+            let workers: Vec<_> = (0..opt.workers)
+                .map(|i| {
+                    coordinator::run_worker(
+                        coordinator::CoordinatorAddress {
+                            addr: opt.coordinator.clone(),
+                            token: opt.token.clone(),
+                        },
+                        format!("{}/{}", name, i),
+                        zoro_params.clone(),
+                        backend.clone(),
+                    )
+                })
+                .collect();
             futures::future::join_all(workers).await;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bazuka::wallet::TxBuilder;
+    use bazuka::zk::{ZkHasher as _, ZkScalar};
+
+    fn custom_token(id: u64) -> TokenId {
+        TokenId::Custom(ZkScalar::from(id))
+    }
+
+    /// An update paying the given fee, only the transaction matters here.
+    fn update(fee: Money) -> bazuka::mpn::UpdateTransition {
+        let builder = TxBuilder::new(b"fee payer");
+        let tx = builder.create_mpn_transaction(
+            0,
+            MpnAddress {
+                pub_key: builder.get_zk_address(),
+            },
+            0,
+            Money::ziesha(1),
+            1,
+            fee,
+            1,
+        );
+        bazuka::mpn::UpdateTransition {
+            src_index: 0,
+            dst_index: 1,
+            src_token_index: 0,
+            src_fee_token_index: 1,
+            dst_token_index: 0,
+            tx,
+            src_before: Default::default(),
+            src_before_balances_hash: ZkScalar::from(0),
+            src_before_balance: Money::ziesha(0),
+            src_before_fee_balance: fee,
+            src_proof: vec![],
+            src_balance_proof: vec![],
+            src_fee_balance_proof: vec![],
+            dst_before: Default::default(),
+            dst_before_balances_hash: ZkScalar::from(0),
+            dst_before_balance: Money::ziesha(0),
+            dst_proof: vec![],
+            dst_balance_proof: vec![],
+        }
+    }
+
+    fn aux_data(token: TokenId, fee_sum: u64) -> ZkScalar {
+        bazuka::core::ZkHasher::hash(&[token.into(), fee_sum.into()])
+    }
+
+    #[test]
+    fn test_update_fee_token_policy_without_fees() {
+        assert_eq!(
+            update_fee_token(&[], aux_data(TokenId::Ziesha, 0), TokenId::Ziesha),
+            Some(TokenId::Ziesha)
+        );
+    }
+
+    #[test]
+    fn test_update_fee_token_of_transactions() {
+        let token = custom_token(5);
+        let updates = [
+            update(Money::new(token, 3)),
+            update(Money::new(TokenId::Ziesha, 4)),
+            update(Money::new(token, 2)),
+        ];
+        // Only the fees paid in the committed token are summed
+        assert_eq!(
+            update_fee_token(&updates, aux_data(token, 5), TokenId::Ziesha),
+            Some(token)
+        );
+        assert_eq!(
+            update_fee_token(&updates, aux_data(TokenId::Ziesha, 4), TokenId::Ziesha),
+            Some(TokenId::Ziesha)
+        );
+        assert_eq!(
+            update_fee_token(&updates, aux_data(token, 9), TokenId::Ziesha),
+            None
+        );
+        assert_eq!(
+            update_fee_token(&updates, aux_data(custom_token(6), 0), TokenId::Ziesha),
+            None
+        );
+    }
+
+    #[test]
+    fn test_update_fee_token_overflowing_fees() {
+        let token = custom_token(5);
+        let updates = [
+            update(Money::new(token, u64::MAX)),
+            update(Money::new(token, 2)),
+        ];
+        // The wrapped-around sum must not be mistaken for the committed one
+        assert_eq!(
+            update_fee_token(&updates, aux_data(token, 1), TokenId::Ziesha),
+            None
+        );
+        assert_eq!(fee_sum(&updates, token), None);
+        assert_eq!(multi_fee_tokens(&updates, aux_data(token, 1)), None);
+    }
+
+    fn multi_aux_data(slots: &[(TokenId, u64)]) -> ZkScalar {
+        bazuka::core::ZkHasher::hash(
+            &slots
+                .iter()
+                .flat_map(|(token, fee_sum)| [(*token).into(), (*fee_sum).into()])
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn test_multi_fee_tokens_of_mixed_fees() {
+        let token = custom_token(5);
+        let updates = [
+            update(Money::new(token, 3)),
+            update(Money::new(TokenId::Ziesha, 4)),
+            update(Money::new(token, 2)),
+        ];
+        let aux_data = multi_aux_data(&[(token, 5), (TokenId::Ziesha, 4), (TokenId::Ziesha, 0)]);
+        assert_eq!(
+            multi_fee_tokens(&updates, aux_data),
+            Some([token, TokenId::Ziesha, TokenId::Ziesha])
+        );
+        // Not a single-token batch
+        assert_eq!(update_fee_token(&updates, aux_data, TokenId::Ziesha), None);
+    }
+
+    #[test]
+    fn test_multi_fee_tokens_count_padding_once() {
+        let updates = [
+            update(Money::new(TokenId::Ziesha, 3)),
+            update(Money::new(TokenId::Ziesha, 2)),
+        ];
+        assert_eq!(
+            multi_fee_tokens(
+                &updates,
+                multi_aux_data(&[
+                    (TokenId::Ziesha, 5),
+                    (TokenId::Ziesha, 0),
+                    (TokenId::Ziesha, 0)
+                ])
+            ),
+            Some([TokenId::Ziesha; circuits::FEE_SLOTS])
+        );
+        assert_eq!(
+            multi_fee_tokens(
+                &updates,
+                multi_aux_data(&[
+                    (TokenId::Ziesha, 5),
+                    (TokenId::Ziesha, 5),
+                    (TokenId::Ziesha, 5)
+                ])
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_multi_fee_tokens_more_tokens_than_slots() {
+        let updates = (1..=circuits::FEE_SLOTS as u64 + 1)
+            .map(|id| update(Money::new(custom_token(id), 1)))
+            .collect::<Vec<_>>();
+        let slots = (1..=circuits::FEE_SLOTS as u64)
+            .map(|id| (custom_token(id), 1))
+            .collect::<Vec<_>>();
+        assert_eq!(multi_fee_tokens(&updates, multi_aux_data(&slots)), None);
+    }
+}
@@ -0,0 +1,398 @@
+use crate::bank::ZoroVerifyKeys;
+use crate::mpn_model::{amount_of, balance, MpnState};
+use crate::shapes::Shape;
+
+use bazuka::client::messages::{
+    GetMpnAccountResponse, GetMpnWorkResponse, PostMpnSolutionResponse, ValidatorClaim,
+};
+use bazuka::client::PeerAddress;
+use bazuka::core::{Address, Money, MpnAddress, TokenId};
+use bazuka::crypto::SignatureScheme;
+use bazuka::mpn::{MpnWork, MpnWorkData, UpdateTransition};
+use bazuka::wallet::TxBuilder;
+use bazuka::zk::{ZkCompressedState, ZkHasher as _, ZkPublicInputs, ZkScalar, ZkStateModel};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Mock validators are reached through these made up addresses
+const MOCK_VALIDATORS: [&str; 2] = ["127.0.0.1:1", "127.0.0.2:1"];
+const WORKS_PER_ROUND: usize = 3;
+const REWARD: u64 = 1000;
+// Users of the mock network, user `i` owns the account at index `i`
+const USERS: usize = 4;
+const INITIAL_BALANCE: u64 = 1_000_000;
+const TRANSFER_AMOUNT: u64 = 10;
+const TRANSFER_FEE: u64 = 1;
+
+pub fn validator_peer(index: usize) -> PeerAddress {
+    MOCK_VALIDATORS[index % MOCK_VALIDATORS.len()]
+        .parse()
+        .unwrap()
+}
+
+/// Problems the mock node deliberately causes, for seeing how the prover
+/// reacts to them.
+#[derive(Debug, Clone, Default)]
+pub struct MockFaults {
+    /// The validator claim moves to another node this often
+    pub validator_switch: Option<Duration>,
+    /// Every response is delayed this much
+    pub slow_responses: Option<Duration>,
+    /// Ratio of works whose claimed next-state is wrong, and thus can't be proven
+    pub invalid_works: f64,
+}
+
+/// The MPN state of the mock network. Each block has an empty deposit and
+/// withdraw batch, and an update batch of transfers between the users, in
+/// the fee token.
+struct MpnSimulator {
+    shape: Shape,
+    fee_token: TokenId,
+    users: Vec<TxBuilder>,
+    height: u64,
+    state: MpnState,
+    // State once the works of the current block are all solved
+    next: MpnState,
+    works: HashMap<usize, MpnWork>,
+    solved: HashMap<usize, bool>,
+}
+
+impl MpnSimulator {
+    fn new(shape: Shape, fee_token: TokenId) -> Self {
+        let dimensions = shape.dimensions();
+        let mut state = MpnState::new(dimensions.log4_tree_size, dimensions.log4_tokens_tree_size);
+        let users = (0..USERS)
+            .map(|i| TxBuilder::new(format!("mock user {}", i).as_bytes()))
+            .collect::<Vec<_>>();
+        for (index, user) in users.iter().enumerate() {
+            let mut account = state.account(index as u64);
+            account.address = user.get_zk_address().decompress();
+            account
+                .tokens
+                .insert(0, Money::new(fee_token, INITIAL_BALANCE));
+            state.set_account(index as u64, account);
+        }
+        Self {
+            shape,
+            fee_token,
+            users,
+            height: 0,
+            next: state.clone(),
+            state,
+            works: HashMap::new(),
+            solved: HashMap::new(),
+        }
+    }
+
+    fn tx_root<const FIELDS: usize>(log4_batch_size: u8) -> ZkScalar {
+        ZkCompressedState::empty::<bazuka::core::ZkHasher>(ZkStateModel::List {
+            item_type: Box::new(ZkStateModel::Struct {
+                field_types: vec![ZkStateModel::Scalar; FIELDS],
+            }),
+            log4_size: log4_batch_size,
+        })
+        .state_hash
+    }
+
+    /// Sends `TRANSFER_AMOUNT` from user `src` to user `dst`, applying it to
+    /// `state`. Everything is kept in the first token slot.
+    fn transfer(&self, state: &mut MpnState, src: usize, dst: usize) -> UpdateTransition {
+        let (src_index, dst_index) = (src as u64, dst as u64);
+        let src_before = state.account(src_index);
+        let src_before_balance = balance(&src_before, 0);
+        let mut src_middle = src_before.clone();
+        src_middle.tokens.insert(
+            0,
+            Money::new(
+                self.fee_token,
+                amount_of(src_before_balance) - TRANSFER_AMOUNT,
+            ),
+        );
+        let src_before_fee_balance = balance(&src_middle, 0);
+        let mut src_after = src_middle.clone();
+        src_after.tokens.insert(
+            0,
+            Money::new(
+                self.fee_token,
+                amount_of(src_before_fee_balance) - TRANSFER_FEE,
+            ),
+        );
+        src_after.tx_nonce += 1;
+        let src_proof = state.proof(src_index);
+        state.set_account(src_index, src_after.clone());
+
+        let dst_before = state.account(dst_index);
+        let dst_before_balance = balance(&dst_before, 0);
+        let mut dst_after = dst_before.clone();
+        dst_after.tokens.insert(
+            0,
+            Money::new(
+                self.fee_token,
+                amount_of(dst_before_balance) + TRANSFER_AMOUNT,
+            ),
+        );
+        let dst_proof = state.proof(dst_index);
+        state.set_account(dst_index, dst_after);
+
+        let tx = self.users[src].create_mpn_transaction(
+            0,
+            MpnAddress {
+                pub_key: self.users[dst].get_zk_address(),
+            },
+            0,
+            Money::new(self.fee_token, TRANSFER_AMOUNT),
+            0,
+            Money::new(self.fee_token, TRANSFER_FEE),
+            src_after.tx_nonce,
+        );
+        let src_balances = state.balances_tree(&src_before);
+        let dst_balances = state.balances_tree(&dst_before);
+        UpdateTransition {
+            src_index,
+            dst_index,
+            src_token_index: 0,
+            src_fee_token_index: 0,
+            dst_token_index: 0,
+            tx,
+            src_before_balances_hash: src_balances.root(),
+            src_before_balance,
+            src_before_fee_balance,
+            src_proof,
+            src_balance_proof: src_balances.proof(0),
+            src_fee_balance_proof: state.balances_tree(&src_middle).proof(0),
+            src_before,
+            dst_before_balances_hash: dst_balances.root(),
+            dst_before_balance,
+            dst_proof,
+            dst_balance_proof: dst_balances.proof(0),
+            dst_before,
+        }
+    }
+
+    fn work(&self, data: MpnWorkData, invalid: bool) -> MpnWork {
+        let dimensions = self.shape.dimensions();
+        let (aux_data, next_state) = match &data {
+            MpnWorkData::Deposit(_) => (
+                Self::tx_root::<4>(dimensions.log4_deposit_batch_size),
+                self.state.root(),
+            ),
+            MpnWorkData::Withdraw(_) => (
+                Self::tx_root::<7>(dimensions.log4_withdraw_batch_size),
+                self.state.root(),
+            ),
+            // Hash of the fee token and the sum of the fees
+            MpnWorkData::Update(updates) => (
+                bazuka::core::ZkHasher::hash(&[
+                    self.fee_token.into(),
+                    (updates.len() as u64 * TRANSFER_FEE).into(),
+                ]),
+                self.next.root(),
+            ),
+        };
+        MpnWork {
+            public_inputs: ZkPublicInputs {
+                height: self.height,
+                state: self.state.root(),
+                aux_data,
+                next_state: if invalid {
+                    next_state + ZkScalar::from(1)
+                } else {
+                    next_state
+                },
+            },
+            data,
+            reward: Money::ziesha(REWARD),
+        }
+    }
+
+    /// Creates the works of the next block, if the previous ones are done.
+    fn works(&mut self, invalid_ratio: f64) -> HashMap<usize, MpnWork> {
+        if self.works.is_empty() || self.solved.len() == self.works.len() {
+            let mut rng = rand::thread_rng();
+            let batch_size = 1 << (2 * self.shape.dimensions().log4_update_batch_size);
+            let mut next = self.state.clone();
+            let updates = (0..USERS.min(batch_size))
+                .map(|src| self.transfer(&mut next, src, (src + 1) % USERS))
+                .collect::<Vec<_>>();
+            self.next = next;
+            let mut updates = Some(updates);
+            self.solved.clear();
+            self.works = (0..WORKS_PER_ROUND)
+                .map(|id| {
+                    let data = match id % 3 {
+                        0 => MpnWorkData::Deposit(vec![]),
+                        1 => MpnWorkData::Withdraw(vec![]),
+                        // The transfers are only in the first update batch
+                        _ => MpnWorkData::Update(updates.take().unwrap_or_default()),
+                    };
+                    (id, self.work(data, rng.gen::<f64>() < invalid_ratio))
+                })
+                .collect();
+        }
+        self.works
+            .iter()
+            .filter(|(id, _)| !self.solved.contains_key(id))
+            .map(|(id, w)| (*id, w.clone()))
+            .collect()
+    }
+
+    fn solve(&mut self, id: usize) {
+        self.solved.insert(id, true);
+        if self.solved.len() == self.works.len() {
+            self.height += 1;
+            self.state = self.next.clone();
+        }
+    }
+}
+
+/// A fake validator network, answering the requests of the prover
+/// in-process, so that it can be run without a network.
+pub struct MockNode {
+    faults: MockFaults,
+    verify_keys: ZoroVerifyKeys,
+    started: Instant,
+    // Claims handed over by `switch_validator_after_works`, on top of the
+    // timed ones
+    switches: AtomicUsize,
+    switch_after_works: AtomicBool,
+    simulator: Mutex<MpnSimulator>,
+}
+
+impl MockNode {
+    pub fn new(
+        faults: MockFaults,
+        shape: Shape,
+        fee_token: TokenId,
+        verify_keys: ZoroVerifyKeys,
+    ) -> Self {
+        Self {
+            faults,
+            verify_keys,
+            started: Instant::now(),
+            switches: AtomicUsize::new(0),
+            switch_after_works: AtomicBool::new(false),
+            simulator: Mutex::new(MpnSimulator::new(shape, fee_token)),
+        }
+    }
+
+    async fn respond(&self) {
+        if let Some(delay) = self.faults.slow_responses {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn current_validator(&self) -> usize {
+        let timed = match self.faults.validator_switch {
+            Some(period) if !period.is_zero() => {
+                (self.started.elapsed().as_millis() / period.as_millis()) as usize
+            }
+            _ => 0,
+        };
+        timed + self.switches.load(Ordering::Relaxed)
+    }
+
+    /// Hands the claim to the next validator right after the works are
+    /// handed out, i.e in the middle of the round of the prover.
+    #[cfg(test)]
+    pub fn switch_validator_after_works(&self) {
+        self.switch_after_works.store(true, Ordering::Relaxed);
+    }
+
+    /// Only the node holding the claim produces blocks, the others have
+    /// nothing to hand out or accept.
+    fn is_validator(&self, node: &PeerAddress) -> bool {
+        validator_peer(self.current_validator()) == *node
+    }
+
+    #[cfg(test)]
+    pub fn height(&self) -> u64 {
+        self.simulator.lock().unwrap().height
+    }
+
+    #[cfg(test)]
+    pub fn state(&self) -> ZkScalar {
+        self.simulator.lock().unwrap().state.root()
+    }
+
+    pub async fn validator_claim(&self) -> Option<ValidatorClaim> {
+        self.respond().await;
+        let index = self.current_validator();
+        let (address, sk) =
+            <bazuka::core::Signer as SignatureScheme>::generate_keys(&index.to_le_bytes());
+        // Claims are equal as long as the same validator is in charge
+        let timestamp = index as u32;
+        let sig = <bazuka::core::Signer as SignatureScheme>::sign(&sk, &timestamp.to_le_bytes());
+        Some(ValidatorClaim {
+            timestamp,
+            address,
+            node: validator_peer(index),
+            sig,
+        })
+    }
+
+    pub async fn get_mpn_works(&self, node: PeerAddress, _address: Address) -> GetMpnWorkResponse {
+        self.respond().await;
+        if !self.is_validator(&node) {
+            return GetMpnWorkResponse {
+                works: HashMap::new(),
+            };
+        }
+        let works = self
+            .simulator
+            .lock()
+            .unwrap()
+            .works(self.faults.invalid_works);
+        if self.switch_after_works.swap(false, Ordering::Relaxed) {
+            self.switches.fetch_add(1, Ordering::Relaxed);
+        }
+        GetMpnWorkResponse { works }
+    }
+
+    /// Every node knows the accounts, user `i` of the mock network owns the
+    /// account at index `i`, the others get an empty one.
+    pub async fn get_mpn_account(&self, address: MpnAddress) -> GetMpnAccountResponse {
+        self.respond().await;
+        let simulator = self.simulator.lock().unwrap();
+        let account = simulator
+            .users
+            .iter()
+            .position(|user| user.get_zk_address() == address.pub_key)
+            .map(|index| simulator.state.account(index as u64))
+            .unwrap_or_default();
+        GetMpnAccountResponse { account }
+    }
+
+    pub async fn post_mpn_proof(
+        &self,
+        node: PeerAddress,
+        address: Address,
+        proofs: HashMap<usize, bazuka::zk::ZkProof>,
+    ) -> PostMpnSolutionResponse {
+        self.respond().await;
+        if !self.is_validator(&node) {
+            return PostMpnSolutionResponse { accepted: 0 };
+        }
+        let mut simulator = self.simulator.lock().unwrap();
+        let mut accepted = 0;
+        for (id, proof) in proofs {
+            let work = if let Some(work) = simulator.works.get(&id) {
+                simulator
+                    .shape
+                    .work(address.clone(), work.clone(), simulator.fee_token)
+            } else {
+                continue;
+            };
+            let bazuka::zk::ZkProof::Groth16(proof) = proof else {
+                continue;
+            };
+            if work.verify(&self.verify_keys, &proof) {
+                simulator.solve(id);
+                accepted += 1;
+            }
+        }
+        PostMpnSolutionResponse { accepted }
+    }
+}
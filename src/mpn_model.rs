@@ -0,0 +1,171 @@
+use bazuka::core::{Money, TokenId};
+use bazuka::zk::{MpnAccount, ZkHasher as _, ZkScalar};
+use std::collections::HashMap;
+
+fn hash(vals: &[ZkScalar]) -> ZkScalar {
+    bazuka::core::ZkHasher::hash(vals)
+}
+
+/// Balance of an empty token slot
+pub fn null_money() -> Money {
+    Money::new(TokenId::Custom(ZkScalar::from(0)), 0)
+}
+
+pub fn amount_of(money: Money) -> u64 {
+    Into::<u64>::into(money.amount)
+}
+
+pub fn balance_hash(money: Money) -> ZkScalar {
+    hash(&[money.token_id.into(), amount_of(money).into()])
+}
+
+pub fn balance(account: &MpnAccount, index: u64) -> Money {
+    account
+        .tokens
+        .get(&index)
+        .copied()
+        .unwrap_or_else(null_money)
+}
+
+/// Arity-4 merkle tree of poseidon hashes, the MPN state and the balances of
+/// its accounts are made of. Only the non-empty nodes are stored, so that
+/// trees of the production size fit in memory.
+#[derive(Debug, Clone)]
+pub struct SparseTree {
+    log4_size: u8,
+    // Hash of an empty subtree, for each level starting from the leaves
+    defaults: Vec<ZkScalar>,
+    levels: Vec<HashMap<u64, ZkScalar>>,
+}
+
+impl SparseTree {
+    pub fn new(log4_size: u8, default_leaf: ZkScalar) -> Self {
+        let mut defaults = vec![default_leaf];
+        for _ in 0..log4_size {
+            let prev = *defaults.last().unwrap();
+            defaults.push(hash(&[prev; 4]));
+        }
+        Self {
+            log4_size,
+            defaults,
+            levels: vec![HashMap::new(); log4_size as usize + 1],
+        }
+    }
+
+    pub fn from_leaves(
+        log4_size: u8,
+        default_leaf: ZkScalar,
+        leaves: impl IntoIterator<Item = (u64, ZkScalar)>,
+    ) -> Self {
+        let mut tree = Self::new(log4_size, default_leaf);
+        for (index, leaf) in leaves {
+            tree.set(index, leaf);
+        }
+        tree
+    }
+
+    fn node(&self, level: usize, index: u64) -> ZkScalar {
+        self.levels[level]
+            .get(&index)
+            .copied()
+            .unwrap_or(self.defaults[level])
+    }
+
+    pub fn set(&mut self, mut index: u64, leaf: ZkScalar) {
+        self.levels[0].insert(index, leaf);
+        for level in 1..self.levels.len() {
+            index /= 4;
+            let children = (0..4)
+                .map(|i| self.node(level - 1, index * 4 + i))
+                .collect::<Vec<_>>();
+            self.levels[level].insert(index, hash(&children));
+        }
+    }
+
+    pub fn root(&self) -> ZkScalar {
+        self.node(self.log4_size as usize, 0)
+    }
+
+    /// Siblings of the leaf at each level, from the bottom.
+    pub fn proof(&self, mut index: u64) -> Vec<[ZkScalar; 3]> {
+        let mut proof = Vec::new();
+        for level in 0..self.log4_size as usize {
+            let first = index - index % 4;
+            proof.push(
+                (0..4)
+                    .filter(|i| *i != index % 4)
+                    .map(|i| self.node(level, first + i))
+                    .collect::<Vec<_>>()
+                    .try_into()
+                    .unwrap(),
+            );
+            index /= 4;
+        }
+        proof
+    }
+}
+
+pub fn balances_tree(account: &MpnAccount, log4_tokens_tree_size: u8) -> SparseTree {
+    SparseTree::from_leaves(
+        log4_tokens_tree_size,
+        balance_hash(null_money()),
+        account
+            .tokens
+            .iter()
+            .map(|(index, money)| (*index, balance_hash(*money))),
+    )
+}
+
+pub fn account_hash(account: &MpnAccount, log4_tokens_tree_size: u8) -> ZkScalar {
+    hash(&[
+        (account.tx_nonce as u64).into(),
+        (account.withdraw_nonce as u64).into(),
+        account.address.0,
+        account.address.1,
+        balances_tree(account, log4_tokens_tree_size).root(),
+    ])
+}
+
+/// Native model of the accounts of the MPN contract, and of the state root
+/// they're committed to.
+#[derive(Debug, Clone)]
+pub struct MpnState {
+    log4_tokens_tree_size: u8,
+    accounts: HashMap<u64, MpnAccount>,
+    tree: SparseTree,
+}
+
+impl MpnState {
+    pub fn new(log4_tree_size: u8, log4_tokens_tree_size: u8) -> Self {
+        Self {
+            log4_tokens_tree_size,
+            accounts: HashMap::new(),
+            tree: SparseTree::new(
+                log4_tree_size,
+                account_hash(&MpnAccount::default(), log4_tokens_tree_size),
+            ),
+        }
+    }
+
+    pub fn account(&self, index: u64) -> MpnAccount {
+        self.accounts.get(&index).cloned().unwrap_or_default()
+    }
+
+    pub fn set_account(&mut self, index: u64, account: MpnAccount) {
+        self.tree
+            .set(index, account_hash(&account, self.log4_tokens_tree_size));
+        self.accounts.insert(index, account);
+    }
+
+    pub fn balances_tree(&self, account: &MpnAccount) -> SparseTree {
+        balances_tree(account, self.log4_tokens_tree_size)
+    }
+
+    pub fn root(&self) -> ZkScalar {
+        self.tree.root()
+    }
+
+    pub fn proof(&self, index: u64) -> Vec<[ZkScalar; 3]> {
+        self.tree.proof(index)
+    }
+}
@@ -0,0 +1,505 @@
+use crate::events::{Event, EventSink};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    ValidatorChanged,
+    ProofsRejected,
+    DeadlineExceeded,
+    RepeatedErrors,
+    CriticalError,
+}
+
+fn default_error_threshold() -> usize {
+    3
+}
+
+fn default_dedup_window() -> u64 {
+    600
+}
+
+fn default_max_per_minute() -> usize {
+    6
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookSettings {
+    pub url: String,
+    /// Alerts sent to this webhook, all of them when empty
+    #[serde(default)]
+    pub events: Vec<AlertKind>,
+    /// Body of the request, `{{field}}`s are replaced with the fields of the
+    /// alert. A JSON object of all the fields is sent when not given.
+    pub template: Option<String>,
+    #[serde(default = "default_max_per_minute")]
+    pub max_per_minute: usize,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifySettings {
+    /// Consecutive failed rounds of a worker before alerting
+    #[serde(default = "default_error_threshold")]
+    pub error_threshold: usize,
+    /// Seconds in which the same alert is not sent again
+    #[serde(default = "default_dedup_window")]
+    pub dedup_window: u64,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookSettings>,
+}
+
+impl Default for NotifySettings {
+    fn default() -> Self {
+        Self {
+            error_threshold: default_error_threshold(),
+            dedup_window: default_dedup_window(),
+            webhooks: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub kind: AlertKind,
+    /// Worker the alert is about, if it isn't about the whole prover
+    pub worker: Option<usize>,
+    pub message: String,
+    pub fields: Vec<(&'static str, String)>,
+}
+
+impl Alert {
+    fn new(kind: AlertKind, message: String) -> Self {
+        Self {
+            kind,
+            worker: None,
+            message,
+            fields: Vec::new(),
+        }
+    }
+
+    fn for_worker(mut self, worker: Option<usize>) -> Self {
+        if let Some(worker) = worker {
+            self.worker = Some(worker);
+            self = self.with("worker", worker);
+        }
+        self
+    }
+
+    fn with(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.fields.push((name, value.to_string()));
+        self
+    }
+
+    fn all_fields(&self) -> Vec<(&'static str, String)> {
+        let kind = serde_json::to_value(self.kind)
+            .ok()
+            .and_then(|v| v.as_str().map(String::from))
+            .unwrap_or_default();
+        let mut fields = vec![("kind", kind), ("message", self.message.clone())];
+        fields.extend(self.fields.iter().cloned());
+        fields
+    }
+
+    /// Fills the `{{field}}`s of the template. Values are JSON-escaped, since
+    /// templates are mostly JSON documents.
+    pub fn render(&self, template: &str) -> String {
+        let mut body = template.to_string();
+        for (name, value) in self.all_fields() {
+            let escaped = serde_json::to_string(&value).unwrap();
+            body = body.replace(&format!("{{{{{}}}}}", name), &escaped[1..escaped.len() - 1]);
+        }
+        body
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.all_fields().into_iter().collect::<HashMap<_, _>>()).unwrap()
+    }
+}
+
+/// Turns prover events into alerts.
+struct AlertDetector {
+    error_threshold: usize,
+    consecutive_errors: HashMap<usize, usize>,
+    // Workers whose last round has failed
+    failing: HashSet<usize>,
+    round_worker: HashMap<u64, usize>,
+}
+
+impl AlertDetector {
+    fn new(error_threshold: usize) -> Self {
+        Self {
+            error_threshold: error_threshold.max(1),
+            consecutive_errors: HashMap::new(),
+            failing: HashSet::new(),
+            round_worker: HashMap::new(),
+        }
+    }
+
+    fn detect(&mut self, event: &Event) -> Option<Alert> {
+        match event {
+            Event::RoundStarted { round, worker } => {
+                // Any round that didn't fail (Even without works) ends the streak
+                if !self.failing.remove(worker) {
+                    self.consecutive_errors.remove(worker);
+                }
+                self.round_worker.retain(|_, w| w != worker);
+                self.round_worker.insert(*round, *worker);
+                None
+            }
+            Event::ValidatorChanged { round } => Some(
+                Alert::new(
+                    AlertKind::ValidatorChanged,
+                    "Validator changed while proving!".into(),
+                )
+                .with("round", round),
+            ),
+            Event::ProofsSubmitted {
+                round,
+                submitted,
+                accepted,
+            } => (accepted < submitted).then(|| {
+                Alert::new(
+                    AlertKind::ProofsRejected,
+                    format!(
+                        "{} of {} proofs were rejected!",
+                        submitted - accepted,
+                        submitted
+                    ),
+                )
+                .with("round", round)
+                .with("submitted", submitted)
+                .with("accepted", accepted)
+                .for_worker(self.round_worker.get(round).cloned())
+            }),
+            Event::WorksProven {
+                round,
+                duration_ms,
+                deadline_ms,
+                ..
+            } => (duration_ms > deadline_ms).then(|| {
+                Alert::new(
+                    AlertKind::DeadlineExceeded,
+                    format!(
+                        "Proving took {}ms, more than the {}ms deadline!",
+                        duration_ms, deadline_ms
+                    ),
+                )
+                .with("round", round)
+                .with("duration_ms", duration_ms)
+                .with("deadline_ms", deadline_ms)
+                .for_worker(self.round_worker.get(round).cloned())
+            }),
+            Event::RoundFailed {
+                round,
+                worker,
+                error,
+                critical,
+            } => {
+                self.failing.insert(*worker);
+                let errors = self.consecutive_errors.entry(*worker).or_default();
+                *errors += 1;
+                if *critical {
+                    return Some(
+                        Alert::new(
+                            AlertKind::CriticalError,
+                            format!("Worker {} produced wrong proofs: {}", worker, error),
+                        )
+                        .with("round", round)
+                        .with("error", error)
+                        .for_worker(Some(*worker)),
+                    );
+                }
+                (*errors == self.error_threshold).then(|| {
+                    Alert::new(
+                        AlertKind::RepeatedErrors,
+                        format!(
+                            "Worker {} failed {} times in a row: {}",
+                            worker, errors, error
+                        ),
+                    )
+                    .with("round", round)
+                    .with("error", error)
+                    .for_worker(Some(*worker))
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+struct Webhook {
+    settings: WebhookSettings,
+    sent: Vec<Instant>,
+}
+
+impl Webhook {
+    fn accepts(&self, alert: &Alert) -> bool {
+        self.settings.events.is_empty() || self.settings.events.contains(&alert.kind)
+    }
+
+    fn rate_limited(&mut self) -> bool {
+        self.sent.retain(|t| t.elapsed() < Duration::from_secs(60));
+        if self.sent.len() >= self.settings.max_per_minute {
+            return true;
+        }
+        self.sent.push(Instant::now());
+        false
+    }
+
+    fn send(&self, alert: &Alert) -> Result<(), ureq::Error> {
+        let body = match &self.settings.template {
+            Some(template) => alert.render(template),
+            None => alert.to_json(),
+        };
+        let content_type = if body.trim_start().starts_with('{') {
+            "application/json"
+        } else {
+            "text/plain"
+        };
+        ureq::post(&self.settings.url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .set("Content-Type", content_type)
+            .send_string(&body)?;
+        Ok(())
+    }
+}
+
+/// Suppresses alerts of a kind (And worker) that were already sent in the
+/// last `window`. Messages aren't compared, since they contain the details
+/// (Durations, counts...) of each event.
+struct Dedup {
+    window: Duration,
+    last_sent: HashMap<(AlertKind, Option<usize>), Instant>,
+}
+
+impl Dedup {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last_sent: HashMap::new(),
+        }
+    }
+
+    fn is_duplicate(&mut self, alert: &Alert, now: Instant) -> bool {
+        let key = (alert.kind, alert.worker);
+        if let Some(at) = self.last_sent.get(&key) {
+            if now.duration_since(*at) < self.window {
+                return true;
+            }
+        }
+        self.last_sent.insert(key, now);
+        false
+    }
+}
+
+fn deliver(alerts: Receiver<Alert>, mut webhooks: Vec<Webhook>, dedup_window: Duration) {
+    let mut dedup = Dedup::new(dedup_window);
+    for alert in alerts {
+        if dedup.is_duplicate(&alert, Instant::now()) {
+            log::debug!("Duplicate alert suppressed: {}", alert.message);
+            continue;
+        }
+        for webhook in webhooks.iter_mut() {
+            if !webhook.accepts(&alert) {
+                continue;
+            }
+            if webhook.rate_limited() {
+                log::warn!(
+                    "Alert not sent to {}, rate limit reached: {}",
+                    webhook.settings.url,
+                    alert.message
+                );
+                continue;
+            }
+            if let Err(e) = webhook.send(&alert) {
+                log::warn!("Unable to call webhook {}: {}", webhook.settings.url, e);
+            }
+        }
+    }
+}
+
+/// Sends alerts about the important events (Validator changes, rejected
+/// proofs...) to the configured webhooks. Requests are made on a separate
+/// thread so that proving is never slowed down by them.
+pub struct Notifier {
+    detector: Mutex<AlertDetector>,
+    alerts: Mutex<Sender<Alert>>,
+}
+
+impl Notifier {
+    pub fn new(settings: NotifySettings) -> Self {
+        let (tx, rx) = channel();
+        let webhooks = settings
+            .webhooks
+            .into_iter()
+            .map(|settings| Webhook {
+                settings,
+                sent: Vec::new(),
+            })
+            .collect();
+        let dedup_window = Duration::from_secs(settings.dedup_window);
+        std::thread::spawn(move || deliver(rx, webhooks, dedup_window));
+        Self {
+            detector: Mutex::new(AlertDetector::new(settings.error_threshold)),
+            alerts: Mutex::new(tx),
+        }
+    }
+}
+
+impl EventSink for Notifier {
+    fn handle(&self, event: &Event) {
+        if let Some(alert) = self.detector.lock().unwrap().detect(event) {
+            let _ = self.alerts.lock().unwrap().send(alert);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn started(round: u64, worker: usize) -> Event {
+        Event::RoundStarted { round, worker }
+    }
+
+    fn failed(round: u64, worker: usize) -> Event {
+        Event::RoundFailed {
+            round,
+            worker,
+            error: "Connection refused".into(),
+            critical: false,
+        }
+    }
+
+    #[test]
+    fn test_incorrect_proofs_always_alerted() {
+        let mut detector = AlertDetector::new(3);
+        detector.detect(&started(0, 0));
+        let alert = detector
+            .detect(&Event::RoundFailed {
+                round: 0,
+                worker: 0,
+                error: "snark proof incorrect!".into(),
+                critical: true,
+            })
+            .unwrap();
+        assert_eq!(alert.kind, AlertKind::CriticalError);
+        assert_eq!(alert.worker, Some(0));
+    }
+
+    #[test]
+    fn test_repeated_errors_alerted_once_at_threshold() {
+        let mut detector = AlertDetector::new(3);
+        let mut alerts = Vec::new();
+        for round in 0..5 {
+            detector.detect(&started(round, 0));
+            alerts.extend(detector.detect(&failed(round, 0)));
+        }
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, AlertKind::RepeatedErrors);
+        assert_eq!(alerts[0].worker, Some(0));
+    }
+
+    #[test]
+    fn test_idle_round_breaks_error_streak() {
+        let mut detector = AlertDetector::new(3);
+        for round in 0..2 {
+            detector.detect(&started(round, 0));
+            assert!(detector.detect(&failed(round, 0)).is_none());
+        }
+        // Nothing to prove, but nothing failed either
+        detector.detect(&started(2, 0));
+        detector.detect(&Event::WorksReceived { round: 2, works: 0 });
+        for round in 3..5 {
+            detector.detect(&started(round, 0));
+            assert!(detector.detect(&failed(round, 0)).is_none());
+        }
+    }
+
+    #[test]
+    fn test_error_streaks_are_per_worker() {
+        let mut detector = AlertDetector::new(2);
+        detector.detect(&started(0, 0));
+        detector.detect(&started(1, 1));
+        assert!(detector.detect(&failed(0, 0)).is_none());
+        assert!(detector.detect(&failed(1, 1)).is_none());
+        detector.detect(&started(2, 0));
+        let alert = detector.detect(&failed(2, 0)).unwrap();
+        assert_eq!(alert.worker, Some(0));
+    }
+
+    #[test]
+    fn test_rejected_proofs_and_exceeded_deadline() {
+        let mut detector = AlertDetector::new(3);
+        detector.detect(&started(7, 1));
+        assert!(detector
+            .detect(&Event::ProofsSubmitted {
+                round: 7,
+                submitted: 4,
+                accepted: 4,
+            })
+            .is_none());
+        let alert = detector
+            .detect(&Event::ProofsSubmitted {
+                round: 7,
+                submitted: 4,
+                accepted: 3,
+            })
+            .unwrap();
+        assert_eq!(alert.kind, AlertKind::ProofsRejected);
+        assert_eq!(alert.worker, Some(1));
+
+        let proven = |duration_ms| Event::WorksProven {
+            round: 7,
+            proofs: 4,
+            duration_ms,
+            deadline_ms: 1000,
+            pipeline: Default::default(),
+        };
+        assert!(detector.detect(&proven(1000)).is_none());
+        let alert = detector.detect(&proven(1001)).unwrap();
+        assert_eq!(alert.kind, AlertKind::DeadlineExceeded);
+    }
+
+    #[test]
+    fn test_dedup_ignores_message_details() {
+        let mut dedup = Dedup::new(Duration::from_secs(600));
+        let now = Instant::now();
+        let alert = |ms: u64, worker| {
+            Alert::new(
+                AlertKind::DeadlineExceeded,
+                format!("Proving took {}ms", ms),
+            )
+            .for_worker(worker)
+        };
+        assert!(!dedup.is_duplicate(&alert(1200, Some(0)), now));
+        assert!(dedup.is_duplicate(&alert(1300, Some(0)), now + Duration::from_secs(10)));
+        // Other workers are alerted separately
+        assert!(!dedup.is_duplicate(&alert(1200, Some(1)), now));
+        // And so are other kinds
+        assert!(!dedup.is_duplicate(
+            &Alert::new(AlertKind::ProofsRejected, "".into()).for_worker(Some(0)),
+            now
+        ));
+        // Until the window is over
+        assert!(!dedup.is_duplicate(&alert(1400, Some(0)), now + Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn test_render_escapes_fields() {
+        let alert =
+            Alert::new(AlertKind::RepeatedErrors, "Failed: \"timeout\"".into()).for_worker(Some(2));
+        assert_eq!(
+            alert.render(r#"{"text": "{{kind}} of {{worker}}: {{message}}"}"#),
+            r#"{"text": "repeated_errors of 2: Failed: \"timeout\""}"#
+        );
+    }
+}
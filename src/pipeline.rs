@@ -0,0 +1,199 @@
+use crate::bank::{BankError, ZoroParams};
+use crate::events::{emit, Event, PipelineStats, Span, Stage};
+use crate::synthesis::SynthesizedCircuit;
+use crate::ZoroWork;
+
+use bellman::groth16::Backend;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+pub struct PipelineMetrics {
+    pub synthesized: AtomicUsize,
+    pub proved: AtomicUsize,
+    pub synthesis_ms: AtomicU64,
+    pub msm_ms: AtomicU64,
+    pub msm_idle_ms: AtomicU64,
+    pub max_queued: AtomicUsize,
+    pub msm_timings: Mutex<Vec<(&'static str, Duration)>>,
+}
+
+impl PipelineMetrics {
+    pub fn stats(&self) -> PipelineStats {
+        let synthesized = self.synthesized.load(Ordering::Relaxed).max(1) as u64;
+        let proved = self.proved.load(Ordering::Relaxed).max(1) as u64;
+        let msm_ms = self.msm_ms.load(Ordering::Relaxed);
+        let msm_idle_ms = self.msm_idle_ms.load(Ordering::Relaxed);
+        PipelineStats {
+            synthesis_ms_per_work: self.synthesis_ms.load(Ordering::Relaxed) / synthesized,
+            msm_ms_per_work: msm_ms / proved,
+            msm_utilization: msm_ms * 100 / (msm_ms + msm_idle_ms).max(1),
+            max_queued: self.max_queued.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A prover of the pipeline, proving one work at a time on its own MSM
+/// backend (Each GPU gets a slot of its own).
+pub struct Slot {
+    pub name: String,
+    pub backend: Backend,
+}
+
+type Synthesized = (usize, ZoroWork, Result<SynthesizedCircuit, BankError>);
+
+fn prove_queued(
+    round: u64,
+    slot: usize,
+    synthesized_rx: &Mutex<Receiver<Synthesized>>,
+    params: &ZoroParams,
+    backend: &Backend,
+    cancel: &Arc<RwLock<bool>>,
+    queued: &AtomicUsize,
+    metrics: &PipelineMetrics,
+) -> (
+    HashMap<usize, bazuka::zk::groth16::Groth16Proof>,
+    Result<(), BankError>,
+) {
+    let mut proofs = HashMap::new();
+    let result = loop {
+        let idle = Instant::now();
+        let next = synthesized_rx.lock().unwrap().recv();
+        metrics
+            .msm_idle_ms
+            .fetch_add(idle.elapsed().as_millis() as u64, Ordering::Relaxed);
+        let (id, work, synthesized) = match next {
+            Ok(next) => next,
+            Err(_) => break Ok(()), // All works are synthesized
+        };
+        queued.fetch_sub(1, Ordering::Relaxed);
+        emit(Event::WorkAssigned {
+            round,
+            work: id,
+            slot,
+        });
+        let start = Instant::now();
+        let proof = synthesized.and_then(|synthesized| {
+            Span::enter(round, Some(id), Stage::Prove).finish(work.prove_synthesized_unchecked(
+                synthesized,
+                params,
+                backend.clone(),
+                Some(cancel.clone()),
+            ))
+        });
+        let msm_time = start.elapsed();
+        let proof = match proof.and_then(|proof| {
+            Span::enter(round, Some(id), Stage::Verify).finish(work.check_proof(params, proof))
+        }) {
+            Ok(proof) => proof,
+            Err(e) => break Err(e),
+        };
+        metrics.proved.fetch_add(1, Ordering::Relaxed);
+        metrics
+            .msm_ms
+            .fetch_add(msm_time.as_millis() as u64, Ordering::Relaxed);
+        metrics
+            .msm_timings
+            .lock()
+            .unwrap()
+            .push((work.circuit_name(), msm_time));
+        proofs.insert(id, proof);
+    };
+    (proofs, result)
+}
+
+/// Proves the works in two stages: witnesses are synthesized on the given
+/// thread-pool while the slots are busy proving the previously synthesized
+/// works. At most `queue_size` synthesized witnesses are kept in memory
+/// waiting for a slot. The proofs finished before an error or a
+/// cancellation are returned too, since they may still be worth submitting.
+pub fn prove_pipelined(
+    round: u64,
+    works: Vec<(usize, ZoroWork)>,
+    params: ZoroParams,
+    slots: &[Slot],
+    cancel: Arc<RwLock<bool>>,
+    pool: &rayon::ThreadPool,
+    queue_size: usize,
+    metrics: &PipelineMetrics,
+) -> (
+    HashMap<usize, bazuka::zk::groth16::Groth16Proof>,
+    Result<(), BankError>,
+) {
+    let num_works = works.len();
+    let (synthesized_tx, synthesized_rx) = sync_channel::<Synthesized>(queue_size);
+    // Dropped once all the provers are gone, so that synthesis doesn't block forever
+    let synthesized_rx = Arc::new(Mutex::new(synthesized_rx));
+    let queued = AtomicUsize::new(0);
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            pool.install(|| {
+                // Stops as soon as the provers hang up (E.g on errors)
+                let _ =
+                    works
+                        .into_par_iter()
+                        .try_for_each_with(synthesized_tx, |tx, (id, work)| {
+                            if *cancel.read().unwrap() {
+                                return Err(());
+                            }
+                            let start = Instant::now();
+                            let synthesized = Span::enter(round, Some(id), Stage::Synthesize)
+                                .finish(work.synthesize());
+                            metrics.synthesized.fetch_add(1, Ordering::Relaxed);
+                            metrics
+                                .synthesis_ms
+                                .fetch_add(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+                            let now_queued = queued.fetch_add(1, Ordering::Relaxed) + 1;
+                            metrics.max_queued.fetch_max(now_queued, Ordering::Relaxed);
+                            tx.send((id, work, synthesized)).map_err(|_| ())
+                        });
+            })
+        });
+
+        let provers = slots
+            .iter()
+            .enumerate()
+            .map(|(slot, Slot { backend, .. })| {
+                let synthesized_rx = synthesized_rx.clone();
+                let (params, cancel, queued) = (&params, &cancel, &queued);
+                s.spawn(move || {
+                    let (proofs, result) = prove_queued(
+                        round,
+                        slot,
+                        &synthesized_rx,
+                        params,
+                        backend,
+                        cancel,
+                        queued,
+                        metrics,
+                    );
+                    if result.is_err() {
+                        // Let the other stages know they can stop
+                        *cancel.write().unwrap() = true;
+                    }
+                    (proofs, result)
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(synthesized_rx);
+
+        let mut proofs = HashMap::new();
+        let mut result = Ok(());
+        for prover in provers {
+            let (p, r) = prover.join().unwrap();
+            proofs.extend(p);
+            if let Err(e) = r {
+                result = Err(e);
+            }
+        }
+        if result.is_ok() && proofs.len() != num_works {
+            result = Err(BankError::Cancelled);
+        }
+        (proofs, result)
+    })
+}
@@ -0,0 +1,146 @@
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CpuSetError {
+    #[error("invalid cpu-set: {0}")]
+    Invalid(String),
+}
+
+/// List of CPU cores Zoro is allowed to use, e.g `0-15,32-47` for keeping the
+/// prover on a single NUMA node.
+#[derive(Debug, Clone)]
+pub struct CpuSet(pub Vec<usize>);
+
+impl FromStr for CpuSet {
+    type Err = CpuSetError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cores = Vec::new();
+        for part in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let invalid = || CpuSetError::Invalid(part.into());
+            if let Some((from, to)) = part.split_once('-') {
+                let from = from.parse::<usize>().map_err(|_| invalid())?;
+                let to = to.parse::<usize>().map_err(|_| invalid())?;
+                if from > to {
+                    return Err(invalid());
+                }
+                cores.extend(from..=to);
+            } else {
+                cores.push(part.parse::<usize>().map_err(|_| invalid())?);
+            }
+        }
+        if cores.is_empty() {
+            return Err(CpuSetError::Invalid(s.into()));
+        }
+        cores.sort_unstable();
+        cores.dedup();
+        Ok(Self(cores))
+    }
+}
+
+/// How the CPU threads of the machine are divided between witness synthesis
+/// and the CPU part of the multiexps.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadBudget {
+    pub synthesis: usize,
+    pub msm: usize,
+}
+
+impl ThreadBudget {
+    pub fn new(
+        available: usize,
+        gpu: bool,
+        synthesis_threads: Option<usize>,
+        msm_threads: Option<usize>,
+    ) -> Self {
+        let available = available.max(1);
+        // With a GPU, the multiexps are mostly done on the device and the CPU
+        // is only needed for preparing them.
+        let default_msm = if gpu { available / 4 } else { available / 2 }.max(1);
+        let msm = msm_threads.unwrap_or(default_msm).max(1);
+        let synthesis = synthesis_threads
+            .unwrap_or_else(|| available.saturating_sub(msm))
+            .max(1);
+        Self { synthesis, msm }
+    }
+}
+
+/// Restricts the calling thread, and every thread it starts afterwards, to
+/// the given cores.
+#[cfg(target_os = "linux")]
+fn restrict_affinity(cores: &[usize]) -> bool {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for core in cores {
+            libc::CPU_SET(*core, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn restrict_affinity(_cores: &[usize]) -> bool {
+    false
+}
+
+/// Restricts the process to the cpu-set, and divides its cores between
+/// synthesis and the multiexps. Has to be called before any other thread is
+/// started (Including the async runtime), so that they all inherit the
+/// cpu-set.
+pub fn plan_threads(
+    gpu: bool,
+    synthesis_threads: Option<usize>,
+    msm_threads: Option<usize>,
+    cpu_set: Option<CpuSet>,
+) -> (Vec<usize>, ThreadBudget) {
+    let cores = match &cpu_set {
+        Some(cpu_set) => {
+            if !restrict_affinity(&cpu_set.0) {
+                log::warn!("Unable to restrict Zoro to the cpu-set {:?}", cpu_set.0);
+            }
+            cpu_set.0.clone()
+        }
+        None => (0..std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1))
+            .collect(),
+    };
+    let budget = ThreadBudget::new(cores.len(), gpu, synthesis_threads, msm_threads);
+    (cores, budget)
+}
+
+/// Thread-pools shared by all the workers of the process. They are built once
+/// at startup instead of once per proving round.
+pub struct ProverPools {
+    pub synthesis: rayon::ThreadPool,
+}
+
+impl ProverPools {
+    pub fn new(cores: &[usize], budget: ThreadBudget, pin_threads: bool) -> Self {
+        // The first cores of the set are for synthesis, the rest are left for
+        // the multiexps (Unless the budget asks for more threads than cores).
+        let synthesis_cores = cores[..budget.synthesis.min(cores.len())].to_vec();
+        let mut builder = rayon::ThreadPoolBuilder::new()
+            .num_threads(budget.synthesis)
+            .thread_name(|i| format!("zoro-synthesis-{}", i));
+        if pin_threads {
+            builder = builder.start_handler(move |i| {
+                let core = core_affinity::CoreId {
+                    id: synthesis_cores[i % synthesis_cores.len()],
+                };
+                if !core_affinity::set_for_current(core) {
+                    log::warn!("Unable to pin synthesis thread {} to core {}", i, core.id);
+                }
+            });
+        }
+        let synthesis = builder.build().unwrap();
+
+        log::info!(
+            "Using {} threads for synthesis and {} threads for CPU multiexps",
+            budget.synthesis,
+            budget.msm
+        );
+        Self { synthesis }
+    }
+}
@@ -0,0 +1,217 @@
+use crate::bank::ZoroVerifyKeys;
+use crate::settings::SettingsError;
+use crate::shapes::{ParamPaths, Shape};
+
+use bazuka::client::PeerAddress;
+use bazuka::core::TokenId;
+use bazuka::zk::groth16::Groth16VerifyingKey;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+const PELMENI_PEERS: &[&str] = &[
+    "65.108.248.12:8767",
+    "93.81.246.111:8767",
+    "136.169.209.154:8767",
+    "5.78.75.120:8767",
+    "34.125.14.164:8767",
+    "178.208.252.54:8767",
+    "185.188.249.208:8767",
+    "78.46.185.127:8767",
+    "94.16.109.190:8767",
+    "159.69.146.240:8767",
+    "65.108.65.36:8767",
+    "3.239.88.25:8767",
+    "178.238.230.218:8767",
+    "65.108.240.128:8767",
+    "65.108.218.99:8767",
+    "61.213.68.129:8767",
+    "135.181.199.165:8767",
+    "65.108.72.230:8767",
+    "65.108.193.133:8767",
+];
+
+/// Hex-encoded verifying keys, as printed by `zoro generate-params`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VerifyKeysSettings {
+    pub update: String,
+    pub deposit: String,
+    pub withdraw: String,
+    /// Only for the networks accepting multi-fee update batches
+    #[serde(default)]
+    pub multi_fee_update: Option<String>,
+}
+
+/// A network defined in the config file, under `[networks.NAME]`.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileSettings {
+    /// Name the nodes know the network by, the name of the profile if not given
+    pub network: Option<String>,
+    pub shape: Option<Shape>,
+    /// When not given, our own params are trusted
+    pub verify_keys: Option<VerifyKeysSettings>,
+    pub params_dir: Option<PathBuf>,
+    pub peers: Vec<String>,
+    pub fee_token: Option<String>,
+}
+
+/// Everything that differs between the networks Zoro can prove for.
+#[derive(Debug, Clone)]
+pub struct NetworkProfile {
+    pub name: String,
+    pub network: String,
+    pub shape: Shape,
+    pub verify_keys: Option<ZoroVerifyKeys>,
+    pub params_dir: Option<PathBuf>,
+    pub peers: Vec<PeerAddress>,
+    pub fee_token: TokenId,
+}
+
+impl NetworkProfile {
+    /// Profile of a network we know nothing about, besides its name.
+    pub fn unknown(network: &str) -> Self {
+        Self {
+            name: network.into(),
+            network: network.into(),
+            shape: Shape::Mainnet,
+            verify_keys: None,
+            params_dir: None,
+            peers: Vec::new(),
+            fee_token: TokenId::Ziesha,
+        }
+    }
+
+    pub fn params_path(&self, shape: Shape, circuit: &str) -> PathBuf {
+        match &self.params_dir {
+            Some(dir) => dir.join(shape.params_path(circuit)),
+            None => shape.params_path(circuit),
+        }
+    }
+
+    pub fn param_paths(&self, shape: Shape) -> ParamPaths {
+        ParamPaths {
+            update: self.params_path(shape, "update"),
+            deposit: self.params_path(shape, "deposit"),
+            withdraw: self.params_path(shape, "withdraw"),
+            multi_fee_update: self.params_path(shape, "multi_fee_update"),
+        }
+    }
+
+    fn from_settings(name: &str, settings: &ProfileSettings) -> Result<Self, SettingsError> {
+        let verify_keys = match &settings.verify_keys {
+            Some(keys) => Some(ZoroVerifyKeys {
+                update: parse_vk(name, &keys.update)?,
+                deposit: parse_vk(name, &keys.deposit)?,
+                withdraw: parse_vk(name, &keys.withdraw)?,
+                multi_fee_update: keys
+                    .multi_fee_update
+                    .as_ref()
+                    .map(|vk| parse_vk(name, vk))
+                    .transpose()?,
+            }),
+            None => None,
+        };
+        let fee_token = match &settings.fee_token {
+            Some(token) => token.parse::<TokenId>().map_err(|_| {
+                SettingsError::Invalid("networks.fee_token", format!("{}: {}", name, token))
+            })?,
+            None => TokenId::Ziesha,
+        };
+        Ok(Self {
+            name: name.into(),
+            network: settings.network.clone().unwrap_or_else(|| name.into()),
+            shape: settings.shape.unwrap_or(Shape::Mainnet),
+            verify_keys,
+            params_dir: settings.params_dir.clone(),
+            peers: parse_peers(&settings.peers)?,
+            fee_token,
+        })
+    }
+}
+
+fn parse_vk(name: &str, hex_vk: &str) -> Result<Groth16VerifyingKey, SettingsError> {
+    hex::decode(hex_vk.trim())
+        .ok()
+        .and_then(|bytes| bincode::deserialize(&bytes).ok())
+        .ok_or_else(|| {
+            SettingsError::Invalid(
+                "networks.verify_keys",
+                format!("{} has a malformed key", name),
+            )
+        })
+}
+
+pub fn parse_peers(peers: &[String]) -> Result<Vec<PeerAddress>, SettingsError> {
+    peers
+        .iter()
+        .map(|p| {
+            p.parse::<PeerAddress>()
+                .map_err(|_| SettingsError::Invalid("peers", p.clone()))
+        })
+        .collect()
+}
+
+fn builtin(name: &str) -> Option<NetworkProfile> {
+    match name {
+        "pelmeni-9" => Some(NetworkProfile {
+            name: "pelmeni-9".into(),
+            network: "pelmeni-9".into(),
+            shape: Shape::Mainnet,
+            verify_keys: Some(ZoroVerifyKeys {
+                update: bazuka::config::blockchain::MPN_UPDATE_VK.clone(),
+                deposit: bazuka::config::blockchain::MPN_DEPOSIT_VK.clone(),
+                withdraw: bazuka::config::blockchain::MPN_WITHDRAW_VK.clone(),
+                multi_fee_update: None,
+            }),
+            params_dir: None,
+            peers: PELMENI_PEERS.iter().map(|p| p.parse().unwrap()).collect(),
+            fee_token: TokenId::Ziesha,
+        }),
+        "dev" => Some(NetworkProfile {
+            name: "dev".into(),
+            network: "dev".into(),
+            shape: Shape::Dev,
+            verify_keys: None,
+            params_dir: None,
+            peers: vec!["127.0.0.1:8765".parse().unwrap()],
+            fee_token: TokenId::Ziesha,
+        }),
+        _ => None,
+    }
+}
+
+/// Finds a network by its profile name (Or the name the nodes know it by),
+/// among the ones of the config file first, and then the built-in ones.
+pub fn lookup(
+    name: &str,
+    custom: &HashMap<String, ProfileSettings>,
+) -> Result<Option<NetworkProfile>, SettingsError> {
+    if let Some((profile_name, settings)) = custom
+        .iter()
+        .find(|(n, s)| *n == name || s.network.as_deref() == Some(name))
+    {
+        return NetworkProfile::from_settings(profile_name, settings).map(Some);
+    }
+    Ok(builtin(name))
+}
+
+/// Same as `lookup`, but unknown networks are an error.
+pub fn find(
+    name: &str,
+    custom: &HashMap<String, ProfileSettings>,
+) -> Result<NetworkProfile, SettingsError> {
+    lookup(name, custom)?.ok_or_else(|| {
+        let mut known = vec!["pelmeni-9".to_string(), "dev".to_string()];
+        known.extend(custom.keys().cloned());
+        SettingsError::Invalid(
+            "network",
+            format!(
+                "{} is unknown (Known networks: {}), define it under [networks.{}]",
+                name,
+                known.join(", "),
+                name
+            ),
+        )
+    })
+}
@@ -0,0 +1,474 @@
+use crate::backoff::{Backoff, PeerHealth};
+use crate::bank::{self, ZoroParams};
+use crate::client::{self, Connector};
+use crate::coordinator::Coordinator;
+use crate::deadline::ProvingDeadline;
+use crate::events::{self, Event, Span, Stage};
+use crate::pipeline;
+use crate::pools::ProverPools;
+use crate::settings::ProveConfig;
+use crate::{fatal_error, ErrorClass, ZoroError};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// How often the validator claim is checked while proving
+const CLAIM_CHECK_INTERVAL: Duration = Duration::from_millis(3000);
+
+/// What proving needs on this machine.
+pub struct LocalProver {
+    pub params: ZoroParams,
+    pub slots: Vec<pipeline::Slot>,
+    pub pools: ProverPools,
+}
+
+/// Where the works of the rounds are proven.
+pub enum Proving {
+    /// By the pipeline of this process (`zoro prove`)
+    Local(Arc<LocalProver>),
+    /// By the workers of a coordinator (`zoro coordinator`)
+    Remote(Arc<Coordinator>),
+}
+
+/// Drives the proving rounds of `zoro prove` and `zoro coordinator`, shared
+/// by all of their workers.
+pub struct Prover {
+    conf: ProveConfig,
+    connector: Connector,
+    proving: Proving,
+    deadline: Mutex<ProvingDeadline>,
+    peer_health: Mutex<PeerHealth>,
+}
+
+impl Prover {
+    pub fn new(conf: ProveConfig, connector: Connector, proving: Proving) -> Self {
+        let deadline = ProvingDeadline::new(
+            Duration::from_secs(conf.max_proving_time),
+            conf.learn_deadline,
+        );
+        Self {
+            conf,
+            connector,
+            proving,
+            deadline: Mutex::new(deadline),
+            peer_health: Mutex::new(PeerHealth::default()),
+        }
+    }
+
+    /// Proves the works of the current validator, if there is one, and
+    /// submits the proofs.
+    pub async fn round(self: &Arc<Self>, round: u64, worker: usize) -> Result<(), ZoroError> {
+        let opt = &self.conf;
+        let cancel = Arc::new(RwLock::new(false));
+
+        events::emit(Event::RoundStarted { round, worker });
+        let validator_claim = client::find_validator(
+            &opt.peers,
+            &self.connector,
+            Duration::from_secs(2),
+            &self.peer_health,
+        )
+        .await?;
+        self.deadline
+            .lock()
+            .unwrap()
+            .observe_claim(&validator_claim);
+
+        let claim = match validator_claim.clone() {
+            Some(claim) => claim,
+            None => {
+                events::emit(Event::NoValidator { round });
+                return Ok(());
+            }
+        };
+        events::emit(Event::ValidatorFound {
+            round,
+            validator: claim.node.to_string(),
+        });
+        if !self.peer_health.lock().unwrap().is_available(&claim.node) {
+            return Err(ZoroError::PeerUnavailable(claim.node.to_string()));
+        }
+        let client = self.connector.connect(claim.node, Duration::from_secs(5));
+
+        let works = Span::enter(round, None, Stage::Fetch)
+            .finish(client.get_mpn_works(opt.address.clone()).await);
+        client::record_result(&self.peer_health, &claim.node, &works);
+        let works = works?;
+        events::emit(Event::WorksReceived {
+            round,
+            works: works.works.len(),
+        });
+        if works.works.is_empty() {
+            return Ok(());
+        }
+
+        let (cancel_controller_tx, mut cancel_controller_rx) =
+            tokio::sync::mpsc::unbounded_channel::<()>();
+        let prover = self.clone();
+        let cancel_cloned = cancel.clone();
+        let cancel_controller = tokio::task::spawn(async move {
+            loop {
+                if let Ok(new_claim) = client::find_validator(
+                    &prover.conf.peers,
+                    &prover.connector,
+                    Duration::from_secs(1),
+                    &prover.peer_health,
+                )
+                .await
+                {
+                    prover.deadline.lock().unwrap().observe_claim(&new_claim);
+                    if new_claim != validator_claim {
+                        events::emit(Event::ValidatorChanged { round });
+                        *cancel_cloned.write().unwrap() = true;
+                    }
+                }
+                tokio::select! {
+                    _ = cancel_controller_rx.recv() => break,
+                    _ = tokio::time::sleep(CLAIM_CHECK_INTERVAL) => {}
+                }
+            }
+            Ok::<(), ZoroError>(())
+        });
+
+        let start = Instant::now();
+        let works = works
+            .works
+            .into_iter()
+            .map(|(id, p)| {
+                (
+                    id,
+                    opt.shape
+                        .work(opt.address.clone(), p, opt.profile.fee_token),
+                )
+            })
+            .collect::<Vec<_>>();
+        let msm_slots = match &self.proving {
+            Proving::Local(local) => local.slots.len(),
+            // Nothing is recorded about the proving times of the workers, so
+            // their works are never refused, the deadline still ends the round
+            Proving::Remote(_) => 1,
+        };
+        let (round_deadline, (works, refused)) = {
+            let deadline = self.deadline.lock().unwrap();
+            (deadline.deadline(), deadline.schedule(works, msm_slots))
+        };
+        if !refused.is_empty() {
+            events::emit(Event::WorksRefused {
+                round,
+                works: refused,
+                deadline_ms: round_deadline.as_millis() as u64,
+            });
+        }
+
+        // Proofs submitted after the deadline are worthless, the ones
+        // already finished are submitted
+        let deadline_cancel = cancel.clone();
+        let deadline_reached = Arc::new(AtomicBool::new(false));
+        let deadline_reached_cloned = deadline_reached.clone();
+        let deadline_timer = tokio::task::spawn(async move {
+            tokio::time::sleep(round_deadline).await;
+            events::emit(Event::DeadlineReached { round });
+            deadline_reached_cloned.store(true, Ordering::Relaxed);
+            *deadline_cancel.write().unwrap() = true;
+        });
+
+        let metrics = Arc::new(pipeline::PipelineMetrics::default());
+        let proofs = match &self.proving {
+            Proving::Local(local) => {
+                let local = local.clone();
+                let queue_size = opt.synthesis_queue;
+                let pipeline_metrics = metrics.clone();
+                tokio::task::spawn_blocking(move || {
+                    pipeline::prove_pipelined(
+                        round,
+                        works,
+                        local.params.clone(),
+                        &local.slots,
+                        cancel,
+                        &local.pools.synthesis,
+                        queue_size,
+                        &pipeline_metrics,
+                    )
+                })
+                .await
+            }
+            Proving::Remote(coordinator) => Ok(coordinator.prove(works, cancel).await),
+        };
+        deadline_timer.abort();
+        {
+            let mut deadline = self.deadline.lock().unwrap();
+            for (circuit, msm_time) in metrics.msm_timings.lock().unwrap().iter() {
+                deadline.record(circuit, *msm_time);
+            }
+        }
+        let (proofs, result) = proofs?;
+        match result {
+            Err(bank::BankError::Cancelled)
+                if deadline_reached.load(Ordering::Relaxed) && !proofs.is_empty() => {}
+            result => result?,
+        }
+        events::emit(Event::WorksProven {
+            round,
+            proofs: proofs.len(),
+            duration_ms: start.elapsed().as_millis() as u64,
+            deadline_ms: round_deadline.as_millis() as u64,
+            pipeline: metrics.stats(),
+        });
+
+        let submitted = proofs.len();
+        let resp = Span::enter(round, None, Stage::Submit).finish(
+            client
+                .post_mpn_solution(
+                    opt.address.clone(),
+                    proofs
+                        .into_iter()
+                        .map(|(id, proof)| (id, bazuka::zk::ZkProof::Groth16(Box::new(proof))))
+                        .collect(),
+                )
+                .await,
+        );
+        client::record_result(&self.peer_health, &claim.node, &resp);
+        let resp = resp?;
+        events::emit(Event::ProofsSubmitted {
+            round,
+            submitted,
+            accepted: resp.accepted,
+        });
+
+        let _ = cancel_controller_tx.send(());
+        cancel_controller.await??;
+        Ok(())
+    }
+
+    /// Runs rounds forever, backing off while they keep failing.
+    pub async fn run_worker(self: Arc<Self>, worker: usize) {
+        let mut backoff = Backoff::default();
+        loop {
+            let round = events::new_round();
+            match self.round(round, worker).await {
+                Ok(()) => {
+                    backoff.reset();
+                    tokio::time::sleep(Duration::from_millis(1000)).await;
+                }
+                Err(e) => {
+                    events::emit(Event::RoundFailed {
+                        round,
+                        worker,
+                        error: e.to_string(),
+                        critical: e.class() == ErrorClass::Critical,
+                    });
+                    match e.class() {
+                        ErrorClass::Fatal => fatal_error(e),
+                        ErrorClass::Expected => {
+                            backoff.reset();
+                            tokio::time::sleep(Duration::from_millis(1000)).await;
+                        }
+                        ErrorClass::Transient | ErrorClass::Critical => {
+                            let delay = backoff.next_delay();
+                            log::info!("Worker {} retrying in {}ms", worker, delay.as_millis());
+                            tokio::time::sleep(delay).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Transport;
+    use crate::mock::{MockFaults, MockNode};
+    use crate::pools::ThreadBudget;
+    use crate::settings::Settings;
+    use crate::shapes::{ParamPaths, Shape};
+    use crate::ProveOpt;
+    use bazuka::core::MpnAddress;
+    use bazuka::crypto::SignatureScheme;
+    use bazuka::wallet::TxBuilder;
+    use bellman::groth16::Backend;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+    use std::sync::OnceLock;
+    use structopt::StructOpt;
+
+    /// Params of the dev circuits, generated once for all the tests.
+    fn params() -> ZoroParams {
+        static PARAMS: OnceLock<ZoroParams> = OnceLock::new();
+        PARAMS
+            .get_or_init(|| {
+                let dir = std::env::temp_dir().join(format!("zoro-test-{}", std::process::id()));
+                std::fs::create_dir_all(&dir).unwrap();
+                let paths = ParamPaths {
+                    update: dir.join("update.dat"),
+                    deposit: dir.join("deposit.dat"),
+                    withdraw: dir.join("withdraw.dat"),
+                    multi_fee_update: dir.join("multi_fee_update.dat"),
+                };
+                let params = Shape::Dev.load_params(&paths, Some(ChaCha20Rng::seed_from_u64(1)));
+                std::fs::remove_dir_all(&dir).unwrap();
+                params
+            })
+            .clone()
+    }
+
+    fn prover(faults: MockFaults) -> (Arc<Prover>, Arc<MockNode>) {
+        let (address, _) = <bazuka::core::Signer as SignatureScheme>::generate_keys(b"prover");
+        let address = address.to_string();
+        let opt = ProveOpt::from_iter([
+            "zoro",
+            "--mock-node",
+            "--shape",
+            "dev",
+            "--address",
+            &address,
+        ]);
+        let conf = ProveConfig::resolve(&opt, Settings::default()).unwrap();
+        let params = params();
+        let mock = Arc::new(MockNode::new(
+            faults,
+            conf.shape,
+            conf.profile.fee_token,
+            params.verify_keys(),
+        ));
+        let connector = Connector::new(&conf.network, Transport::Mock(mock.clone()), None);
+        // Bellman's thread count is left alone, the tests run in parallel
+        let pools = ProverPools::new(
+            &[0],
+            ThreadBudget {
+                synthesis: 1,
+                msm: 1,
+            },
+            false,
+        );
+        let local = LocalProver {
+            params,
+            slots: vec![pipeline::Slot {
+                name: "CPU".into(),
+                backend: Backend::Cpu,
+            }],
+            pools,
+        };
+        let prover = Prover::new(conf, connector, Proving::Local(Arc::new(local)));
+        (Arc::new(prover), mock)
+    }
+
+    #[tokio::test]
+    async fn test_round_advances_mock_state() {
+        let (prover, mock) = prover(MockFaults::default());
+        let genesis = mock.state();
+        prover.round(events::new_round(), 0).await.unwrap();
+        assert_eq!(mock.height(), 1);
+        let state = mock.state();
+        assert_ne!(state, genesis);
+        // The transfers of the next block start from the new state
+        prover.round(events::new_round(), 0).await.unwrap();
+        assert_eq!(mock.height(), 2);
+        assert_ne!(mock.state(), state);
+    }
+
+    #[tokio::test]
+    async fn test_round_with_invalid_works() {
+        let (prover, mock) = prover(MockFaults {
+            invalid_works: 1.0,
+            ..Default::default()
+        });
+        let genesis = mock.state();
+        let err = prover.round(events::new_round(), 0).await.unwrap_err();
+        assert!(matches!(
+            err,
+            ZoroError::BankError(bank::BankError::IncorrectProof)
+        ));
+        assert_eq!(err.class(), ErrorClass::Critical);
+        assert_eq!(mock.height(), 0);
+        assert_eq!(mock.state(), genesis);
+    }
+
+    #[tokio::test]
+    async fn test_round_with_slow_responses() {
+        // Slower than the timeout of the validator claim request
+        let (prover, mock) = prover(MockFaults {
+            slow_responses: Some(Duration::from_secs(3)),
+            ..Default::default()
+        });
+        let err = prover.round(events::new_round(), 0).await.unwrap_err();
+        assert!(matches!(err, ZoroError::HttpTimeout(_)));
+        assert_eq!(err.class(), ErrorClass::Transient);
+        // The only peer is given a break after failing
+        let err = prover.round(events::new_round(), 0).await.unwrap_err();
+        assert!(matches!(err, ZoroError::NoPeersAvailable));
+        assert_eq!(mock.height(), 0);
+    }
+
+    /// Keeps the events of a single round, the sinks are shared by all the
+    /// tests.
+    struct RoundEvents {
+        round: u64,
+        events: Mutex<Vec<Event>>,
+    }
+
+    impl events::EventSink for RoundEvents {
+        fn handle(&self, event: &Event) {
+            let round = match event {
+                Event::RoundStarted { round, .. }
+                | Event::ValidatorChanged { round }
+                | Event::ProofsSubmitted { round, .. } => *round,
+                _ => return,
+            };
+            if round == self.round {
+                self.events.lock().unwrap().push(event.clone());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_with_validator_switch() {
+        let (prover, mock) = prover(MockFaults::default());
+        mock.switch_validator_after_works();
+        let round = events::new_round();
+        let sink = Arc::new(RoundEvents {
+            round,
+            events: Mutex::new(Vec::new()),
+        });
+        events::subscribe(sink.clone());
+
+        // The proofs are either cancelled, or refused by the old validator
+        match prover.round(round, 0).await {
+            Ok(()) => {}
+            Err(e) => assert!(matches!(
+                e,
+                ZoroError::BankError(bank::BankError::Cancelled)
+            )),
+        }
+        let events = sink.events.lock().unwrap();
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, Event::ValidatorChanged { .. })));
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, Event::ProofsSubmitted { accepted, .. } if *accepted > 0)));
+        assert_eq!(mock.height(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_mpn_accounts_follow_the_mock_state() {
+        let (prover, mock) = prover(MockFaults::default());
+        let user = MpnAddress {
+            pub_key: TxBuilder::new(b"mock user 1").get_zk_address(),
+        };
+        let client = prover
+            .connector
+            .connect(crate::mock::validator_peer(0), Duration::from_secs(1));
+        let before = client.get_mpn_account(user.clone()).await.unwrap().account;
+        assert_eq!(before.tx_nonce, 0);
+        assert_eq!(before.address, user.pub_key.decompress());
+
+        prover.round(events::new_round(), 0).await.unwrap();
+        assert_eq!(mock.height(), 1);
+        // Every user sent a single transfer
+        let after = client.get_mpn_account(user).await.unwrap().account;
+        assert_eq!(after.tx_nonce, 1);
+    }
+}
@@ -0,0 +1,422 @@
+//! Reading and writing circuits in the binary formats of iden3 (`.r1cs` for
+//! the constraints and `.wtns` for the witness), so that they can be checked,
+//! counted and proven by other tools (snarkjs, circom's tooling...).
+//!
+//! Wire 0 is ONE, followed by the public inputs of the circuit and then its
+//! auxiliary variables, the same order bellman uses.
+
+use crate::synthesis::{SynthesizedCircuit, Terms};
+
+use bellman::Index;
+use ff::{Field, PrimeField};
+use std::io::{self, Read, Write};
+use zeekit::BellmanFr;
+
+const FIELD_SIZE: usize = 32;
+
+const R1CS_HEADER: u32 = 1;
+const R1CS_CONSTRAINTS: u32 = 2;
+const R1CS_WIRE_TO_LABEL: u32 = 3;
+
+const WTNS_HEADER: u32 = 1;
+const WTNS_VALUES: u32 = 2;
+
+pub type Lc = Vec<(u32, BellmanFr)>;
+
+/// Constraints of a circuit, as read from an `.r1cs` file.
+#[derive(Debug, Clone)]
+pub struct R1cs {
+    pub num_wires: u32,
+    pub num_public_inputs: u32,
+    pub constraints: Vec<(Lc, Lc, Lc)>,
+}
+
+impl R1cs {
+    /// Index of the first constraint the witness doesn't satisfy.
+    pub fn first_unsatisfied(&self, witness: &[BellmanFr]) -> io::Result<Option<usize>> {
+        if witness.len() != self.num_wires as usize {
+            return Err(malformed(format!(
+                "witness has {} values, but the circuit has {} wires",
+                witness.len(),
+                self.num_wires
+            )));
+        }
+        if witness[0] != BellmanFr::one() {
+            return Err(malformed("first wire of the witness is not ONE".into()));
+        }
+        let eval = |lc: &Lc| {
+            let mut sum = BellmanFr::zero();
+            for (wire, coeff) in lc {
+                sum += witness[*wire as usize] * coeff;
+            }
+            sum
+        };
+        Ok(self
+            .constraints
+            .iter()
+            .position(|(a, b, c)| eval(a) * eval(b) != eval(c)))
+    }
+}
+
+/// Modulus of the scalar field, little-endian.
+fn modulus() -> [u8; FIELD_SIZE] {
+    let mut bytes = [0u8; FIELD_SIZE];
+    bytes.copy_from_slice((-BellmanFr::one()).to_repr().as_ref());
+    for b in bytes.iter_mut() {
+        let (sum, carry) = b.overflowing_add(1);
+        *b = sum;
+        if !carry {
+            break;
+        }
+    }
+    bytes
+}
+
+fn malformed(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn num_wires(circuit: &SynthesizedCircuit) -> usize {
+    1 + circuit.inputs().len() + circuit.aux().len()
+}
+
+fn wire(circuit: &SynthesizedCircuit, index: Index) -> u32 {
+    (match index {
+        Index::Input(i) => i,
+        Index::Aux(i) => 1 + circuit.inputs().len() + i,
+    }) as u32
+}
+
+fn write_u32<W: Write>(out: &mut W, v: u32) -> io::Result<()> {
+    out.write_all(&v.to_le_bytes())
+}
+
+fn write_u64<W: Write>(out: &mut W, v: u64) -> io::Result<()> {
+    out.write_all(&v.to_le_bytes())
+}
+
+fn write_field<W: Write>(out: &mut W, v: &BellmanFr) -> io::Result<()> {
+    out.write_all(v.to_repr().as_ref())
+}
+
+fn write_lc<W: Write>(out: &mut W, circuit: &SynthesizedCircuit, lc: &Terms) -> io::Result<()> {
+    write_u32(out, lc.len() as u32)?;
+    for (index, coeff) in lc {
+        write_u32(out, wire(circuit, *index))?;
+        write_field(out, coeff)?;
+    }
+    Ok(())
+}
+
+pub fn write_r1cs<W: Write>(circuit: &SynthesizedCircuit, out: &mut W) -> io::Result<()> {
+    let num_wires = num_wires(circuit);
+    out.write_all(b"r1cs")?;
+    write_u32(out, 1)?; // Version
+    write_u32(out, 3)?; // Number of sections
+
+    write_u32(out, R1CS_HEADER)?;
+    write_u64(out, (4 + FIELD_SIZE + 4 * 4 + 8 + 4) as u64)?;
+    write_u32(out, FIELD_SIZE as u32)?;
+    out.write_all(&modulus())?;
+    write_u32(out, num_wires as u32)?;
+    write_u32(out, 0)?; // Public outputs
+    write_u32(out, circuit.inputs().len() as u32)?; // Public inputs
+    write_u32(out, 0)?; // Private inputs
+    write_u64(out, num_wires as u64)?; // Labels
+    write_u32(out, circuit.num_constraints() as u32)?;
+
+    let lc_size = |lc: &Terms| 4 + lc.len() * (4 + FIELD_SIZE);
+    let constraints_size: usize = circuit
+        .constraints()
+        .iter()
+        .map(|(a, b, c)| lc_size(a) + lc_size(b) + lc_size(c))
+        .sum();
+    write_u32(out, R1CS_CONSTRAINTS)?;
+    write_u64(out, constraints_size as u64)?;
+    for (a, b, c) in circuit.constraints() {
+        write_lc(out, circuit, a)?;
+        write_lc(out, circuit, b)?;
+        write_lc(out, circuit, c)?;
+    }
+
+    // Wires are not optimized away, so each one is its own label
+    write_u32(out, R1CS_WIRE_TO_LABEL)?;
+    write_u64(out, 8 * num_wires as u64)?;
+    for i in 0..num_wires {
+        write_u64(out, i as u64)?;
+    }
+    Ok(())
+}
+
+pub fn write_wtns<W: Write>(circuit: &SynthesizedCircuit, out: &mut W) -> io::Result<()> {
+    let num_wires = num_wires(circuit);
+    out.write_all(b"wtns")?;
+    write_u32(out, 2)?; // Version
+    write_u32(out, 2)?; // Number of sections
+
+    write_u32(out, WTNS_HEADER)?;
+    write_u64(out, (4 + FIELD_SIZE + 4) as u64)?;
+    write_u32(out, FIELD_SIZE as u32)?;
+    out.write_all(&modulus())?;
+    write_u32(out, num_wires as u32)?;
+
+    write_u32(out, WTNS_VALUES)?;
+    write_u64(out, (num_wires * FIELD_SIZE) as u64)?;
+    write_field(out, &BellmanFr::one())?;
+    for v in circuit.inputs().iter().chain(circuit.aux().iter()) {
+        write_field(out, v)?;
+    }
+    Ok(())
+}
+
+struct Reader<R: Read>(R);
+
+impl<R: Read> Reader<R> {
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.0.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+    fn field(&mut self) -> io::Result<BellmanFr> {
+        let mut repr = <BellmanFr as PrimeField>::Repr::default();
+        repr.as_mut().copy_from_slice(&self.bytes::<FIELD_SIZE>()?);
+        Option::from(BellmanFr::from_repr(repr))
+            .ok_or_else(|| malformed("field element out of range".into()))
+    }
+    fn lc(&mut self) -> io::Result<Lc> {
+        let num_terms = self.u32()?;
+        (0..num_terms)
+            .map(|_| Ok((self.u32()?, self.field()?)))
+            .collect()
+    }
+    fn skip(&mut self, size: u64) -> io::Result<()> {
+        if io::copy(&mut (&mut self.0).take(size), &mut io::sink())? != size {
+            return Err(malformed("truncated section".into()));
+        }
+        Ok(())
+    }
+    /// Checks the magic and version of the file, returns the number of sections.
+    fn preamble(&mut self, magic: &[u8; 4], version: u32) -> io::Result<u32> {
+        if &self.bytes::<4>()? != magic {
+            return Err(malformed(format!(
+                "not a .{} file",
+                String::from_utf8_lossy(magic)
+            )));
+        }
+        let file_version = self.u32()?;
+        if file_version != version {
+            return Err(malformed(format!("unsupported version {}", file_version)));
+        }
+        self.u32()
+    }
+    fn field_header(&mut self) -> io::Result<()> {
+        let field_size = self.u32()?;
+        if field_size as usize != FIELD_SIZE || self.bytes::<FIELD_SIZE>()? != modulus() {
+            return Err(malformed("field is not the BLS12-381 scalar field".into()));
+        }
+        Ok(())
+    }
+}
+
+pub fn read_r1cs<R: Read>(input: R) -> io::Result<R1cs> {
+    let mut r = Reader(input);
+    let num_sections = r.preamble(b"r1cs", 1)?;
+    let mut header = None;
+    let mut constraints = None;
+    for _ in 0..num_sections {
+        let section = r.u32()?;
+        let size = r.u64()?;
+        match section {
+            R1CS_HEADER => {
+                r.field_header()?;
+                let num_wires = r.u32()?;
+                let num_outputs = r.u32()?;
+                let num_public_inputs = r.u32()?;
+                let _num_private_inputs = r.u32()?;
+                let _num_labels = r.u64()?;
+                let num_constraints = r.u32()?;
+                header = Some((num_wires, num_outputs + num_public_inputs, num_constraints));
+            }
+            R1CS_CONSTRAINTS => {
+                // Sections may come in any order, so the constraints are only
+                // parsed once their count is known
+                let mut section = Vec::new();
+                (&mut r.0).take(size).read_to_end(&mut section)?;
+                if section.len() as u64 != size {
+                    return Err(malformed("truncated constraints section".into()));
+                }
+                constraints = Some(section);
+            }
+            _ => r.skip(size)?,
+        }
+    }
+    let (num_wires, num_public_inputs, num_constraints) =
+        header.ok_or_else(|| malformed("missing header section".into()))?;
+    let section = constraints.ok_or_else(|| malformed("missing constraints".into()))?;
+    let mut section = Reader(&section[..]);
+    let constraints = (0..num_constraints)
+        .map(|_| Ok((section.lc()?, section.lc()?, section.lc()?)))
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => malformed(format!(
+                "expected {} constraints, the section is too short",
+                num_constraints
+            )),
+            _ => e,
+        })?;
+    if !section.0.is_empty() {
+        return Err(malformed(format!(
+            "expected {} constraints, the section is too long",
+            num_constraints
+        )));
+    }
+    if let Some((wire, _)) = constraints
+        .iter()
+        .flat_map(|(a, b, c)| a.iter().chain(b.iter()).chain(c.iter()))
+        .find(|(wire, _)| *wire >= num_wires)
+    {
+        return Err(malformed(format!("wire {} out of range", wire)));
+    }
+    Ok(R1cs {
+        num_wires,
+        num_public_inputs,
+        constraints,
+    })
+}
+
+pub fn read_wtns<R: Read>(input: R) -> io::Result<Vec<BellmanFr>> {
+    let mut r = Reader(input);
+    let num_sections = r.preamble(b"wtns", 2)?;
+    let mut num_values = None;
+    let mut values = None;
+    for _ in 0..num_sections {
+        let section = r.u32()?;
+        let size = r.u64()?;
+        match section {
+            WTNS_HEADER => {
+                r.field_header()?;
+                num_values = Some(r.u32()?);
+            }
+            WTNS_VALUES => {
+                values = Some(
+                    (0..size / FIELD_SIZE as u64)
+                        .map(|_| r.field())
+                        .collect::<io::Result<Vec<_>>>()?,
+                );
+            }
+            _ => r.skip(size)?,
+        }
+    }
+    let values = values.ok_or_else(|| malformed("missing witness values".into()))?;
+    if num_values != Some(values.len() as u32) {
+        return Err(malformed("witness size doesn't match its header".into()));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bellman::{Circuit, ConstraintSystem, SynthesisError};
+
+    /// Proves knowledge of `x` and `y` such that `x * y` is the input.
+    struct ProductCircuit {
+        x: u64,
+        y: u64,
+    }
+
+    impl Circuit<BellmanFr> for ProductCircuit {
+        fn synthesize<CS: ConstraintSystem<BellmanFr>>(
+            self,
+            cs: &mut CS,
+        ) -> Result<(), SynthesisError> {
+            let product = cs.alloc_input(|| "product", || Ok(BellmanFr::from(self.x * self.y)))?;
+            let x = cs.alloc(|| "x", || Ok(BellmanFr::from(self.x)))?;
+            let y = cs.alloc(|| "y", || Ok(BellmanFr::from(self.y)))?;
+            cs.enforce(|| "product", |lc| lc + x, |lc| lc + y, |lc| lc + product);
+            // Neither factor is one
+            for (name, v) in [("x", x), ("y", y)] {
+                let inv = cs.alloc(
+                    || format!("{} - 1 inverse", name),
+                    || {
+                        let value = BellmanFr::from(if name == "x" { self.x } else { self.y });
+                        Ok(Option::from((value - BellmanFr::one()).invert())
+                            .unwrap_or(BellmanFr::zero()))
+                    },
+                )?;
+                cs.enforce(
+                    || format!("{} != 1", name),
+                    |lc| lc + v - CS::one(),
+                    |lc| lc + inv,
+                    |lc| lc + CS::one(),
+                );
+            }
+            Ok(())
+        }
+    }
+
+    fn files() -> (SynthesizedCircuit, Vec<u8>, Vec<u8>) {
+        let synthesized = SynthesizedCircuit::synthesize(ProductCircuit { x: 3, y: 7 }).unwrap();
+        let mut r1cs_file = Vec::new();
+        let mut wtns_file = Vec::new();
+        write_r1cs(&synthesized, &mut r1cs_file).unwrap();
+        write_wtns(&synthesized, &mut wtns_file).unwrap();
+        (synthesized, r1cs_file, wtns_file)
+    }
+
+    #[test]
+    fn test_r1cs_roundtrip() {
+        let (synthesized, r1cs_file, wtns_file) = files();
+        let constraints = read_r1cs(&r1cs_file[..]).unwrap();
+        let mut witness = read_wtns(&wtns_file[..]).unwrap();
+        assert_eq!(constraints.constraints.len(), synthesized.num_constraints());
+        assert_eq!(constraints.num_public_inputs, 1);
+        assert_eq!(constraints.first_unsatisfied(&witness).unwrap(), None);
+
+        // Tampering with the witness should break a constraint
+        let last = witness.len() - 1;
+        witness[last] += BellmanFr::one();
+        assert!(constraints.first_unsatisfied(&witness).unwrap().is_some());
+    }
+
+    // Offset of the constraint count in the header, which is the first section
+    const NUM_CONSTRAINTS_OFFSET: usize = 4 + 4 + 4 + 4 + 8 + 4 + FIELD_SIZE + 4 * 4 + 8;
+
+    fn with_num_constraints(r1cs_file: &[u8], num_constraints: u32) -> Vec<u8> {
+        let mut file = r1cs_file.to_vec();
+        file[NUM_CONSTRAINTS_OFFSET..NUM_CONSTRAINTS_OFFSET + 4]
+            .copy_from_slice(&num_constraints.to_le_bytes());
+        file
+    }
+
+    #[test]
+    fn test_r1cs_constraint_count_mismatch() {
+        let (synthesized, r1cs_file, _) = files();
+        let num_constraints = synthesized.num_constraints() as u32;
+        assert_eq!(
+            read_r1cs(&with_num_constraints(&r1cs_file, num_constraints)[..])
+                .unwrap()
+                .constraints
+                .len(),
+            num_constraints as usize
+        );
+        for wrong in [num_constraints - 1, num_constraints + 1] {
+            let err = read_r1cs(&with_num_constraints(&r1cs_file, wrong)[..]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_r1cs_truncated() {
+        let (_, r1cs_file, _) = files();
+        for len in [10, r1cs_file.len() / 2, r1cs_file.len() - 1] {
+            assert!(read_r1cs(&r1cs_file[..len]).is_err());
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use crate::{ErrorClass, ZoroError};
+
+use bazuka::client::messages::{GetMpnWorkResponse, PostMpnSolutionResponse};
+use bazuka::core::Address;
+use bazuka::mpn::MpnWork;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A single request made through a `NodeClient`, and what the node answered.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Exchange {
+    pub seq: usize,
+    /// Milliseconds since the beginning of the session
+    pub at_ms: u64,
+    pub node: String,
+    pub method: String,
+    pub request: serde_json::Value,
+    pub response: Result<serde_json::Value, RecordedError>,
+    pub elapsed_ms: u64,
+}
+
+/// A failed request. The class of the error is kept, so that the replayed
+/// failure is handled like the original one.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RecordedError {
+    pub class: ErrorClass,
+    pub message: String,
+}
+
+pub struct Recorder {
+    started: Instant,
+    seq: usize,
+    out: BufWriter<File>,
+}
+
+/// Exchanges are looked up by method and request, so that concurrent
+/// requests (E.g of several workers) get their own responses, whatever order
+/// they're made in.
+pub struct Replayer {
+    started: Instant,
+    exchanges: HashMap<String, VecDeque<Exchange>>,
+}
+
+impl Replayer {
+    fn take(&mut self, method: &str, request: &serde_json::Value) -> Result<Exchange, ZoroError> {
+        let recorded = self
+            .exchanges
+            .get_mut(method)
+            .filter(|q| !q.is_empty())
+            .ok_or(ZoroError::ReplayFinished)?;
+        let index = recorded
+            .iter()
+            .position(|e| e.request == *request)
+            .ok_or_else(|| ZoroError::ReplayMismatch(format!("{} {}", method, request)))?;
+        Ok(recorded.remove(index).unwrap())
+    }
+}
+
+/// Traffic of the validator sessions is either written to a file, or read
+/// back from one instead of talking to the network.
+pub enum Session {
+    Record(Mutex<Recorder>),
+    Replay(Mutex<Replayer>),
+}
+
+pub fn record(path: &Path) -> Result<Arc<Session>, ZoroError> {
+    let recorder = Recorder {
+        started: Instant::now(),
+        seq: 0,
+        out: BufWriter::new(File::create(path)?),
+    };
+    Ok(Arc::new(Session::Record(Mutex::new(recorder))))
+}
+
+pub fn replay(path: &Path) -> Result<Arc<Session>, ZoroError> {
+    let mut exchanges = HashMap::<String, VecDeque<Exchange>>::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let exchange: Exchange = serde_json::from_str(&line)?;
+        exchanges
+            .entry(exchange.method.clone())
+            .or_default()
+            .push_back(exchange);
+    }
+    let replayer = Replayer {
+        started: Instant::now(),
+        exchanges,
+    };
+    Ok(Arc::new(Session::Replay(Mutex::new(replayer))))
+}
+
+/// Works the nodes handed out during a recorded session, along with the
+/// sequence number of the exchange and the address they were requested for.
+pub fn recorded_works(path: &Path) -> Result<Vec<(usize, Address, usize, MpnWork)>, ZoroError> {
+    let mut works = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let exchange: Exchange = serde_json::from_str(&line)?;
+        if exchange.method != "get_mpn_works" {
+            continue;
+        }
+        let Ok(response) = exchange.response else {
+            continue;
+        };
+        let address: Address = serde_json::from_value(exchange.request["address"].clone())?;
+        let response: GetMpnWorkResponse = serde_json::from_value(response)?;
+        let mut ids = response.works.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+        for id in ids {
+            works.push((
+                exchange.seq,
+                address.clone(),
+                id,
+                response.works[&id].clone(),
+            ));
+        }
+    }
+    Ok(works)
+}
+
+/// Recorded works whose proofs the node accepted, along with the sequence
+/// number of the exchange they were handed out in and the address they were
+/// requested for. The node only tells how many proofs it accepted, so only the
+/// submissions it accepted entirely are taken into account.
+pub fn accepted_works(path: &Path) -> Result<Vec<(usize, Address, usize, MpnWork)>, ZoroError> {
+    let mut handed_out = HashMap::<(String, usize), (usize, MpnWork)>::new();
+    let mut works = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let exchange: Exchange = serde_json::from_str(&line)?;
+        let Ok(response) = exchange.response else {
+            continue;
+        };
+        let requester = exchange.request["address"].to_string();
+        match exchange.method.as_str() {
+            "get_mpn_works" => {
+                let response: GetMpnWorkResponse = serde_json::from_value(response)?;
+                for (id, work) in response.works {
+                    handed_out.insert((requester.clone(), id), (exchange.seq, work));
+                }
+            }
+            "post_mpn_solution" => {
+                let address: Address = serde_json::from_value(exchange.request["address"].clone())?;
+                let ids: Vec<usize> = serde_json::from_value(exchange.request["works"].clone())?;
+                let response: PostMpnSolutionResponse = serde_json::from_value(response)?;
+                if response.accepted != ids.len() {
+                    continue;
+                }
+                for id in ids {
+                    if let Some((seq, work)) = handed_out.remove(&(requester.clone(), id)) {
+                        works.push((seq, address.clone(), id, work));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    works.sort_by_key(|(seq, _, id, _)| (*seq, *id));
+    Ok(works)
+}
+
+impl Session {
+    /// Runs the request (Or replays it) and records the exchange if needed.
+    pub async fn exchange<R, Fut>(
+        &self,
+        node: String,
+        method: &'static str,
+        request: serde_json::Value,
+        f: Fut,
+    ) -> Result<R, ZoroError>
+    where
+        R: Serialize + DeserializeOwned,
+        Fut: std::future::Future<Output = Result<R, ZoroError>>,
+    {
+        match self {
+            Session::Record(recorder) => {
+                let start = Instant::now();
+                let result = f.await;
+                let mut recorder = recorder.lock().unwrap();
+                let exchange = Exchange {
+                    seq: recorder.seq,
+                    at_ms: start.duration_since(recorder.started).as_millis() as u64,
+                    node,
+                    method: method.into(),
+                    request,
+                    response: match &result {
+                        Ok(resp) => Ok(serde_json::to_value(resp)?),
+                        Err(e) => Err(RecordedError {
+                            class: e.class(),
+                            message: e.to_string(),
+                        }),
+                    },
+                    elapsed_ms: start.elapsed().as_millis() as u64,
+                };
+                recorder.seq += 1;
+                serde_json::to_writer(&mut recorder.out, &exchange)?;
+                writeln!(recorder.out)?;
+                recorder.out.flush()?;
+                result
+            }
+            Session::Replay(replayer) => {
+                let (exchange, started) = {
+                    let mut replayer = replayer.lock().unwrap();
+                    (replayer.take(method, &request)?, replayer.started)
+                };
+                // Keep the recorded timing, so that races happen the same way
+                let sent_at = started + Duration::from_millis(exchange.at_ms);
+                let respond_at = sent_at + Duration::from_millis(exchange.elapsed_ms);
+                tokio::time::sleep_until(respond_at.max(Instant::now()).into()).await;
+                match exchange.response {
+                    Ok(resp) => Ok(serde_json::from_value(resp)?),
+                    Err(e) => Err(ZoroError::ReplayedError(e.class, e.message)),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const NODE: &str = "127.0.0.1:8765";
+
+    async fn not_sent<R>() -> Result<R, ZoroError> {
+        unreachable!("replayed requests are not sent")
+    }
+
+    async fn replayed<R: Serialize + DeserializeOwned>(
+        session: &Session,
+        method: &'static str,
+        request: serde_json::Value,
+    ) -> Result<R, ZoroError> {
+        session
+            .exchange(NODE.into(), method, request, not_sent())
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_replay_fixture() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/fixtures/session.jsonl");
+        let session = replay(&path).unwrap();
+
+        let claim: Option<u64> = replayed(&session, "validator_claim", serde_json::Value::Null)
+            .await
+            .unwrap();
+        assert_eq!(claim, None);
+
+        // Responses are matched to requests, not to the order they were made in
+        let err = replayed::<u64>(&session, "get_mpn_works", json!({ "address": "bob" }))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ZoroError::ReplayedError(ErrorClass::Transient, _)
+        ));
+        assert_eq!(err.class(), ErrorClass::Transient);
+        let works: u64 = replayed(&session, "get_mpn_works", json!({ "address": "alice" }))
+            .await
+            .unwrap();
+        assert_eq!(works, 1);
+
+        let err = replayed::<u64>(
+            &session,
+            "post_mpn_solution",
+            json!({ "address": "alice", "works": [1] }),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, ZoroError::ReplayMismatch(_)));
+        assert_eq!(err.class(), ErrorClass::Fatal);
+        let err = replayed::<u64>(
+            &session,
+            "post_mpn_solution",
+            json!({ "address": "alice", "works": [0] }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.class(), ErrorClass::Expected);
+        assert_eq!(
+            err.to_string(),
+            "replayed error: you are not the validator anymore!"
+        );
+
+        let err = replayed::<u64>(&session, "get_mpn_works", json!({ "address": "alice" }))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ZoroError::ReplayFinished));
+    }
+
+    #[tokio::test]
+    async fn test_record_replay_roundtrip() {
+        let path = std::env::temp_dir().join(format!("zoro-session-{}.jsonl", std::process::id()));
+        let recorder = record(&path).unwrap();
+        for (method, request, response) in [
+            ("validator_claim", serde_json::Value::Null, Ok::<u64, _>(7)),
+            ("get_mpn_works", json!({ "address": "alice" }), Ok(1)),
+            ("get_mpn_works", json!({ "address": "alice" }), Ok(2)),
+            (
+                "post_mpn_solution",
+                json!({ "address": "alice", "works": [0, 1] }),
+                Err(ZoroError::NotValidator),
+            ),
+            (
+                "validator_claim",
+                serde_json::Value::Null,
+                Err(ZoroError::NoPeersAvailable),
+            ),
+        ] {
+            // The recorded requests still get their responses
+            let succeeded = response.is_ok();
+            let result = recorder
+                .exchange(NODE.into(), method, request, async move { response })
+                .await;
+            assert_eq!(result.is_ok(), succeeded);
+        }
+        drop(recorder);
+
+        let session = replay(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let err = replayed::<u64>(
+            &session,
+            "post_mpn_solution",
+            json!({ "address": "alice", "works": [0, 1] }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.class(), ErrorClass::Expected);
+        // Identical requests are answered in the recorded order
+        for expected in [1, 2] {
+            let works: u64 = replayed(&session, "get_mpn_works", json!({ "address": "alice" }))
+                .await
+                .unwrap();
+            assert_eq!(works, expected);
+        }
+        let claim: u64 = replayed(&session, "validator_claim", serde_json::Value::Null)
+            .await
+            .unwrap();
+        assert_eq!(claim, 7);
+        let err = replayed::<u64>(&session, "validator_claim", serde_json::Value::Null)
+            .await
+            .unwrap_err();
+        assert_eq!(err.class(), ErrorClass::Transient);
+        assert_eq!(
+            err.to_string(),
+            "replayed error: none of the peers are available!"
+        );
+    }
+}
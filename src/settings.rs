@@ -0,0 +1,327 @@
+use crate::events::LogFormat;
+use crate::mock::{self, MockFaults};
+use crate::notify::NotifySettings;
+use crate::pools::CpuSet;
+use crate::profiles::{self, NetworkProfile, ProfileSettings};
+use crate::shapes::Shape;
+use crate::{Optimization, ProveOpt};
+
+use bazuka::client::PeerAddress;
+use bazuka::core::Address;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SettingsError {
+    #[error("cannot read config file {0}: {1}")]
+    IoError(PathBuf, std::io::Error),
+    #[error("cannot parse config file: {0}")]
+    TomlError(#[from] toml::de::Error),
+    #[error("cannot parse config file: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("`{0}` is not given, neither in the config file nor on the command line")]
+    Missing(&'static str),
+    #[error("invalid `{0}`: {1}")]
+    Invalid(&'static str, String),
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParamsSettings {
+    pub update: Option<PathBuf>,
+    pub deposit: Option<PathBuf>,
+    pub withdraw: Option<PathBuf>,
+    pub multi_fee_update: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GpuSettings {
+    pub enabled: Option<bool>,
+    pub devices: Option<Vec<usize>>,
+    pub tuning: Option<Optimization>,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThreadSettings {
+    pub synthesis: Option<usize>,
+    pub msm: Option<usize>,
+    pub cpu_set: Option<String>,
+    pub pin: Option<bool>,
+    pub synthesis_queue: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeadlineSettings {
+    pub max_proving_time: Option<u64>,
+    pub learn: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSettings {
+    pub level: Option<String>,
+    pub format: Option<LogFormat>,
+}
+
+/// Contents of the config file. Everything is optional, since all of them
+/// can also be given (Or overridden) on the command line.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub network: Option<String>,
+    pub peers: Vec<String>,
+    pub address: Option<String>,
+    pub shape: Option<Shape>,
+    pub params: ParamsSettings,
+    pub gpu: GpuSettings,
+    pub workers: Option<usize>,
+    pub threads: ThreadSettings,
+    pub deadline: DeadlineSettings,
+    pub logging: LoggingSettings,
+    pub notify: NotifySettings,
+    pub networks: HashMap<String, ProfileSettings>,
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    home::home_dir().map(|h| h.join(".zoro").join("config.toml"))
+}
+
+impl Settings {
+    fn parse(path: &Path, content: &str) -> Result<Self, SettingsError> {
+        if path.extension().map(|e| e == "json").unwrap_or(false) {
+            Ok(serde_json::from_str(content)?)
+        } else {
+            Ok(toml::from_str(content)?)
+        }
+    }
+
+    /// Loads the given config file, or the default one if it exists.
+    pub fn load(path: Option<&Path>) -> Result<Self, SettingsError> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+        let content =
+            std::fs::read_to_string(&path).map_err(|e| SettingsError::IoError(path.clone(), e))?;
+        Self::parse(&path, &content)
+    }
+}
+
+/// A switch given by a `--x`/`--no-x` pair of flags, which both override the
+/// config file.
+fn flag(on: bool, off: bool, setting: Option<bool>) -> bool {
+    if on {
+        true
+    } else if off {
+        false
+    } else {
+        setting.unwrap_or(false)
+    }
+}
+
+/// Final configuration of `zoro prove`, after applying command line overrides.
+#[derive(Debug, Clone)]
+pub struct ProveConfig {
+    pub profile: NetworkProfile,
+    pub network: String,
+    pub peers: Vec<PeerAddress>,
+    pub address: Address,
+    pub shape: Shape,
+    pub update_circuit_params: PathBuf,
+    pub deposit_circuit_params: PathBuf,
+    pub withdraw_circuit_params: PathBuf,
+    pub multi_fee_update_circuit_params: PathBuf,
+    pub gpu: bool,
+    pub gpu_devices: Option<Vec<usize>>,
+    pub gpu_tuning: Option<Optimization>,
+    pub workers: usize,
+    pub synthesis_queue: usize,
+    pub synthesis_threads: Option<usize>,
+    pub msm_threads: Option<usize>,
+    pub cpu_set: Option<CpuSet>,
+    pub pin_threads: bool,
+    pub max_proving_time: u64,
+    pub learn_deadline: bool,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    pub notify: NotifySettings,
+    pub mock: Option<MockFaults>,
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+}
+
+impl ProveConfig {
+    pub fn resolve(opt: &ProveOpt, settings: Settings) -> Result<Self, SettingsError> {
+        let mock = opt.mock_node.then(|| MockFaults {
+            validator_switch: opt.mock_validator_switch.map(Duration::from_secs),
+            slow_responses: opt.mock_slow_responses.map(Duration::from_millis),
+            invalid_works: opt.mock_invalid_works,
+        });
+
+        let network = opt
+            .network
+            .clone()
+            .or(settings.network)
+            .or_else(|| mock.as_ref().map(|_| "mock".into()))
+            .or_else(|| opt.replay.as_ref().map(|_| "replay".into()))
+            .ok_or(SettingsError::Missing("network"))?;
+
+        let profile = if mock.is_some() || opt.replay.is_some() {
+            profiles::lookup(&network, &settings.networks)?
+                .unwrap_or_else(|| NetworkProfile::unknown(&network))
+        } else {
+            profiles::find(&network, &settings.networks)?
+        };
+
+        let mut peers = if mock.is_some() {
+            vec![mock::validator_peer(0)]
+        } else if !opt.connect.is_empty() {
+            opt.connect.clone()
+        } else if !settings.peers.is_empty() {
+            profiles::parse_peers(&settings.peers)?
+        } else {
+            profile.peers.clone()
+        };
+        if peers.is_empty() {
+            if opt.replay.is_some() {
+                // The recorded nodes are answering anyway
+                peers.push(mock::validator_peer(0));
+            } else {
+                return Err(SettingsError::Missing("peers"));
+            }
+        }
+
+        let address = match (&opt.address, &settings.address) {
+            (Some(addr), _) => addr.clone(),
+            (None, Some(addr)) => addr
+                .parse::<Address>()
+                .map_err(|_| SettingsError::Invalid("address", addr.clone()))?,
+            (None, None) => return Err(SettingsError::Missing("address")),
+        };
+
+        let cpu_set = match (&opt.cpu_set, &settings.threads.cpu_set) {
+            (Some(cpu_set), _) => Some(cpu_set.clone()),
+            (None, Some(cpu_set)) => Some(
+                cpu_set
+                    .parse::<CpuSet>()
+                    .map_err(|e| SettingsError::Invalid("threads.cpu_set", e.to_string()))?,
+            ),
+            (None, None) => None,
+        };
+
+        let workers = opt.workers.or(settings.workers).unwrap_or(1);
+        if workers == 0 {
+            return Err(SettingsError::Invalid("workers", "0".into()));
+        }
+        let synthesis_queue = opt
+            .synthesis_queue
+            .or(settings.threads.synthesis_queue)
+            .unwrap_or(2);
+        if synthesis_queue == 0 {
+            return Err(SettingsError::Invalid(
+                "threads.synthesis_queue",
+                "0".into(),
+            ));
+        }
+
+        let shape = opt.shape.or(settings.shape).unwrap_or(profile.shape);
+
+        Ok(Self {
+            network: profile.network.clone(),
+            peers,
+            address,
+            shape,
+            update_circuit_params: opt
+                .update_circuit_params
+                .clone()
+                .or(settings.params.update)
+                .unwrap_or_else(|| profile.params_path(shape, "update")),
+            deposit_circuit_params: opt
+                .deposit_circuit_params
+                .clone()
+                .or(settings.params.deposit)
+                .unwrap_or_else(|| profile.params_path(shape, "deposit")),
+            withdraw_circuit_params: opt
+                .withdraw_circuit_params
+                .clone()
+                .or(settings.params.withdraw)
+                .unwrap_or_else(|| profile.params_path(shape, "withdraw")),
+            multi_fee_update_circuit_params: opt
+                .multi_fee_update_circuit_params
+                .clone()
+                .or(settings.params.multi_fee_update)
+                .unwrap_or_else(|| profile.params_path(shape, "multi_fee_update")),
+            gpu: flag(opt.gpu, opt.no_gpu, settings.gpu.enabled),
+            gpu_devices: if !opt.gpu_devices.is_empty() {
+                Some(opt.gpu_devices.clone())
+            } else {
+                settings.gpu.devices
+            },
+            gpu_tuning: settings.gpu.tuning,
+            workers,
+            synthesis_queue,
+            synthesis_threads: opt.synthesis_threads.or(settings.threads.synthesis),
+            msm_threads: opt.msm_threads.or(settings.threads.msm),
+            cpu_set,
+            pin_threads: flag(opt.pin_threads, opt.no_pin_threads, settings.threads.pin),
+            max_proving_time: opt
+                .max_proving_time
+                .or(settings.deadline.max_proving_time)
+                .unwrap_or(50),
+            learn_deadline: flag(
+                opt.learn_deadline,
+                opt.no_learn_deadline,
+                settings.deadline.learn,
+            ),
+            log_level: settings.logging.level.unwrap_or_else(|| "info".into()),
+            log_format: opt
+                .log_format
+                .or(settings.logging.format)
+                .unwrap_or(LogFormat::Text),
+            notify: settings.notify,
+            mock,
+            record: opt.record.clone(),
+            replay: opt.replay.clone(),
+            profile,
+        })
+    }
+
+    /// Problems which would only show up after the prover has started.
+    pub fn check(&self) -> Result<(), SettingsError> {
+        for (name, path) in [
+            ("params.update", &self.update_circuit_params),
+            ("params.deposit", &self.deposit_circuit_params),
+            ("params.withdraw", &self.withdraw_circuit_params),
+        ] {
+            if !path.exists() {
+                return Err(SettingsError::Invalid(
+                    name,
+                    format!("{} does not exist", path.to_string_lossy()),
+                ));
+            }
+        }
+        for webhook in self.notify.webhooks.iter() {
+            if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                return Err(SettingsError::Invalid(
+                    "notify.webhooks.url",
+                    webhook.url.clone(),
+                ));
+            }
+        }
+        if self.log_level.parse::<log::LevelFilter>().is_err() {
+            return Err(SettingsError::Invalid(
+                "logging.level",
+                self.log_level.clone(),
+            ));
+        }
+        Ok(())
+    }
+}
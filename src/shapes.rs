@@ -0,0 +1,440 @@
+use crate::bank::{BankError, ZoroParams, ZoroVerifyKeys, ZoroWork};
+use crate::circuits;
+use crate::config::{self, Dimensions};
+use crate::debug::Unsatisfied;
+use crate::stats::CircuitStats;
+use crate::synthesis::SynthesizedCircuit;
+use crate::{load_params, to_zoro_work};
+
+use bazuka::core::{Address, TokenId};
+use bazuka::mpn::MpnWork;
+use bellman::groth16::Backend;
+use bellman::SynthesisError;
+use bls12_381::Bls12;
+use rand::Rng;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+/// Where the params of the circuits of a shape are stored.
+#[derive(Debug, Clone)]
+pub struct ParamPaths {
+    pub update: PathBuf,
+    pub deposit: PathBuf,
+    pub withdraw: PathBuf,
+    /// Optional, only loaded when the file exists
+    pub multi_fee_update: PathBuf,
+}
+
+// Circuits are generic over their dimensions, so every supported shape is a
+// separate instantiation of them. This generates the enums dispatching
+// between those instantiations at runtime.
+macro_rules! shapes {
+    ($($shape:ident($name:literal) => $dims:ident),* $(,)?) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        pub enum Shape {
+            $($shape),*
+        }
+
+        impl Shape {
+            pub const ALL: &'static [Shape] = &[$(Shape::$shape),*];
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Shape::$shape => $name),*
+                }
+            }
+
+            pub fn dimensions(&self) -> Dimensions {
+                match self {
+                    $(Shape::$shape => config::$dims),*
+                }
+            }
+
+            pub fn work(&self, address: Address, work: MpnWork, fee_token: TokenId) -> ShapedWork {
+                match self {
+                    $(Shape::$shape => ShapedWork::$shape(to_zoro_work(
+                        address,
+                        work,
+                        fee_token,
+                        config::$dims.strict_circuits,
+                    ))),*
+                }
+            }
+
+            /// Loads the params of all circuits of this shape, or generates
+            /// (And writes) them when a `rng` is given. The params of the
+            /// multi-fee update circuit are skipped when not generated yet.
+            pub fn load_params<R: Rng + Clone>(&self, paths: &ParamPaths, rng: Option<R>) -> ZoroParams {
+                match self {
+                    $(Shape::$shape => ZoroParams {
+                        shape: *self,
+                        deposit: load_params(
+                            circuits::DepositCircuit::<
+                                { config::$dims.log4_deposit_batch_size },
+                                { config::$dims.log4_tree_size },
+                                { config::$dims.log4_tokens_tree_size },
+                            > {
+                                strict: config::$dims.strict_circuits,
+                                ..Default::default()
+                            },
+                            paths.deposit.clone(),
+                            rng.clone(),
+                        ),
+                        withdraw: load_params(
+                            circuits::WithdrawCircuit::<
+                                { config::$dims.log4_withdraw_batch_size },
+                                { config::$dims.log4_tree_size },
+                                { config::$dims.log4_tokens_tree_size },
+                            > {
+                                strict: config::$dims.strict_circuits,
+                                ..Default::default()
+                            },
+                            paths.withdraw.clone(),
+                            rng.clone(),
+                        ),
+                        update: load_params(
+                            circuits::UpdateCircuit::<
+                                { config::$dims.log4_update_batch_size },
+                                { config::$dims.log4_tree_size },
+                                { config::$dims.log4_tokens_tree_size },
+                            > {
+                                strict: config::$dims.strict_circuits,
+                                ..Default::default()
+                            },
+                            paths.update.clone(),
+                            rng.clone(),
+                        ),
+                        multi_fee_update: (rng.is_some() || paths.multi_fee_update.exists())
+                            .then(|| {
+                                load_params(
+                                    circuits::MultiFeeUpdateCircuit::<
+                                        { config::$dims.log4_update_batch_size },
+                                        { config::$dims.log4_tree_size },
+                                        { config::$dims.log4_tokens_tree_size },
+                                    > {
+                                        strict: config::$dims.strict_circuits,
+                                        ..Default::default()
+                                    },
+                                    paths.multi_fee_update.clone(),
+                                    rng,
+                                )
+                            }),
+                    }),*
+                }
+            }
+
+            /// Synthesizes the empty instance of a circuit of this shape, for
+            /// exporting its constraints. None if there is no such circuit.
+            pub fn synthesize_circuit(&self, circuit: &str) -> Result<Option<SynthesizedCircuit>, SynthesisError> {
+                match self {
+                    $(Shape::$shape => Ok(Some(match circuit {
+                        "update" => SynthesizedCircuit::synthesize(circuits::UpdateCircuit::<
+                            { config::$dims.log4_update_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        > {
+                            strict: config::$dims.strict_circuits,
+                            ..Default::default()
+                        })?,
+                        "multi_fee_update" => SynthesizedCircuit::synthesize(circuits::MultiFeeUpdateCircuit::<
+                            { config::$dims.log4_update_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        > {
+                            strict: config::$dims.strict_circuits,
+                            ..Default::default()
+                        })?,
+                        "key_rotation" => SynthesizedCircuit::synthesize(circuits::KeyRotationCircuit::<
+                            { config::$dims.log4_update_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        >::default())?,
+                        "multi_transfer" => SynthesizedCircuit::synthesize(circuits::MultiTransferCircuit::<
+                            { config::$dims.log4_update_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        >::default())?,
+                        "swap" => SynthesizedCircuit::synthesize(circuits::SwapCircuit::<
+                            { config::$dims.log4_update_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        >::default())?,
+                        "sweep" => SynthesizedCircuit::synthesize(circuits::SweepCircuit::<
+                            { config::$dims.log4_update_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        >::default())?,
+                        "deposit" => SynthesizedCircuit::synthesize(circuits::DepositCircuit::<
+                            { config::$dims.log4_deposit_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        > {
+                            strict: config::$dims.strict_circuits,
+                            ..Default::default()
+                        })?,
+                        "withdraw" => SynthesizedCircuit::synthesize(circuits::WithdrawCircuit::<
+                            { config::$dims.log4_withdraw_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        > {
+                            strict: config::$dims.strict_circuits,
+                            ..Default::default()
+                        })?,
+                        _ => return Ok(None),
+                    }))),*
+                }
+            }
+
+            /// Synthesizes the empty instance of a circuit of this shape, and
+            /// measures it. None if there is no such circuit.
+            pub fn circuit_stats(&self, circuit: &str) -> Result<Option<CircuitStats>, SynthesisError> {
+                match self {
+                    $(Shape::$shape => Ok(Some(match circuit {
+                        "update" => CircuitStats::measure(circuits::UpdateCircuit::<
+                            { config::$dims.log4_update_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        > {
+                            strict: config::$dims.strict_circuits,
+                            ..Default::default()
+                        })?,
+                        "multi_fee_update" => CircuitStats::measure(circuits::MultiFeeUpdateCircuit::<
+                            { config::$dims.log4_update_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        > {
+                            strict: config::$dims.strict_circuits,
+                            ..Default::default()
+                        })?,
+                        "key_rotation" => CircuitStats::measure(circuits::KeyRotationCircuit::<
+                            { config::$dims.log4_update_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        >::default())?,
+                        "multi_transfer" => CircuitStats::measure(circuits::MultiTransferCircuit::<
+                            { config::$dims.log4_update_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        >::default())?,
+                        "swap" => CircuitStats::measure(circuits::SwapCircuit::<
+                            { config::$dims.log4_update_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        >::default())?,
+                        "sweep" => CircuitStats::measure(circuits::SweepCircuit::<
+                            { config::$dims.log4_update_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        >::default())?,
+                        "deposit" => CircuitStats::measure(circuits::DepositCircuit::<
+                            { config::$dims.log4_deposit_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        > {
+                            strict: config::$dims.strict_circuits,
+                            ..Default::default()
+                        })?,
+                        "withdraw" => CircuitStats::measure(circuits::WithdrawCircuit::<
+                            { config::$dims.log4_withdraw_batch_size },
+                            { config::$dims.log4_tree_size },
+                            { config::$dims.log4_tokens_tree_size },
+                        > {
+                            strict: config::$dims.strict_circuits,
+                            ..Default::default()
+                        })?,
+                        _ => return Ok(None),
+                    }))),*
+                }
+            }
+        }
+
+        /// A work, along with the circuit instantiation it's proven with.
+        #[derive(Clone, serde::Serialize, serde::Deserialize)]
+        pub enum ShapedWork {
+            $(
+                $shape(
+                    ZoroWork<
+                        { config::$dims.log4_deposit_batch_size },
+                        { config::$dims.log4_withdraw_batch_size },
+                        { config::$dims.log4_update_batch_size },
+                        { config::$dims.log4_tree_size },
+                        { config::$dims.log4_tokens_tree_size },
+                    >,
+                )
+            ),*
+        }
+
+        impl ShapedWork {
+            pub fn shape(&self) -> Shape {
+                match self {
+                    $(ShapedWork::$shape(_) => Shape::$shape),*
+                }
+            }
+
+            pub fn circuit_name(&self) -> &'static str {
+                match self {
+                    $(ShapedWork::$shape(work) => work.circuit.name()),*
+                }
+            }
+
+            pub fn fee_tokens(&self) -> Vec<TokenId> {
+                match self {
+                    $(ShapedWork::$shape(work) => work.circuit.fee_tokens()),*
+                }
+            }
+
+            pub fn verify(
+                &self,
+                params: &ZoroVerifyKeys,
+                proof: &bazuka::zk::groth16::Groth16Proof,
+            ) -> bool {
+                match self {
+                    $(ShapedWork::$shape(work) => work.verify(params, proof)),*
+                }
+            }
+
+            pub fn check_proof(
+                &self,
+                params: &ZoroParams,
+                proof: bellman::groth16::Proof<Bls12>,
+            ) -> Result<bazuka::zk::groth16::Groth16Proof, BankError> {
+                self.check_shape(params)?;
+                match self {
+                    $(ShapedWork::$shape(work) => work.check_proof(params, proof)),*
+                }
+            }
+
+            pub fn prove(
+                &self,
+                params: ZoroParams,
+                backend: Backend,
+                cancel: Option<Arc<RwLock<bool>>>,
+            ) -> Result<bazuka::zk::groth16::Groth16Proof, BankError> {
+                self.check_shape(&params)?;
+                match self {
+                    $(ShapedWork::$shape(work) => work.prove(params, backend, cancel)),*
+                }
+            }
+
+            pub fn synthesize(&self) -> Result<SynthesizedCircuit, BankError> {
+                match self {
+                    $(ShapedWork::$shape(work) => work.synthesize()),*
+                }
+            }
+
+            pub fn debug(&self) -> Result<Option<Unsatisfied>, BankError> {
+                match self {
+                    $(ShapedWork::$shape(work) => work.debug()),*
+                }
+            }
+
+            pub fn prove_synthesized_unchecked(
+                &self,
+                synthesized: SynthesizedCircuit,
+                params: &ZoroParams,
+                backend: Backend,
+                cancel: Option<Arc<RwLock<bool>>>,
+            ) -> Result<bellman::groth16::Proof<Bls12>, BankError> {
+                self.check_shape(params)?;
+                match self {
+                    $(ShapedWork::$shape(work) => {
+                        work.prove_synthesized_unchecked(synthesized, params, backend, cancel)
+                    }),*
+                }
+            }
+        }
+    };
+}
+
+shapes! {
+    Mainnet("mainnet") => MAINNET,
+    MainnetStrict("mainnet_strict") => MAINNET_STRICT,
+    Dev("dev") => DEV,
+}
+
+impl Shape {
+    /// Names of the circuits every shape has.
+    pub const CIRCUITS: &'static [&'static str] = &[
+        "update",
+        "multi_fee_update",
+        "key_rotation",
+        "multi_transfer",
+        "swap",
+        "sweep",
+        "deposit",
+        "withdraw",
+    ];
+
+    /// Mainnet keeps the historical file names, the others are suffixed with
+    /// the name of the shape, so that they can live in the same directory.
+    pub fn params_path(&self, circuit: &str) -> PathBuf {
+        match self {
+            Shape::Mainnet => format!("{}_params.dat", circuit).into(),
+            _ => format!("{}_params_{}.dat", circuit, self.name()).into(),
+        }
+    }
+
+    pub fn param_paths(&self) -> ParamPaths {
+        ParamPaths {
+            update: self.params_path("update"),
+            deposit: self.params_path("deposit"),
+            withdraw: self.params_path("withdraw"),
+            multi_fee_update: self.params_path("multi_fee_update"),
+        }
+    }
+}
+
+impl std::fmt::Display for Shape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Shape {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Shape::ALL
+            .iter()
+            .find(|shape| shape.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "unknown shape `{}`, supported shapes are: {}",
+                    s,
+                    Shape::ALL
+                        .iter()
+                        .map(|shape| shape.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+impl ShapedWork {
+    /// Short human-readable summary of the work, e.g for the logs.
+    pub fn describe(&self) -> String {
+        let fee_tokens = self.fee_tokens();
+        if fee_tokens.is_empty() {
+            return self.circuit_name().to_string();
+        }
+        format!(
+            "{} (Fees in {})",
+            self.circuit_name(),
+            fee_tokens
+                .iter()
+                .map(|token| token.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+
+    fn check_shape(&self, params: &ZoroParams) -> Result<(), BankError> {
+        if params.shape != self.shape() {
+            return Err(BankError::ShapeMismatch(self.shape(), params.shape));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+use bellman::{Circuit, ConstraintSystem, Index, LinearCombination, SynthesisError, Variable};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use zeekit::BellmanFr;
+
+/// Size of a circuit, and where its constraints come from.
+#[derive(Debug, Clone, Default)]
+pub struct CircuitStats {
+    pub num_constraints: usize,
+    pub num_variables: usize,
+    pub num_inputs: usize, // Excluding ONE
+    /// Constraints enforced directly in each namespace path, e.g
+    /// `transition 3/signature`
+    pub by_path: HashMap<String, usize>,
+}
+
+/// Constraints of a group of namespaces, e.g of all the `transition N`s.
+#[derive(Debug, Clone, Default)]
+pub struct Group {
+    pub name: String,
+    /// How many times the namespace is repeated (The batch size for the
+    /// transitions)
+    pub instances: usize,
+    pub num_constraints: usize,
+    /// Constraints of each check of the group, per instance
+    pub checks: BTreeMap<String, usize>,
+}
+
+impl CircuitStats {
+    pub fn measure<C: Circuit<BellmanFr>>(circuit: C) -> Result<Self, SynthesisError> {
+        let mut cs = StatsSystem {
+            stats: Self::default(),
+            namespaces: Vec::new(),
+            path: String::new(),
+        };
+        circuit.synthesize(&mut cs)?;
+        Ok(cs.stats)
+    }
+
+    /// Groups the constraints by their top-level namespace, with the indices
+    /// of the repeated ones (`transition 0`, `transition 1`, ...) stripped.
+    pub fn groups(&self) -> Vec<Group> {
+        let mut groups = BTreeMap::<String, Group>::new();
+        let mut instances = HashMap::<String, HashSet<String>>::new();
+        for (path, count) in self.by_path.iter() {
+            let mut parts = path.splitn(2, '/');
+            let top = parts.next().filter(|p| !p.is_empty()).unwrap_or("<root>");
+            let check = parts.next().map(|p| p.split('/').next().unwrap());
+            let name = strip_index(top);
+            instances
+                .entry(name.to_string())
+                .or_default()
+                .insert(top.to_string());
+            let group = groups.entry(name.to_string()).or_insert_with(|| Group {
+                name: name.to_string(),
+                ..Default::default()
+            });
+            group.num_constraints += count;
+            *group
+                .checks
+                .entry(check.unwrap_or("<unnamed>").to_string())
+                .or_default() += count;
+        }
+        let mut groups = groups.into_values().collect::<Vec<_>>();
+        for group in groups.iter_mut() {
+            group.instances = instances[&group.name].len();
+            for count in group.checks.values_mut() {
+                *count /= group.instances;
+            }
+        }
+        groups.sort_by(|a, b| b.num_constraints.cmp(&a.num_constraints));
+        groups
+    }
+}
+
+fn strip_index(name: &str) -> &str {
+    match name.rsplit_once(' ') {
+        Some((prefix, index)) if index.parse::<usize>().is_ok() => prefix,
+        _ => name,
+    }
+}
+
+struct StatsSystem {
+    stats: CircuitStats,
+    namespaces: Vec<String>,
+    path: String,
+}
+
+impl ConstraintSystem<BellmanFr> for StatsSystem {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(&mut self, _annotation: A, _f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.stats.num_variables += 1;
+        Ok(Variable::new_unchecked(Index::Aux(
+            self.stats.num_variables - 1,
+        )))
+    }
+
+    fn alloc_input<F, A, AR>(&mut self, _annotation: A, _f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        // Input 0 is reserved for ONE
+        self.stats.num_inputs += 1;
+        Ok(Variable::new_unchecked(Index::Input(self.stats.num_inputs)))
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(&mut self, _annotation: A, _a: LA, _b: LB, _c: LC)
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+        LA: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LB: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LC: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+    {
+        self.stats.num_constraints += 1;
+        match self.stats.by_path.get_mut(&self.path) {
+            Some(count) => *count += 1,
+            None => {
+                self.stats.by_path.insert(self.path.clone(), 1);
+            }
+        }
+    }
+
+    fn push_namespace<NR, N>(&mut self, name_fn: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+        self.namespaces.push(name_fn().into());
+        self.path = self.namespaces.join("/");
+    }
+
+    fn pop_namespace(&mut self) {
+        self.namespaces.pop();
+        self.path = self.namespaces.join("/");
+    }
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A batch of 3 transitions, each made of a 2-constraint signature check,
+    /// a 1-constraint proof check and an unnamed constraint. Plus a constraint
+    /// at the root, and one in a non-repeated namespace.
+    struct BatchCircuit;
+
+    fn enforce<CS: ConstraintSystem<BellmanFr>>(cs: &mut CS) {
+        cs.enforce(|| "", |lc| lc, |lc| lc, |lc| lc);
+    }
+
+    impl Circuit<BellmanFr> for BatchCircuit {
+        fn synthesize<CS: ConstraintSystem<BellmanFr>>(
+            self,
+            cs: &mut CS,
+        ) -> Result<(), SynthesisError> {
+            cs.alloc_input(|| "input", || Ok(BellmanFr::one()))?;
+            for i in 0..3 {
+                let cs = &mut cs.namespace(|| format!("transition {}", i));
+                cs.alloc(|| "variable", || Ok(BellmanFr::one()))?;
+                enforce(&mut cs.namespace(|| "signature"));
+                enforce(&mut cs.namespace(|| "signature"));
+                enforce(&mut cs.namespace(|| "proof"));
+                enforce(cs);
+            }
+            enforce(&mut cs.namespace(|| "fee sum"));
+            enforce(cs);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_groups() {
+        let stats = CircuitStats::measure(BatchCircuit).unwrap();
+        assert_eq!(stats.num_constraints, 14);
+        assert_eq!(stats.num_variables, 3);
+        assert_eq!(stats.num_inputs, 1);
+        assert_eq!(stats.by_path["transition 1/signature"], 2);
+
+        let groups = stats.groups();
+        assert_eq!(
+            groups
+                .iter()
+                .map(|g| (g.name.as_str(), g.instances, g.num_constraints))
+                .collect::<Vec<_>>(),
+            vec![("transition", 3, 12), ("<root>", 1, 1), ("fee sum", 1, 1)]
+        );
+        // Per transition
+        assert_eq!(
+            groups[0].checks,
+            BTreeMap::from([
+                ("<unnamed>".to_string(), 1),
+                ("proof".to_string(), 1),
+                ("signature".to_string(), 2),
+            ])
+        );
+        assert_eq!(
+            groups[1].checks,
+            BTreeMap::from([("<unnamed>".to_string(), 1)])
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use bellman::{Circuit, ConstraintSystem, Index, LinearCombination, SynthesisError, Variable};
+use zeekit::BellmanFr;
+
+pub type Terms = Vec<(Index, BellmanFr)>;
+
+/// A circuit whose witness has already been calculated. Synthesizing the
+/// original circuits (Poseidon hashes, EdDSA checks...) is the CPU-heavy part
+/// of proving, so it is done ahead of time and the recorded constraints are
+/// replayed when the MSM backend becomes free.
+pub struct SynthesizedCircuit {
+    inputs: Vec<BellmanFr>, // Excluding ONE
+    aux: Vec<BellmanFr>,
+    constraints: Vec<(Terms, Terms, Terms)>,
+}
+
+impl SynthesizedCircuit {
+    pub fn synthesize<C: Circuit<BellmanFr>>(circuit: C) -> Result<Self, SynthesisError> {
+        let mut cs = Self {
+            inputs: Vec::new(),
+            aux: Vec::new(),
+            constraints: Vec::new(),
+        };
+        circuit.synthesize(&mut cs)?;
+        Ok(cs)
+    }
+
+    pub fn num_constraints(&self) -> usize {
+        self.constraints.len()
+    }
+
+    pub fn inputs(&self) -> &[BellmanFr] {
+        &self.inputs
+    }
+
+    pub fn aux(&self) -> &[BellmanFr] {
+        &self.aux
+    }
+
+    pub fn constraints(&self) -> &[(Terms, Terms, Terms)] {
+        &self.constraints
+    }
+}
+
+fn terms(lc: LinearCombination<BellmanFr>) -> Terms {
+    lc.as_ref()
+        .iter()
+        .map(|(v, coeff)| (v.get_unchecked(), *coeff))
+        .collect()
+}
+
+impl ConstraintSystem<BellmanFr> for SynthesizedCircuit {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(&mut self, _annotation: A, f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.aux.push(f()?);
+        Ok(Variable::new_unchecked(Index::Aux(self.aux.len() - 1)))
+    }
+
+    fn alloc_input<F, A, AR>(&mut self, _annotation: A, f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        // Input 0 is reserved for ONE
+        self.inputs.push(f()?);
+        Ok(Variable::new_unchecked(Index::Input(self.inputs.len())))
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(&mut self, _annotation: A, a: LA, b: LB, c: LC)
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+        LA: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LB: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LC: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+    {
+        self.constraints.push((
+            terms(a(LinearCombination::zero())),
+            terms(b(LinearCombination::zero())),
+            terms(c(LinearCombination::zero())),
+        ));
+    }
+
+    fn push_namespace<NR, N>(&mut self, _name_fn: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+    }
+
+    fn pop_namespace(&mut self) {}
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
+
+impl Circuit<BellmanFr> for SynthesizedCircuit {
+    fn synthesize<CS: ConstraintSystem<BellmanFr>>(
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        let mut inputs = vec![CS::one()];
+        for v in self.inputs {
+            inputs.push(cs.alloc_input(|| "", || Ok(v))?);
+        }
+        let mut aux = Vec::with_capacity(self.aux.len());
+        for v in self.aux {
+            aux.push(cs.alloc(|| "", || Ok(v))?);
+        }
+        let lc = |ts: Terms| {
+            let mut lc = LinearCombination::zero();
+            for (index, coeff) in ts {
+                let var = match index {
+                    Index::Input(i) => inputs[i],
+                    Index::Aux(i) => aux[i],
+                };
+                lc = lc + (coeff, var);
+            }
+            lc
+        };
+        for (a, b, c) in self.constraints {
+            cs.enforce(|| "", |_| lc(a), |_| lc(b), |_| lc(c));
+        }
+        Ok(())
+    }
+}